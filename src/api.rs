@@ -0,0 +1,694 @@
+use crate::cli::{build_conf, include_file, keep_prefix, run_captured, UserArgs};
+use crate::lua::{LuaString, STDIN_FILE};
+use crate::types::{ArgError, Error, JitCmd, JitOpts, KeepPrefix, LogLevel, Prefix, Shdict};
+use crate::util::validate_temp_root;
+use crate::RUSTY_CLI;
+use std::ffi::OsString;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Options for a run of Lua code in nginx.
+///
+/// Each method corresponds to a command line option of the `rusty-cli`
+/// binary, and takes the same kind of value, already parsed. Options that
+/// depend on the filesystem (like the files to include) are checked when
+/// the options are used, and errors are reported as [`Error::Args`].
+///
+/// ```no_run
+/// use rusty_cli::{Options, Shdict};
+///
+/// let conf = Options::new()
+///     .shdict(Shdict::new("cache", "1m").expect("valid shdict"))
+///     .eval("ngx.say('hi')")
+///     .render_conf()
+///     .expect("valid options");
+///
+/// assert!(conf.contains("lua_shared_dict cache 1m;"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    eval: Vec<String>,
+    file: Option<PathBuf>,
+    args: Vec<String>,
+    include_dirs: Vec<PathBuf>,
+    requires: Vec<String>,
+    nginx: Option<PathBuf>,
+    worker_connections: Option<u32>,
+    nameservers: Vec<IpAddr>,
+    resolve_ipv6: bool,
+    shdicts: Vec<Shdict>,
+    main_conf: Vec<String>,
+    main_includes: Vec<PathBuf>,
+    http_conf: Vec<String>,
+    http_includes: Vec<PathBuf>,
+    stream_conf: Vec<String>,
+    no_stream: bool,
+    load_modules: Vec<String>,
+    errlog_level: Option<LogLevel>,
+    jit_cmds: Vec<JitCmd>,
+    jit_opts: Vec<JitOpts>,
+    prefix: Option<PathBuf>,
+    tmpdir: Option<PathBuf>,
+    keep_prefix: bool,
+    keep_prefix_on_error: bool,
+    timeout: Option<Duration>,
+    timeout_grace: Option<Duration>,
+}
+
+/// A path as the `String` that the generated configuration is made of.
+fn utf8(arg: &str, path: &Path) -> Result<String, ArgError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| ArgError::InvalidValue {
+            arg: arg.to_string(),
+            value: path.to_string_lossy().into_owned(),
+            err: "not valid UTF-8".to_string(),
+        })
+}
+
+/// The result of [`Options::render_conf`].
+///
+/// Paths in the configuration refer to the prefix directory, which exists
+/// until this is dropped (and afterwards if [`prefix`](Options::prefix) or
+/// [`keep_prefix`](Options::keep_prefix) was used).
+#[derive(Debug)]
+pub struct RenderedConf {
+    conf: String,
+    prefix: Prefix,
+}
+
+impl RenderedConf {
+    /// The contents of nginx.conf.
+    pub fn as_str(&self) -> &str {
+        &self.conf
+    }
+
+    /// The prefix directory that the configuration refers to.
+    pub fn prefix(&self) -> &Path {
+        &self.prefix.root
+    }
+}
+
+impl Deref for RenderedConf {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.conf
+    }
+}
+
+impl fmt::Display for RenderedConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.conf)
+    }
+}
+
+/// The result of [`Options::run`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Output {
+    /// The exit code that `rusty-cli` would have exited with.
+    pub code: i32,
+
+    /// The signal that terminated nginx, if any.
+    pub signal: Option<i32>,
+
+    /// Everything nginx wrote to stdout.
+    pub stdout: Vec<u8>,
+
+    /// Everything nginx wrote to stderr.
+    pub stderr: Vec<u8>,
+}
+
+impl Options {
+    /// Options with nothing set, just like running `rusty-cli` without any
+    /// arguments. At least one of [`eval`](Self::eval),
+    /// [`require`](Self::require), or [`file`](Self::file) is needed to run.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run inline Lua code (`-e`). May be given more than once.
+    pub fn eval(mut self, code: impl Into<String>) -> Self {
+        self.eval.push(code.into());
+        self
+    }
+
+    /// Run a Lua script file.
+    ///
+    /// Reading the script from stdin (`-`) is not supported, since stdin
+    /// belongs to the host program; use [`eval`](Self::eval) instead.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Add an argument for the Lua script file (available as `arg[N]`).
+    ///
+    /// Arguments are only passed when a [file](Self::file) is given.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add several arguments for the Lua script file.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a directory to the Lua module search paths (`-I`).
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Require a Lua module before running any code (`-l`).
+    pub fn require(mut self, module: impl Into<String>) -> Self {
+        self.requires.push(module.into());
+        self
+    }
+
+    /// Use a specific nginx binary (`--nginx`).
+    pub fn nginx(mut self, bin: impl Into<PathBuf>) -> Self {
+        self.nginx = Some(bin.into());
+        self
+    }
+
+    /// Set the maximum connection count (`-c`).
+    pub fn worker_connections(mut self, count: u32) -> Self {
+        self.worker_connections = Some(count);
+        self
+    }
+
+    /// Add a resolver name server (`--ns`).
+    pub fn nameserver(mut self, addr: impl Into<IpAddr>) -> Self {
+        self.nameservers.push(addr.into());
+        self
+    }
+
+    /// Resolve both IPv4 and IPv6 addresses (`--resolve-ipv6`).
+    pub fn resolve_ipv6(mut self) -> Self {
+        self.resolve_ipv6 = true;
+        self
+    }
+
+    /// Create a lua shared dict (`--shdict`).
+    pub fn shdict(mut self, shdict: Shdict) -> Self {
+        self.shdicts.push(shdict);
+        self
+    }
+
+    /// Insert a snippet into the main configuration block (`--main-conf`).
+    pub fn main_conf(mut self, conf: impl Into<String>) -> Self {
+        self.main_conf.push(conf.into());
+        self
+    }
+
+    /// Include a file in the main configuration block (`--main-include`).
+    pub fn main_include(mut self, path: impl Into<PathBuf>) -> Self {
+        self.main_includes.push(path.into());
+        self
+    }
+
+    /// Insert a snippet into the http configuration block (`--http-conf`).
+    pub fn http_conf(mut self, conf: impl Into<String>) -> Self {
+        self.http_conf.push(conf.into());
+        self
+    }
+
+    /// Include a file in the http configuration block (`--http-include`).
+    pub fn http_include(mut self, path: impl Into<PathBuf>) -> Self {
+        self.http_includes.push(path.into());
+        self
+    }
+
+    /// Insert a snippet into the stream configuration block
+    /// (`--stream-conf`).
+    pub fn stream_conf(mut self, conf: impl Into<String>) -> Self {
+        self.stream_conf.push(conf.into());
+        self
+    }
+
+    /// Leave out the stream configuration block (`--no-stream`).
+    pub fn no_stream(mut self) -> Self {
+        self.no_stream = true;
+        self
+    }
+
+    /// Load a dynamic nginx module (`--load-module`).
+    pub fn load_module(mut self, module: impl Into<String>) -> Self {
+        self.load_modules.push(module.into());
+        self
+    }
+
+    /// Set the nginx error_log level (`--errlog-level`).
+    pub fn errlog_level(mut self, level: LogLevel) -> Self {
+        self.errlog_level = Some(level);
+        self
+    }
+
+    /// Run a LuaJIT command like `v`, `dump=+rsx,out.txt`, or `off` (`-j`).
+    /// May be called more than once.
+    pub fn jit(mut self, cmd: JitCmd) -> Self {
+        self.jit_cmds.push(cmd);
+        self
+    }
+
+    /// Set LuaJIT optimizations like `3`, `-fold`, or `hotloop=5` (`-O`).
+    /// May be called more than once.
+    pub fn jit_opt(mut self, opts: JitOpts) -> Self {
+        self.jit_opts.push(opts);
+        self
+    }
+
+    /// Use a specific nginx prefix directory, which is kept afterwards
+    /// (`--prefix`).
+    pub fn prefix(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.prefix = Some(dir.into());
+        self
+    }
+
+    /// Create the temporary prefix directory inside `dir` (`--tmpdir`).
+    pub fn tmpdir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.tmpdir = Some(dir.into());
+        self
+    }
+
+    /// Don't remove the temporary prefix directory (`--keep-prefix`).
+    pub fn keep_prefix(mut self) -> Self {
+        self.keep_prefix = true;
+        self
+    }
+
+    /// Don't remove the temporary prefix directory if nginx fails
+    /// (`--keep-prefix-on-error`).
+    pub fn keep_prefix_on_error(mut self) -> Self {
+        self.keep_prefix_on_error = true;
+        self
+    }
+
+    /// Stop nginx if it runs for longer than `after` (`--timeout`). A zero
+    /// duration means no timeout.
+    pub fn timeout(mut self, after: Duration) -> Self {
+        self.timeout = Some(after);
+        self
+    }
+
+    /// How long nginx has to exit once the timeout elapses before it is
+    /// killed (`--timeout-grace`).
+    pub fn timeout_grace(mut self, grace: Duration) -> Self {
+        self.timeout_grace = Some(grace);
+        self
+    }
+
+    /// The equivalent `rusty-cli` command line, including the program name.
+    ///
+    /// This is for showing or logging a run. The options are used as they
+    /// are, without going through the command line parser.
+    pub fn to_args(&self) -> Vec<OsString> {
+        fn push<T: Into<OsString>>(
+            args: &mut Vec<OsString>,
+            name: &str,
+            values: impl IntoIterator<Item = T>,
+        ) {
+            for value in values {
+                args.push(name.into());
+                args.push(value.into());
+            }
+        }
+
+        let mut args = vec![OsString::from(RUSTY_CLI)];
+        let secs = |d: &Duration| d.as_secs_f64().to_string();
+
+        push(&mut args, "-l", &self.requires);
+        push(&mut args, "-e", &self.eval);
+        push(&mut args, "-I", &self.include_dirs);
+        push(
+            &mut args,
+            "-j",
+            self.jit_cmds.iter().map(ToString::to_string),
+        );
+        push(
+            &mut args,
+            "-O",
+            self.jit_opts.iter().map(ToString::to_string),
+        );
+        push(
+            &mut args,
+            "-c",
+            self.worker_connections.as_ref().map(ToString::to_string),
+        );
+        push(
+            &mut args,
+            "--ns",
+            self.nameservers.iter().map(ToString::to_string),
+        );
+        push(
+            &mut args,
+            "--shdict",
+            self.shdicts.iter().map(ToString::to_string),
+        );
+        push(&mut args, "--nginx", &self.nginx);
+        push(&mut args, "--http-conf", &self.http_conf);
+        push(&mut args, "--stream-conf", &self.stream_conf);
+        push(&mut args, "--load-module", &self.load_modules);
+        push(&mut args, "--main-conf", &self.main_conf);
+        push(&mut args, "--http-include", &self.http_includes);
+        push(&mut args, "--main-include", &self.main_includes);
+        push(
+            &mut args,
+            "--errlog-level",
+            self.errlog_level.as_ref().map(ToString::to_string),
+        );
+        push(&mut args, "--prefix", &self.prefix);
+        push(&mut args, "--tmpdir", &self.tmpdir);
+        push(&mut args, "--timeout", self.timeout.as_ref().map(secs));
+        push(
+            &mut args,
+            "--timeout-grace",
+            self.timeout_grace.as_ref().map(secs),
+        );
+
+        for (enabled, flag) in [
+            (self.resolve_ipv6, "--resolve-ipv6"),
+            (self.no_stream, "--no-stream"),
+            (self.keep_prefix, "--keep-prefix"),
+            (self.keep_prefix_on_error, "--keep-prefix-on-error"),
+        ] {
+            if enabled {
+                args.push(flag.into());
+            }
+        }
+
+        if let Some(file) = &self.file {
+            args.push("--".into());
+            args.push(file.into());
+            args.extend(self.args.iter().map(OsString::from));
+        }
+
+        args
+    }
+
+    /// The arguments for a run, built from the options directly rather
+    /// than by parsing a command line.
+    fn parse(&self) -> Result<Box<UserArgs>, Error> {
+        // never read Lua code from stdin or start the REPL when embedded
+        if self.file.as_deref() == Some(Path::new(STDIN_FILE)) {
+            return Err(Error::Unsupported("reading the Lua script from stdin"));
+        }
+
+        let mut inline_lua: Vec<String> = self
+            .requires
+            .iter()
+            .map(|module| format!("require({})", module.lua_quote()))
+            .collect();
+        inline_lua.extend(self.eval.iter().cloned());
+
+        if inline_lua.is_empty()
+            && self.file.is_none()
+            && self.jit_cmds.is_empty()
+            && self.jit_opts.is_empty()
+        {
+            return Err(ArgError::NoLuaInput.into());
+        }
+
+        let mut jit_opts = JitOpts::default();
+        for opts in &self.jit_opts {
+            jit_opts.extend(opts.clone());
+        }
+
+        let mut keep = KeepPrefix::Never;
+        if self.keep_prefix {
+            keep_prefix(&mut keep, KeepPrefix::Always)?;
+        }
+        if self.keep_prefix_on_error {
+            keep_prefix(&mut keep, KeepPrefix::OnError)?;
+        }
+
+        if let Some(dir) = &self.tmpdir {
+            if let Err(err) = validate_temp_root(dir) {
+                return Err(ArgError::InvalidValue {
+                    arg: "--tmpdir".to_string(),
+                    value: dir.display().to_string(),
+                    err,
+                }
+                .into());
+            }
+        }
+
+        let includes = |section: &str, paths: &[PathBuf]| -> Result<Vec<String>, ArgError> {
+            paths
+                .iter()
+                .map(|path| include_file(section, utf8(&format!("--{section}-include"), path)?))
+                .collect()
+        };
+
+        let mut user = UserArgs {
+            inline_lua,
+            lua_file: self
+                .file
+                .as_deref()
+                .map(|file| utf8("--", file))
+                .transpose()?,
+            lua_args: if self.file.is_some() {
+                self.args.clone()
+            } else {
+                vec![]
+            },
+            jit_cmds: self.jit_cmds.clone(),
+            jit_opts,
+            nginx_bin: self.nginx.clone(),
+            worker_connections: self.worker_connections.unwrap_or(64),
+            errlog_level: self.errlog_level.clone().unwrap_or_default(),
+            lua_package_path: self
+                .include_dirs
+                .iter()
+                .map(|dir| utf8("-I", dir))
+                .collect::<Result<_, _>>()?,
+            nameservers: self.nameservers.iter().copied().map(Into::into).collect(),
+            resolve_ipv6: self.resolve_ipv6,
+            http_conf: self.http_conf.clone(),
+            http_include: includes("http", &self.http_includes)?,
+            main_conf: self.main_conf.clone(),
+            main_include: includes("main", &self.main_includes)?,
+            load_modules: self.load_modules.clone(),
+            user_shdicts: self.shdicts.clone(),
+            stream_conf: self.stream_conf.clone(),
+            no_stream: self.no_stream,
+            prefix: self.prefix.clone(),
+            tmpdir: self.tmpdir.clone(),
+            keep_prefix: keep,
+            timeout: self.timeout.map(Into::into),
+            timeout_grace: self.timeout_grace.map(Into::into),
+            // the Lua `arg` table is laid out as if run from the command line
+            arg_c: self.to_args().len(),
+            arg_0: RUSTY_CLI.to_string(),
+            ..Default::default()
+        };
+
+        user.finish()?;
+        Ok(Box::new(user))
+    }
+
+    /// Render the nginx.conf for these options without running nginx.
+    ///
+    /// Supporting files are written to a prefix directory just as they are
+    /// for a real run, which is kept for as long as the returned
+    /// [`RenderedConf`] is.
+    pub fn render_conf(&self) -> Result<RenderedConf, Error> {
+        let mut user = self.parse()?;
+        let prefix = user.new_prefix()?;
+
+        let (conf_builder, _label) = build_conf(&mut user, &prefix)?;
+
+        let mut buf = Vec::new();
+        conf_builder.render(&mut buf).map_err(|source| Error::Io {
+            context: "failed rendering nginx.conf",
+            source,
+        })?;
+
+        Ok(RenderedConf {
+            conf: String::from_utf8_lossy(&buf).into_owned(),
+            prefix,
+        })
+    }
+
+    /// Run nginx to completion, capturing its output.
+    ///
+    /// Unlike the `rusty-cli` binary this does not install any signal
+    /// handlers, so runs may happen concurrently from multiple threads.
+    pub fn run(&self) -> Result<Output, Error> {
+        let user = self.parse()?;
+        let (status, ()) = run_captured(*user, false, |_| ())?;
+        let (stdout, stderr) = status.output.unwrap_or_default();
+
+        Ok(Output {
+            code: status.code,
+            signal: status.signal.map(|sig| sig as i32),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Stdin;
+
+    #[test]
+    fn options_to_args() {
+        let opts = Options::new()
+            .eval("print(1)")
+            .shdict(Shdict::new("cache", "1m").unwrap())
+            .no_stream()
+            .timeout(Duration::from_millis(1500))
+            .include_dir("/my/lib")
+            .nameserver([127, 0, 0, 1])
+            .errlog_level(LogLevel::Debug)
+            .jit("dump=+rsx,out.txt".parse().unwrap())
+            .jit_opt("3,-fold".parse().unwrap());
+
+        assert_eq!(
+            vec![
+                "rusty-cli",
+                "-e",
+                "print(1)",
+                "-I",
+                "/my/lib",
+                "-j",
+                "dump=+rsx,out.txt",
+                "-O",
+                "3,-fold",
+                "--ns",
+                "127.0.0.1",
+                "--shdict",
+                "cache 1m",
+                "--errlog-level",
+                "debug",
+                "--timeout",
+                "1.5",
+                "--no-stream",
+            ],
+            opts.to_args()
+        );
+
+        let opts = Options::new()
+            .arg("first")
+            .file("-script.lua")
+            .args(["a", "-b"]);
+
+        assert_eq!(
+            vec!["rusty-cli", "--", "-script.lua", "first", "a", "-b"],
+            opts.to_args()
+        );
+
+        let opts = Options::new().eval("1").arg("ignored");
+        assert_eq!(vec!["rusty-cli", "-e", "1"], opts.to_args());
+    }
+
+    #[test]
+    fn typed_values() {
+        assert_eq!("cache 1m", Shdict::new("cache", "1m").unwrap().to_string());
+        assert!(Shdict::new("my cache", "1m").is_err());
+        assert!(Shdict::new("cache", "1m 2").is_err());
+        assert!(Shdict::new("cache", "").is_err());
+
+        // typed values turn back into the same command line arguments
+        for cmd in ["v", "dump=+rsx,out.txt", "dump=,out.txt", "off"] {
+            assert_eq!(cmd, cmd.parse::<JitCmd>().unwrap().to_string());
+        }
+        assert_eq!("3,+fold", "3,+fold".parse::<JitOpts>().unwrap().to_string());
+        assert!("nope".parse::<JitCmd>().is_err());
+        assert!("+nope".parse::<JitOpts>().is_err());
+        assert!("loud".parse::<LogLevel>().is_err());
+
+        let opts = Options::new()
+            .nameserver("::1".parse::<IpAddr>().unwrap())
+            .eval("1")
+            .render_conf()
+            .unwrap();
+        assert!(opts.contains("resolver [::1]"), "{}", opts.as_str());
+    }
+
+    #[test]
+    fn same_as_cli() {
+        use crate::cli::Action;
+
+        let opts = Options::new()
+            .require("cjson")
+            .eval("-1")
+            .shdict(Shdict::new("cache", "1m").unwrap())
+            .nameserver([127, 0, 0, 1])
+            .nameserver("::1".parse::<IpAddr>().unwrap())
+            .worker_connections(8)
+            .errlog_level(LogLevel::Info)
+            .jit("v".parse().unwrap())
+            .jit_opt("3".parse().unwrap())
+            .jit_opt("-fold".parse().unwrap())
+            .http_conf("a;")
+            .no_stream()
+            .keep_prefix_on_error()
+            .timeout(Duration::from_millis(1500))
+            .timeout_grace(Duration::from_secs(1));
+
+        let args = opts
+            .to_args()
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap());
+
+        let Ok(Action::Main(cli)) = Action::try_from_with_stdin(args, || Stdin::Other) else {
+            panic!("expected a main action");
+        };
+
+        assert_eq!(cli, opts.parse().unwrap());
+        assert_eq!(vec!["require([=[cjson]=])", "-1"], cli.inline_lua);
+    }
+
+    #[test]
+    fn non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = Path::new(OsStr::from_bytes(b"/lib/\xff"));
+        let opts = Options::new().include_dir(dir).eval("1");
+
+        // shown as they are
+        assert_eq!(dir.as_os_str(), opts.to_args()[4]);
+
+        // but nginx.conf can't refer to them
+        let err = opts.render_conf().unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Args(ArgError::InvalidValue { ref arg, .. }) if arg == "-I"
+        ));
+    }
+
+    #[test]
+    fn invalid_options() {
+        let err = Options::new()
+            .http_include("/does/not/exist.conf")
+            .eval("1")
+            .render_conf()
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::Args(ArgError::MissingInclude(ref section, _)) if section == "http"
+        ));
+
+        let err = Options::new().render_conf().unwrap_err();
+        assert!(matches!(err, Error::Args(ArgError::NoLuaInput)));
+
+        let err = Options::new().file("-").render_conf().unwrap_err();
+        assert!(matches!(err, Error::Unsupported(_)));
+    }
+}
@@ -0,0 +1,292 @@
+//! Batch mode (`--parallel N`): run many Lua files at once, each in its own
+//! prefix and nginx instance.
+//!
+//! The output of each run is captured and written out once it exits, with
+//! every line tagged with the name of the Lua file, so that output from
+//! different runs never interleaves mid-line.
+
+use crate::cli::{run_captured, UserArgs};
+use crate::coverage::Coverage;
+use crate::lua::STDIN_FILE;
+use crate::nginx::Runner;
+use crate::profile::Profile;
+use crate::run;
+use crate::types::{ArgError, Prefix};
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Check the arguments for `--parallel`, collecting the Lua files to run.
+pub(crate) fn validate(user: &mut UserArgs) -> Result<(), ArgError> {
+    user.scripts.extend(user.lua_file.take());
+    user.scripts.append(&mut user.lua_args);
+
+    for (set, opt) in [
+        (user.repl, "--repl"),
+        (user.watch, "--watch"),
+        (user.dump_nginx_conf, "--dump-nginx-conf"),
+        (user.report_json.is_some(), "--report-json"),
+        (user.prefix.is_some(), "--prefix"),
+        (user.relative_include, "--relative-include"),
+        (matches!(user.runner, Runner::Gdb(_)), "--gdb"),
+        (user.scripts.iter().any(|s| s == STDIN_FILE), STDIN_FILE),
+    ] {
+        if set {
+            return Err(ArgError::Conflict(
+                "--parallel".to_string(),
+                opt.to_string(),
+            ));
+        }
+    }
+
+    if user.scripts.is_empty() {
+        return Err(ArgError::NoLuaInput);
+    }
+
+    for fname in &user.scripts {
+        if File::open(fname).is_err() {
+            return Err(ArgError::LuaFileNotFound(fname.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// The outcome of one run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Job {
+    script: String,
+
+    /// The exit code, or `None` if the run never started.
+    code: Option<i32>,
+    elapsed: Duration,
+}
+
+/// Prefix each line of `output` with `tag`.
+fn tag_lines(tag: &str, output: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(output.len());
+
+    for line in output.split_inclusive(|c| *c == b'\n') {
+        out.extend_from_slice(format!("[{tag}] ").as_bytes());
+        out.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+    }
+
+    out
+}
+
+/// A table of the exit code and duration of each run.
+fn summary(jobs: &[Job]) -> String {
+    let width = jobs
+        .iter()
+        .map(|job| job.script.len())
+        .chain(["SCRIPT".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut out = format!("{:width$}  {:>4}  TIME\n", "SCRIPT", "EXIT");
+
+    for job in jobs {
+        let line = match job.code {
+            Some(code) => format!(
+                "{:width$}  {code:>4}  {:.2}s\n",
+                job.script,
+                job.elapsed.as_secs_f64()
+            ),
+            None => format!("{:width$}  {:>4}  not run\n", job.script, "-"),
+        };
+        out.push_str(&line);
+    }
+
+    out
+}
+
+/// The exit code for the whole batch: the code of the first run that failed
+/// (in command line order), or 0 if they all succeeded.
+fn exit_code(jobs: &[Job]) -> i32 {
+    jobs.iter()
+        .filter_map(|job| job.code)
+        .find(|code| *code != 0)
+        .unwrap_or(0)
+}
+
+/// What the runs collected for `--coverage` and `--lua-profile`, added up.
+#[derive(Default)]
+struct Collected {
+    coverage: Coverage,
+    profile: Profile,
+}
+
+impl Collected {
+    fn read(prefix: &Prefix) -> Self {
+        Self {
+            coverage: Coverage::read(prefix),
+            profile: Profile::read(prefix),
+        }
+    }
+
+    fn merge(&mut self, other: Collected) {
+        self.coverage.merge(other.coverage);
+        self.profile.merge(other.profile);
+    }
+
+    fn write(&self, user: &UserArgs) {
+        if user.coverage.enabled {
+            if let Err(e) = self.coverage.write(&user.coverage) {
+                eprintln!("{e}");
+            }
+        }
+
+        if user.profile.enabled() {
+            if let Err(e) = self.profile.write(&user.profile) {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+fn run_job(user: &UserArgs, script: &str, output: &Mutex<()>, collected: &Mutex<Collected>) -> i32 {
+    let mut job = user.clone();
+
+    // only count the arguments that this run would have been given
+    job.arg_c -= user.scripts.len() - 1;
+    job.lua_file = Some(script.to_string());
+    job.scripts.clear();
+    job.parallel = None;
+
+    let (code, stdout, stderr) = match run_captured(job, true, Collected::read) {
+        Ok((status, job_collected)) => {
+            collected
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .merge(job_collected);
+
+            let (stdout, stderr) = status.output.unwrap_or_default();
+            (status.code, stdout, stderr)
+        }
+        Err(e) => (e.exit_code(), vec![], format!("{e}\n").into_bytes()),
+    };
+
+    // write each run's output in one go
+    let _lock = output.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = io::stdout().write_all(&tag_lines(script, &stdout));
+    let _ = io::stdout().flush();
+    let _ = io::stderr().write_all(&tag_lines(script, &stderr));
+
+    code
+}
+
+/// Run every Lua file, printing a summary to stderr at the end, and return
+/// the exit code.
+pub(crate) fn run(user: Box<UserArgs>) -> i32 {
+    let jobs: Vec<Mutex<Job>> = user
+        .scripts
+        .iter()
+        .map(|script| {
+            Mutex::new(Job {
+                script: script.clone(),
+                code: None,
+                elapsed: Duration::ZERO,
+            })
+        })
+        .collect();
+
+    let next = AtomicUsize::new(0);
+    let output = Mutex::new(());
+    let collected = Mutex::new(Collected::default());
+    let workers = user.parallel.unwrap_or(1).min(jobs.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                // don't start anything new after Ctrl-C
+                if run::interrupted() {
+                    return;
+                }
+
+                let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    return;
+                };
+
+                let script = job
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .script
+                    .clone();
+
+                let started = Instant::now();
+                let code = run_job(&user, &script, &output, &collected);
+
+                let mut job = job.lock().unwrap_or_else(PoisonError::into_inner);
+                job.code = Some(code);
+                job.elapsed = started.elapsed();
+            });
+        }
+    });
+
+    let jobs: Vec<Job> = jobs
+        .into_iter()
+        .map(|job| job.into_inner().unwrap_or_else(PoisonError::into_inner))
+        .collect();
+
+    eprint!("{}", summary(&jobs));
+
+    collected
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+        .write(&user);
+
+    run::interrupted_code().unwrap_or_else(|| exit_code(&jobs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(script: &str, code: Option<i32>, ms: u64) -> Job {
+        Job {
+            script: script.to_string(),
+            code,
+            elapsed: Duration::from_millis(ms),
+        }
+    }
+
+    #[test]
+    fn tagging() {
+        assert_eq!(
+            b"[a.lua] one\n[a.lua] two\n".to_vec(),
+            tag_lines("a.lua", b"one\ntwo")
+        );
+        assert_eq!(b"[a.lua] \n".to_vec(), tag_lines("a.lua", b"\n"));
+        assert!(tag_lines("a.lua", b"").is_empty());
+    }
+
+    #[test]
+    fn summary_table() {
+        let jobs = [
+            job("a.lua", Some(0), 120),
+            job("scripts/long.lua", Some(3), 1500),
+            job("b.lua", None, 0),
+        ];
+
+        assert_eq!(
+            "SCRIPT            EXIT  TIME\n\
+             a.lua                0  0.12s\n\
+             scripts/long.lua     3  1.50s\n\
+             b.lua                -  not run\n",
+            summary(&jobs)
+        );
+
+        assert_eq!(3, exit_code(&jobs));
+        assert_eq!(0, exit_code(&jobs[..1]));
+        assert_eq!(
+            1,
+            exit_code(&[job("a.lua", Some(1), 0), job("b.lua", Some(2), 0)])
+        );
+    }
+}
@@ -0,0 +1,888 @@
+//! Single-file bundles of a Lua script and the modules it requires.
+//!
+//! `rusty-cli bundle tool.lua -I lib` writes `tool.rusty`, which holds
+//! `tool.lua`, every module it (transitively) requires from the `-I`
+//! directories, and a manifest of the shdicts and nginx.conf snippets that
+//! it needs. Running `rusty-cli tool.rusty args...` extracts the bundle into
+//! the prefix, puts its modules first in the package path, and runs the
+//! script as if it had been given directly.
+//!
+//! Like a Python zipapp, a bundle is a `#!` line (so that bundles can be
+//! executed directly) followed by a zip archive, whose offsets count from
+//! the start of the file. The archive holds `manifest.toml`, the script under
+//! `bin/` and the modules under `lua/`, and its comment is `rusty-bundle 1`,
+//! which is how bundles are told apart from other zip files.
+//!
+//! Self-contained executables (see [`crate::exe`]) are the rusty-cli binary
+//! followed by the same kind of archive, whose manifest also holds the
+//! options to run with.
+
+use crate::cli::UserArgs;
+use crate::directives::{self, Directive};
+use crate::types::{ArgError, Prefix, Shdict};
+use crate::util;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// The file extension for bundles.
+const EXTENSION: &str = "rusty";
+
+const SHEBANG: &str = "#!/usr/bin/env rusty-cli";
+
+/// The comment of the zip archive.
+const MAGIC: &str = "rusty-bundle 1";
+
+/// The end of central directory record of a zip archive, up to the length
+/// of its comment.
+const EOCD_SIGNATURE: &[u8] = b"PK\x05\x06";
+const EOCD_LEN: usize = 22;
+
+/// Where bundles are extracted to, relative to the prefix.
+const DIR: &str = "bundle";
+
+const MANIFEST: &str = "manifest.toml";
+
+/// Where the entry script, the modules and the included nginx.conf files
+/// are kept in a bundle.
+const BIN_DIR: &str = "bin";
+const LUA_DIR: &str = "lua";
+const INCLUDE_DIR: &str = "include";
+
+/// Options for the `bundle` subcommand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BundleArgs {
+    pub(crate) output: Option<PathBuf>,
+
+    /// Modules to bundle even if no `require` call for them is found.
+    pub(crate) modules: Vec<String>,
+}
+
+/// What a bundle needs besides its files.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Manifest {
+    /// The path of the entry script in the bundle.
+    pub(crate) entry: String,
+    #[serde(rename = "shdict", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) shdicts: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) http_conf: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) main_conf: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) stream_conf: Vec<String>,
+
+    /// The paths in the bundle of the files given with `--http-include` and
+    /// `--main-include`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) http_include: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) main_include: Vec<String>,
+
+    /// The options that a self-contained executable runs with.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) args: Vec<String>,
+}
+
+impl Manifest {
+    fn render(&self) -> String {
+        toml::to_string(self).expect("manifests only hold strings")
+    }
+
+    fn parse(src: &str) -> Result<Self, String> {
+        let manifest: Self = toml::from_str(src).map_err(|e| match e.span() {
+            Some(span) => format!(
+                "manifest: line {}: {}",
+                util::line_at(src, span.start),
+                e.message()
+            ),
+            None => format!("manifest: {}", e.message()),
+        })?;
+
+        if manifest.entry.is_empty() {
+            return Err("manifest: missing entry".to_string());
+        }
+
+        Ok(manifest)
+    }
+
+    /// Read only the manifest of a bundle, without the files.
+    pub(crate) fn read(path: &Path) -> Result<Self, ArgError> {
+        let invalid = |err: String| ArgError::InvalidBundle {
+            file: path.display().to_string(),
+            err,
+        };
+
+        let file = fs::File::open(path).map_err(|e| invalid(e.to_string()))?;
+        let mut zip = open(BufReader::new(file)).map_err(invalid)?;
+        let mut manifest = zip
+            .by_name(MANIFEST)
+            .map_err(|_| invalid("missing manifest".to_string()))?;
+
+        let mut src = String::new();
+        manifest
+            .read_to_string(&mut src)
+            .map_err(|e| invalid(format!("{MANIFEST}: {e}")))?;
+        Self::parse(&src).map_err(invalid)
+    }
+}
+
+/// A bundle, read into memory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Bundle {
+    pub(crate) manifest: Manifest,
+
+    /// The contents of each file, by its path in the bundle.
+    pub(crate) files: Vec<(String, Vec<u8>)>,
+}
+
+/// Whether a path in a bundle is safe to extract.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Whether the end of a file is the end of a bundle's zip archive.
+fn has_magic(tail: &[u8]) -> bool {
+    let Some(eocd) = tail
+        .len()
+        .checked_sub(EOCD_LEN + MAGIC.len())
+        .map(|start| &tail[start..])
+    else {
+        return false;
+    };
+
+    eocd.starts_with(EOCD_SIGNATURE)
+        && eocd[EOCD_LEN - 2..EOCD_LEN] == (MAGIC.len() as u16).to_le_bytes()
+        && eocd.ends_with(MAGIC.as_bytes())
+}
+
+/// Whether a file is a bundle (or a self-contained executable), judging by
+/// its last bytes.
+pub(crate) fn is_bundle(path: &Path) -> bool {
+    let check = || -> io::Result<bool> {
+        let mut file = fs::File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Ok(false);
+        }
+
+        let mut tail = vec![];
+        let len = (EOCD_LEN + MAGIC.len()) as i64;
+        if file.seek(SeekFrom::End(0))? < len as u64 {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::End(-len))?;
+        file.read_to_end(&mut tail)?;
+
+        Ok(has_magic(&tail))
+    };
+
+    check().unwrap_or(false)
+}
+
+/// Open the zip archive of a bundle.
+fn open<R: Read + Seek>(mut reader: R) -> Result<ZipArchive<R>, String> {
+    let mut tail = vec![];
+    let len = (EOCD_LEN + MAGIC.len()) as u64;
+    let size = reader.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    reader
+        .seek(SeekFrom::Start(size.saturating_sub(len)))
+        .and_then(|_| reader.read_to_end(&mut tail))
+        .map_err(|e| e.to_string())?;
+
+    if !has_magic(&tail) {
+        return Err("not a rusty-cli bundle".to_string());
+    }
+
+    ZipArchive::new(reader).map_err(|e| e.to_string())
+}
+
+/// The offset of the zip archive in a file that ends with a bundle, which
+/// is where the rest of the file ends.
+pub(crate) fn start<R: Read + Seek>(reader: R) -> Result<u64, String> {
+    let mut zip = open(reader)?;
+    let mut start = None;
+
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i).map_err(|e| e.to_string())?;
+        start = Some(start.unwrap_or(u64::MAX).min(file.header_start()));
+    }
+
+    start.ok_or_else(|| "missing manifest".to_string())
+}
+
+impl Bundle {
+    /// Write the bundle as a zip archive after some leading data, like a
+    /// `#!` line or an executable.
+    pub(crate) fn write_after(&self, head: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = Cursor::new(head);
+        out.seek(SeekFrom::End(0))?;
+
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut zip = ZipWriter::new(out);
+        zip.set_comment(MAGIC)?;
+
+        zip.start_file(MANIFEST, options)?;
+        zip.write_all(self.manifest.render().as_bytes())?;
+
+        for (name, contents) in &self.files {
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(contents)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Serialize the bundle.
+    pub(crate) fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        self.write_after(format!("{SHEBANG}\n").into_bytes())
+    }
+
+    /// Read a bundle from anything that ends with one.
+    pub(crate) fn from_reader<R: Read + Seek>(reader: R) -> Result<Self, String> {
+        let mut zip = open(reader)?;
+
+        let mut manifest = None;
+        let mut files = vec![];
+
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).map_err(|e| e.to_string())?;
+            if file.is_dir() {
+                continue;
+            }
+
+            let name = file.name().to_string();
+            let mut contents = vec![];
+            file.read_to_end(&mut contents)
+                .map_err(|e| format!("{name}: {e}"))?;
+
+            if name == MANIFEST {
+                let src = String::from_utf8_lossy(&contents);
+                manifest = Some(Manifest::parse(&src)?);
+            } else if is_valid_name(&name) {
+                files.push((name, contents));
+            } else {
+                return Err(format!("invalid file name `{name}`"));
+            }
+        }
+
+        let Some(manifest) = manifest else {
+            return Err("missing manifest".to_string());
+        };
+
+        if !files.iter().any(|(name, _)| *name == manifest.entry) {
+            return Err(format!("missing entry script `{}`", manifest.entry));
+        }
+
+        let includes = manifest.http_include.iter().chain(&manifest.main_include);
+        for include in includes {
+            if !files.iter().any(|(name, _)| name == include) {
+                return Err(format!("missing include `{include}`"));
+            }
+        }
+
+        Ok(Self { manifest, files })
+    }
+
+    /// Read a bundle from a file. Only the zip archive at the end of the file
+    /// is read, not whatever comes before it.
+    pub(crate) fn read(path: &Path) -> Result<Self, ArgError> {
+        let invalid = |err: String| ArgError::InvalidBundle {
+            file: path.display().to_string(),
+            err,
+        };
+
+        let file = fs::File::open(path).map_err(|e| invalid(e.to_string()))?;
+        Self::from_reader(BufReader::new(file)).map_err(invalid)
+    }
+
+    fn entry(&self) -> &[u8] {
+        self.files
+            .iter()
+            .find(|(name, _)| *name == self.manifest.entry)
+            .map_or(&[], |(_, contents)| contents)
+    }
+
+    /// The path of a file in the bundle once extracted.
+    fn extracted_path(prefix: &Prefix, name: &str) -> String {
+        prefix
+            .root
+            .join(DIR)
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The path of the entry script once extracted.
+    pub(crate) fn entry_path(&self, prefix: &Prefix) -> String {
+        Self::extracted_path(prefix, &self.manifest.entry)
+    }
+
+    /// The paths of the `--http-include` and `--main-include` files once
+    /// extracted.
+    pub(crate) fn include_paths(&self, prefix: &Prefix) -> (Vec<String>, Vec<String>) {
+        let paths = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| Self::extracted_path(prefix, name))
+                .collect()
+        };
+
+        (
+            paths(&self.manifest.http_include),
+            paths(&self.manifest.main_include),
+        )
+    }
+
+    /// The chunk name for the entry script, which is what it would have
+    /// been if the script had been run directly from its own directory.
+    pub(crate) fn entry_chunk_name(&self) -> String {
+        let name = Path::new(&self.manifest.entry)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("@{name}")
+    }
+
+    /// Extract the bundle into the prefix, and return the directory to add
+    /// to the package path.
+    pub(crate) fn extract(&self, prefix: &Prefix) -> io::Result<String> {
+        let dir = prefix.root.join(DIR);
+
+        for (name, contents) in &self.files {
+            let path = dir.join(name);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, contents)?;
+        }
+
+        fs::create_dir_all(dir.join(LUA_DIR))?;
+        Ok(dir.join(LUA_DIR).to_string_lossy().into_owned())
+    }
+
+    /// Apply the manifest to the arguments for running the bundle.
+    fn apply(&self, user: &mut UserArgs, fname: &str) -> Result<(), ArgError> {
+        let manifest = &self.manifest;
+
+        for shdict in &manifest.shdicts {
+            let shdict: Shdict = shdict.parse().map_err(|e| ArgError::InvalidBundle {
+                file: fname.to_string(),
+                err: format!("shdict `{shdict}`: {e}"),
+            })?;
+            user.user_shdicts.push(shdict);
+        }
+
+        user.http_conf.extend(manifest.http_conf.iter().cloned());
+        user.main_conf.extend(manifest.main_conf.iter().cloned());
+        user.stream_conf
+            .extend(manifest.stream_conf.iter().cloned());
+
+        Ok(())
+    }
+}
+
+/// Load the bundle that is being run, if the Lua file is one.
+pub(crate) fn load(user: &mut UserArgs) -> Result<(), ArgError> {
+    let Some(fname) = user.lua_file.clone() else {
+        return Ok(());
+    };
+
+    if !is_bundle(Path::new(&fname)) {
+        return Ok(());
+    }
+
+    let bundle = Bundle::read(Path::new(&fname))?;
+    bundle.apply(user, &fname)?;
+    user.archive = Some(bundle);
+
+    Ok(())
+}
+
+/// Read the directives of the entry script of a bundle.
+pub(crate) fn read_directives(path: &Path) -> io::Result<Vec<Directive>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let bundle =
+        Bundle::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    directives::parse(bundle.entry())
+}
+
+/// Find the names of the modules that Lua code requires with a literal
+/// string, like `require "foo.bar"` or `require("foo.bar")`.
+///
+/// This is a plain text scan, not a parse: `require` calls in comments and
+/// strings count too (names that aren't found in the search path are ignored
+/// anyway), and modules whose name is computed at run time, like
+/// `require(name)` or `require("driver." .. kind)`, are missed. Those can be
+/// listed with `--module`.
+fn required_modules(src: &str) -> Vec<String> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut found = vec![];
+
+    for (pos, _) in src.match_indices("require") {
+        if src[..pos].chars().next_back().is_some_and(is_ident) {
+            continue;
+        }
+
+        let mut rest = src[pos + "require".len()..].trim_start();
+        if let Some(r) = rest.strip_prefix('(') {
+            rest = r.trim_start();
+        }
+
+        let name = if let Some(r) = rest.strip_prefix(['"', '\'']) {
+            let quote = rest.chars().next().unwrap_or('"');
+            r.split_once(quote).map(|(name, _)| name)
+        } else if let Some(r) = rest.strip_prefix("[[") {
+            r.split_once("]]").map(|(name, _)| name)
+        } else {
+            None
+        };
+
+        if let Some(name) = name.filter(|name| {
+            !name.is_empty() && name.chars().all(|c| is_ident(c) || c == '.' || c == '-')
+        }) {
+            found.push(name.to_string());
+        }
+    }
+
+    found
+}
+
+/// Find a module in the search path, returning its file and its path
+/// relative to the directory that it was found in.
+fn resolve(module: &str, dirs: &[String]) -> Option<(PathBuf, String)> {
+    let base = module.replace('.', "/");
+
+    dirs.iter().find_map(|dir| {
+        [format!("{base}.lua"), format!("{base}/init.lua")]
+            .into_iter()
+            .map(|rel| (Path::new(dir).join(&rel), rel))
+            .find(|(path, _)| path.is_file())
+    })
+}
+
+/// Collect the entry script, the given modules, and the modules that they
+/// require, returning the bundle and the source file of each bundled file.
+fn collect(
+    entry: &str,
+    modules: &[String],
+    dirs: &[String],
+    manifest: Manifest,
+) -> io::Result<(Bundle, Vec<String>)> {
+    let mut bundle = Bundle {
+        manifest,
+        files: vec![],
+    };
+    let mut sources = vec![];
+
+    let contents = fs::read(entry)?;
+    let mut queue = modules.to_vec();
+    queue.extend(required_modules(&String::from_utf8_lossy(&contents)));
+    bundle.files.push((bundle.manifest.entry.clone(), contents));
+    sources.push(entry.to_string());
+
+    let mut seen = HashSet::new();
+    let mut next = 0;
+
+    while let Some(module) = queue.get(next).cloned() {
+        next += 1;
+
+        if !seen.insert(module.clone()) {
+            continue;
+        }
+
+        let Some((path, rel)) = resolve(&module, dirs) else {
+            if modules.contains(&module) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("module `{module}` not found"),
+                ));
+            }
+
+            // provided by OpenResty, or not available at all
+            continue;
+        };
+
+        let name = format!("{LUA_DIR}/{rel}");
+        if bundle.files.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+
+        let contents = fs::read(&path)?;
+        queue.extend(required_modules(&String::from_utf8_lossy(&contents)));
+        bundle.files.push((name, contents));
+        sources.push(path.to_string_lossy().into_owned());
+    }
+
+    Ok((bundle, sources))
+}
+
+/// The `-I` directories from the directives of the entry script, relative
+/// to its directory.
+fn include_directives(entry: &Path) -> Vec<String> {
+    let dir = entry.parent().unwrap_or(Path::new(""));
+
+    directives::read(entry)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|d| d.opt.name == "-I")
+        .filter_map(|d| d.value)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            let path: PathBuf = dir.join(value).components().collect();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+/// Check the arguments for the `bundle` and `build-exe` subcommands.
+pub(crate) fn validate(user: &mut UserArgs) -> Result<(), ArgError> {
+    let cmd = user
+        .subcommand
+        .map(|cmd| cmd.to_string())
+        .unwrap_or_default();
+
+    for (set, opt) in [
+        (user.repl, "--repl"),
+        (user.dump_nginx_conf, "--dump-nginx-conf"),
+        (user.watch, "--watch"),
+        (user.report_json.is_some(), "--report-json"),
+        (user.parallel.is_some(), "--parallel"),
+        (user.coverage.enabled, "--coverage"),
+        (user.profile.enabled(), "--lua-profile"),
+        (!user.inline_lua.is_empty(), "-e"),
+    ] {
+        if set {
+            return Err(ArgError::Conflict(cmd, opt.to_string()));
+        }
+    }
+
+    match &user.operands[..] {
+        [] => Err(ArgError::NoLuaInput),
+        [entry] if !Path::new(entry).is_file() => Err(ArgError::LuaFileNotFound(entry.clone())),
+        [_] => Ok(()),
+        [_, extra, ..] => Err(ArgError::UnknownArgument(extra.clone())),
+    }
+}
+
+/// The default output file for an entry script: its name with the bundle
+/// extension, in the current directory.
+fn default_output(entry: &str) -> PathBuf {
+    let name = Path::new(entry).file_name().unwrap_or_default();
+    PathBuf::from(name).with_extension(EXTENSION)
+}
+
+/// Collect the bundle for the `bundle` and `build-exe` subcommands,
+/// returning it and the source file of each bundled file.
+pub(crate) fn build(user: &UserArgs) -> io::Result<(Bundle, Vec<String>)> {
+    let entry = &user.operands[0];
+
+    let mut dirs = user.lua_package_path.clone();
+    dirs.extend(include_directives(Path::new(entry)));
+
+    let name = Path::new(entry).file_name().unwrap_or_default();
+    let manifest = Manifest {
+        entry: format!("{BIN_DIR}/{}", name.to_string_lossy()),
+        shdicts: user.user_shdicts.iter().map(ToString::to_string).collect(),
+        http_conf: user.http_conf.clone(),
+        main_conf: user.main_conf.clone(),
+        stream_conf: user.stream_conf.clone(),
+        args: user.exe.args.clone(),
+        ..Default::default()
+    };
+
+    let (mut bundle, mut sources) = collect(entry, &user.bundle.modules, &dirs, manifest)?;
+
+    // the included files travel with the bundle, since they might not exist
+    // where it is run
+    for (section, files) in [("http", &user.http_include), ("main", &user.main_include)] {
+        for (i, file) in files.iter().enumerate() {
+            let base = Path::new(file).file_name().unwrap_or_default();
+            let name = format!(
+                "{INCLUDE_DIR}/{section}/{}-{}",
+                i + 1,
+                base.to_string_lossy()
+            );
+
+            bundle.files.push((name.clone(), fs::read(file)?));
+            sources.push(file.clone());
+
+            match section {
+                "http" => bundle.manifest.http_include.push(name),
+                _ => bundle.manifest.main_include.push(name),
+            }
+        }
+    }
+
+    Ok((bundle, sources))
+}
+
+/// Write an executable file, and report the files that went into it.
+/// Returns the exit code.
+pub(crate) fn write_output(output: &Path, sources: &[String], contents: &[u8]) -> i32 {
+    let write = || -> io::Result<()> {
+        fs::write(output, contents)?;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))
+    };
+
+    if let Err(e) = write() {
+        eprintln!("ERROR: failed writing {}: {e}", output.display());
+        return 2;
+    }
+
+    for source in sources {
+        println!("{source}");
+    }
+    println!("wrote {}", output.display());
+    0
+}
+
+/// Write the bundle, and return the exit code.
+pub(crate) fn run(user: Box<UserArgs>) -> i32 {
+    let output = user
+        .bundle
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(&user.operands[0]));
+
+    match build(&user) {
+        Ok((bundle, sources)) => match bundle.to_bytes() {
+            Ok(contents) => write_output(&output, &sources, &contents),
+            Err(e) => {
+                eprintln!("ERROR: failed writing {}: {e}", output.display());
+                2
+            }
+        },
+        Err(e) => {
+            eprintln!("ERROR: failed reading Lua files: {e}");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use std::env;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            entry: "bin/tool.lua".to_string(),
+            shdicts: vec!["cache 10m".to_string()],
+            http_conf: vec!["lua_socket_log_errors off;".to_string()],
+            ..Default::default()
+        }
+    }
+
+    /// A zip archive after a `#!` line, written with the given comment.
+    fn zip_file(comment: &str, files: &[(&str, &str)]) -> Vec<u8> {
+        let mut out = Cursor::new(b"#!/bin/sh\n".to_vec());
+        out.seek(SeekFrom::End(0)).unwrap();
+
+        let mut zip = ZipWriter::new(out);
+        zip.set_comment(comment).unwrap();
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bundle = Bundle {
+            manifest: manifest(),
+            files: vec![
+                ("bin/tool.lua".to_string(), b"print(1)\n".to_vec()),
+                ("lua/a/init.lua".to_string(), b"\nPK\x05\x06\n\0".to_vec()),
+                ("lua/empty.lua".to_string(), vec![]),
+            ],
+        };
+
+        let data = bundle.to_bytes().unwrap();
+        assert!(data.starts_with(b"#!/usr/bin/env rusty-cli\nPK\x03\x04"));
+        assert_eq!(Ok(bundle.clone()), Bundle::from_reader(Cursor::new(&data)));
+
+        // a real zip archive, with offsets from the start of the file
+        let mut zip = ZipArchive::new(Cursor::new(&data)).unwrap();
+        assert_eq!(0, zip.offset());
+        assert_eq!(MAGIC.as_bytes(), zip.comment());
+        assert_eq!(
+            vec![MANIFEST, "bin/tool.lua", "lua/a/init.lua", "lua/empty.lua"],
+            zip.file_names().collect::<Vec<_>>()
+        );
+        let mut manifest = String::new();
+        zip.by_name(MANIFEST)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        assert_eq!(bundle.manifest.render(), manifest);
+
+        // after other data, like an executable
+        let exe = b"\x7fELF binary".to_vec();
+        let data = bundle.write_after(exe.clone()).unwrap();
+        assert!(data.starts_with(&exe));
+        assert_eq!(Ok(bundle.clone()), Bundle::from_reader(Cursor::new(&data)));
+        assert_eq!(Ok(exe.len() as u64), start(Cursor::new(&data)));
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        let parse = |data: Vec<u8>| Bundle::from_reader(Cursor::new(&data)).unwrap_err();
+        let entry = ("bin/a.lua", "print(1)");
+
+        assert_eq!("not a rusty-cli bundle", parse(b"print(1)\n".to_vec()));
+        assert_eq!(
+            "not a rusty-cli bundle",
+            parse(zip_file(
+                "other",
+                &[(MANIFEST, "entry = \"bin/a.lua\""), entry]
+            ))
+        );
+        assert_eq!("missing manifest", parse(zip_file(MAGIC, &[entry])));
+        assert_eq!(
+            "invalid file name `../a.lua`",
+            parse(zip_file(MAGIC, &[("../a.lua", "")]))
+        );
+        assert_eq!(
+            "missing entry script `bin/a.lua`",
+            parse(zip_file(MAGIC, &[(MANIFEST, "entry = \"bin/a.lua\"")]))
+        );
+        assert_eq!(
+            "manifest: line 2: unknown field `nope`, expected one of `entry`, `shdict`, `http-conf`, `main-conf`, `stream-conf`, `http-include`, `main-include`, `args`",
+            parse(zip_file(MAGIC, &[(MANIFEST, "entry = \"bin/a.lua\"\nnope = []"), entry]))
+        );
+        assert_eq!(
+            "missing include `include/http/1-a.conf`",
+            parse(zip_file(
+                MAGIC,
+                &[
+                    (
+                        MANIFEST,
+                        "entry = \"bin/a.lua\"\nhttp-include = [\"include/http/1-a.conf\"]"
+                    ),
+                    entry
+                ]
+            ))
+        );
+        assert_eq!(
+            "manifest: missing entry",
+            parse(zip_file(MAGIC, &[(MANIFEST, "shdict = []"), entry]))
+        );
+
+        let mut truncated = zip_file(MAGIC, &[(MANIFEST, "entry = \"bin/a.lua\""), entry]);
+        truncated.drain(10..40);
+        let err = parse(truncated);
+        assert!(err.starts_with("invalid Zip archive"), "{err}");
+    }
+
+    #[test]
+    fn detect_bundles() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+        let bundle = Bundle {
+            manifest: manifest(),
+            files: vec![("bin/tool.lua".to_string(), b"print(1)\n".to_vec())],
+        };
+
+        assert!(is_bundle(&file("a.rusty", &bundle.to_bytes().unwrap())));
+        assert!(is_bundle(&file(
+            "b",
+            &bundle.write_after(b"\x7fELF".to_vec()).unwrap()
+        )));
+        assert!(!is_bundle(&file("c.zip", &zip_file("", &[("a", "")]))));
+        assert!(!is_bundle(&file(
+            "d.lua",
+            b"#!/usr/bin/env rusty-cli\nprint(1)\n"
+        )));
+        assert!(!is_bundle(&file("e", b"")));
+        assert!(!is_bundle(&dir.join("missing")));
+        assert!(!is_bundle(&dir));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_required_modules() {
+        let src = r#"
+local a = require "a"
+local b = require('b.c')
+local c = require [[c-d]]
+local d = require(name)
+local e = my_require "e"
+require'f'
+"#;
+
+        assert_eq!(vec!["a", "b.c", "c-d", "f"], required_modules(src));
+    }
+
+    #[test]
+    fn collect_modules() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        for (name, contents) in [
+            ("tool.lua", "require 'a'\nrequire 'resty.core'\n"),
+            ("lib/a.lua", "require 'b'\nrequire 'a'\n"),
+            ("lib/b/init.lua", "return {}\n"),
+            ("other/b.lua", "return {}\n"),
+        ] {
+            let p = dir.join(name);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, contents).unwrap();
+        }
+
+        let dirs = [path("lib"), path("other")];
+        let (bundle, sources) = collect(&path("tool.lua"), &[], &dirs, manifest()).unwrap();
+
+        assert_eq!(
+            vec!["bin/tool.lua", "lua/a.lua", "lua/b/init.lua"],
+            bundle
+                .files
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![path("tool.lua"), path("lib/a.lua"), path("lib/b/init.lua")],
+            sources
+        );
+
+        // modules that are only loaded dynamically
+        fs::write(dir.join("lib/plugin.lua"), "require 'b'\n").unwrap();
+        let modules = ["plugin".to_string()];
+        let (bundle, _) = collect(&path("tool.lua"), &modules, &dirs, manifest()).unwrap();
+        assert_eq!(
+            vec![
+                "bin/tool.lua",
+                "lua/plugin.lua",
+                "lua/a.lua",
+                "lua/b/init.lua"
+            ],
+            bundle
+                .files
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let modules = ["missing".to_string()];
+        let err = collect(&path("tool.lua"), &modules, &dirs, manifest()).unwrap_err();
+        assert_eq!("module `missing` not found", err.to_string());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,117 +1,137 @@
+use crate::batch;
+use crate::bundle::{self, Bundle};
+use crate::compile;
+use crate::completions;
+use crate::config::{self, Config};
+use crate::coredump::{self, Capture};
+use crate::coverage::{Coverage, CoverageArgs};
+use crate::exe;
 use crate::lua::*;
+use crate::man;
 use crate::nginx;
 use crate::nginx::*;
-use crate::run::run;
+use crate::options::{self, Opt, Subcommand};
+use crate::profile::{self, Profile, ProfileArgs};
+use crate::repl;
+use crate::report::{self, Report};
+use crate::run::{self, run, Process, Status};
+use crate::spec;
 use crate::types::*;
 use crate::util::*;
+use crate::watch::Watcher;
 use crate::RESTY_COMPAT_VERSION;
 use crate::VERSION;
+use nix::sys::resource::Resource;
 use std::collections::VecDeque;
 use std::convert::From;
 use std::env;
-use std::fmt::Display;
+use std::fmt::{Display, Write as _};
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How long nginx has to exit after SIGQUIT once `--timeout` elapses.
+const DEFAULT_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
 
 fn print_usage() {
-    let resty_version = *RESTY_COMPAT_VERSION;
+    print!("{}", usage(&config::profile_names()));
+}
 
-    let stdout = std::io::stdout().lock();
-    let mut stdout = std::io::BufWriter::new(stdout);
-    use std::io::Write;
-    let _ = stdout.write_all(r#"
+/// The help text for `--help`, generated from the option table.
+fn usage(profiles: &[String]) -> String {
+    let mut out = String::from(
+        r#"
 Arguments:
   [lua-file]
+          Lua script to run (use `-` to read the script from stdin).
 
   [args]...
 
 
 Options:
-  -V
-          Print version numbers and nginx configurations.
-  -I <DIR>
-          Add dir to the search paths for Lua libraries.
-  -e <PROG>
-          Run the inlined Lua code in "prog".
-  -l <LIB>
-          require lua library "lib"
-  -j <OPT>
-          LuaJIT option:
-
-          -j dump    Use LuaJIT's jit.dump module to output detailed info of
-                     the traces generated by the JIT compiler.
-
-          -j off     Turn off the LuaJIT JIT compiler.
-
-          -j v       Use LuaJIT's jit.v module to output brief info of the
-                     traces generated by the JIT compiler. [possible values: v, dump, off]
-  -c <NUM>
-          Set maximal connection count [default: 64]
-      --ns <IP>
-          Specify a custom name server (multiple instances are supported).
-      --shdict <NAME SIZE>
-          Create the specified lua shared dicts in the http configuration block (multiple instances are supported).
-      --nginx <PATH>
-          Specify the nginx path (this option might be removed in the future).
-      --http-conf <CONF>
-          Specifies nginx.conf snippet inserted into the http {} configuration block (multiple instances are supported).
-      --stream-conf <CONF>
-          Disable the stream {} configuration in auto-generated nginx.conf."#.as_bytes());
-
-    if resty_version >= (0, 31).into() {
-        let _ = stdout.write_all(
-            r#"
-      --load-module <MOD>
-          Load the specified nginx module. (multiple instances are supported)."#
-                .as_bytes(),
-        );
-    }
-
-    let _ = stdout.write_all(r#"
-      --main-conf <CONF>
-          Specifies nginx.conf snippet inserted into the nginx main {} configuration block (multiple instances are supported).
-      --http-include <PATH>
-          Include the specified file in the nginx http configuration block (multiple instances are supported).
-      --main-include <PATH>
-          Include the specified file in the nginx main configuration block (multiple instances are supported)."#.as_bytes());
-
-    if resty_version >= (0, 32).into() {
-        let _ = stdout.write_all(
-            r#"
-      --dump-nginx-conf
-          Print the generated nginx configuration file instead of running nginx."#
-                .as_bytes(),
-        );
-    }
-
-    let _ = stdout.write_all(r#"
-      --valgrind
-          Use valgrind to run nginx.
-      --valgrind-opts <OPTS>
-          Pass extra options to valgrind.
-      --errlog-level <LEVEL>
-          Set nginx error_log level. [possible values: debug, info, notice, warn, error, crit, alert, emerg]
-      --resolve-ipv6
-          Make the nginx resolver lookup both IPv4 and IPv6 addresses.
-      --user-runner <user-runner>
-          Use CMD as user runner for the underlying nginx process.
-      --stap
-          Use sysetmtap to run the underlying nginx C process.
-      --stap-opts <stap-opts>
-          Pass extra systemtap command line options.
-      --gdb
-          Use GDB to run the underlying nginx C process.
-      --gdb-opts <gdb-opts>
-          Pass extra command-line options to GDB.
-      --no-stream
-          Specifies nginx.conf snippet inserted into the nginx stream {} configuration block (multiple instances are supported).
-      --rr
-          Use Mozilla rr to record the execution of the underlying nginx C process.
-  -h, --help
-          Print help (see more with '--help')
-"#.as_bytes());
+"#,
+    );
+
+    write_options(&mut out, options::documented(), profiles);
+    out
+}
+
+/// The help text for `-h` after a subcommand.
+fn command_usage(cmd: Subcommand, bin: &str) -> String {
+    let mut out = format!(
+        "Usage: {bin} {cmd} [OPTIONS] {}\n\n{}\n\nOptions:\n",
+        cmd.args(),
+        cmd.about()
+    );
+
+    write_options(&mut out, cmd.options().chunks(1), &[]);
+
+    let _ = writeln!(
+        out,
+        "\nAll options for running Lua code are accepted as well (see `{bin} --help`)."
+    );
+    out
+}
+
+/// Render groups of options with their help text.
+fn write_options<'a>(
+    out: &mut String,
+    groups: impl Iterator<Item = &'a [Opt]>,
+    profiles: &[String],
+) {
+    for opts in groups {
+        let opt = &opts[0];
+
+        let names: Vec<String> = opts
+            .iter()
+            .flat_map(|opt| {
+                opt.names().map(|name| match opt.metavar {
+                    Some(metavar) => format!("{name} <{metavar}>"),
+                    None => name.to_string(),
+                })
+            })
+            .collect();
+
+        // long options line up after the short ones
+        let indent = if opt.name.starts_with("--") { 6 } else { 2 };
+        let _ = writeln!(out, "{:indent$}{}", "", names.join(", "));
+
+        let mut help = opt.help.to_string();
+
+        for (choice, doc) in opt.choice_docs() {
+            let label = format!("{} {choice}", opt.name);
+            help.push('\n');
+
+            for (i, line) in doc.lines().enumerate() {
+                let label = if i == 0 { label.as_str() } else { "" };
+                let _ = write!(help, "\n{label:10} {line}");
+            }
+        }
+
+        if let Some(default) = opt.default {
+            let _ = write!(help, " [default: {default}]");
+        }
+
+        if !opt.choices.is_empty() {
+            let _ = write!(help, " [possible values: {}]", opt.choices.join(", "));
+        }
+
+        if opt.name == "--profile" && !profiles.is_empty() {
+            let _ = write!(help, " [available: {}]", profiles.join(", "));
+        }
+
+        for line in help.lines() {
+            if line.is_empty() {
+                out.push('\n');
+            } else {
+                let _ = writeln!(out, "{:10}{line}", "");
+            }
+        }
+    }
 }
 
 fn resolver(user: &mut UserArgs) -> String {
@@ -177,6 +197,15 @@ fn main_conf(user: &mut UserArgs) -> Vec<String> {
         user.errlog_level.to_owned()
     ));
 
+    // keep a copy of the log for the report
+    if user.report_json.is_some() {
+        conf.push(format!(
+            "error_log {} {};",
+            report::ERROR_LOG,
+            user.errlog_level.to_owned()
+        ));
+    }
+
     conf.append(&mut user.main_conf);
     conf.extend(
         user.main_include
@@ -205,8 +234,6 @@ pub(crate) trait CliOpt {
     }
 
     fn is_opt(&self) -> bool;
-
-    fn parse_opt_eq(&self) -> Option<(String, String)>;
 }
 
 impl<S: AsRef<str>> CliOpt for S {
@@ -235,20 +262,17 @@ impl<S: AsRef<str>> CliOpt for S {
 
     fn is_opt(&self) -> bool {
         let s = self.as_ref();
-        s.starts_with("--") || s.starts_with('-')
-    }
 
-    fn parse_opt_eq(&self) -> Option<(String, String)> {
-        if self.is_opt() {
-            if let Some((arg, optarg)) = self.as_ref().split_once('=') {
-                return Some((arg.to_string(), optarg.to_string()));
-            }
+        // a lone `-` is the conventional name for stdin
+        if s == STDIN_FILE {
+            return false;
         }
-        None
+
+        s.starts_with("--") || s.starts_with('-')
     }
 }
 
-fn include_file(section: &str, fname: String) -> Result<String, ArgError> {
+pub(crate) fn include_file(section: &str, fname: String) -> Result<String, ArgError> {
     let path = std::path::Path::new(&fname);
     if !path.is_file() {
         return Err(ArgError::MissingInclude(section.to_string(), fname));
@@ -263,6 +287,58 @@ fn include_file(section: &str, fname: String) -> Result<String, ArgError> {
     Ok(path.to_str().expect("uh oh").to_string())
 }
 
+pub(crate) fn keep_prefix(current: &mut KeepPrefix, new: KeepPrefix) -> Result<(), ArgError> {
+    fn name(keep: KeepPrefix) -> String {
+        match keep {
+            KeepPrefix::Always => "--keep-prefix",
+            KeepPrefix::OnError => "--keep-prefix-on-error",
+            KeepPrefix::Never => unreachable!(),
+        }
+        .to_string()
+    }
+
+    match *current {
+        KeepPrefix::Never => {
+            *current = new;
+            Ok(())
+        }
+        old if old == new => Err(ArgError::Duplicate(name(new))),
+        old => Err(ArgError::Conflict(name(old), name(new))),
+    }
+}
+
+/// Split up the options from a script's `#!` line.
+///
+/// The kernel passes everything after the interpreter in a `#!` line as a
+/// single argument, followed by the path of the script:
+///
+/// ```text
+/// #!/usr/bin/rusty-cli --shdict "cache 1m" -I lib
+/// ```
+///
+/// The combined argument is only split when the next argument really is a
+/// script that starts with a `#!` line containing it, so that an option value
+/// with spaces (`"--shdict=cache 1m"`) is left alone.
+fn split_shebang_args(mut args: Vec<String>) -> Vec<String> {
+    let (Some(combined), Some(script)) = (args.get(1), args.get(2)) else {
+        return args;
+    };
+
+    if !combined.is_opt() || !combined.contains(char::is_whitespace) {
+        return args;
+    }
+
+    if !shebang_line(script).is_some_and(|line| line.contains(combined.as_str())) {
+        return args;
+    }
+
+    if let Some(split) = try_split_shell_args(combined) {
+        args.splice(1..2, split);
+    }
+
+    args
+}
+
 fn basename(s: &str) -> &str {
     if s.is_empty() {
         return crate::NAME;
@@ -280,15 +356,51 @@ fn basename(s: &str) -> &str {
     }
 }
 
+/// What to do, as determined by the command line arguments.
 #[derive(Debug, PartialEq, Eq)]
-pub(crate) enum Action {
+#[non_exhaustive]
+pub enum Action {
+    /// Print usage information.
     Help(String),
+
+    /// Print version information (including that of nginx).
     Version(String, Option<PathBuf>),
+
+    /// Run Lua code in nginx.
     Main(Box<UserArgs>),
+
+    /// Print the merged contents of all config files.
+    PrintConfig(String),
+
+    /// Print a shell completion script.
+    Completions(String),
+
+    /// Print the man page.
+    Man(String),
+
+    /// Print usage information for a subcommand.
+    CommandHelp(String),
+
+    /// Run tests with the `test` subcommand.
+    Test(Box<UserArgs>),
+
+    /// Run several Lua files at once with `--parallel`.
+    Batch(Box<UserArgs>),
+
+    /// Compile Lua files to bytecode with the `compile` subcommand.
+    Compile(Box<UserArgs>),
+
+    /// Write a bundle with the `bundle` subcommand.
+    Bundle(Box<UserArgs>),
+
+    /// Write a self-contained executable with the `build-exe` subcommand.
+    BuildExe(Box<UserArgs>),
 }
 
 impl Action {
-    pub(crate) fn run(self) -> i32 {
+    /// Carry out the action, printing to stdout/stderr, and return the exit
+    /// code for the process.
+    pub fn run(self) -> i32 {
         match self {
             Action::Help(argv_0) => {
                 let argv_0 = basename(&argv_0);
@@ -314,110 +426,386 @@ impl Action {
                 run(cmd)
             }
 
-            Action::Main(mut user) => {
-                let prefix = match Prefix::new() {
+            Action::PrintConfig(config) => {
+                print!("{config}");
+                0
+            }
+
+            Action::Completions(script) | Action::Man(script) | Action::CommandHelp(script) => {
+                print!("{script}");
+                0
+            }
+
+            Action::Test(user) => spec::run(user),
+
+            Action::Batch(user) => batch::run(user),
+
+            Action::Compile(user) => compile::run(user),
+
+            Action::Bundle(user) => bundle::run(user),
+
+            Action::BuildExe(user) => exe::run(user),
+
+            Action::Main(user) if user.watch => run_watch(user),
+
+            Action::Main(user) => {
+                let prefix = match user.new_prefix() {
                     Ok(p) => p,
-                    Err(e) => {
-                        eprintln!("failed creating prefix directory: {}", e);
-                        return 2;
-                    }
+                    Err(e) => return fail(e),
                 };
 
-                if let Some(jit) = &user.jit_cmd {
-                    user.inline_lua.insert(0, jit.to_lua());
-                }
+                let rc = run_main(user, &prefix);
+                prefix.finish(rc);
+                rc
+            }
+        }
+    }
+}
 
-                let mut label = None;
+/// Generate the nginx configuration for a run, writing any supporting Lua
+/// files into the prefix directory.
+pub(crate) fn build_conf(
+    user: &mut UserArgs,
+    prefix: &Prefix,
+) -> Result<(nginx::ConfBuilder, Option<String>), Error> {
+    // LuaJIT settings go first, in the order they were given
+    let jit: Vec<String> = user
+        .jit_opts
+        .to_lua()
+        .into_iter()
+        .chain(user.jit_cmds.iter().filter_map(JitCmd::to_lua))
+        .collect();
+    user.inline_lua.splice(0..0, jit);
+
+    // bundled modules take precedence over any others
+    if let Some(archive) = &user.archive {
+        let dir = archive.extract(prefix).map_err(|source| Error::Io {
+            context: "failed extracting bundle",
+            source,
+        })?;
+        user.lua_package_path.insert(0, dir);
+
+        let (http, main) = archive.include_paths(prefix);
+        user.http_include.extend(http);
+        user.main_include.extend(main);
+    }
 
-                if *RESTY_COMPAT_VERSION >= (0, 30).into() {
-                    let mut s = String::from("# ");
-                    if !user.inline_lua.is_empty() {
-                        s.push_str("-e '");
-                        s.push_str(user.inline_lua.join("; ").as_ref());
-                        s.push('\'');
+    let mut label = None;
 
-                        if user.lua_file.is_some() {
-                            s.push(' ');
-                        }
-                    }
+    if *RESTY_COMPAT_VERSION >= (0, 30).into() {
+        let mut s = String::from("# ");
+        if !user.inline_lua.is_empty() {
+            s.push_str("-e '");
+            s.push_str(user.inline_lua.join("; ").as_ref());
+            s.push('\'');
 
-                    if let Some(fname) = &user.lua_file {
-                        s.push_str(fname);
-                    }
+            if user.lua_file.is_some() {
+                s.push(' ');
+            }
+        }
 
-                    s = s.replace(['\r', '\n'], "");
+        if let Some(fname) = &user.lua_file {
+            s.push_str(fname);
+        }
 
-                    label = Some(s);
-                }
+        s = s.replace(['\r', '\n'], "");
 
-                let lua_loader = match generate_lua_loader(
-                    &prefix,
-                    &user.lua_file,
-                    &user.inline_lua,
-                    &user.lua_args,
-                    user.arg_0.clone(),
-                    user.arg_c,
-                ) {
-                    Ok(ll) => ll,
-                    Err(e) => {
-                        eprintln!("failed to generate inline lua: {}", e);
-                        return e.raw_os_error().unwrap_or(2);
-                    }
-                };
+        label = Some(s);
+    }
 
-                let events_conf = vec![format!("worker_connections {};", user.worker_connections)];
-
-                let conf_builder = nginx::ConfBuilder::new()
-                    .load_modules(user.load_modules.clone())
-                    .main(main_conf(&mut user))
-                    .events(events_conf)
-                    .stream(stream_conf(&mut user), !user.no_stream)
-                    .http(http_conf(&mut user))
-                    .lua(lua_loader);
-
-                if user.dump_nginx_conf {
-                    let stdout = std::io::stdout();
-                    let handle = stdout.lock();
-                    if let Err(e) = conf_builder.render(handle) {
-                        eprintln!("failed writing nginx.conf to stdout: {}", e);
-                        return 2;
-                    }
-                    return 0;
-                }
+    let lua_loader = generate_lua_loader(prefix, user).map_err(Error::Lua)?;
 
-                let conf_path = prefix.conf.join("nginx.conf");
-                let file = match fs::File::create(conf_path) {
-                    Ok(file) => std::io::BufWriter::new(file),
-                    Err(e) => {
-                        eprintln!("failed opening nginx.conf for writing: {}", e);
-                        return 2;
-                    }
-                };
+    let events_conf = vec![format!("worker_connections {};", user.worker_connections)];
+
+    let conf_builder = nginx::ConfBuilder::new()
+        .load_modules(user.load_modules.clone())
+        .main(main_conf(user))
+        .events(events_conf)
+        .stream(stream_conf(user), !user.no_stream)
+        .http(http_conf(user))
+        .lua(lua_loader)
+        .expand_table(user.repl || user.subcommand == Some(Subcommand::Test));
+
+    Ok((conf_builder, label))
+}
+
+/// Render the nginx configuration to `conf/nginx.conf` inside the prefix.
+pub(crate) fn write_conf(conf_builder: nginx::ConfBuilder, prefix: &Prefix) -> Result<(), Error> {
+    let conf_path = prefix.conf.join("nginx.conf");
+    let file = fs::File::create(conf_path)
+        .map(std::io::BufWriter::new)
+        .map_err(|source| Error::Io {
+            context: "failed opening nginx.conf for writing",
+            source,
+        })?;
+
+    conf_builder.render(file).map_err(|source| Error::Io {
+        context: "failed writing nginx.conf file",
+        source,
+    })
+}
+
+/// Build the nginx process for a run, returning it along with the path of
+/// the nginx binary.
+pub(crate) fn nginx_process(
+    user: &mut UserArgs,
+    prefix: &Prefix,
+    label: Option<String>,
+) -> (Process, PathBuf) {
+    let nginx = nginx::nginx_bin(user.nginx_bin.take());
+
+    let mut rlimits = std::mem::take(&mut user.rlimits);
+    if user.capture.enabled()
+        && !rlimits
+            .iter()
+            .any(|lim| lim.resource == Resource::RLIMIT_CORE)
+    {
+        rlimits.push(coredump::core_rlimit());
+    }
+
+    let ngx = nginx::Exec {
+        bin: Some(nginx.clone()),
+        prefix: prefix.root.clone(),
+        runner: std::mem::take(&mut user.runner),
+        label,
+        rlimits,
+    };
+
+    let mut proc = Process::new(Command::from(ngx));
+
+    if let Some(timeout) = user.timeout {
+        let grace = user
+            .timeout_grace
+            .map_or(DEFAULT_TIMEOUT_GRACE, Duration::from);
+        proc = proc.timeout(timeout.into(), grace);
+    }
+
+    (proc, nginx)
+}
+
+/// Run nginx once in a new prefix with its output captured, calling
+/// `inspect` before the prefix is cleaned up.
+///
+/// Signals are forwarded to nginx unless `forward_signals` is false, which
+/// leaves the process-wide signal handlers alone (for embedding).
+pub(crate) fn run_captured<T, F>(
+    mut user: UserArgs,
+    forward_signals: bool,
+    inspect: F,
+) -> Result<(Status, T), Error>
+where
+    F: FnOnce(&Prefix) -> T,
+{
+    let prefix = user.new_prefix()?;
+
+    let (conf_builder, label) = build_conf(&mut user, &prefix)?;
+    write_conf(conf_builder, &prefix)?;
+
+    let (mut proc, nginx) = nginx_process(&mut user, &prefix, label);
+    if !forward_signals {
+        proc = proc.without_signal_forwarding();
+    }
+
+    let started = SystemTime::now();
+    let status = proc.capture_output().run_with_status();
+    user.capture.handle(&status, &nginx, &prefix, started);
+
+    let inspected = inspect(&prefix);
+    prefix.finish(status.code);
+
+    Ok((status, inspected))
+}
+
+fn fail(e: Error) -> i32 {
+    eprintln!("{}", e);
+    e.exit_code()
+}
+
+fn run_main(mut user: Box<UserArgs>, prefix: &Prefix) -> i32 {
+    let (conf_builder, label) = match build_conf(&mut user, prefix) {
+        Ok(built) => built,
+        Err(e) => return fail(e),
+    };
+
+    if user.dump_nginx_conf {
+        let stdout = std::io::stdout();
+        let handle = stdout.lock();
+        if let Err(e) = conf_builder.render(handle) {
+            eprintln!("failed writing nginx.conf to stdout: {}", e);
+            return 2;
+        }
+        return 0;
+    }
+
+    if let Err(e) = write_conf(conf_builder, prefix) {
+        return fail(e);
+    }
+
+    let (proc, nginx) = nginx_process(&mut user, prefix, label);
+    let argv = proc.argv();
+
+    let started = SystemTime::now();
+
+    let status = if user.repl {
+        let fifos = repl::Fifos::new(prefix);
+        if let Err(e) = fifos.create() {
+            eprintln!("failed creating repl fifos: {}", e);
+            return 2;
+        }
+
+        let status = proc
+            .on_spawn(move || repl::frontend(fifos))
+            .run_with_status();
+        repl::restore_terminal();
+        status
+    } else {
+        proc.run_with_status()
+    };
+
+    let ended = SystemTime::now();
+
+    user.capture.handle(&status, &nginx, prefix, started);
+
+    if let Some(path) = &user.report_json {
+        let report = Report {
+            nginx,
+            argv,
+            prefix: prefix.root.clone(),
+            started,
+            ended,
+            status: status.clone(),
+        };
 
-                if let Err(e) = conf_builder.render(file) {
-                    eprintln!("failed writing nginx.conf file: {}", e);
-                    return 2;
+        if let Err(e) = report.write(path) {
+            eprintln!("failed writing report to {}: {}", path.display(), e);
+        }
+    }
+
+    if user.coverage.enabled {
+        if let Err(e) = Coverage::read(prefix).write(&user.coverage) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if user.profile.enabled() {
+        if let Err(e) = Profile::read(prefix).write(&user.profile) {
+            eprintln!("{}", e);
+        }
+    }
+
+    status.code
+}
+
+/// Start a file watcher for everything that `--watch` looks at.
+fn watcher(user: &UserArgs) -> std::io::Result<Watcher> {
+    let mut watcher = Watcher::new()?;
+
+    if let Some(fname) = &user.lua_file {
+        watcher.file(Path::new(fname))?;
+    }
+
+    // -I directories don't have to exist
+    for dir in user.lua_package_path.iter().map(Path::new) {
+        if dir.is_dir() {
+            watcher.dir(dir)?;
+        }
+    }
+
+    for fname in user.http_include.iter().chain(user.main_include.iter()) {
+        watcher.file(Path::new(fname))?;
+    }
+
+    Ok(watcher)
+}
+
+/// The line printed between two runs.
+fn watch_separator(changed: &[PathBuf]) -> String {
+    let mut s = format!("----- {} changed", changed[0].display());
+    if changed.len() > 1 {
+        let _ = write!(s, " (and {} more)", changed.len() - 1);
+    }
+    s.push_str(", rerunning -----");
+    s
+}
+
+/// Run nginx, and rerun it in a new prefix whenever a watched file changes.
+///
+/// A change while nginx is still running stops it first. This only returns
+/// when nginx is interrupted by a signal, or when watching fails.
+fn run_watch(user: Box<UserArgs>) -> i32 {
+    let mut watcher = match watcher(&user) {
+        Ok(watcher) => watcher,
+        Err(source) => {
+            return fail(Error::Io {
+                context: "failed watching files for changes",
+                source,
+            })
+        }
+    };
+
+    let (changes_tx, changes) = channel();
+    thread::spawn(move || loop {
+        match watcher.wait() {
+            Ok(changed) => {
+                if changes_tx.send(changed).is_err() {
+                    return;
                 }
+                run::stop_children();
+            }
+            Err(e) => {
+                eprintln!("ERROR: failed watching files for changes: {e}");
+                return;
+            }
+        }
+    });
+
+    loop {
+        let rc = match user.new_prefix() {
+            Ok(prefix) => {
+                let rc = run_main(user.clone(), &prefix);
+                prefix.finish(rc);
+                rc
+            }
+            Err(e) => fail(e),
+        };
 
-                let ngx = nginx::Exec {
-                    bin: user.nginx_bin,
-                    prefix: prefix.root.clone(),
-                    runner: user.runner,
-                    label,
-                };
+        if run::interrupted() {
+            return rc;
+        }
 
-                run(Command::from(ngx))
+        let mut changed = match changes.try_recv() {
+            Ok(changed) => changed,
+            Err(TryRecvError::Empty) => {
+                eprintln!("----- exited with code {rc}, waiting for changes -----");
+                match changes.recv() {
+                    Ok(changed) => changed,
+                    Err(_) => return rc,
+                }
+            }
+            Err(TryRecvError::Disconnected) => return rc,
+        };
+
+        // changes that came in while nginx was stopping
+        for path in changes.try_iter().flatten() {
+            if !changed.contains(&path) {
+                changed.push(path);
             }
         }
+
+        eprintln!("{}", watch_separator(&changed));
     }
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
-pub(crate) struct UserArgs {
+/// The parsed arguments for running Lua code in nginx.
+///
+/// This type is opaque. It is produced by [`Action::try_from`].
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct UserArgs {
     pub(crate) inline_lua: Vec<String>,
     pub(crate) lua_file: Option<String>,
     pub(crate) lua_args: Vec<String>,
-    pub(crate) jit_cmd: Option<JitCmd>,
+    pub(crate) jit_cmds: Vec<JitCmd>,
+    pub(crate) jit_opts: JitOpts,
 
     pub(crate) nginx_bin: Option<PathBuf>,
     pub(crate) worker_connections: u32,
@@ -442,11 +830,122 @@ pub(crate) struct UserArgs {
     pub(crate) runner: Runner,
 
     pub(crate) dump_nginx_conf: bool,
+    pub(crate) repl: bool,
+
+    pub(crate) prefix: Option<PathBuf>,
+    pub(crate) tmpdir: Option<PathBuf>,
+    pub(crate) keep_prefix: KeepPrefix,
+    pub(crate) timeout: Option<Seconds>,
+    pub(crate) timeout_grace: Option<Seconds>,
+    pub(crate) rlimits: Vec<Rlimit>,
+    pub(crate) capture: Capture,
+    pub(crate) report_json: Option<PathBuf>,
+    pub(crate) relative_include: bool,
+    /// The indexes of the `lua_package_path` entries from the real command
+    /// line, which are the only ones that `--relative-include` applies to.
+    pub(crate) cli_includes: Vec<usize>,
+    pub(crate) watch: bool,
+    pub(crate) coverage: CoverageArgs,
+    pub(crate) profile: ProfileArgs,
+
+    pub(crate) parallel: Option<usize>,
+    /// The Lua files to run with `--parallel`.
+    pub(crate) scripts: Vec<String>,
+
+    pub(crate) subcommand: Option<Subcommand>,
+    /// The positional arguments of a subcommand.
+    pub(crate) operands: Vec<String>,
+    pub(crate) test: spec::TestArgs,
+    pub(crate) compile: compile::CompileArgs,
+    pub(crate) bundle: bundle::BundleArgs,
+    pub(crate) exe: exe::ExeArgs,
+
+    /// The bundle being run, if the Lua file is one.
+    pub(crate) archive: Option<Bundle>,
 
     pub(crate) arg_c: usize,
     pub(crate) arg_0: String,
 }
 
+impl UserArgs {
+    pub(crate) fn new_prefix(&self) -> Result<Prefix, Error> {
+        Prefix::new(self.prefix.clone(), self.tmpdir.clone(), self.keep_prefix)
+            .map_err(Error::Prefix)
+    }
+
+    /// Check the options and fill in defaults once all of them are known,
+    /// however they were given.
+    pub(crate) fn finish(&mut self) -> Result<(), ArgError> {
+        if self.jit_cmds.iter().any(|cmd| cmd.module == JitModule::P)
+            && self.profile.output.is_none()
+        {
+            self.profile.output = Some(PathBuf::from(profile::DEFAULT_OUTPUT));
+        }
+
+        if self.capture.enabled() && self.runner != Runner::Default {
+            let opt = if self.capture.backtrace {
+                "--backtrace"
+            } else {
+                "--save-core"
+            };
+            return Err(ArgError::Conflict(opt.to_string(), self.runner.arg_name()));
+        }
+
+        if let (Some(grace), None) = (self.timeout_grace, self.timeout) {
+            return Err(ArgError::InvalidValue {
+                arg: "--timeout-grace".to_string(),
+                value: Duration::from(grace).as_secs_f64().to_string(),
+                err: "only used with --timeout".to_string(),
+            });
+        }
+
+        // like timeout(1), a timeout of 0 means no timeout at all
+        if self.timeout.is_some_and(|secs| Duration::from(secs).is_zero()) {
+            self.timeout = None;
+            self.timeout_grace = None;
+        }
+
+        if self.prefix.is_some() && self.tmpdir.is_some() {
+            return Err(ArgError::Conflict(
+                "--prefix".to_string(),
+                "--tmpdir".to_string(),
+            ));
+        }
+
+        if let Some(fname) = &self.lua_file {
+            if fname != STDIN_FILE && File::open(fname).is_err() {
+                return Err(ArgError::LuaFileNotFound(fname.to_string()));
+            }
+        }
+
+        if self.subcommand.is_none() {
+            bundle::load(self)?;
+        }
+
+        if self.relative_include {
+            if let Some(dir) = self
+                .lua_file
+                .as_deref()
+                .filter(|fname| *fname != STDIN_FILE)
+                .and_then(|fname| Path::new(fname).parent())
+            {
+                for &i in self.cli_includes.iter() {
+                    let path = &mut self.lua_package_path[i];
+                    if Path::new(path).is_relative() {
+                        *path = dir.join(&*path).to_string_lossy().into_owned();
+                    }
+                }
+            }
+        }
+
+        if self.nameservers.is_empty() {
+            self.nameservers.extend(discover_system_nameservers());
+        }
+
+        Ok(())
+    }
+}
+
 fn discover_system_nameservers() -> Vec<IpAddr> {
     let mut ns = try_parse_resolv_conf();
 
@@ -460,12 +959,61 @@ fn discover_system_nameservers() -> Vec<IpAddr> {
 }
 
 impl Action {
-    pub(crate) fn try_from<T>(args: T) -> Result<Self, ArgError>
+    /// Parse command line arguments, the first of which is the program name.
+    ///
+    /// This is more than a pure parse, since it reads the environment and the
+    /// filesystem. A combined `#!` argument is split first, and then options
+    /// are layered underneath the command line, in order of increasing
+    /// precedence:
+    ///
+    /// 1. the system and user config files and any `.rustyrc` files, unless
+    ///    `--no-config` is given
+    /// 2. `RUSTY_CLI_*` environment variables, also skipped by `--no-config`
+    /// 3. `--!` directives at the top of the Lua file (or the manifest of a
+    ///    bundle), unless `--no-directives` is given
+    /// 4. the profiles selected with `--profile`
+    ///
+    /// If those can't be loaded, options that only print information (`-h`,
+    /// `-V`, `--man`, and `--completions`) still work, without them.
+    ///
+    /// When no Lua input is given, the kind of file attached to stdin decides
+    /// whether the script is read from stdin or the REPL is started.
+    pub fn try_from<T>(args: T) -> Result<Self, ArgError>
+    where
+        T: IntoIterator<Item = String>,
+    {
+        let args = split_shebang_args(args.into_iter().collect());
+        let cli = args.get(1..).unwrap_or_default();
+
+        let config = match Config::for_args(cli) {
+            Ok(config) => config,
+            // a broken config file doesn't keep anyone from reading the help
+            Err(_) if options::given(cli).iter().any(|(opt, _)| opt.is_info()) => Config::default(),
+            Err(e) => return Err(e),
+        };
+
+        Self::parse(args.clone(), &config, stdin_kind).map_err(|e| config.annotate(e, cli))
+    }
+
+    /// Parse command line arguments without reading any config files.
+    #[cfg(test)]
+    pub(crate) fn try_from_with_stdin<T, F>(args: T, stdin: F) -> Result<Self, ArgError>
     where
         T: IntoIterator<Item = String>,
+        F: FnOnce() -> Stdin,
+    {
+        let args = split_shebang_args(args.into_iter().collect());
+        Self::parse(args, &Config::default(), stdin)
+    }
+
+    fn parse<F>(args: Vec<String>, config: &Config, stdin: F) -> Result<Self, ArgError>
+    where
+        F: FnOnce() -> Stdin,
     {
-        let mut args: VecDeque<String> = args.into_iter().collect();
+        let mut args: VecDeque<String> = args.into();
 
+        // arguments from config files don't count towards the Lua `arg`
+        // table, so `arg_c` only reflects the real command line
         let mut user = UserArgs {
             arg_c: args.len(),
             worker_connections: 64,
@@ -475,20 +1023,69 @@ impl Action {
         let runner = &mut user.runner;
 
         let mut show_version = false;
+        let mut print_config = false;
 
         user.arg_0 = args.pop_front().ok_or(ArgError::EmptyArgv0)?;
 
+        user.subcommand = Subcommand::find(args.make_contiguous());
+
+        // only options from the real command line are baked in, along with
+        // those of its profiles, which might not exist where the executable
+        // is run
+        if user.subcommand == Some(Subcommand::BuildExe) {
+            let cli = args.make_contiguous();
+            user.exe.args = exe::baked_args(&config.profile_args(cli));
+            user.exe.args.extend(exe::baked_args(cli));
+        }
+
+        if user.subcommand.is_some() {
+            args.pop_front();
+        }
+
+        let real_args = args.len();
+
+        for arg in config.to_args(args.make_contiguous()).into_iter().rev() {
+            args.push_front(arg);
+        }
+
         while let Some(arg) = args.pop_front() {
+            // the config arguments all come before the real ones
+            let from_cli = args.len() < real_args;
+
             let mut optarg: Option<String> = None;
             let mut combined_opt_arg = false;
             let mut arg = arg;
 
+            let mut opt = None;
+
             if arg.is_opt() {
-                if let Some((a, o)) = arg.parse_opt_eq() {
-                    arg = a;
-                    optarg = Some(o);
+                let (name, value) = options::split_attached(&arg);
+
+                if let Some(value) = value {
+                    optarg = Some(value.to_string());
+                    arg = name.to_string();
                     combined_opt_arg = true;
-                } else {
+                }
+
+                if arg != "--" {
+                    let Some(found) = Opt::find(&arg)
+                        .or_else(|| user.subcommand.and_then(|cmd| cmd.find_opt(&arg)))
+                    else {
+                        return Err(ArgError::UnknownArgument(arg));
+                    };
+
+                    if combined_opt_arg && !found.takes_value() {
+                        return Err(ArgError::InvalidValue {
+                            arg,
+                            value: optarg.unwrap_or_default(),
+                            err: "arg does not take a value".to_string(),
+                        });
+                    }
+
+                    opt = Some(found);
+                }
+
+                if !combined_opt_arg && opt.is_none_or(Opt::takes_value) {
                     optarg = args.pop_front();
                 }
             }
@@ -497,22 +1094,50 @@ impl Action {
 
             let optarg = &mut optarg;
 
-            match arg.as_str() {
-                "-v" | "-V" | "--version" => {
+            // aliases are resolved to the canonical option name
+            match opt.map_or(arg.as_str(), |opt| opt.name) {
+                "-V" => {
                     show_version = true;
                     if user.nginx_bin.is_some() {
                         return Ok(Action::Version(user.arg_0, user.nginx_bin));
                     }
                 }
 
-                "-h" | "--help" => {
+                "-h" => {
+                    if let Some(cmd) = user.subcommand {
+                        return Ok(Action::CommandHelp(command_usage(
+                            cmd,
+                            basename(&user.arg_0),
+                        )));
+                    }
                     return Ok(Action::Help(user.arg_0));
                 }
 
+                "--man" => {
+                    return Ok(Action::Man(man::generate(basename(&user.arg_0))));
+                }
+
+                "--print-config" => {
+                    print_config = true;
+                }
+
+                "--completions" => {
+                    let shell = arg.parse_to(optarg)?;
+                    let script = completions::generate(shell, basename(&user.arg_0));
+                    return Ok(Action::Completions(script));
+                }
+
+                // handled before parsing
+                "--no-config" | "--no-directives" => {}
+
+                "--profile" => {
+                    arg.get_arg(optarg)?;
+                }
+
                 "--gdb" => runner.update(Runner::Gdb(None))?,
 
                 "--gdb-opts" => {
-                    let opts = arg.get_arg(optarg)?;
+                    let opts = arg.parse_to(optarg)?;
                     runner.update(Runner::Gdb(Some(opts)))?;
                 }
 
@@ -522,36 +1147,188 @@ impl Action {
                     user.dump_nginx_conf = true;
                 }
 
-                "--stap" => runner.update(Runner::Stap(None))?,
+                "--repl" => {
+                    user.repl = true;
+                }
 
-                "--stap-opts" => {
-                    let opts = arg.get_arg(optarg)?;
-                    runner.update(Runner::Stap(Some(opts)))?;
+                "--prefix" => {
+                    let value = arg.get_arg(optarg)?;
+                    user.prefix = Some(value.into());
                 }
 
-                "--user-runner" => {
-                    let opts = arg.get_arg(optarg)?;
-                    runner.update(Runner::User(opts))?;
+                "--tmpdir" => {
+                    let value = arg.get_arg(optarg)?;
+                    let path = PathBuf::from(&value);
+                    if let Err(err) = validate_temp_root(&path) {
+                        return Err(ArgError::InvalidValue { arg, value, err });
+                    }
+                    user.tmpdir = Some(path);
                 }
 
-                "--valgrind" => runner.update(Runner::Valgrind(None))?,
+                "--timeout" => {
+                    user.timeout = Some(arg.parse_to(optarg)?);
+                }
 
-                "--valgrind-opts" => {
-                    let opts = arg.get_arg(optarg)?;
-                    runner.update(Runner::Valgrind(Some(opts)))?;
+                "--timeout-grace" => {
+                    user.timeout_grace = Some(arg.parse_to(optarg)?);
                 }
 
-                "-c" => {
-                    user.worker_connections = arg.parse_to(optarg)?;
+                opt if Rlimit::resource_for_opt(opt).is_some() => {
+                    let resource = Rlimit::resource_for_opt(opt).expect("known rlimit option");
+                    let value = arg.parse_to(optarg)?;
+                    Rlimit::set(&mut user.rlimits, resource, value);
                 }
 
-                "--http-conf" => {
-                    arg.push_to(&mut user.http_conf, optarg)?;
+                "--backtrace" => {
+                    user.capture.backtrace = true;
                 }
 
-                "--http-include" => {
-                    let value = arg.get_arg(optarg)?;
-                    user.http_include.push(include_file("http", value)?);
+                "--save-core" => {
+                    user.capture.save_to = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--report-json" => {
+                    user.report_json = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--relative-include" => {
+                    user.relative_include = true;
+                }
+
+                "--watch" => {
+                    user.watch = true;
+                }
+
+                "--coverage" => {
+                    user.coverage.enabled = true;
+                }
+
+                "--coverage-dir" => {
+                    user.coverage.dir = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--coverage-include" => {
+                    arg.push_to(&mut user.coverage.include, optarg)?;
+                }
+
+                "--coverage-exclude" => {
+                    arg.push_to(&mut user.coverage.exclude, optarg)?;
+                }
+
+                "--lua-profile" => {
+                    user.profile.output = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--lua-profile-interval" | "--lua-profile-depth" => {
+                    let value = arg.get_arg(optarg)?;
+                    let n = match value.parse::<u32>() {
+                        Ok(n) if n > 0 => n,
+                        _ => {
+                            return Err(ArgError::InvalidValue {
+                                arg,
+                                value,
+                                err: "expected a positive number".to_string(),
+                            });
+                        }
+                    };
+
+                    if arg == "--lua-profile-interval" {
+                        user.profile.interval = Some(n);
+                    } else {
+                        user.profile.depth = Some(n);
+                    }
+                }
+
+                "--parallel" => {
+                    let value = arg.get_arg(optarg)?;
+                    match value.parse() {
+                        Ok(n) if (1..=run::MAX_CHILDREN).contains(&n) => {
+                            user.parallel = Some(n);
+                        }
+                        _ => {
+                            return Err(ArgError::InvalidValue {
+                                arg,
+                                value,
+                                err: format!("expected a number from 1 to {}", run::MAX_CHILDREN),
+                            });
+                        }
+                    }
+                }
+
+                "--filter" => {
+                    user.test.filter = Some(arg.get_arg(optarg)?);
+                }
+
+                "--junit" => {
+                    user.test.junit = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--strip" => {
+                    user.compile.strip = true;
+                }
+
+                "--force" => {
+                    user.compile.force = true;
+                }
+
+                "--output" => {
+                    user.bundle.output = Some(PathBuf::from(arg.get_arg(optarg)?));
+                }
+
+                "--module" => {
+                    user.bundle.modules.push(arg.get_arg(optarg)?);
+                }
+
+                "--single-instance" => {
+                    user.test.single_instance = true;
+                }
+
+                "--keep-prefix" => {
+                    keep_prefix(&mut user.keep_prefix, KeepPrefix::Always)?;
+                }
+
+                "--keep-prefix-on-error" => {
+                    keep_prefix(&mut user.keep_prefix, KeepPrefix::OnError)?;
+                }
+
+                "--stap" => runner.update(Runner::Stap(None))?,
+
+                "--stap-opts" => {
+                    let opts = arg.parse_to(optarg)?;
+                    runner.update(Runner::Stap(Some(opts)))?;
+                }
+
+                "--user-runner" => {
+                    let value = arg.get_arg(optarg)?;
+                    let opts = match value.parse::<ShellArgs>() {
+                        Ok(opts) if opts.0.is_empty() => Err("expected a command".to_string()),
+                        parsed => parsed,
+                    };
+
+                    match opts {
+                        Ok(opts) => runner.update(Runner::User(opts))?,
+                        Err(err) => return Err(ArgError::InvalidValue { arg, value, err }),
+                    }
+                }
+
+                "--valgrind" => runner.update(Runner::Valgrind(None))?,
+
+                "--valgrind-opts" => {
+                    let opts = arg.parse_to(optarg)?;
+                    runner.update(Runner::Valgrind(Some(opts)))?;
+                }
+
+                "-c" => {
+                    user.worker_connections = arg.parse_to(optarg)?;
+                }
+
+                "--http-conf" => {
+                    arg.push_to(&mut user.http_conf, optarg)?;
+                }
+
+                "--http-include" => {
+                    let value = arg.get_arg(optarg)?;
+                    user.http_include.push(include_file("http", value)?);
                 }
 
                 "--main-conf" => {
@@ -602,10 +1379,17 @@ impl Action {
 
                 "-I" => {
                     arg.push_to(&mut user.lua_package_path, optarg)?;
+                    if from_cli {
+                        user.cli_includes.push(user.lua_package_path.len() - 1);
+                    }
                 }
 
                 "-j" => {
-                    user.jit_cmd = Some(arg.parse_to(optarg)?);
+                    arg.push_to(&mut user.jit_cmds, optarg)?;
+                }
+
+                "-O" => {
+                    user.jit_opts.extend(arg.parse_to(optarg)?);
                 }
 
                 "-l" => {
@@ -618,6 +1402,11 @@ impl Action {
                     arg.push_to(&mut user.inline_lua, optarg)?;
                 }
 
+                "--" if user.subcommand.is_some() => {
+                    user.operands.extend(optarg.take());
+                    end_of_args = true;
+                }
+
                 "--" => {
                     user.lua_file = optarg.take();
                     end_of_args = true;
@@ -626,6 +1415,8 @@ impl Action {
                 _ => {
                     if arg.is_opt() {
                         return Err(ArgError::UnknownArgument(arg));
+                    } else if user.subcommand.is_some() {
+                        user.operands.push(arg.clone());
                     } else {
                         end_of_args = true;
                         user.lua_file = Some(arg.clone());
@@ -650,24 +1441,101 @@ impl Action {
             }
         }
 
-        user.lua_args.extend(args);
+        if user.subcommand.is_some() {
+            user.operands.extend(args);
+        } else {
+            user.lua_args.extend(args);
+        }
+
+        if print_config {
+            return Ok(Action::PrintConfig(config.render()));
+        }
 
         if show_version {
             return Ok(Action::Version(user.arg_0, user.nginx_bin));
         }
 
-        if user.inline_lua.is_empty() && user.lua_file.is_none() && user.jit_cmd.is_none() {
-            return Err(ArgError::NoLuaInput);
+        if user.repl && user.lua_file.as_deref() == Some(STDIN_FILE) {
+            return Err(ArgError::Conflict(
+                "--repl".to_string(),
+                STDIN_FILE.to_string(),
+            ));
         }
 
-        if let Some(fname) = &user.lua_file {
-            if File::open(fname).is_err() {
-                return Err(ArgError::LuaFileNotFound(fname.to_string()));
+        if user.parallel.is_some() && user.subcommand.is_none() {
+            batch::validate(&mut user)?;
+        }
+
+        if user.subcommand.is_none()
+            && user.parallel.is_none()
+            && user.inline_lua.is_empty()
+            && user.lua_file.is_none()
+            && user.jit_cmds.is_empty()
+            && user.jit_opts == JitOpts::default()
+            && !user.repl
+        {
+            match stdin() {
+                Stdin::Stream => {
+                    // behave as if `-` had been given on the command line
+                    user.lua_file = Some(STDIN_FILE.to_string());
+                    user.arg_c += 1;
+                }
+                Stdin::Terminal => {
+                    user.repl = true;
+                }
+                Stdin::Other => return Err(ArgError::NoLuaInput),
+            }
+        }
+
+        // subcommands check their own conflicts
+        if user.watch && user.subcommand.is_none() {
+            let conflict = if user.repl {
+                Some("--repl")
+            } else if user.dump_nginx_conf {
+                Some("--dump-nginx-conf")
+            } else if user.lua_file.as_deref() == Some(STDIN_FILE) {
+                Some(STDIN_FILE)
+            } else {
+                None
+            };
+
+            if let Some(opt) = conflict {
+                return Err(ArgError::Conflict("--watch".to_string(), opt.to_string()));
+            }
+
+            if user.lua_file.is_none()
+                && user.lua_package_path.is_empty()
+                && user.http_include.is_empty()
+                && user.main_include.is_empty()
+            {
+                return Err(ArgError::NothingToWatch);
             }
         }
 
-        if user.nameservers.is_empty() {
-            user.nameservers.extend(discover_system_nameservers());
+        user.finish()?;
+
+        match user.subcommand {
+            Some(Subcommand::Test) => {
+                spec::validate(&mut user)?;
+                return Ok(Action::Test(Box::new(user)));
+            }
+            Some(Subcommand::Compile) => {
+                compile::validate(&mut user)?;
+                return Ok(Action::Compile(Box::new(user)));
+            }
+            Some(Subcommand::Bundle) => {
+                bundle::validate(&mut user)?;
+                return Ok(Action::Bundle(Box::new(user)));
+            }
+            Some(Subcommand::BuildExe) => {
+                exe::validate(&mut user)?;
+                return Ok(Action::BuildExe(Box::new(user)));
+            }
+            None => {}
+        }
+
+        if user.parallel.is_some() {
+            return Ok(Action::Batch(Box::new(user)));
         }
 
         Ok(Action::Main(Box::new(user)))
@@ -677,12 +1545,22 @@ impl Action {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::options::OPTIONS;
 
     macro_rules! action {
         ( $( $x:expr ),* ) => {
             {
                 let v = vec![$($x.to_string(),)*];
-                Action::try_from(v)
+                Action::try_from_with_stdin(v, || Stdin::Other)
+            }
+        };
+    }
+
+    macro_rules! action_with_stdin {
+        ( $stdin:expr; $( $x:expr ),* ) => {
+            {
+                let v = vec![$($x.to_string(),)*];
+                Action::try_from_with_stdin(v, || $stdin)
             }
         };
     }
@@ -706,6 +1584,251 @@ mod tests {
         assert_eq!(Err(ArgError::NoLuaInput), action!("bin"));
     }
 
+    #[test]
+    fn no_lua_with_stdin() {
+        assert_eq!(
+            Err(ArgError::NoLuaInput),
+            action_with_stdin!(Stdin::Other; "bin")
+        );
+
+        let Ok(Action::Main(args)) = action_with_stdin!(Stdin::Stream; "bin") else {
+            panic!("expected stdin to be used as the lua file");
+        };
+        assert_eq!(Some(STDIN_FILE.to_string()), args.lua_file);
+        assert_eq!(2, args.arg_c);
+
+        // stdin is not consulted when there is some other form of input
+        let Ok(Action::Main(args)) = action_with_stdin!(Stdin::Stream; "bin", "-e", "1") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(None, args.lua_file);
+    }
+
+    #[test]
+    fn repl() {
+        let Ok(Action::Main(args)) = action_with_stdin!(Stdin::Terminal; "bin") else {
+            panic!("expected a terminal on stdin to start the repl");
+        };
+        assert!(args.repl);
+        assert_eq!(None, args.lua_file);
+
+        let Ok(Action::Main(args)) = action!("bin", "--repl") else {
+            panic!("expected a main action");
+        };
+        assert!(args.repl);
+
+        let Ok(Action::Main(args)) = action!("bin", "--repl", "-l", "cjson") else {
+            panic!("expected a main action");
+        };
+        assert!(args.repl);
+        assert_eq!(svec!["require([=[cjson]=])"], args.inline_lua);
+
+        assert_eq!(
+            Err(ArgError::Conflict("--repl".into(), "-".into())),
+            action!("bin", "--repl", "-")
+        );
+    }
+
+    #[test]
+    fn keep_prefix_opts() {
+        let Ok(Action::Main(args)) = action!("bin", "--prefix", "/my/prefix", "-e", "1") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(Some(PathBuf::from("/my/prefix")), args.prefix);
+        assert_eq!(KeepPrefix::Never, args.keep_prefix);
+
+        let Ok(Action::Main(args)) = action!("bin", "--keep-prefix", "-e", "1") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(KeepPrefix::Always, args.keep_prefix);
+
+        let Ok(Action::Main(args)) = action!("bin", "--keep-prefix-on-error", "-e", "1") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(KeepPrefix::OnError, args.keep_prefix);
+
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "--keep-prefix".into(),
+                "--keep-prefix-on-error".into()
+            )),
+            action!("bin", "--keep-prefix", "--keep-prefix-on-error", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::Duplicate("--keep-prefix".into())),
+            action!("bin", "--keep-prefix", "--keep-prefix", "-e", "1")
+        );
+    }
+
+    #[test]
+    fn tmpdir_opt() {
+        let Ok(Action::Main(args)) = action!("bin", "--tmpdir", "/tmp", "-e", "1") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(Some(PathBuf::from("/tmp")), args.tmpdir);
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "--tmpdir".into(),
+                value: "/a/b/i-dont-exist".into(),
+                err: "directory does not exist".into(),
+            }),
+            action!("bin", "--tmpdir", "/a/b/i-dont-exist", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::Conflict("--prefix".into(), "--tmpdir".into())),
+            action!(
+                "bin",
+                "--tmpdir",
+                "/tmp",
+                "--prefix",
+                "/my/prefix",
+                "-e",
+                "1"
+            )
+        );
+    }
+
+    #[test]
+    fn timeout_opts() {
+        let Ok(Action::Main(args)) =
+            action!("bin", "--timeout", "1.5", "--timeout-grace", "0", "-e", "1")
+        else {
+            panic!("expected a main action");
+        };
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            args.timeout.map(Duration::from)
+        );
+        assert_eq!(Some(Duration::ZERO), args.timeout_grace.map(Duration::from));
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "--timeout".into(),
+                value: "soon".into(),
+                err: "expecting a number of seconds".into(),
+            }),
+            action!("bin", "--timeout", "soon", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "--timeout-grace".into(),
+                value: "2.5".into(),
+                err: "only used with --timeout".into(),
+            }),
+            action!("bin", "--timeout-grace", "2.5", "-e", "1")
+        );
+
+        // 0 turns the timeout off, along with its grace period
+        let Ok(Action::Main(args)) =
+            action!("bin", "--timeout", "0", "--timeout-grace", "1", "-e", "1")
+        else {
+            panic!("expected a main action");
+        };
+        assert_eq!(None, args.timeout);
+        assert_eq!(None, args.timeout_grace);
+    }
+
+    #[test]
+    fn rlimit_opts() {
+        use nix::sys::resource::Resource;
+
+        let Ok(Action::Main(args)) = action!(
+            "bin",
+            "--rlimit-nofile",
+            "1024",
+            "--rlimit-core",
+            "unlimited",
+            "--rlimit-as=1g",
+            "--rlimit-nofile",
+            "4096",
+            "-e",
+            "1"
+        ) else {
+            panic!("expected a main action");
+        };
+
+        assert_eq!(
+            vec![
+                Rlimit {
+                    resource: Resource::RLIMIT_CORE,
+                    value: RlimitValue::Unlimited,
+                },
+                Rlimit {
+                    resource: Resource::RLIMIT_AS,
+                    value: RlimitValue::Limit(1 << 30),
+                },
+                Rlimit {
+                    resource: Resource::RLIMIT_NOFILE,
+                    value: RlimitValue::Limit(4096),
+                },
+            ],
+            args.rlimits
+        );
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "--rlimit-core".into(),
+                value: "lots".into(),
+                err: "expecting a number (with optional k/m/g suffix) or `unlimited`".into(),
+            }),
+            action!("bin", "--rlimit-core", "lots", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::UnknownArgument("--rlimit-bogus".into())),
+            action!("bin", "--rlimit-bogus", "1", "-e", "1")
+        );
+    }
+
+    #[test]
+    fn core_capture_opts() {
+        let Ok(Action::Main(args)) =
+            action!("bin", "--backtrace", "--save-core", "/my/cores", "-e", "1")
+        else {
+            panic!("expected a main action");
+        };
+
+        assert_eq!(
+            Capture {
+                backtrace: true,
+                save_to: Some(PathBuf::from("/my/cores")),
+            },
+            args.capture
+        );
+
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "--backtrace".into(),
+                "--valgrind".into()
+            )),
+            action!("bin", "--backtrace", "--valgrind", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::Conflict("--save-core".into(), "--gdb".into())),
+            action!("bin", "--gdb", "--save-core", "/my/cores", "-e", "1")
+        );
+    }
+
+    #[test]
+    fn stdin_lua_file() {
+        let Ok(Action::Main(args)) = action!("bin", "-", "a", "-b") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(Some(STDIN_FILE.to_string()), args.lua_file);
+        assert_eq!(svec!["a", "-b"], args.lua_args);
+
+        let Ok(Action::Main(args)) = action!("bin", "-e", "1", "--", "-", "a") else {
+            panic!("expected a main action");
+        };
+        assert_eq!(Some(STDIN_FILE.to_string()), args.lua_file);
+        assert_eq!(svec!["a"], args.lua_args);
+    }
+
     #[test]
     fn version_action() {
         let exp = Ok(Action::Version("bin".into(), None));
@@ -762,6 +1885,661 @@ mod tests {
         assert_eq!(exp, action!("bin", "--no-stream", "--help"));
     }
 
+    #[test]
+    fn usage_text() {
+        let text = usage(&["a".to_string(), "b".to_string()]);
+
+        for opts in options::documented() {
+            for opt in opts {
+                for name in opt.names() {
+                    assert!(text.contains(name), "{name}");
+                }
+            }
+        }
+
+        for expected in [
+            "\n  -V, -v, --version\n",
+            "\n      --tmpdir <DIR>\n",
+            "\n          -j dump    Use LuaJIT's jit.dump module to output detailed info of\n                     the traces generated by the JIT compiler.\n",
+            " [possible values: v, dump, on, off, flush, p]\n",
+            "\n          Set maximal connection count [default: 64]\n",
+            " later profiles take precedence. [available: a, b]\n",
+        ] {
+            assert!(text.contains(expected), "{expected}\n{text}");
+        }
+
+        assert!(!usage(&[]).contains("[available:"));
+    }
+
+    #[test]
+    fn option_table_matches_parser() {
+        for opt in OPTIONS.iter().filter(|opt| opt.available()) {
+            for name in opt.names() {
+                let result = action!("bin", "-e", "1", name);
+
+                if opt.takes_value() {
+                    assert!(
+                        matches!(result, Err(ArgError::MissingValue(_))),
+                        "{name}: {result:?}"
+                    );
+                } else {
+                    assert!(
+                        !matches!(result, Err(ArgError::UnknownArgument(_))),
+                        "{name}: {result:?}"
+                    );
+                }
+            }
+        }
+
+        let Ok(Action::Main(user)) = action!("bin", "-e", "1") else {
+            panic!("expected Action::Main");
+        };
+
+        let default = |name| Opt::find(name).unwrap().default.unwrap();
+        assert_eq!(default("-c"), user.worker_connections.to_string());
+        assert_eq!(
+            default("--timeout-grace"),
+            DEFAULT_TIMEOUT_GRACE.as_secs().to_string()
+        );
+    }
+
+    #[test]
+    fn shebang_args() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let script = dir.join("script.lua");
+        let script = script.to_str().unwrap();
+        fs::write(
+            script,
+            "#!/usr/bin/rusty-cli --shdict 'cache 1m' -I lib --\nprint(1)\n",
+        )
+        .unwrap();
+
+        let split = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            split_shebang_args(args)
+        };
+
+        assert_eq!(
+            vec!["bin", "--shdict", "cache 1m", "-I", "lib", "--", script, "a"],
+            split(&["bin", "--shdict 'cache 1m' -I lib --", script, "a"])
+        );
+
+        // not from the #! line
+        assert_eq!(
+            vec!["bin", "--shdict=cache 1m", script],
+            split(&["bin", "--shdict=cache 1m", script])
+        );
+        assert_eq!(
+            vec!["bin", "--shdict 'cache 1m'", "nope.lua"],
+            split(&["bin", "--shdict 'cache 1m'", "nope.lua"])
+        );
+
+        let Ok(Action::Main(user)) = action!("bin", "--shdict 'cache 1m' -I lib --", script, "a")
+        else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            svec!["cache 1m"],
+            user.user_shdicts
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["lib"], user.lua_package_path);
+        assert_eq!(Some(script), user.lua_file.as_deref());
+        assert_eq!(vec!["a"], user.lua_args);
+        assert_eq!(8, user.arg_c);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_include() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let script = dir.join("script.lua");
+        fs::write(&script, "print(1)\n").unwrap();
+        let script = script.to_str().unwrap();
+
+        let Ok(Action::Main(user)) = action!(
+            "bin",
+            "--relative-include",
+            "-I",
+            "lib",
+            "-I",
+            "/abs",
+            script
+        ) else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            vec![dir.join("lib").to_str().unwrap(), "/abs"],
+            user.lua_package_path
+        );
+
+        let Ok(Action::Main(user)) = action!("bin", "-I", "lib", script) else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(vec!["lib"], user.lua_package_path);
+
+        let Ok(Action::Main(user)) = action!("bin", "--relative-include", "-I", "lib", "-e", "1")
+        else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(vec!["lib"], user.lua_package_path);
+
+        // directories from the environment stay relative to the current
+        // directory
+        let mut config = Config::default();
+        config
+            .add_env(|name| (name == "RUSTY_CLI_INCLUDE").then(|| "envlib".into()))
+            .unwrap();
+
+        let args = svec!["bin", "--relative-include", "-I", "lib", script];
+        let Ok(Action::Main(user)) = Action::parse(args, &config, || Stdin::Other) else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            vec!["envlib", dir.join("lib").to_str().unwrap()],
+            user.lua_package_path
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn watch() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let script = dir.join("script.lua");
+        fs::write(&script, "print(1)\n").unwrap();
+        let script = script.to_str().unwrap();
+
+        let Ok(Action::Main(user)) = action!("bin", "--watch", script) else {
+            panic!("expected Action::Main");
+        };
+        assert!(user.watch);
+
+        assert!(matches!(
+            action!("bin", "--watch", "-I", "lib", "-e", "1"),
+            Ok(Action::Main(_))
+        ));
+
+        assert_eq!(
+            Err(ArgError::NothingToWatch),
+            action!("bin", "--watch", "-e", "1")
+        );
+
+        for (args, opt) in [
+            (svec!["--repl"], "--repl"),
+            (svec!["--dump-nginx-conf", script], "--dump-nginx-conf"),
+            (svec!["-"], "-"),
+        ] {
+            let mut argv = svec!["bin", "--watch"];
+            argv.extend(args);
+            assert_eq!(
+                Err(ArgError::Conflict("--watch".to_string(), opt.to_string())),
+                Action::try_from_with_stdin(argv, || Stdin::Other)
+            );
+        }
+
+        assert_eq!(
+            "----- main.lua changed, rerunning -----",
+            watch_separator(&[PathBuf::from("main.lua")])
+        );
+        assert_eq!(
+            "----- main.lua changed (and 2 more), rerunning -----",
+            watch_separator(&[
+                PathBuf::from("main.lua"),
+                PathBuf::from("lib/a.lua"),
+                PathBuf::from("lib/b.lua"),
+            ])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parallel() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let a = dir.join("a.lua");
+        let b = dir.join("b.lua");
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let Ok(Action::Batch(user)) = action!("bin", "--parallel", "4", "-e", "1", a, b) else {
+            panic!("expected Action::Batch");
+        };
+        assert_eq!(Some(4), user.parallel);
+        assert_eq!(svec![a, b], user.scripts);
+        assert_eq!(None, user.lua_file);
+        assert!(user.lua_args.is_empty());
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "--parallel".to_string(),
+                value: "65".to_string(),
+                err: "expected a number from 1 to 64".to_string(),
+            }),
+            action!("bin", "--parallel", "65", a)
+        );
+
+        assert_eq!(
+            Err(ArgError::LuaFileNotFound("nope.lua".to_string())),
+            action!("bin", "--parallel", "2", a, "nope.lua")
+        );
+
+        for (args, opt) in [
+            (svec!["--repl"], "--repl"),
+            (svec!["--prefix", "/tmp", a], "--prefix"),
+            (svec!["--gdb", a], "--gdb"),
+            (svec![a, "-"], "-"),
+        ] {
+            let mut argv = svec!["bin", "--parallel", "2"];
+            argv.extend(args);
+            assert_eq!(
+                Err(ArgError::Conflict(
+                    "--parallel".to_string(),
+                    opt.to_string()
+                )),
+                Action::try_from_with_stdin(argv, || Stdin::Other)
+            );
+        }
+
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "test".to_string(),
+                "--parallel".to_string()
+            )),
+            action!("bin", "test", "--parallel", "2", a)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn coverage() {
+        let Ok(Action::Main(user)) = action!("bin", "-e", "1") else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(CoverageArgs::default(), user.coverage);
+
+        let Ok(Action::Main(user)) = action!(
+            "bin",
+            "--coverage",
+            "--coverage-dir",
+            "cov",
+            "--coverage-include",
+            "^lib/",
+            "--coverage-include",
+            "^src/",
+            "--coverage-exclude",
+            "_spec%.lua$",
+            "-e",
+            "1"
+        ) else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            CoverageArgs {
+                enabled: true,
+                dir: Some(PathBuf::from("cov")),
+                include: svec!["^lib/", "^src/"],
+                exclude: svec!["_spec%.lua$"],
+            },
+            user.coverage
+        );
+    }
+
+    #[test]
+    fn lua_profile() {
+        let Ok(Action::Main(user)) = action!("bin", "-e", "1") else {
+            panic!("expected Action::Main");
+        };
+        assert!(!user.profile.enabled());
+
+        let Ok(Action::Main(user)) = action!("bin", "-j", "p", "-e", "1") else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            Some(PathBuf::from("lua-profile.folded")),
+            user.profile.output
+        );
+        assert!(user.inline_lua.iter().all(|lua| !lua.contains("jit")));
+
+        let Ok(Action::Main(user)) = action!(
+            "bin",
+            "-j",
+            "p",
+            "--lua-profile",
+            "flame.svg",
+            "--lua-profile-interval",
+            "2",
+            "--lua-profile-depth",
+            "16",
+            "-e",
+            "1"
+        ) else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            ProfileArgs {
+                output: Some(PathBuf::from("flame.svg")),
+                interval: Some(2),
+                depth: Some(16),
+            },
+            user.profile
+        );
+
+        for value in ["fast", "0"] {
+            assert_eq!(
+                Err(ArgError::InvalidValue {
+                    arg: "--lua-profile-interval".to_string(),
+                    value: value.to_string(),
+                    err: "expected a positive number".to_string(),
+                }),
+                action!("bin", "--lua-profile-interval", value, "-e", "1")
+            );
+        }
+    }
+
+    #[test]
+    fn luajit_options() {
+        let Ok(Action::Main(user)) = action!(
+            "bin",
+            "-j",
+            "dump=+rsx,out.txt",
+            "-jv=trace.log",
+            "-j=off",
+            "-O3",
+            "-O-fold,+cse",
+            "-O-fwd,+dse",
+            "-Ohotloop=5",
+            "-O",
+            "maxside=10",
+            "-e",
+            "1"
+        ) else {
+            panic!("expected Action::Main");
+        };
+
+        assert_eq!(
+            vec![
+                "dump=+rsx,out.txt".parse::<JitCmd>().unwrap(),
+                "v=trace.log".parse().unwrap(),
+                "off".parse().unwrap(),
+            ],
+            user.jit_cmds
+        );
+
+        let mut opts: JitOpts = "3,-fold,+cse,-fwd,+dse".parse().unwrap();
+        opts.extend("hotloop=5,maxside=10".parse().unwrap());
+        assert_eq!(opts, user.jit_opts);
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "-j".to_string(),
+                value: "v=a,b".to_string(),
+                err: "`v` takes at most 1 argument".to_string(),
+            }),
+            action!("bin", "-jv=a,b", "-e", "1")
+        );
+
+        assert_eq!(
+            Err(ArgError::InvalidValue {
+                arg: "-O".to_string(),
+                value: "hotloop=x".to_string(),
+                err: "expected a number for `hotloop`".to_string(),
+            }),
+            action!("bin", "-Ohotloop=x", "-e", "1")
+        );
+    }
+
+    #[test]
+    fn test_command() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let a = dir.join("a_spec.lua");
+        let b = dir.join("b_test.lua");
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let dir_str = dir.to_str().unwrap();
+
+        let Ok(Action::Test(user)) = action!(
+            "bin",
+            "test",
+            "-I",
+            "lib",
+            a,
+            "--filter",
+            "adds",
+            "--junit=out.xml",
+            "--single-instance",
+            "--",
+            b
+        ) else {
+            panic!("expected Action::Test");
+        };
+        assert_eq!(Some(Subcommand::Test), user.subcommand);
+        assert_eq!(svec![a, b], user.operands);
+        assert_eq!(svec!["lib"], user.lua_package_path);
+        assert_eq!(Some("adds".to_string()), user.test.filter);
+        assert_eq!(Some(PathBuf::from("out.xml")), user.test.junit);
+        assert!(user.test.single_instance);
+        assert_eq!(None, user.lua_file);
+        assert!(user.lua_args.is_empty());
+
+        let Ok(Action::Test(user)) = action!("bin", "test", dir_str) else {
+            panic!("expected Action::Test");
+        };
+        assert_eq!(svec![a, b], user.test.files);
+
+        // subcommand options are only known to the subcommand
+        assert_eq!(
+            Err(ArgError::UnknownArgument("--filter".to_string())),
+            action!("bin", "--filter", "x", "test")
+        );
+
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "test".to_string(),
+                "--watch".to_string()
+            )),
+            action!("bin", "test", "--watch", a)
+        );
+
+        let Ok(Action::CommandHelp(help)) = action!("/usr/bin/resty", "test", "-h") else {
+            panic!("expected Action::CommandHelp");
+        };
+        assert!(help.starts_with("Usage: resty test [OPTIONS] [PATH]...\n"));
+        assert!(help.contains("\n      --junit <FILE>\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compile_command() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let a = dir.join("a.lua");
+        fs::write(&a, "").unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        let a = a.to_str().unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let Ok(Action::Compile(user)) =
+            action!("bin", "compile", "--strip", dir_str, "--force", "-I", "lib")
+        else {
+            panic!("expected Action::Compile");
+        };
+        assert_eq!(Some(Subcommand::Compile), user.subcommand);
+        assert!(user.compile.strip);
+        assert!(user.compile.force);
+        assert_eq!(
+            vec![(
+                a.to_string(),
+                dir.join("a.ljbc").to_str().unwrap().to_string()
+            )],
+            user.compile.files
+        );
+
+        assert_eq!(
+            Err(ArgError::UnknownArgument("--strip".to_string())),
+            action!("bin", "test", "--strip", a)
+        );
+
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "compile".to_string(),
+                "--coverage".to_string()
+            )),
+            action!("bin", "compile", "--coverage", a)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bundle_command() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let a = dir.join("a.lua");
+        fs::write(&a, "").unwrap();
+        let a = a.to_str().unwrap();
+
+        let Ok(Action::Bundle(user)) = action!("bin", "bundle", a, "-o", "out.rusty") else {
+            panic!("expected Action::Bundle");
+        };
+        assert_eq!(Some(Subcommand::Bundle), user.subcommand);
+        assert_eq!(Some(PathBuf::from("out.rusty")), user.bundle.output);
+        assert_eq!(vec![a.to_string()], user.operands);
+
+        let Ok(Action::Bundle(user)) = action!("bin", "bundle", "--module", "a.b", a, "--module=c")
+        else {
+            panic!("expected Action::Bundle");
+        };
+        assert_eq!(
+            vec!["a.b".to_string(), "c".to_string()],
+            user.bundle.modules
+        );
+
+        assert_eq!(Err(ArgError::NoLuaInput), action!("bin", "bundle"));
+        assert_eq!(
+            Err(ArgError::UnknownArgument("b.lua".to_string())),
+            action!("bin", "bundle", a, "b.lua")
+        );
+        assert_eq!(
+            Err(ArgError::Conflict("bundle".to_string(), "-e".to_string())),
+            action!("bin", "bundle", "-e", "print(1)", a)
+        );
+
+        // running a bundle
+        let archive = Bundle {
+            manifest: bundle::Manifest {
+                entry: "bin/a.lua".to_string(),
+                shdicts: vec!["cache 1m".to_string()],
+                ..Default::default()
+            },
+            files: vec![("bin/a.lua".to_string(), b"print(1)".to_vec())],
+        };
+        let path = dir.join("a.rusty");
+        fs::write(&path, archive.to_bytes().unwrap()).unwrap();
+        let path = path.to_str().unwrap();
+
+        let Ok(Action::Main(user)) = action!("bin", "--shdict", "x 1m", path, "arg") else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(Some(archive), user.archive);
+        assert_eq!(vec!["arg".to_string()], user.lua_args);
+        assert_eq!(
+            vec![
+                "x 1m".parse::<Shdict>().unwrap(),
+                "cache 1m".parse().unwrap()
+            ],
+            user.user_shdicts
+        );
+
+        let Ok(Action::BuildExe(user)) = action!(
+            "bin",
+            "build-exe",
+            "-I",
+            "lib",
+            "--no-stream",
+            a,
+            "-o",
+            "tool"
+        ) else {
+            panic!("expected Action::BuildExe");
+        };
+        assert_eq!(vec!["--no-stream".to_string()], user.exe.args);
+        assert_eq!(Some(PathBuf::from("tool")), user.bundle.output);
+        assert_eq!(
+            Err(ArgError::Conflict(
+                "build-exe".to_string(),
+                "--watch".to_string()
+            )),
+            action!("bin", "build-exe", "--watch", a)
+        );
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        zip.set_comment("rusty-bundle 1").unwrap();
+        fs::write(dir.join("b.rusty"), zip.finish().unwrap().into_inner()).unwrap();
+        assert_eq!(
+            Err(ArgError::InvalidBundle {
+                file: dir.join("b.rusty").to_string_lossy().into_owned(),
+                err: "missing manifest".to_string(),
+            }),
+            action!("bin", dir.join("b.rusty").to_str().unwrap())
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn man_action() {
+        let Ok(Action::Man(page)) = action!("/usr/bin/resty", "-e", "1", "--man") else {
+            panic!("expected Action::Man");
+        };
+        assert!(page.starts_with(".TH RESTY 1 "));
+    }
+
+    #[test]
+    fn completions_action() {
+        let Ok(Action::Completions(script)) = action!("/usr/bin/resty", "--completions", "bash")
+        else {
+            panic!("expected Action::Completions");
+        };
+        assert!(script.contains("complete -o filenames -F _resty resty"));
+
+        assert!(matches!(
+            action!("bin", "--completions", "tcsh"),
+            Err(ArgError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn runner_opts() {
+        let Ok(Action::Main(user)) = action!("bin", "--gdb-opts", "-ex 'b main'", "-e", "1") else {
+            panic!("expected Action::Main");
+        };
+        assert_eq!(
+            Runner::Gdb(Some(ShellArgs(vec!["-ex".into(), "b main".into()]))),
+            user.runner
+        );
+
+        for (arg, value, err) in [
+            ("--gdb-opts", "-ex 'b main", "unbalanced quotes"),
+            ("--user-runner", "perf 'record", "unbalanced quotes"),
+            ("--user-runner", " ", "expected a command"),
+        ] {
+            assert_eq!(
+                Err(ArgError::InvalidValue {
+                    arg: arg.to_string(),
+                    value: value.to_string(),
+                    err: err.to_string(),
+                }),
+                action!("bin", arg, value, "-e", "1")
+            );
+        }
+    }
+
     #[test]
     fn jit_command_only() {
         // resty-cli doesn't actually complain if you pass in a luajit command
@@ -784,6 +2562,21 @@ mod tests {
             "--load-module",
             "--errlog-level",
             "--nginx",
+            "--prefix",
+            "--tmpdir",
+            "--timeout",
+            "--timeout-grace",
+            "--save-core",
+            "--report-json",
+            "--profile",
+            "--completions",
+            "--rlimit-as",
+            "--rlimit-core",
+            "--rlimit-cpu",
+            "--rlimit-data",
+            "--rlimit-nofile",
+            "--rlimit-nproc",
+            "--rlimit-stack",
             "--stream-conf",
             "--ns",
             "--shdict",
@@ -811,6 +2604,16 @@ mod tests {
             "--valgrind",
             "--resolve-ipv6",
             "--dump-nginx-conf",
+            "--repl",
+            "--keep-prefix",
+            "--backtrace",
+            "--keep-prefix-on-error",
+            "--no-config",
+            "--print-config",
+            "--man",
+            "--relative-include",
+            "--no-directives",
+            "--watch",
         ];
 
         for opt in opts {
@@ -876,7 +2679,13 @@ mod tests {
         assert_eq!(svec!["http-1", "http-2"], args.http_conf);
         assert_eq!(svec!["main-1", "main-2"], args.main_conf);
 
-        assert!(matches!(args.jit_cmd, Some(JitCmd::Off)));
+        assert_eq!(
+            vec![JitCmd {
+                module: JitModule::Off,
+                args: vec![],
+            }],
+            args.jit_cmds
+        );
 
         assert_eq!(Some("my-nginx".into()), args.nginx_bin);
 
@@ -897,4 +2706,48 @@ mod tests {
 
         dbg!(&args);
     }
+
+    #[test]
+    fn config_args() {
+        let mut config = Config::default();
+        config
+            .add_file(
+                std::path::Path::new("/src/.rustyrc"),
+                "shdict = ['cache 1m']\nerrlog-level = 'info'\nno-stream = true\n",
+            )
+            .unwrap();
+
+        let args = svec![
+            "bin",
+            "--errlog-level",
+            "debug",
+            "--shdict",
+            "x 1k",
+            "-e",
+            "1"
+        ];
+        let Ok(Action::Main(user)) = Action::parse(args, &config, || Stdin::Other) else {
+            panic!("expected Action::Main");
+        };
+
+        assert_eq!(LogLevel::Debug, user.errlog_level);
+        assert!(user.no_stream);
+        assert_eq!(
+            svec!["cache 1m", "x 1k"],
+            user.user_shdicts
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        // config args don't count towards the Lua `arg` table
+        assert_eq!(7, user.arg_c);
+
+        let args = svec!["bin", "--print-config", "--no-stream"];
+        let Ok(Action::PrintConfig(rendered)) = Action::parse(args, &config, || Stdin::Other)
+        else {
+            panic!("expected Action::PrintConfig");
+        };
+        assert!(rendered.contains("errlog-level = \"info\"\n"), "{rendered}");
+    }
 }
@@ -0,0 +1,295 @@
+//! The `compile` subcommand: precompile Lua files to LuaJIT bytecode.
+//!
+//! The files are compiled inside nginx, so the bytecode is always produced
+//! by the same LuaJIT that will load it. Each `foo.lua` is compiled to
+//! `foo.ljbc` next to it, which `-I` puts ahead of `foo.lua` in the package
+//! path.
+
+use crate::cli::{run_captured, UserArgs};
+use crate::lua::LuaString;
+use crate::types::ArgError;
+use crate::util::find_files;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Options for the `compile` subcommand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CompileArgs {
+    /// The files to compile, with their bytecode files.
+    pub(crate) files: Vec<(String, String)>,
+
+    /// How many files were skipped because their bytecode is up to date.
+    pub(crate) up_to_date: usize,
+
+    pub(crate) strip: bool,
+    pub(crate) force: bool,
+}
+
+fn is_lua_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "lua")
+}
+
+/// The bytecode file for a Lua file.
+fn bytecode_path(src: &str) -> String {
+    PathBuf::from(src)
+        .with_extension("ljbc")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Whether `dst` is missing or older than `src`.
+fn is_stale(src: &Path, dst: &Path) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|md| md.modified()).ok();
+
+    match (modified(src), modified(dst)) {
+        (Some(src), Some(dst)) => dst < src,
+        _ => true,
+    }
+}
+
+/// Find the Lua files to compile.
+///
+/// Directories are searched recursively for `*.lua` files, skipping hidden
+/// ones. Files are compiled no matter what they are named.
+fn discover(paths: &[String]) -> Result<Vec<String>, ArgError> {
+    let mut files = vec![];
+
+    let default = [".".to_string()];
+    let paths = if paths.is_empty() {
+        &default[..]
+    } else {
+        paths
+    };
+
+    for path in paths {
+        let p = Path::new(path);
+        if p.is_dir() {
+            find_files(p, &is_lua_file, &mut files);
+        } else if p.is_file() {
+            files.push(path.clone());
+        } else {
+            return Err(ArgError::LuaFileNotFound(path.clone()));
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+
+    if files.is_empty() {
+        return Err(ArgError::NoLuaFiles);
+    }
+
+    Ok(files)
+}
+
+/// Check the arguments for the `compile` subcommand and find the files that
+/// need compiling.
+pub(crate) fn validate(user: &mut UserArgs) -> Result<(), ArgError> {
+    for (set, opt) in [
+        (user.repl, "--repl"),
+        (user.dump_nginx_conf, "--dump-nginx-conf"),
+        (user.watch, "--watch"),
+        (user.report_json.is_some(), "--report-json"),
+        (user.parallel.is_some(), "--parallel"),
+        (user.coverage.enabled, "--coverage"),
+        (user.profile.enabled(), "--lua-profile"),
+    ] {
+        if set {
+            return Err(ArgError::Conflict("compile".to_string(), opt.to_string()));
+        }
+    }
+
+    let args = &mut user.compile;
+
+    for src in discover(&user.operands)? {
+        let dst = bytecode_path(&src);
+        if args.force || is_stale(Path::new(&src), Path::new(&dst)) {
+            args.files.push((src, dst));
+        } else {
+            args.up_to_date += 1;
+        }
+    }
+
+    Ok(())
+}
+
+const COMPILE_LUA: &str = r##"local compile_gen = function()
+    local function compile(src, dst)
+        -- loadfile() names the chunk "@src", the same as require() would
+        local chunk, err = loadfile(src)
+        if not chunk then
+            return nil, err
+        end
+
+        -- the old file stays in place until the new one is complete
+        local tmp = dst .. ".tmp"
+        local f
+        f, err = io.open(tmp, "wb")
+        if not f then
+            return nil, err
+        end
+
+        local ok
+        ok, err = f:write(string.dump(chunk, compile_strip))
+        f:close()
+        if not ok then
+            os.remove(tmp)
+            return nil, err
+        end
+
+        return os.rename(tmp, dst)
+    end
+
+    local failed = false
+
+    for _, file in ipairs(compile_files) do
+        local src, dst = file[1], file[2]
+        local ok, err = compile(src, dst)
+        if ok then
+            io.stdout:write(src, " -> ", dst, "\n")
+        else
+            io.stderr:write("ERROR: ", err, "\n")
+            failed = true
+        end
+    end
+
+    if failed then
+        os.exit(1)
+    end
+end"##;
+
+/// The Lua code that compiles the files, for `LuaGenerator`.
+pub(crate) fn lua_loader(args: &CompileArgs) -> Vec<String> {
+    let mut lines = vec![
+        format!("local compile_strip = {}", args.strip),
+        "local compile_files = {".to_string(),
+    ];
+
+    for (src, dst) in &args.files {
+        lines.push(format!(
+            "    {{ {}, {} }},",
+            src.lua_quote(),
+            dst.lua_quote()
+        ));
+    }
+    lines.push("}".to_string());
+
+    lines.extend(COMPILE_LUA.lines().map(String::from));
+    lines
+}
+
+/// Compile the files, and return the exit code.
+pub(crate) fn run(user: Box<UserArgs>) -> i32 {
+    let up_to_date = user.compile.up_to_date;
+    if up_to_date > 0 {
+        let files = if up_to_date == 1 { "file" } else { "files" };
+        println!("{up_to_date} {files} up to date");
+    }
+
+    // nothing to start nginx for
+    if user.compile.files.is_empty() {
+        return 0;
+    }
+
+    match run_captured(*user, true, |_| ()) {
+        Ok((status, _)) => {
+            let (stdout, stderr) = status.output.unwrap_or_default();
+            let _ = io::stdout().write_all(&stdout);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().write_all(&stderr);
+            status.code
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            e.exit_code()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn discover_lua_files() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        for file in ["a.lua", "b.ljbc", "lib/c.lua", ".hidden/d.lua", "e.txt"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+
+        assert_eq!(
+            vec![path("a.lua"), path("lib/c.lua")],
+            discover(&[dir.to_string_lossy().into_owned(), path("lib/c.lua")]).unwrap()
+        );
+        assert_eq!(vec![path("e.txt")], discover(&[path("e.txt")]).unwrap());
+        assert_eq!(
+            Err(ArgError::LuaFileNotFound(path("nope"))),
+            discover(&[path("nope")])
+        );
+
+        fs::create_dir(dir.join("empty")).unwrap();
+        assert_eq!(Err(ArgError::NoLuaFiles), discover(&[path("empty")]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn staleness() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let src = dir.join("a.lua");
+        let dst = dir.join("a.ljbc");
+        fs::write(&src, "").unwrap();
+
+        assert_eq!("lib/a.ljbc", bytecode_path("lib/a.lua"));
+        assert!(is_stale(&src, &dst));
+
+        fs::write(&dst, "").unwrap();
+        let now = SystemTime::now();
+        let set_mtime = |path: &Path, time| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+
+        set_mtime(&src, now - Duration::from_secs(60));
+        set_mtime(&dst, now);
+        assert!(!is_stale(&src, &dst));
+
+        set_mtime(&src, now + Duration::from_secs(60));
+        assert!(is_stale(&src, &dst));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loader() {
+        let args = CompileArgs {
+            files: vec![("lib/a.lua".to_string(), "lib/a.ljbc".to_string())],
+            strip: true,
+            ..Default::default()
+        };
+
+        let lines = lua_loader(&args);
+        assert_eq!(
+            vec![
+                "local compile_strip = true",
+                "local compile_files = {",
+                "    { [=[lib/a.lua]=], [=[lib/a.ljbc]=] },",
+                "}",
+                "local compile_gen = function()",
+            ],
+            lines[..5]
+        );
+    }
+}
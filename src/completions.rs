@@ -0,0 +1,383 @@
+//! Shell completion scripts, generated from the option table.
+
+use crate::config::PROFILE_DIR;
+use crate::options::{Opt, Value, OPTIONS};
+use std::fmt::Write as _;
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    strum_macros::Display,
+    strum_macros::EnumString,
+    strum_macros::VariantNames,
+    PartialEq,
+    Eq,
+)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Generate the completion script for `bin` (the name that rusty-cli was
+/// invoked as).
+pub(crate) fn generate(shell: Shell, bin: &str) -> String {
+    match shell {
+        Shell::Bash => bash(bin),
+        Shell::Zsh => zsh(bin),
+        Shell::Fish => fish(bin),
+    }
+}
+
+/// Options that exist for the current compat version.
+fn options() -> impl Iterator<Item = &'static Opt> {
+    OPTIONS.iter().filter(|opt| opt.available())
+}
+
+/// A shell function name for `bin`.
+fn ident(bin: &str) -> String {
+    bin.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn bash(bin: &str) -> String {
+    let func = format!("_{}", ident(bin));
+
+    let names: Vec<&str> = options().flat_map(Opt::names).collect();
+    let with_value: Vec<&str> = options()
+        .filter(|opt| opt.takes_value())
+        .flat_map(Opt::names)
+        .collect();
+
+    let lua_files = r#"COMPREPLY=($(compgen -f -X '!*.lua' -- "$cur") $(compgen -d -- "$cur"))"#;
+    let files = r#"COMPREPLY=($(compgen -f -- "$cur"))"#;
+
+    let mut values = String::new();
+    for opt in options().filter(|opt| opt.takes_value()) {
+        let action = if !opt.choices.is_empty() {
+            format!(
+                r#"COMPREPLY=($(compgen -W "{}" -- "$cur"))"#,
+                opt.choices.join(" ")
+            )
+        } else if opt.name == "--profile" {
+            format!(
+                r#"COMPREPLY=($(compgen -W "$(command ls "${{XDG_CONFIG_HOME:-$HOME/.config}}/{PROFILE_DIR}" 2>/dev/null | sed 's/\.toml$//')" -- "$cur"))"#
+            )
+        } else {
+            match opt.value {
+                Value::Path => files.to_string(),
+                Value::Dir => r#"COMPREPLY=($(compgen -d -- "$cur"))"#.to_string(),
+                Value::Program => r#"COMPREPLY=($(compgen -c -- "$cur"))"#.to_string(),
+                Value::Text | Value::None => "COMPREPLY=()".to_string(),
+            }
+        };
+
+        let pattern = opt.names().collect::<Vec<_>>().join("|");
+        let _ = write!(
+            values,
+            "
+        {pattern})
+            {action}
+            return
+            ;;"
+        );
+    }
+
+    format!(
+        r#"# bash completion for {bin}
+
+{func}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    # `--opt=value` is split into three words
+    if [[ "$prev" == "=" ]]; then
+        prev="${{COMP_WORDS[COMP_CWORD-2]}}"
+    elif [[ "$cur" == "=" ]]; then
+        cur=""
+    fi
+
+    # everything after the Lua file belongs to the script
+    local i word
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        case "$word" in
+            {with_value})
+                [[ "${{COMP_WORDS[i+1]}}" == "=" ]] && ((i++))
+                ((i++))
+                ;;
+            --)
+                if ((i + 1 == COMP_CWORD)); then
+                    {lua_files}
+                else
+                    {files}
+                fi
+                return
+                ;;
+            -*)
+                ;;
+            *)
+                {files}
+                return
+                ;;
+        esac
+    done
+
+    case "$prev" in{values}
+    esac
+
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{names}" -- "$cur"))
+    else
+        {lua_files}
+    fi
+}}
+
+complete -o filenames -F {func} {bin}
+"#,
+        with_value = with_value.join("|"),
+        names = names.join(" "),
+    )
+}
+
+/// Escape the characters that are special in an `_arguments` spec.
+fn zsh_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        if matches!(c, '[' | ']' | '\\' | ':') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Quote a string for zsh.
+fn zsh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn zsh(bin: &str) -> String {
+    let func = format!("_{bin}");
+    let mut specs = String::new();
+
+    for opt in options() {
+        let names: Vec<&str> = opt.names().collect();
+
+        let value = match opt.metavar {
+            Some(metavar) => {
+                let action = if !opt.choices.is_empty() {
+                    format!("({})", opt.choices.join(" "))
+                } else if opt.name == "--profile" {
+                    format!(
+                        "{{compadd -- ${{XDG_CONFIG_HOME:-$HOME/.config}}/{PROFILE_DIR}/*(N:t:r)}}"
+                    )
+                } else {
+                    match opt.value {
+                        Value::Path => "_files".to_string(),
+                        Value::Dir => "_files -/".to_string(),
+                        Value::Program => "_command_names -e".to_string(),
+                        Value::Text | Value::None => " ".to_string(),
+                    }
+                };
+                format!(":{}:{action}", zsh_escape(metavar))
+            }
+            None => String::new(),
+        };
+
+        // long options accept both `--opt value` and `--opt=value`
+        let suffix = |name: &str| {
+            if opt.takes_value() && name.starts_with("--") {
+                "="
+            } else {
+                ""
+            }
+        };
+
+        let desc = format!("[{}]{value}", zsh_escape(opt.summary()));
+
+        let spec = if names.len() > 1 {
+            let names: Vec<String> = names.iter().map(|n| format!("{n}{}", suffix(n))).collect();
+            format!(
+                "{}{{{}}}{}",
+                zsh_quote(&format!("({})", opt.names().collect::<Vec<_>>().join(" "))),
+                names.join(","),
+                zsh_quote(&desc),
+            )
+        } else {
+            let repeat = if opt.repeat { "*" } else { "" };
+            zsh_quote(&format!("{repeat}{}{}{desc}", opt.name, suffix(opt.name)))
+        };
+
+        let _ = write!(specs, "\n        {spec}");
+    }
+
+    format!(
+        r#"#compdef {bin}
+
+{func}() {{
+    local -a args
+    args=({specs}
+        '1:Lua file:_files -g "*.lua(-.)"'
+        '*::arguments:_files'
+    )
+
+    _arguments -s -S $args
+}}
+
+if [ "$funcstack[1]" = "{func}" ]; then
+    {func} "$@"
+else
+    compdef {func} {bin}
+fi
+"#
+    )
+}
+
+/// Quote a string for fish.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+fn fish(bin: &str) -> String {
+    let mut out = format!(
+        "# fish completion for {bin}
+
+complete -c {bin} -k -f -a '(__fish_complete_suffix .lua)'
+"
+    );
+
+    for opt in options() {
+        let mut line = format!("complete -c {bin}");
+
+        for name in opt.names() {
+            match name.strip_prefix("--") {
+                Some(long) => {
+                    let _ = write!(line, " -l {long}");
+                }
+                // fish only has single-character short options, so `-ns`
+                // style options are "old-style" options
+                None if name.len() == 2 => {
+                    let _ = write!(line, " -s {}", &name[1..]);
+                }
+                None => {
+                    let _ = write!(line, " -o {}", &name[1..]);
+                }
+            }
+        }
+
+        if opt.takes_value() {
+            if !opt.choices.is_empty() {
+                let _ = write!(line, " -x -a {}", fish_quote(&opt.choices.join(" ")));
+            } else if opt.name == "--profile" {
+                let _ = write!(
+                    line,
+                    r" -x -a '(string replace -r \'\.toml$\' \'\' -- (command ls (set -q XDG_CONFIG_HOME; and echo $XDG_CONFIG_HOME; or echo ~/.config)/{PROFILE_DIR} 2>/dev/null))'"
+                );
+            } else {
+                line.push_str(match opt.value {
+                    Value::Path => " -r -F",
+                    Value::Dir => " -x -a '(__fish_complete_directories (commandline -ct))'",
+                    Value::Program => " -x -a '(__fish_complete_command)'",
+                    Value::Text | Value::None => " -x",
+                });
+            }
+        }
+
+        let _ = writeln!(line, " -d {}", fish_quote(opt.summary()));
+        out.push_str(&line);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_contains(script: &str, expected: &[&str]) {
+        for exp in expected {
+            assert!(script.contains(exp), "missing {exp:?} in:\n{script}");
+        }
+    }
+
+    #[test]
+    fn bash_completions() {
+        let script = generate(Shell::Bash, "rusty-cli");
+
+        assert_contains(
+            &script,
+            &[
+                "\n_rusty_cli() {\n",
+                "\ncomplete -o filenames -F _rusty_cli rusty-cli\n",
+                "\n        -j)\n            COMPREPLY=($(compgen -W \"v dump on off flush p\" -- \"$cur\"))\n",
+                "\n        --errlog-level)\n            COMPREPLY=($(compgen -W \"debug info notice warn error crit alert emerg\" -- \"$cur\"))\n",
+                "\n        --http-include)\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n",
+                "\n        -I)\n            COMPREPLY=($(compgen -d -- \"$cur\"))\n",
+                "\n        --completions)\n            COMPREPLY=($(compgen -W \"bash zsh fish\" -- \"$cur\"))\n",
+                "compgen -f -X '!*.lua'",
+            ],
+        );
+        assert!(!script.contains("\n        --repl)"));
+    }
+
+    #[test]
+    fn zsh_completions() {
+        let script = generate(Shell::Zsh, "resty");
+
+        assert_contains(
+            &script,
+            &[
+                "#compdef resty\n",
+                "\n        '(-V -v --version)'{-V,-v,--version}'[Print version numbers and nginx configurations]'\n",
+                "\n        '*-I[Add dir to the search paths for Lua libraries]:DIR:_files -/'\n",
+                "\n        '*-j[LuaJIT option]:CMD:(v dump on off flush p)'\n",
+                "\n        '--nginx=[Specify the nginx path (this option might be removed in the future)]:PATH:_command_names -e'\n",
+                "\n        '*--main-include=[Include the specified file in the nginx main configuration block (multiple instances are supported)]:PATH:_files'\n",
+                "\n        '*-l[require lua library \"lib\"]:LIB: '\n",
+                "\n        '(-h --help)'{-h,--help}'[Print help (see more with '\\''--help'\\'')]'\n",
+                "\n    compdef _resty resty\n",
+            ],
+        );
+    }
+
+    #[test]
+    fn fish_completions() {
+        let script = generate(Shell::Fish, "rusty-cli");
+
+        assert_contains(
+            &script,
+            &[
+                "\ncomplete -c rusty-cli -k -f -a '(__fish_complete_suffix .lua)'\n",
+                "\ncomplete -c rusty-cli -s V -s v -l version -d 'Print version numbers and nginx configurations'\n",
+                "\ncomplete -c rusty-cli -s j -x -a 'v dump on off flush p' -d 'LuaJIT option'\n",
+                "\ncomplete -c rusty-cli -l http-include -r -F -d ",
+                "\ncomplete -c rusty-cli -s h -l help -d 'Print help (see more with \\'--help\\')'\n",
+            ],
+        );
+    }
+
+    #[test]
+    fn compat_version_gating() {
+        for name in ["--load-module", "--dump-nginx-conf"] {
+            let available = Opt::find(name).unwrap().available();
+
+            for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+                let script = generate(shell, "resty");
+                let long = name.trim_start_matches('-');
+                assert_eq!(available, script.contains(long), "{shell} {name}");
+            }
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(r"a\:b \[c\] d\\e", zsh_escape(r"a:b [c] d\e"));
+        assert_eq!(r"'it'\''s'", zsh_quote("it's"));
+        assert_eq!(r"'it\'s a \\'", fish_quote(r"it's a \"));
+        assert_eq!("_my_resty_2", format!("_{}", ident("my-resty.2")));
+    }
+}
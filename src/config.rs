@@ -0,0 +1,1241 @@
+//! Layered config files.
+//!
+//! Options are read from (in order of increasing precedence):
+//!
+//! 1. the system config file, `/etc/rusty-cli/config.toml`
+//! 2. the user config file, `$XDG_CONFIG_HOME/rusty-cli/config.toml`
+//!    (`~/.config/rusty-cli/config.toml` by default)
+//! 3. `.rustyrc` files in the current directory and its parents up to the
+//!    repository root or home directory, with the one closest to the current
+//!    directory taking precedence. These may only set a few options that
+//!    can't load code or write files (see [`Opt::project`]), and are skipped
+//!    unless owned by the current user (or root) and not writable by anyone
+//!    else.
+//! 4. `RUSTY_CLI_*` environment variables (e.g. `RUSTY_CLI_NGINX`)
+//!
+//! Each key (or variable) corresponds to a command line option (see [`crate::options`]).
+//! Keys in tables are joined to the table name with `-`, so `core` in an
+//! `[rlimit]` table is `rlimit-core`.
+//! Settings from all layers are translated into command line arguments
+//! that are parsed before the real ones. Options that may be repeated are
+//! appended to, while any other option replaces the value from a lower
+//! layer, and is dropped entirely if it is also given on the command line.
+//!
+//! Directives in the Lua file (see [`crate::directives`]) are applied on top
+//! of those, unless `--no-directives` is given. Relative paths in them are
+//! relative to the Lua file.
+//!
+//! Profiles selected with `--profile NAME` use the same format and are
+//! applied on top of everything else, in the order they are given. Unlike
+//! config files, a profile is only overridden by the same option: setting
+//! e.g. `--valgrind` on the command line while a profile sets `gdb` is a
+//! conflict rather than a silent replacement.
+
+use crate::bundle;
+use crate::directives::{self, Directive};
+use crate::options::{self, Opt, Value};
+use crate::types::ArgError;
+use crate::util;
+use serde::de::{Deserialize, IntoDeserializer};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
+
+pub(crate) const SYSTEM_CONFIG: &str = "/etc/rusty-cli/config.toml";
+pub(crate) const USER_CONFIG: &str = "rusty-cli/config.toml";
+pub(crate) const PROJECT_CONFIG: &str = ".rustyrc";
+pub(crate) const PROFILE_DIR: &str = "rusty-cli/profiles";
+const PROFILE_EXT: &str = "toml";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layer {
+    File,
+    Profile,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Setting {
+    opt: &'static Opt,
+    values: Vec<toml::Value>,
+    sources: Vec<String>,
+    layer: Layer,
+}
+
+impl Setting {
+    /// Whether setting `other` in `layer` replaces this setting.
+    fn overridden_by(&self, other: &Opt, layer: Layer) -> bool {
+        match self.layer {
+            Layer::File => self.opt.overridden_by(other),
+            Layer::Profile if layer == Layer::Profile => {
+                !self.opt.repeat && self.opt.name == other.name
+            }
+            // profiles are never overridden by config files
+            Layer::Profile => false,
+        }
+    }
+}
+
+/// The merged result of all config files and profiles.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Config {
+    files: Vec<PathBuf>,
+    settings: Vec<Setting>,
+}
+
+/// The base directory for config files in the user's home directory.
+fn user_config_dir<F>(getenv: F) -> Option<PathBuf>
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    let getenv = |name| getenv(name).filter(|value: &OsString| !value.is_empty());
+
+    if let Some(dir) = getenv("XDG_CONFIG_HOME") {
+        Some(PathBuf::from(dir))
+    } else {
+        getenv("HOME").map(|home| PathBuf::from(home).join(".config"))
+    }
+}
+
+/// The directory that holds profiles.
+pub(crate) fn profile_dir() -> Option<PathBuf> {
+    user_config_dir(|name| env::var_os(name)).map(|dir| dir.join(PROFILE_DIR))
+}
+
+/// The names of all available profiles, sorted.
+pub(crate) fn profile_names() -> Vec<String> {
+    let Some(Ok(entries)) = profile_dir().map(fs::read_dir) else {
+        return vec![];
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            let path = entry.path();
+            match path.extension() {
+                Some(ext) if ext == PROFILE_EXT => path.file_stem().map(|s| s.to_owned()),
+                Some(_) => None,
+                None => path.file_name().map(|s| s.to_owned()),
+            }
+        })
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The config file locations to check, lowest precedence first.
+fn impl_config_paths<F>(cwd: Option<&Path>, getenv: F) -> Vec<PathBuf>
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    let mut paths = vec![PathBuf::from(SYSTEM_CONFIG)];
+
+    if let Some(dir) = user_config_dir(&getenv) {
+        paths.push(dir.join(USER_CONFIG));
+    }
+
+    if let Some(cwd) = cwd {
+        let home = getenv("HOME").map(PathBuf::from);
+        let mut project = Vec::new();
+
+        // stop at the repository root or home directory, whichever is
+        // closer, so that nothing above the project is picked up
+        for dir in cwd.ancestors() {
+            project.push(dir.join(PROJECT_CONFIG));
+
+            if dir.join(".git").exists() || home.as_deref() == Some(dir) {
+                break;
+            }
+        }
+
+        project.reverse();
+        paths.extend(project);
+    }
+
+    paths
+}
+
+/// Whether a project file is owned by the current user (or root) and can't be
+/// changed by anyone else.
+fn is_trusted(path: &Path) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+
+    let uid = nix::unistd::geteuid().as_raw();
+    (meta.uid() == uid || meta.uid() == 0) && meta.mode() & 0o022 == 0
+}
+
+fn error(file: &Path, line: Option<usize>, err: impl ToString) -> ArgError {
+    let file = match line {
+        Some(line) => format!("{}:{}", file.display(), line),
+        None => file.display().to_string(),
+    };
+
+    ArgError::Config {
+        file,
+        err: err.to_string(),
+    }
+}
+
+/// Parse the value of a flag given as a string.
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// A key in a config file, with the keys of any tables it is nested in
+/// joined by `-` (so `core` in an `[rlimit]` table is `rlimit-core`).
+struct Entry {
+    key: String,
+    value: toml::Value,
+    line: usize,
+}
+
+/// Parse a config file into its entries, in the order they are given.
+fn parse(src: &str) -> Result<Vec<Entry>, (Option<usize>, String)> {
+    let invalid = |e: toml::de::Error| {
+        let line = e.span().map(|span| util::line_at(src, span.start));
+        (line, e.message().to_string())
+    };
+
+    let table = DeTable::parse(src).map_err(invalid)?;
+    let mut entries = vec![];
+    flatten(src, None, table.into_inner(), &mut entries).map_err(invalid)?;
+    Ok(entries)
+}
+
+fn flatten(
+    src: &str,
+    prefix: Option<&str>,
+    table: DeTable,
+    entries: &mut Vec<Entry>,
+) -> Result<(), toml::de::Error> {
+    for (key, value) in table {
+        let line = util::line_at(src, key.span().start);
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}-{}", key.get_ref()),
+            None => key.into_inner().into_owned(),
+        };
+
+        let span = value.span();
+        match value.into_inner() {
+            DeValue::Table(table) => flatten(src, Some(&key), table, entries)?,
+            value => {
+                let value = Spanned::new(span, value).into_deserializer();
+                let value = toml::Value::deserialize(value)?;
+                entries.push(Entry { key, value, line });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The command line value of a setting.
+fn to_arg(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Convert a config value to the command line value(s) of an option.
+///
+/// Paths are resolved to strings, and any other value keeps its type.
+fn to_values(opt: &Opt, value: toml::Value, dir: &Path) -> Result<Vec<toml::Value>, String> {
+    use toml::Value as V;
+
+    let scalar = |value: V| -> Result<V, String> {
+        let s = match &value {
+            V::String(s) => s.clone(),
+            V::Integer(_) | V::Float(_) => value.to_string(),
+            other => return Err(format!("expected a string, found {}", other.type_str())),
+        };
+
+        let resolve = match opt.value {
+            Value::Path | Value::Dir => true,
+            Value::Program => s.contains('/'),
+            Value::Text | Value::None => false,
+        };
+
+        // relative paths are relative to the config file
+        if resolve && !s.is_empty() && Path::new(&s).is_relative() {
+            // collecting the components drops any `.` in the path
+            let path: PathBuf = dir.join(&s).components().collect();
+            Ok(V::String(path.to_string_lossy().into_owned()))
+        } else {
+            Ok(value)
+        }
+    };
+
+    match (opt.takes_value(), value) {
+        (false, V::Boolean(_)) => Ok(vec![]),
+        (false, other) => Err(format!("expected a boolean, found {}", other.type_str())),
+        (true, V::Array(items)) if opt.repeat => items.into_iter().map(scalar).collect(),
+        (true, V::Array(_)) => Err("expected a single value, found array".to_string()),
+        (true, value) => Ok(vec![scalar(value)?]),
+    }
+}
+
+impl Config {
+    /// Load the config files and profiles for a command line (not including
+    /// the program name).
+    pub(crate) fn for_args<S: AsRef<str>>(cli: &[S]) -> Result<Self, ArgError> {
+        let given = options::given(cli);
+
+        let mut config = Self::default();
+
+        if !given.iter().any(|(opt, _)| opt.name == "--no-config") {
+            config = Self::load()?;
+            config.add_env(|name| env::var_os(name))?;
+        }
+
+        if !given.iter().any(|(opt, _)| opt.name == "--no-directives") {
+            // a missing Lua file is reported by the parser
+            if let Some(script) = options::lua_file(cli).map(Path::new) {
+                // a bundle has the directives of its entry script
+                let found = if bundle::is_bundle(script) {
+                    bundle::read_directives(script)
+                } else {
+                    directives::read(script)
+                };
+
+                if let Ok(found) = found {
+                    config.add_directives(script, found)?;
+                }
+            }
+        }
+
+        for (opt, name) in given {
+            if let ("--profile", Some(name)) = (opt.name, name) {
+                config.load_profile(name, profile_dir().as_deref())?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Load config files from their standard locations.
+    pub(crate) fn load() -> Result<Self, ArgError> {
+        let cwd = env::current_dir().ok();
+        let paths = impl_config_paths(cwd.as_deref(), |name| env::var_os(name));
+        Self::load_files(&paths)
+    }
+
+    /// Load config files, skipping any that don't exist.
+    pub(crate) fn load_files(paths: &[PathBuf]) -> Result<Self, ArgError> {
+        let mut config = Self::default();
+
+        for path in paths {
+            let project = path.file_name() == Some(PROJECT_CONFIG.as_ref());
+
+            match fs::read_to_string(path) {
+                Ok(_) if project && !is_trusted(path) => {
+                    eprintln!(
+                        "WARN: ignoring {}, which is owned or writable by another user",
+                        path.display()
+                    );
+                }
+                Ok(src) if project => config.add_project_file(path, &src)?,
+                Ok(src) => config.add_file(path, &src)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) if e.kind() == io::ErrorKind::NotADirectory => {}
+                Err(e) => return Err(error(path, None, e)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Find and merge a profile.
+    fn load_profile(&mut self, name: &str, dir: Option<&Path>) -> Result<(), ArgError> {
+        let invalid = |err: String| ArgError::InvalidValue {
+            arg: "--profile".to_string(),
+            value: name.to_string(),
+            err,
+        };
+
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(invalid("invalid profile name".to_string()));
+        }
+
+        let Some(dir) = dir else {
+            return Err(invalid(
+                "no profile directory ($HOME is not set)".to_string(),
+            ));
+        };
+
+        let candidates = [dir.join(format!("{name}.{PROFILE_EXT}")), dir.join(name)];
+
+        for path in candidates.iter() {
+            match fs::read_to_string(path) {
+                Ok(src) => return self.add_profile(name, path, &src),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(error(path, None, e)),
+            }
+        }
+
+        Err(invalid(format!("profile not found in {}", dir.display())))
+    }
+
+    /// Merge the contents of a config file.
+    pub(crate) fn add_file(&mut self, path: &Path, src: &str) -> Result<(), ArgError> {
+        let source = path.display().to_string();
+        self.add(path, src, Layer::File, source, false)
+    }
+
+    /// Merge the contents of a `.rustyrc` file, which may only set options
+    /// that are marked as safe for one (see [`Opt::project`]).
+    pub(crate) fn add_project_file(&mut self, path: &Path, src: &str) -> Result<(), ArgError> {
+        let source = path.display().to_string();
+        self.add(path, src, Layer::File, source, true)
+    }
+
+    /// Merge the contents of a profile.
+    pub(crate) fn add_profile(
+        &mut self,
+        name: &str,
+        path: &Path,
+        src: &str,
+    ) -> Result<(), ArgError> {
+        self.add(path, src, Layer::Profile, format!("profile {name}"), false)
+    }
+
+    fn add(
+        &mut self,
+        path: &Path,
+        src: &str,
+        layer: Layer,
+        source: String,
+        project: bool,
+    ) -> Result<(), ArgError> {
+        let entries = parse(src).map_err(|(line, err)| error(path, line, err))?;
+        let dir = path.parent().unwrap_or(Path::new("/"));
+
+        for entry in entries {
+            let line = Some(entry.line);
+
+            let Some(opt) = Opt::find_key(&entry.key) else {
+                return Err(error(path, line, format!("unknown key `{}`", entry.key)));
+            };
+
+            if project && !opt.project {
+                return Err(error(
+                    path,
+                    line,
+                    format!(
+                        "`{}` can't be set in {PROJECT_CONFIG} (use the user config file or a profile)",
+                        entry.key
+                    ),
+                ));
+            }
+
+            self.apply(opt, entry.value, dir, &source, layer)
+                .map_err(|err| error(path, line, format!("{}: {err}", entry.key)))?;
+        }
+
+        self.files.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Merge option defaults from `RUSTY_CLI_*` environment variables.
+    ///
+    /// Flags accept `1`/`0`, `true`/`false`, `yes`/`no`, or `on`/`off`.
+    /// Repeatable options may hold several values, separated by the
+    /// option's separator (e.g. `:` for include directories).
+    pub(crate) fn add_env<F>(&mut self, getenv: F) -> Result<(), ArgError>
+    where
+        F: Fn(&str) -> Option<OsString>,
+    {
+        for opt in options::OPTIONS {
+            let Some(var) = opt.env_var() else {
+                continue;
+            };
+
+            let Some(value) = getenv(&var).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+
+            let value = value.to_string_lossy().into_owned();
+
+            let invalid = |err: String| ArgError::InvalidEnv {
+                var: var.clone(),
+                value: value.clone(),
+                err,
+            };
+
+            let parsed = if !opt.takes_value() {
+                match parse_flag(&value) {
+                    Some(flag) => toml::Value::Boolean(flag),
+                    None => return Err(invalid("expected a boolean".to_string())),
+                }
+            } else if let Some(sep) = opt.env_sep {
+                toml::Value::Array(
+                    value
+                        .split(sep)
+                        .filter(|item| !item.is_empty())
+                        .map(|item| toml::Value::String(item.to_string()))
+                        .collect(),
+                )
+            } else {
+                toml::Value::String(value.clone())
+            };
+
+            // relative paths are left for the parser to resolve
+            let source = format!("${var}");
+            self.apply(opt, parsed, Path::new(""), &source, Layer::File)
+                .map_err(invalid)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the directives from a Lua file.
+    ///
+    /// A flag may be given without a value, which enables it.
+    pub(crate) fn add_directives(
+        &mut self,
+        script: &Path,
+        found: Vec<Directive>,
+    ) -> Result<(), ArgError> {
+        let source = script.display().to_string();
+        let dir = script.parent().unwrap_or(Path::new(""));
+
+        for directive in found {
+            let invalid = |err: String| ArgError::Directive {
+                file: format!("{}:{}", script.display(), directive.line),
+                err,
+            };
+
+            let opt = directive.opt;
+            let value = match (opt.takes_value(), directive.value.as_deref()) {
+                (false, None) => toml::Value::Boolean(true),
+                (false, Some(value)) => match parse_flag(value) {
+                    Some(flag) => toml::Value::Boolean(flag),
+                    None => return Err(invalid(format!("{}: expected a boolean", directive.key))),
+                },
+                (true, None | Some("")) => {
+                    return Err(invalid(format!("{}: missing value", directive.key)))
+                }
+                (true, Some(value)) => toml::Value::String(value.to_string()),
+            };
+
+            self.apply(opt, value, dir, &source, Layer::File)
+                .map_err(|err| invalid(format!("{}: {err}", directive.key)))?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        opt: &'static Opt,
+        value: toml::Value,
+        dir: &Path,
+        source: &str,
+        layer: Layer,
+    ) -> Result<(), String> {
+        let enabled = value != toml::Value::Boolean(false);
+        let values = to_values(opt, value, dir)?;
+
+        if enabled {
+            self.set(opt, values, source, layer);
+        } else {
+            self.settings.retain(|s| !s.overridden_by(opt, layer));
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, opt: &'static Opt, values: Vec<toml::Value>, source: &str, layer: Layer) {
+        if opt.repeat {
+            if let Some(setting) = self.settings.iter_mut().find(|s| s.opt == opt) {
+                setting.values.extend(values);
+                if !setting.sources.iter().any(|s| s == source) {
+                    setting.sources.push(source.to_string());
+                }
+                return;
+            }
+        } else {
+            self.settings.retain(|s| !s.overridden_by(opt, layer));
+        }
+
+        self.settings.push(Setting {
+            opt,
+            values,
+            sources: vec![source.to_string()],
+            layer,
+        });
+    }
+
+    /// The command line arguments for the config, given the real command
+    /// line arguments (not including the program name).
+    pub(crate) fn to_args<S: AsRef<str>>(&self, cli: &[S]) -> Vec<String> {
+        self.layer_args(cli, None)
+    }
+
+    /// Like [`Config::to_args`], but only for the settings from profiles.
+    pub(crate) fn profile_args<S: AsRef<str>>(&self, cli: &[S]) -> Vec<String> {
+        self.layer_args(cli, Some(Layer::Profile))
+    }
+
+    fn layer_args<S: AsRef<str>>(&self, cli: &[S], layer: Option<Layer>) -> Vec<String> {
+        let given = options::given(cli);
+        let mut args = vec![];
+
+        for setting in self.settings.iter() {
+            if layer.is_some_and(|layer| setting.layer != layer) {
+                continue;
+            }
+
+            let opt = setting.opt;
+
+            // options on the command line override profiles the same way
+            // that later profiles do
+            if given
+                .iter()
+                .any(|(other, _)| setting.overridden_by(other, Layer::Profile))
+            {
+                continue;
+            }
+
+            if opt.takes_value() {
+                for value in setting.values.iter() {
+                    args.push(opt.name.to_string());
+                    args.push(to_arg(value));
+                }
+            } else {
+                args.push(opt.name.to_string());
+            }
+        }
+
+        args
+    }
+
+    /// Add the source of an option to an error about it, if it came from a
+    /// config file, environment variable, or profile rather than the command
+    /// line.
+    pub(crate) fn annotate<S: AsRef<str>>(&self, err: ArgError, cli: &[S]) -> ArgError {
+        let given = options::given(cli);
+
+        // runner conflicts are reported by the name of the runner, even when
+        // its `-opts` option was used
+        let is = |opt: &Opt, name: &str| {
+            opt.name == name || opt.name.strip_suffix("-opts") == Some(name)
+        };
+
+        let describe = |name: String| {
+            if given.iter().any(|(opt, _)| is(opt, &name)) {
+                return name;
+            }
+
+            match self.settings.iter().find(|s| is(s.opt, &name)) {
+                Some(setting) => format!("{name} (from {})", setting.sources.join(", ")),
+                None => name,
+            }
+        };
+
+        match err {
+            // the value identifies where an invalid value came from, even if
+            // the option was also given on the command line
+            ArgError::InvalidValue { arg, value, err } => {
+                let setting = self
+                    .settings
+                    .iter()
+                    .find(|s| s.opt.name == arg && s.values.iter().any(|v| to_arg(v) == value));
+
+                let arg = match setting {
+                    Some(setting) => format!("{arg} (from {})", setting.sources.join(", ")),
+                    None => arg,
+                };
+
+                ArgError::InvalidValue { arg, value, err }
+            }
+            ArgError::Conflict(a, b) => ArgError::Conflict(describe(a), describe(b)),
+            ArgError::Duplicate(a) => ArgError::Duplicate(describe(a)),
+            err => err,
+        }
+    }
+
+    /// Render the merged config in config file syntax.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        if self.files.is_empty() {
+            out.push_str("# no config files found\n");
+        } else {
+            out.push_str("# config files (lowest precedence first):\n");
+            for file in self.files.iter() {
+                out.push_str(&format!("#   {}\n", file.display()));
+            }
+        }
+
+        let mut settings: Vec<&Setting> = self.settings.iter().collect();
+        settings.sort_by_key(|s| s.opt.key);
+
+        for setting in settings {
+            let key = setting.opt.key.expect("config settings have a key");
+            let value = if !setting.opt.takes_value() {
+                toml::Value::Boolean(true)
+            } else if setting.opt.repeat {
+                toml::Value::Array(setting.values.clone())
+            } else {
+                setting.values[0].clone()
+            };
+
+            out.push_str(&format!("\n# from: {}\n", setting.sources.join(", ")));
+            out.push_str(&format!("{key} = {value}\n"));
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn config_paths() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+
+        let paths = |cwd: Option<&str>, getenv| -> Vec<String> {
+            impl_config_paths(cwd.map(Path::new), getenv)
+                .into_iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        assert_eq!(
+            vec![
+                SYSTEM_CONFIG,
+                "/home/me/.config/rusty-cli/config.toml",
+                "/.rustyrc",
+                "/src/.rustyrc",
+                "/src/project/.rustyrc",
+            ],
+            paths(Some("/src/project"), env(&[("HOME", "/home/me")]))
+        );
+
+        assert_eq!(
+            vec![SYSTEM_CONFIG, "/xdg/rusty-cli/config.toml"],
+            paths(
+                None,
+                env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "/xdg")])
+            )
+        );
+
+        assert_eq!(
+            vec![SYSTEM_CONFIG, "/home/me/.config/rusty-cli/config.toml"],
+            paths(None, env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "")]))
+        );
+
+        assert_eq!(vec![SYSTEM_CONFIG], paths(None, env(&[])));
+
+        assert_eq!(
+            vec![
+                SYSTEM_CONFIG,
+                "/home/me/.config/rusty-cli/config.toml",
+                "/home/me/.rustyrc",
+                "/home/me/src/.rustyrc",
+            ],
+            paths(Some("/home/me/src"), env(&[("HOME", "/home/me")]))
+        );
+
+        let repo = tempdir(&env::temp_dir()).unwrap();
+        let cwd = repo.join("src/lib");
+        fs::create_dir_all(&cwd).unwrap();
+        fs::create_dir(repo.join(".git")).unwrap();
+
+        assert_eq!(
+            vec![
+                PathBuf::from(SYSTEM_CONFIG),
+                repo.join(PROJECT_CONFIG),
+                repo.join("src").join(PROJECT_CONFIG),
+                cwd.join(PROJECT_CONFIG),
+            ],
+            impl_config_paths(Some(&cwd), env(&[]))
+        );
+
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    fn config(files: &[(&str, &str)]) -> Result<Config, ArgError> {
+        let mut config = Config::default();
+        for (path, src) in files {
+            let path = Path::new(path);
+            if path.file_name() == Some(PROJECT_CONFIG.as_ref()) {
+                config.add_project_file(path, src)?;
+            } else {
+                config.add_file(path, src)?;
+            }
+        }
+        Ok(config)
+    }
+
+    #[test]
+    fn merge_layers() {
+        let config = config(&[
+            (
+                "/etc/rusty-cli/config.toml",
+                r#"
+                nginx = "/usr/local/openresty/nginx/sbin/nginx"
+                include = ["/usr/share/lua"]
+                valgrind = true
+                worker-connections = 128
+                "#,
+            ),
+            (
+                "/home/me/.config/rusty-cli/config.toml",
+                r#"
+                errlog_level = "info"
+                gdb-opts = "-q"
+                resolve-ipv6 = true
+                include = ["lib", "/abs/lib"]
+                nginx = "bin/nginx"
+                "#,
+            ),
+            (
+                "/src/.rustyrc",
+                r#"
+                resolve-ipv6 = false
+                shdict = "cache 1m"
+                "#,
+            ),
+        ])
+        .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "-I", "/usr/share/lua",
+                "-I", "/home/me/.config/rusty-cli/lib",
+                "-I", "/abs/lib",
+                "-c", "128",
+                "--errlog-level", "info",
+                "--gdb-opts", "-q",
+                "--nginx", "/home/me/.config/rusty-cli/bin/nginx",
+                "--shdict", "cache 1m",
+            ],
+            config.to_args::<&str>(&[])
+        );
+
+        // scalars on the command line win, repeatable options are appended
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "-I", "/usr/share/lua",
+                "-I", "/home/me/.config/rusty-cli/lib",
+                "-I", "/abs/lib",
+                "--errlog-level", "info",
+                "--nginx", "/home/me/.config/rusty-cli/bin/nginx",
+                "--shdict", "cache 1m",
+            ],
+            config.to_args(&["-c", "1", "--rr", "-I", "x", "script.lua", "--nginx", "n"])
+        );
+
+        let rendered = config.render();
+        for expected in [
+            "#   /etc/rusty-cli/config.toml\n",
+            "\n# from: /etc/rusty-cli/config.toml, /home/me/.config/rusty-cli/config.toml\ninclude = [\"/usr/share/lua\", \"/home/me/.config/rusty-cli/lib\", \"/abs/lib\"]\n",
+            "\n# from: /home/me/.config/rusty-cli/config.toml\nnginx = \"/home/me/.config/rusty-cli/bin/nginx\"\n",
+            "\n# from: /src/.rustyrc\nshdict = [\"cache 1m\"]\n",
+            "\n# from: /etc/rusty-cli/config.toml\nworker-connections = 128\n",
+        ] {
+            assert!(rendered.contains(expected), "{expected}\n{rendered}");
+        }
+
+        assert!(!rendered.contains("valgrind"));
+        assert!(!rendered.contains("resolve-ipv6"));
+    }
+
+    #[test]
+    fn project_files() {
+        let err = |src: &str| config(&[("/src/.rustyrc", src)]).unwrap_err().to_string();
+
+        // only options that can't load code or write files are allowed
+        for key in [
+            "nginx = 'sh'",
+            "user-runner = 'sh -c id'",
+            "include = ['.']",
+            "require = ['pwn']",
+            "http-conf = ['lua_package_cpath \"./?.so;;\";']",
+            "report-json = 'out.json'",
+            "coverage-dir = '.'",
+            "lua-profile = 'profile.txt'",
+            "save-core = '.'",
+            "prefix = '.'",
+            "tmpdir = '.'",
+            "jit = ['dump=+rsx,out.txt']",
+        ] {
+            let name = key.split(' ').next().unwrap();
+            assert_eq!(
+                format!("ERROR: invalid config file /src/.rustyrc:2: `{name}` can't be set in .rustyrc (use the user config file or a profile)"),
+                err(&format!("shdict = ['a 1m']\n{key}")),
+                "{key}"
+            );
+        }
+
+        let config = config(&[(
+            "/src/.rustyrc",
+            "shdict = ['a 1m']\nns = ['1.1.1.1']\nworker-connections = 8\nresolve-ipv6 = true\nerrlog-level = 'info'\ntimeout = 5\n",
+        )])
+        .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "--shdict", "a 1m",
+                "--ns", "1.1.1.1",
+                "-c", "8",
+                "--resolve-ipv6",
+                "--errlog-level", "info",
+                "--timeout", "5",
+            ],
+            config.to_args::<&str>(&[])
+        );
+    }
+
+    #[test]
+    fn nginx_from_path() {
+        let config = config(&[("/etc/rusty-cli/config.toml", "nginx = 'openresty'")]).unwrap();
+        assert_eq!(vec!["--nginx", "openresty"], config.to_args::<&str>(&[]));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let err = |src| {
+            config(&[("/etc/rusty-cli/config.toml", src)])
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:2: unknown key `eval`",
+            err("nginx = 'x'\neval = 'print(1)'")
+        );
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:1: no-stream: expected a boolean, found string",
+            err("no-stream = 'yes'")
+        );
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:1: nginx: expected a single value, found array",
+            err("nginx = ['a', 'b']")
+        );
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:1: include: expected a string, found boolean",
+            err("include = [true]")
+        );
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:2: unknown key `http-include-path`",
+            err("[http]\ninclude-path = 'x'")
+        );
+        assert_eq!(
+            "ERROR: invalid config file /etc/rusty-cli/config.toml:2: unclosed array, expected `]`",
+            err("nginx = 'x'\ninclude = [")
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let config = config(&[(
+            "/etc/rusty-cli/config.toml",
+            "errlog.level = 'info'\n[rlimit]\ncore = 'unlimited'\nnofile = 1024\n[http]\nconf = ['a;']",
+        )])
+        .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "--errlog-level", "info",
+                "--rlimit-core", "unlimited",
+                "--rlimit-nofile", "1024",
+                "--http-conf", "a;",
+            ],
+            config.to_args::<&str>(&[])
+        );
+        assert!(config.render().contains("\nrlimit-nofile = 1024\n"));
+    }
+
+    #[test]
+    fn load_config_files() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+
+        let missing = dir.join("missing.toml");
+        let not_a_dir = dir.join("file/.rustyrc");
+        let file = dir.join(".rustyrc");
+        fs::write(dir.join("file"), "").unwrap();
+        fs::write(&file, "no-stream = true\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let config = Config::load_files(&[missing, not_a_dir, file.clone()]).unwrap();
+        assert_eq!(vec![file.clone()], config.files);
+        assert_eq!(vec!["--no-stream"], config.to_args::<&str>(&[]));
+
+        assert_eq!(Ok(Config::default()), Config::load_files(&[]));
+        assert!(matches!(
+            Config::load_files(std::slice::from_ref(&dir)),
+            Err(ArgError::Config { .. })
+        ));
+
+        // project files may not run code, but other config files can
+        fs::write(&file, "user-runner = 'sh -c id'\n").unwrap();
+        let err = Config::load_files(std::slice::from_ref(&file)).unwrap_err();
+        assert!(
+            err.to_string().contains("`user-runner` can't be set"),
+            "{err}"
+        );
+
+        let toml = dir.join("config.toml");
+        fs::write(&toml, "user-runner = 'sh -c id'\n").unwrap();
+        let config = Config::load_files(std::slice::from_ref(&toml)).unwrap();
+        assert_eq!(vec![toml], config.files);
+
+        // project files that others can write to are ignored
+        fs::write(&file, "no-stream = true\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o666)).unwrap();
+        let config = Config::load_files(std::slice::from_ref(&file)).unwrap();
+        assert_eq!(Config::default(), config);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stacked_profiles() {
+        let mut config = config(&[(
+            "/etc/rusty-cli/config.toml",
+            "valgrind = true\nerrlog-level = 'warn'\nshdict = ['a 1m']\n",
+        )])
+        .unwrap();
+
+        let dir = Path::new("/home/me/.config/rusty-cli/profiles");
+        config
+            .add_profile(
+                "debug",
+                &dir.join("debug.toml"),
+                "gdb = true\nerrlog-level = 'debug'\n",
+            )
+            .unwrap();
+        config
+            .add_profile(
+                "kong",
+                &dir.join("kong"),
+                "shdict = ['b 2m']\nerrlog-level = 'info'\nstap = true\n",
+            )
+            .unwrap();
+
+        // profiles replace config file settings in the same group, but
+        // not each other
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "--shdict", "a 1m",
+                "--shdict", "b 2m",
+                "--gdb",
+                "--errlog-level", "info",
+                "--stap",
+            ],
+            config.to_args::<&str>(&[])
+        );
+
+        // the command line only replaces the same option
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "--shdict", "a 1m",
+                "--shdict", "b 2m",
+                "--gdb",
+                "--stap",
+            ],
+            config.to_args(&["--errlog-level", "crit", "--valgrind"])
+        );
+
+        let err = config.annotate(
+            ArgError::Conflict("--gdb".into(), "--valgrind".into()),
+            &["--valgrind"],
+        );
+        assert_eq!(
+            "ERROR: options --gdb (from profile debug) and --valgrind cannot be specified at the same time.",
+            err.to_string()
+        );
+
+        let err = config.annotate(
+            ArgError::Conflict("--stap".into(), "--gdb".into()),
+            &["--gdb-opts", "-q"],
+        );
+        assert_eq!(
+            ArgError::Conflict("--stap (from profile kong)".into(), "--gdb".into()),
+            err
+        );
+
+        let err = config.annotate(ArgError::NoLuaInput, &["--gdb"]);
+        assert_eq!(ArgError::NoLuaInput, err);
+    }
+
+    #[test]
+    fn load_profiles() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        fs::write(dir.join("bare.toml"), "no-stream = true\n").unwrap();
+        fs::write(dir.join("plain"), "resolve-ipv6 = true\n").unwrap();
+
+        let mut config = Config::default();
+        config.load_profile("bare", Some(&dir)).unwrap();
+        config.load_profile("plain", Some(&dir)).unwrap();
+        assert_eq!(
+            vec!["--no-stream", "--resolve-ipv6"],
+            config.to_args::<&str>(&[])
+        );
+
+        let err = |name: &str, dir: Option<&Path>| match Config::default().load_profile(name, dir) {
+            Err(ArgError::InvalidValue { err, .. }) => err,
+            other => panic!("unexpected result: {other:?}"),
+        };
+
+        assert_eq!(
+            format!("profile not found in {}", dir.display()),
+            err("nope", Some(&dir))
+        );
+        assert_eq!("invalid profile name", err("../bare", Some(&dir)));
+        assert_eq!("invalid profile name", err("", Some(&dir)));
+        assert_eq!("no profile directory ($HOME is not set)", err("bare", None));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn env_layer() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+
+        let mut config = config(&[(
+            "/etc/rusty-cli/config.toml",
+            "nginx = '/opt/nginx'\ninclude = ['/lib']\nresolve-ipv6 = true\nvalgrind = true\n",
+        )])
+        .unwrap();
+
+        config
+            .add_env(env(&[
+                ("RUSTY_CLI_NGINX", "/env/nginx"),
+                ("RUSTY_CLI_INCLUDE", "/a::rel/b"),
+                ("RUSTY_CLI_SHDICT", "x 1m;y 2m;"),
+                ("RUSTY_CLI_HTTP_CONF", "a; b;"),
+                ("RUSTY_CLI_RESOLVE_IPV6", "no"),
+                ("RUSTY_CLI_GDB", "On"),
+                ("RUSTY_CLI_ERRLOG_LEVEL", ""),
+                ("RUSTY_CLI_TMPDIR", "/tmp"),
+            ]))
+            .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "-I", "/lib",
+                "-I", "/a",
+                "-I", "rel/b",
+                "--shdict", "x 1m",
+                "--shdict", "y 2m",
+                "--nginx", "/env/nginx",
+                "--http-conf", "a; b;",
+                "--gdb",
+            ],
+            config.to_args::<&str>(&[])
+        );
+
+        assert!(config
+            .render()
+            .contains("\n# from: $RUSTY_CLI_NGINX\nnginx = \"/env/nginx\"\n"));
+
+        let err = config.annotate(
+            ArgError::InvalidValue {
+                arg: "--shdict".into(),
+                value: "y 2m".into(),
+                err: "nope".into(),
+            },
+            &["--shdict", "z"],
+        );
+        assert_eq!(
+            "ERROR: Invalid --shdict (from $RUSTY_CLI_SHDICT) option value: y 2m\n  (nope)",
+            err.to_string()
+        );
+
+        let err = Config::default()
+            .add_env(env(&[("RUSTY_CLI_NO_STREAM", "maybe")]))
+            .unwrap_err();
+        assert_eq!(
+            "ERROR: Invalid RUSTY_CLI_NO_STREAM environment variable value: maybe\n  (expected a boolean)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn directives_layer() {
+        let mut config = config(&[(
+            "/etc/rusty-cli/config.toml",
+            "errlog-level = 'info'\nshdict = ['file 1m']\nvalgrind = true\n",
+        )])
+        .unwrap();
+
+        let found = directives::parse(
+            "--! shdict: script 2m\n--! include: lib\n--! gdb\n--! resolve-ipv6: no\n".as_bytes(),
+        )
+        .unwrap();
+        config
+            .add_directives(Path::new("/app/run.lua"), found)
+            .unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(
+            vec![
+                "--errlog-level", "info",
+                "--shdict", "file 1m",
+                "--shdict", "script 2m",
+                "-I", "/app/lib",
+                "--gdb",
+            ],
+            config.to_args::<&str>(&[])
+        );
+
+        let err = |src: &str| {
+            let found = directives::parse(src.as_bytes()).unwrap();
+            Config::default()
+                .add_directives(Path::new("run.lua"), found)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            "ERROR: invalid directive in run.lua:1: shdict: missing value",
+            err("--! shdict\n")
+        );
+        assert_eq!(
+            "ERROR: invalid directive in run.lua:1: no-stream: expected a boolean",
+            err("--! no-stream: maybe\n")
+        );
+    }
+}
@@ -0,0 +1,468 @@
+use crate::run::Status;
+use crate::types::{Prefix, Rlimit, RlimitValue};
+use crate::util::find_in_path;
+use nix::sys::resource::{getrlimit, Resource, RLIM_INFINITY};
+use nix::sys::signal::Signal;
+use std::fs;
+use std::io;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+const CORE_USES_PID: &str = "/proc/sys/kernel/core_uses_pid";
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+/// Linux truncates the executable name used for `%e` to TASK_COMM_LEN - 1.
+const COMM_MAX: usize = 15;
+
+/// How many times to ask systemd-coredump for the core, which is written
+/// asynchronously after the process has exited.
+const COREDUMPCTL_ATTEMPTS: usize = 10;
+const COREDUMPCTL_DELAY: Duration = Duration::from_millis(500);
+
+/// File timestamps come from a coarse clock, so a core written just after
+/// nginx started can appear to be slightly older than that.
+const MTIME_SLACK: Duration = Duration::from_secs(1);
+
+const GDB_SCRIPT: &str = r#"set pagination off
+set print pretty on
+echo \n=== C backtrace ===\n
+thread apply all bt full
+python
+try:
+    gdb.execute("lbt")
+except Exception as e:
+    print("(no Lua backtrace available: %s)" % e)
+end
+"#;
+
+/// What to do when nginx dumps core.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Capture {
+    /// Print a backtrace with gdb.
+    pub(crate) backtrace: bool,
+
+    /// Copy the core file into this directory.
+    pub(crate) save_to: Option<PathBuf>,
+}
+
+impl Capture {
+    pub(crate) fn enabled(&self) -> bool {
+        self.backtrace || self.save_to.is_some()
+    }
+
+    /// Inspect the exit status of nginx and, if it crashed, find its core
+    /// file and act on it.
+    ///
+    /// This must be called before the prefix directory is removed.
+    pub(crate) fn handle(
+        &self,
+        status: &Status,
+        nginx: &Path,
+        prefix: &Prefix,
+        started: SystemTime,
+    ) {
+        if !self.enabled() {
+            return;
+        }
+
+        let (Some(pid), Some(signal)) = (status.pid, status.signal) else {
+            return;
+        };
+
+        if !status.core_dumped {
+            if is_crash(signal) {
+                eprintln!("nginx (pid {pid}) was killed by {signal} but did not dump core");
+            }
+            return;
+        }
+
+        eprintln!("nginx (pid {pid}) was killed by {signal} and dumped core");
+
+        let crash = Crash {
+            pid: pid.as_raw(),
+            signal,
+            exe: find_in_path(nginx).unwrap_or_else(|| nginx.to_owned()),
+            started,
+        };
+
+        let core = match crash.find_core(prefix) {
+            Ok(core) => core,
+            Err(e) => {
+                eprintln!("failed locating core dump: {e}");
+                return;
+            }
+        };
+
+        eprintln!("core dump: {}", core.display());
+
+        if let Some(dir) = &self.save_to {
+            match save_core(&core, dir, crash.pid) {
+                Ok(saved) => eprintln!("core dump saved to {}", saved.display()),
+                Err(e) => eprintln!("failed saving core dump to {}: {e}", dir.display()),
+            }
+        }
+
+        if self.backtrace {
+            if let Err(e) = backtrace(&crash.exe, &core, prefix) {
+                eprintln!("failed running gdb: {e}");
+            }
+        }
+    }
+}
+
+fn is_crash(signal: Signal) -> bool {
+    matches!(
+        signal,
+        Signal::SIGSEGV
+            | Signal::SIGABRT
+            | Signal::SIGBUS
+            | Signal::SIGFPE
+            | Signal::SIGILL
+            | Signal::SIGTRAP
+            | Signal::SIGSYS
+    )
+}
+
+/// The RLIMIT_CORE to use when the user asked for core capture but did not
+/// set `--rlimit-core` themselves: as high as we are allowed to go.
+pub(crate) fn core_rlimit() -> Rlimit {
+    let value = match getrlimit(Resource::RLIMIT_CORE) {
+        Ok((_, hard)) if hard != RLIM_INFINITY => RlimitValue::Limit(hard),
+        _ => RlimitValue::Unlimited,
+    };
+
+    Rlimit {
+        resource: Resource::RLIMIT_CORE,
+        value,
+    }
+}
+
+struct Crash {
+    pid: i32,
+    signal: Signal,
+    exe: PathBuf,
+    started: SystemTime,
+}
+
+impl Crash {
+    fn find_core(&self, prefix: &Prefix) -> Result<PathBuf, String> {
+        let pattern = fs::read_to_string(CORE_PATTERN)
+            .map_err(|e| format!("failed reading {CORE_PATTERN}: {e}"))?;
+        let pattern = pattern.trim_end_matches('\n');
+
+        if let Some(handler) = pattern.strip_prefix('|') {
+            if handler.contains("systemd-coredump") {
+                return self.coredumpctl(prefix);
+            }
+
+            return Err(format!(
+                "core dumps are piped to `{handler}` (see {CORE_PATTERN})"
+            ));
+        }
+
+        let uses_pid = fs::read_to_string(CORE_USES_PID).is_ok_and(|s| s.trim() == "1");
+        let hostname = fs::read_to_string(HOSTNAME).unwrap_or_default();
+
+        let parts = expand(pattern, uses_pid, self, hostname.trim());
+        let (dir, name) =
+            split_dir(&parts).ok_or_else(|| format!("unsupported core_pattern `{pattern}`"))?;
+
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir
+        };
+
+        let not_before = self
+            .started
+            .checked_sub(MTIME_SLACK)
+            .unwrap_or(self.started);
+
+        let candidates = fs::read_dir(&dir)
+            .map_err(|e| format!("failed reading directory {}: {e}", dir.display()))?;
+
+        candidates
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|fname| matches(&name, fname))
+            })
+            .filter_map(|entry| {
+                let mtime = entry.metadata().and_then(|md| md.modified()).ok()?;
+                (mtime >= not_before).then(|| (mtime, entry.path()))
+            })
+            .max()
+            .map(|(_, path)| path)
+            .ok_or_else(|| {
+                format!(
+                    "no file matching core_pattern `{pattern}` in {}",
+                    dir.display()
+                )
+            })
+    }
+
+    /// Retrieve the core from systemd-coredump into the prefix directory.
+    fn coredumpctl(&self, prefix: &Prefix) -> Result<PathBuf, String> {
+        let out = prefix.root.join(format!("core.{}", self.pid));
+
+        for _ in 0..COREDUMPCTL_ATTEMPTS {
+            let status = Command::new("coredumpctl")
+                .args(["--no-pager", "--quiet", "dump"])
+                .arg(self.pid.to_string())
+                .arg("--output")
+                .arg(&out)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_err(|e| format!("failed running coredumpctl: {e}"))?;
+
+            if status.success() && out.is_file() {
+                return Ok(out);
+            }
+
+            thread::sleep(COREDUMPCTL_DELAY);
+        }
+
+        Err(format!("coredumpctl has no core dump for pid {}", self.pid))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Any,
+}
+
+fn push_literal(parts: &mut Vec<Part>, s: &str) {
+    if let Some(Part::Literal(last)) = parts.last_mut() {
+        last.push_str(s);
+    } else {
+        parts.push(Part::Literal(s.to_string()));
+    }
+}
+
+/// Expand the specifiers of a core(5) pattern that we know the value of,
+/// leaving wildcards for the rest.
+fn expand(pattern: &str, uses_pid: bool, crash: &Crash, hostname: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut has_pid = false;
+
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            push_literal(&mut parts, c.encode_utf8(&mut [0; 4]));
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') => push_literal(&mut parts, "%"),
+            Some('p') | Some('P') => {
+                has_pid = true;
+                push_literal(&mut parts, &crash.pid.to_string());
+            }
+            Some('s') => push_literal(&mut parts, &(crash.signal as i32).to_string()),
+            Some('h') => push_literal(&mut parts, hostname),
+            Some('e') => match crash.exe.file_name().and_then(|name| name.to_str()) {
+                Some(name) => {
+                    let comm: String = name.chars().take(COMM_MAX).collect();
+                    push_literal(&mut parts, &comm);
+                }
+                None => parts.push(Part::Any),
+            },
+            Some('E') => match crash
+                .exe
+                .canonicalize()
+                .ok()
+                .and_then(|exe| exe.to_str().map(|exe| exe.replace('/', "!")))
+            {
+                Some(exe) => push_literal(&mut parts, &exe),
+                None => parts.push(Part::Any),
+            },
+            Some(_) if parts.last() != Some(&Part::Any) => parts.push(Part::Any),
+            // a trailing `%` is dropped by the kernel
+            Some(_) | None => {}
+        }
+    }
+
+    if uses_pid && !has_pid {
+        push_literal(&mut parts, &format!(".{}", crash.pid));
+    }
+
+    parts
+}
+
+/// Split an expanded pattern into its directory and file name. Wildcards are
+/// only supported in the file name.
+fn split_dir(parts: &[Part]) -> Option<(PathBuf, Vec<Part>)> {
+    let last_slash = parts.iter().rposition(|part| match part {
+        Part::Literal(s) => s.contains('/'),
+        Part::Any => false,
+    });
+
+    let Some(idx) = last_slash else {
+        return Some((PathBuf::new(), parts.to_vec()));
+    };
+
+    if parts[..idx].contains(&Part::Any) {
+        return None;
+    }
+
+    let mut dir = String::new();
+    for part in &parts[..idx] {
+        if let Part::Literal(s) = part {
+            dir.push_str(s);
+        }
+    }
+
+    let Part::Literal(s) = &parts[idx] else {
+        unreachable!();
+    };
+    let (head, tail) = s.rsplit_once('/').expect("literal contains a slash");
+    dir.push_str(head);
+    if dir.is_empty() {
+        dir.push('/');
+    }
+
+    let mut name = Vec::with_capacity(parts.len() - idx);
+    if !tail.is_empty() {
+        name.push(Part::Literal(tail.to_string()));
+    }
+    name.extend_from_slice(&parts[idx + 1..]);
+
+    Some((PathBuf::from(dir), name))
+}
+
+/// Match a file name against pattern parts, where `Part::Any` matches any
+/// (possibly empty) run of characters.
+fn matches(parts: &[Part], name: &str) -> bool {
+    match parts.split_first() {
+        None => name.is_empty(),
+        Some((Part::Literal(lit), rest)) => name
+            .strip_prefix(lit.as_str())
+            .is_some_and(|name| matches(rest, name)),
+        Some((Part::Any, rest)) => name
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([name.len()])
+            .any(|i| matches(rest, &name[i..])),
+    }
+}
+
+fn save_core(core: &Path, dir: &Path, pid: i32) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let dest = dir.join(format!("core.{pid}"));
+    fs::copy(core, &dest)?;
+    Ok(dest)
+}
+
+/// Print a backtrace of the crashed process to stderr with gdb, including
+/// the Lua backtrace when the openresty-gdb-utils `lbt` command is
+/// available.
+fn backtrace(exe: &Path, core: &Path, prefix: &Prefix) -> io::Result<()> {
+    let script = prefix.root.join("backtrace.gdb");
+    fs::write(&script, GDB_SCRIPT)?;
+
+    // keep stdout for the output of the Lua script
+    let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+
+    let status = Command::new("gdb")
+        .arg("-batch")
+        .arg("-x")
+        .arg(&script)
+        .arg(exe)
+        .arg(core)
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(stderr))
+        .status()?;
+
+    if !status.success() {
+        eprintln!("gdb exited with {status}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crash() -> Crash {
+        Crash {
+            pid: 1234,
+            signal: Signal::SIGSEGV,
+            exe: PathBuf::from("/usr/local/openresty/nginx/sbin/nginx"),
+            started: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn lit(s: &str) -> Part {
+        Part::Literal(s.to_string())
+    }
+
+    #[test]
+    fn expand_core_pattern() {
+        let c = crash();
+
+        assert_eq!(vec![lit("core")], expand("core", false, &c, "host"));
+        assert_eq!(vec![lit("core.1234")], expand("core", true, &c, "host"));
+        assert_eq!(vec![lit("core.1234")], expand("core.%p", true, &c, "host"));
+
+        assert_eq!(
+            vec![lit("/var/crash/core.nginx.11.host."), Part::Any],
+            expand("/var/crash/core.%e.%s.%h.%t", false, &c, "host")
+        );
+
+        assert_eq!(
+            vec![lit("%core-"), Part::Any, lit("-1234")],
+            expand("%%core-%u%g-%p%", false, &c, "host")
+        );
+    }
+
+    #[test]
+    fn split_core_pattern_dir() {
+        assert_eq!(
+            Some((PathBuf::from(""), vec![lit("core")])),
+            split_dir(&[lit("core")])
+        );
+
+        assert_eq!(
+            Some((PathBuf::from("/var/crash"), vec![lit("core."), Part::Any])),
+            split_dir(&[lit("/var/crash/core."), Part::Any])
+        );
+
+        assert_eq!(
+            Some((PathBuf::from("/"), vec![Part::Any])),
+            split_dir(&[lit("/"), Part::Any])
+        );
+
+        assert_eq!(
+            Some((PathBuf::from("/cores/1234"), vec![lit("core")])),
+            split_dir(&[lit("/cores/1234/core")])
+        );
+
+        assert_eq!(None, split_dir(&[lit("/cores/"), Part::Any, lit("/core")]));
+    }
+
+    #[test]
+    fn match_core_file_name() {
+        assert!(matches(&[lit("core")], "core"));
+        assert!(!matches(&[lit("core")], "core.1"));
+        assert!(matches(&[lit("core."), Part::Any], "core."));
+        assert!(matches(&[lit("core."), Part::Any], "core.123"));
+        assert!(matches(
+            &[lit("core."), Part::Any, lit(".1234")],
+            "core.nginx.1234"
+        ));
+        assert!(!matches(
+            &[lit("core."), Part::Any, lit(".1234")],
+            "core.nginx.4321"
+        ));
+        assert!(!matches(&[Part::Any, lit("x")], "core"));
+    }
+}
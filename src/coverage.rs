@@ -0,0 +1,569 @@
+//! Lua code coverage for `--coverage`.
+//!
+//! The generated init_worker code wraps `gen` in a function that installs a
+//! `debug.sethook` line hook, and writes the hit counts to a file in the
+//! prefix directory whenever the Lua code is done: when `gen` returns or
+//! fails, and when `os.exit` or `ngx.exit` is called. Once nginx exits, the
+//! counts from every run are merged and written out as a luacov stats file
+//! and an LCOV tracefile.
+
+use crate::lua::LuaString;
+use crate::types::{Error, Prefix};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the hit counts are written, relative to the prefix directory.
+pub(crate) const RAW: &str = "logs/coverage";
+
+/// The luacov stats file name.
+pub(crate) const LUACOV_STATS: &str = "luacov.stats.out";
+
+/// The LCOV tracefile name.
+pub(crate) const LCOV: &str = "lcov.info";
+
+/// Options for `--coverage`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CoverageArgs {
+    pub(crate) enabled: bool,
+
+    /// Where to write the reports (the current directory by default).
+    pub(crate) dir: Option<PathBuf>,
+
+    /// Lua patterns; when there are any, only matching files are covered.
+    pub(crate) include: Vec<String>,
+
+    /// Lua patterns for files that are not covered.
+    pub(crate) exclude: Vec<String>,
+}
+
+const COVERAGE_LUA: &str = r##"local cov_wrap = function(run)
+    local sethook = debug.sethook
+    local getinfo = debug.getinfo
+    local find = string.find
+
+    -- file name => { [line] = hits }
+    local hits = {}
+
+    -- chunk source => hits for the file, or false if it isn't covered
+    local sources = {}
+
+    local function covered(name)
+        local found = #cov_include == 0
+        for _, pattern in ipairs(cov_include) do
+            if pcall(find, name, pattern) and find(name, pattern) then
+                found = true
+                break
+            end
+        end
+
+        if found then
+            for _, pattern in ipairs(cov_exclude) do
+                if pcall(find, name, pattern) and find(name, pattern) then
+                    return false
+                end
+            end
+        end
+
+        return found
+    end
+
+    local function hook(_, line)
+        local source = getinfo(2, "S").source
+        local file = sources[source]
+
+        if file == nil then
+            file = false
+            -- only code loaded from files
+            if source:sub(1, 1) == "@" and covered(source:sub(2)) then
+                file = hits[source:sub(2)] or {}
+                hits[source:sub(2)] = file
+            end
+            sources[source] = file
+        end
+
+        if file then
+            file[line] = (file[line] or 0) + 1
+        end
+    end
+
+    -- the counts so far replace the previous ones, so this can be called
+    -- more than once
+    local function flush()
+        local f = io.open(cov_results, "w")
+        if not f then
+            return
+        end
+
+        for name, lines in pairs(hits) do
+            f:write("@", name, "\n")
+            for line, count in pairs(lines) do
+                f:write(line, " ", count, "\n")
+            end
+        end
+
+        f:close()
+    end
+
+    local exit = os.exit
+    os.exit = function(...)
+        flush()
+        return exit(...)
+    end
+
+    local ngx_exit = ngx.exit
+    ngx.exit = function(...)
+        flush()
+        return ngx_exit(...)
+    end
+
+    return function()
+        -- compiled code doesn't run hooks
+        if jit then
+            jit.off()
+            jit.flush()
+        end
+
+        sethook(hook, "l")
+
+        local ok, err = xpcall(run, function(err)
+            -- the same traceback as the handler that this takes over from
+            return debug.traceback(err, 3)
+        end)
+
+        sethook()
+        flush()
+
+        if not ok then
+            err = string.gsub(err, "^init_worker_by_lua:%d+: ", "")
+            io.stderr:write("ERROR: ", err, "\n")
+            -- not `exit`, so that anything else waiting for os.exit runs
+            return os.exit(1)
+        end
+    end
+end"##;
+
+/// The Lua code that collects coverage, for `LuaGenerator`.
+pub(crate) fn lua_loader(args: &CoverageArgs, prefix: &Prefix) -> Vec<String> {
+    let patterns = |patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|pattern| pattern.lua_quote())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut lines = vec![
+        format!("local cov_include = {{ {} }}", patterns(&args.include)),
+        format!("local cov_exclude = {{ {} }}", patterns(&args.exclude)),
+        format!(
+            "local cov_results = {}",
+            prefix.root.join(RAW).to_string_lossy().lua_quote()
+        ),
+    ];
+
+    lines.extend(COVERAGE_LUA.lines().map(String::from));
+    lines
+}
+
+/// Hit counts for each line of each file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Coverage {
+    files: BTreeMap<String, BTreeMap<usize, u64>>,
+}
+
+impl Coverage {
+    /// Read the hit counts written by a run, if there are any.
+    pub(crate) fn read(prefix: &Prefix) -> Self {
+        fs::read_to_string(prefix.root.join(RAW))
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    fn parse(src: &str) -> Self {
+        let mut coverage = Self::default();
+        let mut file = None;
+
+        for line in src.lines() {
+            if let Some(name) = line.strip_prefix('@') {
+                file = Some(coverage.files.entry(name.to_string()).or_default());
+                continue;
+            }
+
+            let Some(lines) = file.as_mut() else {
+                continue;
+            };
+
+            if let Some((line, count)) = line.split_once(' ') {
+                if let (Ok(line), Ok(count)) = (line.parse(), count.parse::<u64>()) {
+                    *lines.entry(line).or_default() += count;
+                }
+            }
+        }
+
+        coverage
+    }
+
+    /// Add the hit counts from another run.
+    pub(crate) fn merge(&mut self, other: Coverage) {
+        for (name, lines) in other.files {
+            let file = self.files.entry(name).or_default();
+            for (line, count) in lines {
+                *file.entry(line).or_default() += count;
+            }
+        }
+    }
+
+    /// The luacov stats file: for each file, a `MAX:NAME` line followed by
+    /// the hit counts of lines 1 to MAX.
+    fn luacov(&self) -> String {
+        let mut out = String::new();
+
+        for (name, lines) in &self.files {
+            let max = lines.keys().max().copied().unwrap_or_default();
+            let _ = writeln!(out, "{max}:{name}");
+
+            for line in 1..=max {
+                let _ = write!(out, "{} ", lines.get(&line).copied().unwrap_or_default());
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// The LCOV tracefile. Lines that were never run are found by scanning
+    /// the source with `source`.
+    fn lcov<F>(&self, source: F) -> String
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut out = String::new();
+
+        for (name, hits) in &self.files {
+            let mut lines = hits.clone();
+            if let Some(src) = source(name) {
+                for line in executable_lines(&src) {
+                    lines.entry(line).or_default();
+                }
+            }
+
+            let _ = writeln!(out, "TN:\nSF:{name}");
+            for (line, count) in &lines {
+                let _ = writeln!(out, "DA:{line},{count}");
+            }
+
+            let hit = lines.values().filter(|count| **count > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{hit}\nend_of_record", lines.len());
+        }
+
+        out
+    }
+
+    /// Write the luacov stats file and the LCOV tracefile.
+    pub(crate) fn write(&self, args: &CoverageArgs) -> Result<(), Error> {
+        let dir = args.dir.clone().unwrap_or_else(|| PathBuf::from("."));
+
+        let io_err = |context| move |source| Error::Io { context, source };
+
+        fs::create_dir_all(&dir).map_err(io_err("failed creating coverage directory"))?;
+
+        fs::write(dir.join(LUACOV_STATS), self.luacov())
+            .map_err(io_err("failed writing luacov stats"))?;
+
+        fs::write(
+            dir.join(LCOV),
+            self.lcov(|name| fs::read_to_string(name).ok()),
+        )
+        .map_err(io_err("failed writing LCOV tracefile"))
+    }
+}
+
+/// What the scanner is in the middle of at the start of a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LongBracket {
+    Comment(usize),
+    String(usize),
+}
+
+/// What an unclosed bracket or keyword opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Open {
+    Bracket,
+    Block,
+    Repeat,
+}
+
+/// The level of a long bracket (`[[`, `[==[`) starting at `s`, if there is
+/// one.
+fn long_bracket(s: &[u8]) -> Option<usize> {
+    if s.first() != Some(&b'[') {
+        return None;
+    }
+
+    let level = s[1..].iter().take_while(|c| **c == b'=').count();
+    (s.get(level + 1) == Some(&b'[')).then_some(level)
+}
+
+/// Find the end of a long bracket of `level`, returning the index after it.
+fn long_bracket_end(s: &[u8], level: usize) -> Option<usize> {
+    let close: Vec<u8> = std::iter::once(b']')
+        .chain(std::iter::repeat_n(b'=', level))
+        .chain(std::iter::once(b']'))
+        .collect();
+
+    s.windows(close.len())
+        .position(|w| w == close.as_slice())
+        .map(|i| i + close.len())
+}
+
+/// Guess which lines of Lua source code are executable, the way coverage
+/// tools like luacov do.
+///
+/// Blank lines, comments, lines that only close a block (`end`, `else`,
+/// `}`), and lines that continue an expression started on an earlier line
+/// are not executable.
+fn executable_lines(src: &str) -> Vec<usize> {
+    let mut lines = vec![];
+    let mut long: Option<LongBracket> = None;
+    let mut stack: Vec<Open> = vec![];
+
+    for (i, line) in src.lines().enumerate() {
+        let mut s = line.as_bytes();
+
+        // a statement that started in a long string was counted already
+        let starts_in_string = matches!(long, Some(LongBracket::String(_)));
+
+        if let Some(LongBracket::Comment(level) | LongBracket::String(level)) = long {
+            match long_bracket_end(s, level) {
+                Some(end) => {
+                    s = &s[end..];
+                    long = None;
+                }
+                None => continue,
+            }
+        }
+
+        if i == 0 && s.starts_with(b"#!") {
+            continue;
+        }
+
+        let continuation = starts_in_string || stack.last() == Some(&Open::Bracket);
+
+        // the code on this line with strings and comments removed
+        let mut code = String::new();
+        let mut pos = 0;
+
+        while pos < s.len() {
+            let c = s[pos];
+            match c {
+                b'-' if s.get(pos + 1) == Some(&b'-') => {
+                    if let Some(level) = long_bracket(&s[pos + 2..]) {
+                        let start = pos + 2 + level + 2;
+                        match long_bracket_end(&s[start..], level) {
+                            Some(end) => {
+                                pos = start + end;
+                                continue;
+                            }
+                            None => long = Some(LongBracket::Comment(level)),
+                        }
+                    }
+                    break;
+                }
+
+                b'"' | b'\'' => {
+                    code.push('s');
+                    pos += 1;
+                    while pos < s.len() && s[pos] != c {
+                        pos += if s[pos] == b'\\' { 2 } else { 1 };
+                    }
+                    pos += 1;
+                }
+
+                b'[' if long_bracket(&s[pos..]).is_some() => {
+                    let level = long_bracket(&s[pos..]).unwrap_or_default();
+                    code.push('s');
+                    let start = pos + level + 2;
+                    match long_bracket_end(&s[start..], level) {
+                        Some(end) => pos = start + end,
+                        None => {
+                            long = Some(LongBracket::String(level));
+                            break;
+                        }
+                    }
+                }
+
+                _ => {
+                    code.push(c as char);
+                    pos += 1;
+                }
+            }
+        }
+
+        let mut only_closers = true;
+        let mut word = String::new();
+
+        // a trailing space ends the last word
+        for c in code.chars().chain([' ']) {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                word.push(c);
+                continue;
+            }
+
+            if !word.is_empty() {
+                match word.as_str() {
+                    "function" | "if" | "do" => stack.push(Open::Block),
+                    "repeat" => stack.push(Open::Repeat),
+                    "end" | "until" => {
+                        stack.pop();
+                    }
+                    _ => {}
+                }
+
+                if !matches!(word.as_str(), "end" | "else" | "do" | "then" | "repeat") {
+                    only_closers = false;
+                }
+                word.clear();
+            }
+
+            match c {
+                '(' | '{' | '[' => {
+                    stack.push(Open::Bracket);
+                    only_closers = false;
+                }
+                ')' | '}' | ']' => {
+                    stack.pop();
+                }
+                ',' | ';' => {}
+                c if c.is_whitespace() => {}
+                _ => only_closers = false,
+            }
+        }
+
+        // `until` closes a block but has a condition to run
+        if !continuation && !only_closers {
+            lines.push(i + 1);
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::KeepPrefix;
+
+    const SRC: &str = r#"#!/usr/bin/env rusty-cli
+-- a comment
+local M = {}
+
+--[[ a long
+comment ]]
+local t = {
+    "a",
+    b = function()
+        return 1
+    end,
+}
+
+function M.add(a, b)
+    if a then
+        return a + b
+    else
+        return b
+    end
+end
+
+local s = [==[
+long string
+]==] .. "x"
+
+repeat
+    M.n = (M.n or 0) + 1
+until M.n > 2
+
+return M
+"#;
+
+    #[test]
+    fn scan_executable_lines() {
+        assert_eq!(
+            vec![3, 7, 10, 14, 15, 16, 18, 22, 27, 28, 30],
+            executable_lines(SRC)
+        );
+
+        assert_eq!(
+            vec![1, 2],
+            executable_lines("local a = 1\nprint(a) -- ok\n")
+        );
+        assert_eq!(
+            vec![1, 3],
+            executable_lines("local a = \"--[[\"\n--[==[ ]] ]==]\nprint(a)\n")
+        );
+    }
+
+    #[test]
+    fn loader() {
+        let prefix = Prefix::new(None, None, KeepPrefix::Never).unwrap();
+        let args = CoverageArgs {
+            enabled: true,
+            include: vec!["^lib/".to_string()],
+            exclude: vec!["_spec%.lua$".to_string(), "^vendor/".to_string()],
+            ..Default::default()
+        };
+
+        let lines = lua_loader(&args, &prefix);
+        let results = prefix.root.join(RAW);
+        assert_eq!(
+            vec![
+                "local cov_include = { [=[^lib/]=] }".to_string(),
+                "local cov_exclude = { [=[_spec%.lua$]=], [=[^vendor/]=] }".to_string(),
+                format!("local cov_results = [=[{}]=]", results.display()),
+                "local cov_wrap = function(run)".to_string(),
+            ],
+            lines[..4]
+        );
+
+        // the results are read back from where the Lua code writes them
+        fs::write(&results, "@lib/a.lua\n3 1\n").unwrap();
+        assert_eq!(
+            Coverage::parse("@lib/a.lua\n3 1\n"),
+            Coverage::read(&prefix)
+        );
+    }
+
+    #[test]
+    fn parse_and_merge() {
+        let mut coverage = Coverage::parse("@lib/a.lua\n3 1\n7 2\n@b.lua\n1 1\nbogus\n");
+        coverage.merge(Coverage::parse("@lib/a.lua\n3 4\n10 1\n"));
+
+        let mut expected = Coverage::default();
+        expected.files.insert(
+            "lib/a.lua".to_string(),
+            BTreeMap::from([(3, 5), (7, 2), (10, 1)]),
+        );
+        expected
+            .files
+            .insert("b.lua".to_string(), BTreeMap::from([(1, 1)]));
+
+        assert_eq!(expected, coverage);
+    }
+
+    #[test]
+    fn reports() {
+        let coverage = Coverage::parse("@lib/a.lua\n1 1\n3 2\n@b.lua\n2 1\n");
+
+        assert_eq!("2:b.lua\n0 1 \n3:lib/a.lua\n1 0 2 \n", coverage.luacov());
+
+        let source = |name: &str| {
+            (name == "lib/a.lua").then(|| "local a = 1\nlocal b = 2\nprint(a)\n".to_string())
+        };
+        assert_eq!(
+            "TN:\nSF:b.lua\nDA:2,1\nLF:1\nLH:1\nend_of_record\n\
+             TN:\nSF:lib/a.lua\nDA:1,1\nDA:2,0\nDA:3,2\nLF:3\nLH:2\nend_of_record\n",
+            coverage.lcov(source)
+        );
+    }
+}
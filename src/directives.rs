@@ -0,0 +1,159 @@
+//! In-script directives.
+//!
+//! A Lua file can declare the options it needs in `--!` comments at the top
+//! of the file, so that callers don't have to remember them:
+//!
+//! ```lua
+//! #!/usr/bin/env rusty-cli
+//! --! shdict: cache 10m
+//! --! include: ./lib
+//! --! no-stream
+//!
+//! local cache = ngx.shared.cache
+//! ```
+//!
+//! Keys are the same as in config files. Only the leading block of comments
+//! (after an optional `#!` line) is read, and comments that don't look like
+//! `--! key` or `--! key: value` for one of those keys (e.g. `--! strict` or
+//! `--! Note: ...`) are ignored, so scripts written for resty-cli keep
+//! working.
+
+use crate::options::Opt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Directive {
+    pub(crate) opt: &'static Opt,
+
+    /// The key as written in the script.
+    pub(crate) key: String,
+    pub(crate) value: Option<String>,
+    pub(crate) line: usize,
+}
+
+/// Parse one line of the leading comment block.
+fn parse_line(line: &str, lineno: usize) -> Option<Directive> {
+    let rest = line.strip_prefix("--!")?.trim();
+
+    let (key, value) = match rest.split_once(':') {
+        Some((key, value)) => (key.trim_end(), Some(value.trim().to_string())),
+        None => (rest, None),
+    };
+
+    Some(Directive {
+        opt: Opt::find_key(key)?,
+        key: key.to_string(),
+        value,
+        line: lineno,
+    })
+}
+
+/// Parse the directives at the top of a Lua script.
+pub(crate) fn parse<R: BufRead>(src: R) -> io::Result<Vec<Directive>> {
+    let mut directives = vec![];
+
+    for (i, line) in src.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            // not a text file, so there's nothing to find
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => break,
+            Err(e) => return Err(e),
+        };
+
+        if i == 0 && line.starts_with("#!") {
+            continue;
+        }
+
+        let line = line.trim_end();
+        if !line.starts_with("--") {
+            break;
+        }
+
+        directives.extend(parse_line(line, i + 1));
+    }
+
+    Ok(directives)
+}
+
+/// Read the directives from a Lua file.
+pub(crate) fn read(path: &Path) -> io::Result<Vec<Directive>> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directive(key: &str, value: Option<&str>, line: usize) -> Directive {
+        Directive {
+            opt: Opt::find_key(key).unwrap(),
+            key: key.to_string(),
+            value: value.map(str::to_string),
+            line,
+        }
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            Some(directive("shdict", Some("cache 10m"), 1)),
+            parse_line("--! shdict: cache 10m", 1)
+        );
+        assert_eq!(
+            Some(directive("http-conf", Some("a: b;"), 2)),
+            parse_line("--!http-conf :  a: b;", 2)
+        );
+        assert_eq!(
+            Some(directive("no_stream", None, 3)),
+            parse_line("--! no_stream", 3)
+        );
+        assert_eq!(
+            Some(directive("include", Some(""), 4)),
+            parse_line("--! include:", 4)
+        );
+
+        assert_eq!(None, parse_line("-- shdict: cache 10m", 1));
+        assert_eq!(None, parse_line("--!", 1));
+        assert_eq!(None, parse_line("--!!! TODO fix this", 1));
+        assert_eq!(None, parse_line("--! note to self", 1));
+        assert_eq!(None, parse_line("--! strict", 1));
+        assert_eq!(None, parse_line("--! Note: see README", 1));
+        assert_eq!(None, parse_line("--! shdcit: typo 1m", 1));
+    }
+
+    #[test]
+    fn parse_script() {
+        let src = "#!/usr/bin/env rusty-cli
+--! shdict: cache 10m
+-- some other comment
+--! strict
+--! Note: not a directive
+--! include: ./lib
+--[[ a block comment ]]
+--! no-stream
+
+--! shdict: not read 1m
+print('hi')
+";
+
+        assert_eq!(
+            vec![
+                directive("shdict", Some("cache 10m"), 2),
+                directive("include", Some("./lib"), 6),
+                directive("no-stream", None, 8),
+            ],
+            parse(src.as_bytes()).unwrap()
+        );
+
+        assert_eq!(
+            Vec::<Directive>::new(),
+            parse("local x = 1\n--! shdict: x 1m\n".as_bytes()).unwrap()
+        );
+        assert_eq!(
+            Vec::<Directive>::new(),
+            parse(&b"--! shdict: \xff 1m\n"[..]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Self-contained executables.
+//!
+//! `rusty-cli build-exe tool.lua -o tool` copies the rusty-cli binary and
+//! appends a bundle (see [`crate::bundle`]) of the script, the modules that it
+//! requires, and the options given to `build-exe`. The bundle is a zip
+//! archive, like in a `.rusty` file, so the end of the file tells the copy
+//! that it carries a script (and `unzip -l tool` lists what it carries).
+//!
+//! Before parsing arguments, the `rusty-cli` binary checks itself for a
+//! bundle with [`embedded_args`], which only reads the end of the file and
+//! the manifest. When there is one, the command line is rewritten to
+//! run the binary itself as a bundle, with the baked options in front: `tool a
+//! b` becomes `tool --no-config [OPTIONS] /path/to/tool a b`. Config files,
+//! profiles and `RUSTY_CLI_*` variables on the host don't apply, except for
+//! `RUSTY_CLI_NGINX` when no nginx path was baked in.
+
+use crate::bundle::{self, Manifest};
+use crate::cli::UserArgs;
+use crate::options::{self, Opt};
+use crate::types::ArgError;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Options that are used while building the executable instead of being
+/// baked into it.
+const NOT_BAKED: &[&str] = &[
+    "--no-config",
+    // expanded into the options that they set
+    "--profile",
+    "-I",
+    "--shdict",
+    "--http-conf",
+    "--main-conf",
+    "--stream-conf",
+    // embedded in the bundle, since the files might not exist where the
+    // executable is run
+    "--http-include",
+    "--main-include",
+];
+
+/// Options for the `build-exe` subcommand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ExeArgs {
+    /// The options to run the executable with.
+    pub(crate) args: Vec<String>,
+}
+
+/// Strip the bundle from an executable that was built with `build-exe`, if
+/// it has one.
+fn strip_payload(mut exe: Vec<u8>) -> Vec<u8> {
+    if let Ok(start) = bundle::start(io::Cursor::new(&exe)) {
+        exe.truncate(start as usize);
+    }
+    exe
+}
+
+/// The options to bake into the executable, from the `build-exe` command
+/// line (not including the program name).
+pub(crate) fn baked_args<S: AsRef<str>>(cli: &[S]) -> Vec<String> {
+    let mut args = vec![];
+
+    for (opt, value) in options::given(cli) {
+        if NOT_BAKED.contains(&opt.name) {
+            continue;
+        }
+
+        match (opt.takes_value(), value) {
+            (false, _) => args.push(opt.name.to_string()),
+            (true, Some(value)) => {
+                args.push(opt.name.to_string());
+                args.push(value.to_string());
+            }
+            // reported by the parser
+            (true, None) => {}
+        }
+    }
+
+    args
+}
+
+/// The command line to run with, if the running binary is a self-contained
+/// executable built with `rusty-cli build-exe`.
+///
+/// The baked options go in front of the executable itself, which is run as
+/// a bundle, and all of the real arguments are passed to the script. The
+/// result is meant for [`crate::Action::try_from`].
+pub(crate) fn embedded_args(args: &[String]) -> Result<Option<Vec<String>>, ArgError> {
+    let Ok(exe) = env::current_exe() else {
+        return Ok(None);
+    };
+
+    if !bundle::is_bundle(&exe) {
+        return Ok(None);
+    }
+
+    let baked = Manifest::read(&exe)?.args;
+
+    let mut embedded = args.first().cloned().into_iter().collect::<Vec<_>>();
+    embedded.push("--no-config".to_string());
+
+    // the host still decides where nginx is, unless it was baked in
+    if !baked.iter().any(|arg| arg == "--nginx") {
+        let nginx = Opt::find("--nginx")
+            .and_then(Opt::env_var)
+            .and_then(|var| env::var(var).ok())
+            .filter(|nginx| !nginx.is_empty());
+
+        if let Some(nginx) = nginx {
+            embedded.extend(["--nginx".to_string(), nginx]);
+        }
+    }
+
+    embedded.extend(baked);
+    embedded.push(exe.to_string_lossy().into_owned());
+    embedded.extend(args.iter().skip(1).cloned());
+
+    Ok(Some(embedded))
+}
+
+/// The default output file for an entry script: its name without the
+/// extension, in the current directory.
+fn default_output(entry: &str) -> PathBuf {
+    PathBuf::from(Path::new(entry).file_stem().unwrap_or_default())
+}
+
+fn output(user: &UserArgs) -> PathBuf {
+    user.bundle
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(&user.operands[0]))
+}
+
+/// Check the arguments for the `build-exe` subcommand.
+pub(crate) fn validate(user: &mut UserArgs) -> Result<(), ArgError> {
+    bundle::validate(user)?;
+
+    let output = output(user);
+    let same = |a: &Path, b: &Path| {
+        fs::canonicalize(a)
+            .ok()
+            .is_some_and(|a| fs::canonicalize(b).ok() == Some(a))
+    };
+
+    if same(&output, Path::new(&user.operands[0])) {
+        return Err(ArgError::InvalidValue {
+            arg: "--output".to_string(),
+            value: output.display().to_string(),
+            err: "that is the Lua file".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Write the executable, and return the exit code.
+pub(crate) fn run(user: Box<UserArgs>) -> i32 {
+    let exe = match env::current_exe().and_then(fs::read) {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("ERROR: failed reading the rusty-cli binary: {e}");
+            return 2;
+        }
+    };
+
+    let output = output(&user);
+
+    match bundle::build(&user) {
+        Ok((bundle, sources)) => match bundle.write_after(strip_payload(exe)) {
+            Ok(contents) => bundle::write_output(&output, &sources, &contents),
+            Err(e) => {
+                eprintln!("ERROR: failed writing {}: {e}", output.display());
+                2
+            }
+        },
+        Err(e) => {
+            eprintln!("ERROR: failed reading Lua files: {e}");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::Bundle;
+
+    #[test]
+    fn rebuild() {
+        let bundle = |entry: &str| Bundle {
+            manifest: bundle::Manifest {
+                entry: entry.to_string(),
+                ..Default::default()
+            },
+            files: vec![(entry.to_string(), b"print(1)".to_vec())],
+        };
+
+        let exe = b"\x7fELF binary".to_vec();
+        assert_eq!(exe, strip_payload(exe.clone()));
+
+        let built = bundle("bin/a.lua").write_after(exe.clone()).unwrap();
+        assert_eq!(exe, strip_payload(built.clone()));
+
+        // rebuilding from a built executable
+        let rebuilt = bundle("bin/b.lua")
+            .write_after(strip_payload(built))
+            .unwrap();
+        assert_eq!(
+            bundle("bin/b.lua").write_after(exe.clone()).unwrap(),
+            rebuilt
+        );
+        assert_eq!(
+            Ok(bundle("bin/b.lua")),
+            Bundle::from_reader(io::Cursor::new(&rebuilt))
+        );
+    }
+
+    #[test]
+    fn baked_options() {
+        assert_eq!(
+            vec!["--errlog-level", "info", "--no-stream", "-c", "10"],
+            baked_args(&[
+                "build-exe",
+                "-I",
+                "lib",
+                "--errlog-level=info",
+                "tool.lua",
+                "--no-stream",
+                "-o",
+                "tool",
+                "--shdict",
+                "cache 1m",
+                "--http-include",
+                "extra.conf",
+                "-c",
+                "10",
+                "--no-config",
+                "--profile",
+                "ci",
+            ])
+        );
+
+        // values attached the way luajit takes them
+        assert_eq!(
+            vec!["-O", "3", "-j", "v", "--no-stream", "-O", "3", "-j", "v"],
+            baked_args(&[
+                "build-exe",
+                "-O3",
+                "-jv",
+                "tool.lua",
+                "-o",
+                "tool",
+                "--no-stream",
+                "-O",
+                "3",
+                "-j",
+                "v",
+            ])
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! resty-cli, rewritten.
+//!
+//! Besides the `rusty-cli` binary, this crate can be used as a library to
+//! run Lua code in nginx from Rust, or to render the nginx.conf that would be
+//! used for a run without starting nginx at all:
+//!
+//! ```no_run
+//! use rusty_cli::Options;
+//!
+//! let out = Options::new()
+//!     .eval("print(ngx.config.nginx_version)")
+//!     .run()
+//!     .expect("nginx ran");
+//!
+//! assert_eq!(0, out.code);
+//! println!("{}", String::from_utf8_lossy(&out.stdout));
+//! ```
+//!
+//! [`Action`] gives access to the command line interface itself.
+
+#![warn(missing_docs)]
+
+use std::sync::LazyLock;
+
+use crate::compat_version::Version;
+
+mod api;
+mod batch;
+mod bundle;
+mod cli;
+mod compat_version;
+mod compile;
+mod completions;
+mod config;
+mod coredump;
+mod coverage;
+mod directives;
+mod exe;
+mod lua;
+mod man;
+mod nginx;
+mod options;
+mod profile;
+mod repl;
+mod report;
+mod run;
+mod spec;
+mod types;
+mod util;
+mod watch;
+
+pub use api::{Options, Output, RenderedConf};
+pub use cli::{Action, UserArgs};
+pub use types::{ArgError, Error, InvalidShdict, JitCmd, JitModule, JitOpts, LogLevel, Shdict};
+
+/// Helpers for the `rusty-cli` binary. These are not part of the API and may
+/// change at any time.
+#[doc(hidden)]
+pub mod __private {
+    use crate::types::ArgError;
+
+    /// See `exe::embedded_args`.
+    pub fn embedded_args(args: &[String]) -> Result<Option<Vec<String>>, ArgError> {
+        crate::exe::embedded_args(args)
+    }
+}
+
+/// The version of rusty-cli.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const RUSTY_CLI: &str = "rusty-cli";
+
+#[allow(dead_code)]
+pub(crate) const NAME: &str = match option_env!("CARGO_BIN_NAME") {
+    Some(name) => name,
+    None => RUSTY_CLI,
+};
+
+#[cfg(default_resty_compat_version)]
+pub(crate) const RESTY_COMPAT_DEFAULT: compat_version::Version = {
+    match compat_version::Version::from_str(env!("RESTY_CLI_COMPAT_VERSION")) {
+        Some(version) => version,
+        // we already validated the version in build.rs
+        None => unreachable!(),
+    }
+};
+
+#[cfg(not(default_resty_compat_version))]
+pub(crate) const RESTY_COMPAT_DEFAULT: Version = compat_version::RESTY_COMPAT_MAX;
+
+pub(crate) static RESTY_COMPAT_VERSION: LazyLock<Version> = LazyLock::new(|| {
+    use compat_version::*;
+    match Version::from_env() {
+        Some(Ok(value)) => {
+            if value > RESTY_COMPAT_MAX {
+                eprintln!("WARN: {RESTY_COMPAT_VAR} ({value}) is greater than max supported version ({RESTY_COMPAT_MAX})");
+            } else if value < RESTY_COMPAT_MIN {
+                eprintln!("WARN: {RESTY_COMPAT_VAR} ({value}) is less than the minimum supported version ({RESTY_COMPAT_MIN})");
+            }
+            Some(value)
+        },
+        Some(Err(value)) => {
+            eprintln!("WARN: value of {RESTY_COMPAT_VAR} env var (`{value}`) is invalid");
+            None
+        }
+        None => None,
+    }
+        .unwrap_or(RESTY_COMPAT_DEFAULT)
+});
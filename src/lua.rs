@@ -1,6 +1,17 @@
+use crate::cli::UserArgs;
+use crate::compile;
+use crate::coverage;
+use crate::options::Subcommand;
+use crate::profile;
+use crate::repl;
+use crate::spec;
 use crate::types::*;
 use std::cmp::max;
 use std::fs;
+use std::io::{self, Read};
+
+/// The Lua file name that instructs us to read the Lua program from stdin.
+pub(crate) const STDIN_FILE: &str = "-";
 
 // resty-cli has a fancier implementation that stores all observed "levels" in
 // a hash. Then it iterates over 1..$max_level and checks for hash membership,
@@ -57,23 +68,40 @@ where
 
 pub(crate) fn generate_lua_loader(
     prefix: &Prefix,
-    file: &Option<String>,
-    inline: &Vec<String>,
-    lua_args: &Vec<String>,
-    arg_0: String,
-    all_args_len: usize,
+    user: &UserArgs,
 ) -> Result<Vec<String>, std::io::Error> {
     let buf = Buf::new();
     let inline_filename = prefix.conf.join("a.lua").to_str().unwrap().to_owned();
+    let stdin_filename = prefix.conf.join("stdin.lua").to_str().unwrap().to_owned();
 
     LuaGenerator {
-        arg_0,
-        all_args_len,
-        file,
-        inline,
-        lua_args,
+        arg_0: user.arg_0.clone(),
+        all_args_len: user.arg_c,
+        file: &user.lua_file,
+        bundle_entry: user
+            .archive
+            .as_ref()
+            .map(|archive| (archive.entry_path(prefix), archive.entry_chunk_name())),
+        inline: &user.inline_lua,
+        lua_args: &user.lua_args,
         buf,
         inline_filename,
+        stdin_filename,
+        repl: user
+            .repl
+            .then(|| repl::lua_loader(&repl::Fifos::new(prefix))),
+        specs: (user.subcommand == Some(Subcommand::Test))
+            .then(|| spec::lua_loader(&user.test, prefix)),
+        compile: (user.subcommand == Some(Subcommand::Compile))
+            .then(|| compile::lua_loader(&user.compile)),
+        coverage: user
+            .coverage
+            .enabled
+            .then(|| coverage::lua_loader(&user.coverage, prefix)),
+        profile: user
+            .profile
+            .enabled()
+            .then(|| profile::lua_loader(&user.profile, prefix)),
     }
     .generate()
 }
@@ -122,9 +150,17 @@ pub(crate) fn package_cpath(dirs: &Vec<String>) -> Option<String> {
 #[derive(Debug)]
 struct LuaGenerator<'a> {
     file: &'a Option<String>,
+    /// The extracted entry script and its chunk name, when running a bundle.
+    bundle_entry: Option<(String, String)>,
     inline: &'a Vec<String>,
     lua_args: &'a Vec<String>,
     inline_filename: String,
+    stdin_filename: String,
+    repl: Option<Vec<String>>,
+    specs: Option<Vec<String>>,
+    compile: Option<Vec<String>>,
+    coverage: Option<Vec<String>>,
+    profile: Option<Vec<String>>,
     buf: Buf,
     arg_0: String,
     all_args_len: usize,
@@ -142,16 +178,49 @@ impl LuaGenerator<'_> {
         self.insert_inline_lua()?;
         self.buf.newline();
 
-        self.insert_code_for_lua_file();
+        self.insert_code_for_lua_file()?;
         self.buf.newline();
 
+        // the functions that run after the Lua file, in order
+        let mut runners = vec![];
+        for (comment, var, lines) in [
+            ("interactive repl", "repl_gen", self.repl.take()),
+            ("test runner", "spec_gen", self.specs.take()),
+            ("bytecode compiler", "compile_gen", self.compile.take()),
+        ] {
+            if self.insert_section(comment, lines) {
+                runners.push(var);
+            }
+        }
+
+        // the functions that wrap everything else, innermost first
+        let mut wrappers = vec![];
+        for (comment, var, lines) in [
+            ("code coverage", "cov_wrap", self.coverage.take()),
+            ("lua profiler", "prof_wrap", self.profile.take()),
+        ] {
+            if self.insert_section(comment, lines) {
+                wrappers.push(var);
+            }
+        }
+
         self.buf.append("gen = function()");
         self.buf.indent();
         self.buf.append("if inline_gen then inline_gen() end");
         self.buf.append("if file_gen then file_gen() end");
+        for var in runners {
+            self.buf.append(&format!("{var}()"));
+        }
         self.buf.dedent();
         self.buf.append("end");
 
+        if !wrappers.is_empty() {
+            self.buf.newline();
+        }
+        for var in wrappers {
+            self.buf.append(&format!("gen = {var}(gen)"));
+        }
+
         self.buf.dedent();
         self.buf.append("end");
 
@@ -206,37 +275,72 @@ impl LuaGenerator<'_> {
         let fname = self.inline_filename.clone();
         fs::write(&fname, contents)?;
 
-        self.insert_lua_file_loader(&fname, true);
+        self.insert_lua_file_loader(&fname, "inline", "=(command line -e)");
 
         Ok(())
     }
 
-    fn insert_code_for_lua_file(&mut self) {
+    fn insert_code_for_lua_file(&mut self) -> Result<(), std::io::Error> {
         self.buf.append("-- lua file");
-        if self.file.is_none() {
+        let Some(fname) = self.file.clone() else {
             self.buf.append("local file_gen");
-            return;
+            return Ok(());
+        };
+
+        if let Some((entry, chunk_name)) = self.bundle_entry.take() {
+            // `arg[0]` is still the bundle
+            self.insert_lua_file_loader(&entry, "file", &chunk_name);
+        } else if fname == STDIN_FILE {
+            // spool the program into the prefix so that it can be loaded
+            // just like any other file
+            let mut contents = Vec::new();
+            io::stdin().lock().read_to_end(&mut contents)?;
+
+            let spool = self.stdin_filename.clone();
+            fs::write(&spool, contents)?;
+
+            self.insert_lua_file_loader(&spool, "file", "=stdin");
+        } else {
+            self.insert_lua_file_loader(&fname, "file", &format!("@{}", fname));
         }
 
-        let fname = self.file.clone().unwrap();
-        self.insert_lua_file_loader(fname.as_str(), false);
+        Ok(())
     }
 
-    fn insert_lua_file_loader(&mut self, fname: &str, inline: bool) {
+    /// Insert the Lua code for an optional feature, and return whether it
+    /// is enabled.
+    fn insert_section(&mut self, comment: &str, lines: Option<Vec<String>>) -> bool {
+        let Some(lines) = lines else {
+            return false;
+        };
+
+        self.buf.append(&format!("-- {comment}"));
+        for line in lines {
+            if line.is_empty() {
+                self.buf.newline();
+            } else {
+                self.buf.append(&line);
+            }
+        }
+        self.buf.newline();
+
+        true
+    }
+
+    fn insert_lua_file_loader(&mut self, fname: &str, chunk_type: &str, chunk_name: &str) {
         self.buf
             .append(&format!("local fname = {}", fname.lua_quote()));
         self.buf.append(r#"local f = assert(io.open(fname, "r"))"#);
         self.buf.append(r#"local chunk = f:read("*a")"#);
 
-        let chunk_name = match inline {
-            true => "=(command line -e)".to_string(),
-            false => format!("@{}", fname),
-        };
-
-        let chunk_type = match inline {
-            true => "inline",
-            false => "file",
-        };
+        // loadstring() doesn't skip a `#!` line like loadfile() does, so
+        // blank it out, keeping the newline to preserve line numbers
+        self.buf.append(r##"if chunk:sub(1, 2) == "#!" then"##);
+        self.buf.indent();
+        self.buf
+            .append(r##"chunk = chunk:gsub("^#![^\n]*", "", 1)"##);
+        self.buf.dedent();
+        self.buf.append("end");
 
         self.buf.append(&format!(
             "local {}_gen = assert(loadstring(chunk, {}))",
@@ -270,6 +374,64 @@ mod tests {
         assert_eq!("[=[abc[[[def]=]", quote_lua_string("abc[[[def"));
     }
 
+    #[test]
+    fn test_optional_sections() {
+        let file = Some("main.lua".to_string());
+        let (inline, lua_args) = (vec![], vec![]);
+        let generate = |specs: Option<Vec<String>>, coverage: Option<Vec<String>>| {
+            LuaGenerator {
+                file: &file,
+                bundle_entry: None,
+                inline: &inline,
+                lua_args: &lua_args,
+                inline_filename: "a.lua".to_string(),
+                stdin_filename: "stdin.lua".to_string(),
+                repl: None,
+                specs,
+                compile: None,
+                coverage,
+                profile: None,
+                buf: Buf::new(),
+                arg_0: "rusty-cli".to_string(),
+                all_args_len: 2,
+            }
+            .generate()
+            .unwrap()
+            .iter()
+            .map(|line| line.trim().to_string())
+            .collect::<Vec<_>>()
+        };
+
+        let lines = generate(None, None);
+        for var in [
+            "repl_gen",
+            "spec_gen",
+            "compile_gen",
+            "cov_wrap",
+            "prof_wrap",
+        ] {
+            assert!(!lines.iter().any(|line| line.contains(var)), "{var}");
+        }
+
+        let lines = generate(
+            Some(vec!["local spec_gen = run_specs".to_string()]),
+            Some(vec!["local cov_wrap = wrap".to_string()]),
+        );
+        let pos = |line: &str| lines.iter().position(|l| l == line);
+        for line in [
+            "-- test runner",
+            "local spec_gen = run_specs",
+            "-- code coverage",
+            "local cov_wrap = wrap",
+            "spec_gen()",
+            "gen = cov_wrap(gen)",
+        ] {
+            assert!(pos(line).is_some(), "{line}: {lines:#?}");
+        }
+        assert!(pos("spec_gen()") > pos("if file_gen then file_gen() end"));
+        assert!(!lines.iter().any(|line| line.contains("compile_gen")));
+    }
+
     #[test]
     fn test_package_path() {
         assert_eq!(None, package_path(&vec![]));
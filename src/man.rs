@@ -0,0 +1,256 @@
+//! The man page, generated from the option table.
+
+use crate::compat_version::RESTY_COMPAT_VAR;
+use crate::config::{PROFILE_DIR, PROJECT_CONFIG, SYSTEM_CONFIG, USER_CONFIG};
+use crate::options::{self, Opt, Subcommand, ENV_PREFIX, OPTIONS};
+use crate::util::TMPDIR_VAR;
+use crate::VERSION;
+use std::fmt::Write as _;
+use std::str::FromStr;
+use strum::VariantNames;
+
+/// Escape text for roff.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for line in s.split_inclusive('\n') {
+        // a leading `.` or `'` would start a request
+        if line.starts_with(['.', '\'']) {
+            out.push_str("\\&");
+        }
+
+        for c in line.chars() {
+            match c {
+                '\\' => out.push_str("\\e"),
+                '-' => out.push_str("\\-"),
+                c => out.push(c),
+            }
+        }
+    }
+
+    out
+}
+
+fn bold(s: &str) -> String {
+    format!("\\fB{}\\fR", escape(s))
+}
+
+fn italic(s: &str) -> String {
+    format!("\\fI{}\\fR", escape(s))
+}
+
+/// The synopsis of an option, e.g. `-I DIR`.
+fn synopsis(opt: &Opt) -> String {
+    opt.names()
+        .map(|name| match opt.metavar {
+            Some(metavar) => format!("{} {}", bold(name), italic(metavar)),
+            None => bold(name),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn option(out: &mut String, opts: &[Opt]) {
+    let opt = &opts[0];
+
+    let names: Vec<String> = opts.iter().map(synopsis).collect();
+    let _ = writeln!(out, ".TP\n{}", names.join(", "));
+    let _ = writeln!(out, "{}", escape(opt.help));
+
+    let docs = opt.choice_docs();
+    if !docs.is_empty() {
+        out.push_str(".RS\n");
+        for (choice, doc) in docs {
+            let _ = writeln!(
+                out,
+                ".TP\n{} {}\n{}",
+                bold(opt.name),
+                bold(choice),
+                escape(&doc.replace('\n', " "))
+            );
+        }
+        out.push_str(".RE\n");
+    } else if !opt.choices.is_empty() {
+        let choices: Vec<String> = opt.choices.iter().map(|c| bold(c)).collect();
+        let _ = writeln!(out, ".IP\nPossible values: {}.", choices.join(", "));
+    }
+
+    if let Some(default) = opt.default {
+        let _ = writeln!(out, ".IP\nDefault: {}.", bold(default));
+    }
+
+    if let Some(since) = opt.since {
+        let _ = writeln!(
+            out,
+            ".IP\nOnly documented when {} is at least {}.",
+            bold(RESTY_COMPAT_VAR),
+            bold(&since.to_string()),
+        );
+    }
+}
+
+/// Generate the man page for `bin` (the name that rusty-cli was invoked as).
+pub(crate) fn generate(bin: &str) -> String {
+    let mut out = String::new();
+
+    let _ = write!(
+        out,
+        r#".TH {title} 1 "" "{name} {VERSION}" "User Commands"
+.SH NAME
+{name} \- run Lua scripts with OpenResty from the command line
+.SH SYNOPSIS
+{name_bold} [\fIOPTIONS\fR] [\fIlua\-file\fR] [\fIargs\fR...]
+.br
+{name_bold} \fBtest\fR [\fIOPTIONS\fR] [\fIPATH\fR...]
+.br
+{name_bold} \fBcompile\fR [\fIOPTIONS\fR] [\fIPATH\fR...]
+.br
+{name_bold} \fBbundle\fR [\fIOPTIONS\fR] \fIFILE\fR
+.br
+{name_bold} \fBbuild\-exe\fR [\fIOPTIONS\fR] \fIFILE\fR
+.SH DESCRIPTION
+{name_bold} runs Lua code inside a temporary nginx instance, the same way
+resty\-cli does. The code is taken from \fIlua\-file\fR (\fB\-\fR reads it from
+stdin) and \fB\-e\fR options, and \fIargs\fR are made available to it in the
+global \fBarg\fR table.
+.PP
+With no Lua code to run, an interactive prompt is started if stdin is a
+terminal, otherwise the script is read from stdin.
+.SH OPTIONS
+"#,
+        title = escape(&bin.to_uppercase()),
+        name = escape(bin),
+        name_bold = bold(bin),
+    );
+
+    for opts in options::documented() {
+        option(&mut out, opts);
+    }
+
+    out.push_str(".SH COMMANDS\n");
+
+    for name in Subcommand::VARIANTS {
+        let cmd = Subcommand::from_str(name).expect("valid subcommand");
+        let _ = writeln!(
+            out,
+            ".SS {} [{}] {}\n{}\nAll of the options above are accepted as well.",
+            bold(name),
+            italic("OPTIONS"),
+            escape(cmd.args()),
+            escape(cmd.about()),
+        );
+
+        for opt in cmd.options() {
+            option(&mut out, std::slice::from_ref(opt));
+        }
+    }
+
+    out.push_str(".SH ENVIRONMENT\n");
+
+    let _ = writeln!(
+        out,
+        ".TP\n{}\nThe resty\\-cli version to be compatible with, e.g. {}.",
+        bold(RESTY_COMPAT_VAR),
+        bold("0.30"),
+    );
+    let _ = writeln!(
+        out,
+        ".TP\n{}, {}\nThe default location of the temporary nginx prefix directory.",
+        bold(TMPDIR_VAR),
+        bold("TMPDIR"),
+    );
+
+    let vars: Vec<String> = OPTIONS
+        .iter()
+        .filter(|opt| opt.available())
+        .filter_map(Opt::env_var)
+        .map(|var| bold(&var))
+        .collect();
+    let _ = writeln!(
+        out,
+        ".TP\n{}\nSet a default for the option of the same name (e.g. {} for {}). \
+        They take precedence over config files, but not over profiles or the \
+        command line. The variables are: {}.",
+        bold(&format!("{ENV_PREFIX}*")),
+        bold(&format!("{ENV_PREFIX}NGINX")),
+        bold("--nginx"),
+        vars.join(", "),
+    );
+
+    let _ = writeln!(
+        out,
+        r#".SH FILES
+.TP
+{system}
+.TQ
+$XDG_CONFIG_HOME/{user} (\fI~/.config/{user}\fR)
+.TQ
+{project}
+System, user, and project config files, read in that order. Project config
+files are read from the current directory and each of its parents, starting
+from the outermost one. Each key is the name of a command line option without
+the leading dashes.
+.TP
+$XDG_CONFIG_HOME/{profiles}/\fINAME\fR.toml
+Profiles, applied with {profile}.
+.SH SEE ALSO
+\fBnginx\fR(8)"#,
+        system = italic(SYSTEM_CONFIG),
+        user = escape(USER_CONFIG),
+        project = italic(PROJECT_CONFIG),
+        profiles = escape(PROFILE_DIR),
+        profile = bold("--profile"),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(r"\-\-nginx \e", escape(r"--nginx \"));
+        assert_eq!("a\n\\&.b\n\\&'c", escape("a\n.b\n'c"));
+        assert_eq!(r"\fB\-I\fR \fIDIR\fR", synopsis(Opt::find("-I").unwrap()));
+    }
+
+    #[test]
+    fn man_page() {
+        let page = generate("rusty-cli");
+
+        assert!(page.starts_with(".TH RUSTY\\-CLI 1 "));
+
+        for opts in options::documented() {
+            for opt in opts {
+                assert!(page.contains(&synopsis(opt)), "{}", opt.name);
+            }
+        }
+
+        for expected in [
+            "\n.TP\n\\fB\\-c\\fR \\fINUM\\fR\nSet maximal connection count\n.IP\nDefault: \\fB64\\fR.\n",
+            "\n.RS\n.TP\n\\fB\\-j\\fR \\fBv\\fR\nUse LuaJIT's jit.v module to output brief info of the traces generated by the JIT compiler.\n",
+            "\n.TP\n\\fB\\-\\-rlimit\\-nofile\\fR \\fIN\\fR, \\fB\\-\\-rlimit\\-core\\fR \\fIN\\fR, ",
+            "Possible values: \\fBdebug\\fR, \\fBinfo\\fR, ",
+            "\\fBRUSTY_CLI_ERRLOG_LEVEL\\fR",
+            "\n.SS \\fBtest\\fR [\\fIOPTIONS\\fR] [PATH]...\nRun the *_spec.lua ",
+            "\n.TP\n\\fB\\-\\-junit\\fR \\fIFILE\\fR\nAlso write the results to FILE as JUnit XML.\n",
+        ] {
+            assert!(page.contains(expected), "{expected}\n{page}");
+        }
+
+        // the parser knows every option in the man page, and vice versa
+        for line in page.lines().filter(|line| line.starts_with("\\fB\\-")) {
+            let name = line[3..line.find("\\fR").unwrap()].replace("\\-", "-");
+            assert!(
+                Opt::find(&name)
+                    .or_else(|| Subcommand::Test.find_opt(&name))
+                    .or_else(|| Subcommand::Compile.find_opt(&name))
+                    .or_else(|| Subcommand::Bundle.find_opt(&name))
+                    .is_some(),
+                "{name}"
+            );
+        }
+    }
+}
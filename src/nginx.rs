@@ -1,10 +1,12 @@
-use crate::types::ArgError;
+use crate::types::{ArgError, Rlimit, ShellArgs};
 use crate::util::*;
 use crate::RESTY_COMPAT_VERSION;
 use crate::RUSTY_CLI;
 use crate::VERSION;
+use nix::sys::resource::{getrlimit, rlim_t, setrlimit, Resource, RLIM_INFINITY};
 use std::env;
 use std::io;
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -20,6 +22,7 @@ pub(crate) struct ConfBuilder {
     stream: Option<Vec<String>>,
     http: Option<Vec<String>>,
     lua: Option<Vec<String>>,
+    expand_table: bool,
 }
 
 impl ConfBuilder {
@@ -76,6 +79,13 @@ impl ConfBuilder {
         self
     }
 
+    /// Expose the formatter behind `ngx.say` to the Lua code as
+    /// `ngx.config.expand_table`, for the REPL and the test runner.
+    pub(crate) fn expand_table(mut self, enabled: bool) -> Self {
+        self.expand_table = enabled;
+        self
+    }
+
     pub(crate) fn render<T>(self, buf: T) -> io::Result<()>
     where
         T: io::Write,
@@ -94,6 +104,7 @@ impl From<ConfBuilder> for Conf {
             stream,
             http,
             lua,
+            expand_table,
         } = cb;
 
         Self {
@@ -104,6 +115,7 @@ impl From<ConfBuilder> for Conf {
             stream_enabled,
             http: http.unwrap_or_default(),
             lua: lua.unwrap_or_default(),
+            expand_table,
         }
     }
 }
@@ -116,6 +128,7 @@ struct Conf {
     stream: Vec<String>,
     http: Vec<String>,
     lua: Vec<String>,
+    expand_table: bool,
 }
 
 impl Conf {
@@ -369,6 +382,12 @@ impl Conf {
             writeln!(buf)?;
         }
 
+        // not in resty-cli's config, so only added when it is used
+        if self.expand_table {
+            writeln!(buf, "        ngx.config.expand_table = expand_table")?;
+            writeln!(buf)?;
+        }
+
         writeln!(buf, "{}", INIT_BY_LUA_CLOSE)?;
         writeln!(buf)?;
 
@@ -426,11 +445,17 @@ fn find_nginx_bin() -> PathBuf {
     PathBuf::from("nginx")
 }
 
+/// The nginx binary to run: `bin` if given, otherwise the default.
+pub(crate) fn nginx_bin(bin: Option<PathBuf>) -> PathBuf {
+    bin.unwrap_or_else(find_nginx_bin)
+}
+
 pub(crate) struct Exec {
     pub(crate) prefix: PathBuf,
     pub(crate) runner: Runner,
     pub(crate) bin: Option<PathBuf>,
     pub(crate) label: Option<String>,
+    pub(crate) rlimits: Vec<Rlimit>,
 }
 
 pub(crate) trait ArgList<T> {
@@ -473,13 +498,14 @@ impl From<Exec> for Command {
             runner,
             bin,
             label,
+            rlimits,
         } = exec;
 
         let prefix = prefix
             .to_str()
             .expect("nginx prefix directory should be a valid string");
 
-        let nginx = bin.unwrap_or_else(find_nginx_bin);
+        let nginx = nginx_bin(bin);
 
         let mut args = vec![];
 
@@ -493,23 +519,66 @@ impl From<Exec> for Command {
 
         args.arg(("-c", "conf/nginx.conf"));
 
-        runner.into_cmd(nginx, args)
+        let mut cmd = runner.into_cmd(nginx, args);
+        set_rlimits(&mut cmd, &rlimits);
+        cmd
     }
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
+/// Arrange for resource limits to be applied in the child process before it
+/// execs.
+///
+/// The soft limit is set to the requested value. The hard limit is only
+/// raised when the new soft limit exceeds it (which typically requires
+/// privileges), so lowering a limit never prevents raising it again later.
+fn set_rlimits(cmd: &mut Command, rlimits: &[Rlimit]) {
+    if rlimits.is_empty() {
+        return;
+    }
+
+    // compute everything up front so that the pre_exec hook only makes
+    // syscalls and doesn't allocate
+    let limits: Vec<(Resource, rlim_t, rlim_t)> = rlimits
+        .iter()
+        .map(|lim| {
+            let soft = lim.value.to_rlim();
+            let hard = match getrlimit(lim.resource) {
+                Ok((_, hard)) if hard == RLIM_INFINITY => hard,
+                Ok((_, hard)) if soft != RLIM_INFINITY && soft <= hard => hard,
+                _ => soft,
+            };
+
+            (lim.resource, soft, hard)
+        })
+        .collect();
+
+    // SAFETY: setrlimit(2) is async-signal-safe, and the closure only reads
+    // from memory that was allocated before fork(2).
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, soft, hard) in limits.iter() {
+                setrlimit(*resource, *soft, *hard)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub(crate) enum Runner {
     #[default]
     Default,
     RR,
-    Stap(Option<String>),
-    Valgrind(Option<String>),
-    Gdb(Option<String>),
-    User(String),
+    Stap(Option<ShellArgs>),
+    Valgrind(Option<ShellArgs>),
+    Gdb(Option<ShellArgs>),
+
+    /// The command and its arguments; never empty.
+    User(ShellArgs),
 }
 
 impl Runner {
-    fn arg_name(&self) -> String {
+    pub(crate) fn arg_name(&self) -> String {
         match self {
             Self::RR => "--rr",
             Self::Stap(_) => "--stap",
@@ -582,7 +651,7 @@ impl Runner {
                 cmd = Command::new("stap");
 
                 if let Some(opts) = opts {
-                    cmd.args(opts.split_shell_args());
+                    cmd.args(opts.0);
                 }
 
                 cmd.arg("-c");
@@ -596,7 +665,7 @@ impl Runner {
                 cmd = Command::new("valgrind");
 
                 if let Some(opts) = opts {
-                    cmd.args(opts.split_shell_args());
+                    cmd.args(opts.0);
                 }
 
                 cmd.arg(nginx).args(args);
@@ -605,13 +674,13 @@ impl Runner {
                 cmd = Command::new("gdb");
 
                 if let Some(opts) = opts {
-                    cmd.args(opts.split_shell_args());
+                    cmd.args(opts.0);
                 }
 
                 cmd.arg("--args").arg(nginx).args(args);
             }
             Runner::User(runner) => {
-                let mut user_args = runner.split_shell_args();
+                let mut user_args = runner.0;
 
                 cmd = Command::new(user_args.remove(0));
 
@@ -623,11 +692,7 @@ impl Runner {
 }
 
 pub(crate) fn version(nginx: Option<PathBuf>) -> Command {
-    let mut cmd = if let Some(nginx) = nginx {
-        Command::new(nginx)
-    } else {
-        Command::new(find_nginx_bin())
-    };
+    let mut cmd = Command::new(nginx_bin(nginx));
 
     cmd.arg("-V");
     cmd
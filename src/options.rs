@@ -0,0 +1,735 @@
+//! Metadata about the command line options that rusty-cli understands.
+//!
+//! The argument parser in [`crate::cli`] does the real work; this table
+//! describes each option so that other sources of options (like config
+//! files) can be translated into command line arguments, and so that shell
+//! completions can be generated.
+
+use crate::compat_version::Version;
+use crate::completions::Shell;
+use crate::types::{JitModule, LogLevel};
+use crate::util::TMPDIR_VAR;
+use crate::RESTY_COMPAT_VERSION;
+use strum::VariantNames;
+
+/// The kind of value that an option takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Value {
+    /// The option is a flag and takes no value.
+    None,
+
+    /// Any string.
+    Text,
+
+    /// A file.
+    Path,
+
+    /// A directory.
+    Dir,
+
+    /// A program, which is looked up in `$PATH` unless it contains a `/`.
+    Program,
+}
+
+#[derive(Debug)]
+pub(crate) struct Opt {
+    /// The canonical name of the option, as given on the command line.
+    pub(crate) name: &'static str,
+
+    /// Other names for the option.
+    pub(crate) aliases: &'static [&'static str],
+
+    /// What the value is called in help text, if the option takes one.
+    pub(crate) metavar: Option<&'static str>,
+
+    pub(crate) value: Value,
+
+    /// The possible values, if they are limited.
+    pub(crate) choices: &'static [&'static str],
+
+    /// Looks up the description of each of the `choices`.
+    pub(crate) choice_help: Option<fn(&str) -> Option<&'static str>>,
+
+    /// The value that is used when the option is not given, for help text.
+    pub(crate) default: Option<&'static str>,
+
+    pub(crate) help: &'static str,
+
+    /// The resty-cli version that introduced the option, if it is gated on
+    /// `RESTY_CLI_COMPAT_VERSION`.
+    pub(crate) since: Option<Version>,
+
+    /// The name used for the option in config files, if it can be set there.
+    pub(crate) key: Option<&'static str>,
+
+    /// Whether each use of the option adds to a list rather than replacing
+    /// the previous value.
+    pub(crate) repeat: bool,
+
+    /// Options in the same group cannot be combined, so setting one of them
+    /// replaces any of the others.
+    pub(crate) group: Option<&'static str>,
+
+    /// The separator for multiple values of a repeatable option in its
+    /// environment variable. Without one, the variable holds a single value.
+    pub(crate) env_sep: Option<char>,
+
+    /// Whether a `.rustyrc` may set the option. Only options that can't
+    /// load code, run programs, or choose where files are written qualify.
+    pub(crate) project: bool,
+}
+
+// option names are unique
+impl PartialEq for Opt {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Opt {}
+
+impl Opt {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            metavar: None,
+            value: Value::None,
+            choices: &[],
+            choice_help: None,
+            default: None,
+            help,
+            since: None,
+            key: None,
+            repeat: false,
+            group: None,
+            env_sep: None,
+            project: false,
+        }
+    }
+
+    const fn aliases(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    const fn arg(mut self, metavar: &'static str, value: Value) -> Self {
+        self.metavar = Some(metavar);
+        self.value = value;
+        self
+    }
+
+    const fn choices(mut self, choices: &'static [&'static str]) -> Self {
+        self.choices = choices;
+        self
+    }
+
+    const fn choice_help(mut self, help: fn(&str) -> Option<&'static str>) -> Self {
+        self.choice_help = Some(help);
+        self
+    }
+
+    const fn default(mut self, default: &'static str) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    const fn since(mut self, maj: u16, min: u16) -> Self {
+        self.since = Some(Version::new(maj, min));
+        self
+    }
+
+    const fn key(mut self, key: &'static str) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    const fn repeat(mut self) -> Self {
+        self.repeat = true;
+        self
+    }
+
+    const fn group(mut self, group: &'static str) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    const fn env_sep(mut self, sep: char) -> Self {
+        self.env_sep = Some(sep);
+        self
+    }
+
+    const fn project(mut self) -> Self {
+        self.project = true;
+        self
+    }
+
+    pub(crate) fn takes_value(&self) -> bool {
+        self.value != Value::None
+    }
+
+    /// Whether the option exists for the current `RESTY_CLI_COMPAT_VERSION`.
+    ///
+    /// This only decides what `--help`, `--man` and `--completions` show; the
+    /// parser accepts every option regardless of the version.
+    pub(crate) fn available(&self) -> bool {
+        self.since
+            .is_none_or(|since| *RESTY_COMPAT_VERSION >= since)
+    }
+
+    /// All names of the option, starting with the canonical one.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &'static str> {
+        std::iter::once(self.name).chain(self.aliases.iter().copied())
+    }
+
+    /// The first sentence of the help text.
+    pub(crate) fn summary(&self) -> &'static str {
+        let help = self.help;
+        let end = help.find(". ").map_or(help.len(), |i| i + 1);
+        help[..end].trim_end_matches('.')
+    }
+
+    /// The description of each of the possible values, in order.
+    pub(crate) fn choice_docs(&self) -> Vec<(&'static str, &'static str)> {
+        let Some(help) = self.choice_help else {
+            return vec![];
+        };
+
+        self.choices
+            .iter()
+            .filter_map(|choice| Some((*choice, help(choice)?.trim_end())))
+            .collect()
+    }
+
+    /// Whether the option only prints information (help, version, etc.)
+    /// rather than running anything.
+    pub(crate) fn is_info(&self) -> bool {
+        matches!(self.name, "-h" | "-V" | "--man" | "--completions")
+    }
+
+    /// Find an option by any of its names.
+    pub(crate) fn find(name: &str) -> Option<&'static Opt> {
+        OPTIONS.iter().find(|opt| opt.names().any(|n| n == name))
+    }
+
+    /// Find an option by its config file key.
+    ///
+    /// Underscores are accepted in place of dashes.
+    pub(crate) fn find_key(key: &str) -> Option<&'static Opt> {
+        let key = key.replace('_', "-");
+        OPTIONS.iter().find(|opt| opt.key == Some(key.as_str()))
+    }
+
+    /// The environment variable that sets a default for the option, if any.
+    pub(crate) fn env_var(&self) -> Option<String> {
+        let key = self.key?;
+        let var = format!("{ENV_PREFIX}{}", key.to_uppercase().replace('-', "_"));
+
+        // this already sets the default for `--tmpdir`, with a fallback
+        // to `$TMPDIR`
+        (var != TMPDIR_VAR).then_some(var)
+    }
+
+    /// Whether setting `other` replaces this option.
+    pub(crate) fn overridden_by(&self, other: &Opt) -> bool {
+        if self.repeat {
+            return false;
+        }
+
+        self.name == other.name || (self.group.is_some() && self.group == other.group)
+    }
+}
+
+/// The prefix for environment variables that set option defaults.
+pub(crate) const ENV_PREFIX: &str = "RUSTY_CLI_";
+
+const RUNNER: &str = "runner";
+const KEEP_PREFIX: &str = "keep-prefix";
+const PREFIX: &str = "prefix";
+
+const RLIMIT_HELP: &str = "Set the corresponding resource limit (see setrlimit(2)) for the nginx process. N is a number with an optional k/m/g suffix, or `unlimited`.";
+
+use Value::{Dir, Path, Program, Text};
+
+#[rustfmt::skip]
+pub(crate) static OPTIONS: &[Opt] = &[
+    Opt::new("-V", "Print version numbers and nginx configurations.")
+        .aliases(&["-v", "--version"]),
+    Opt::new("-I", "Add dir to the search paths for Lua libraries.")
+        .arg("DIR", Dir).key("include").repeat().env_sep(':'),
+    Opt::new("-e", "Run the inlined Lua code in \"prog\".")
+        .arg("PROG", Text).repeat(),
+    Opt::new("-l", "require lua library \"lib\"")
+        .arg("LIB", Text).key("require").repeat().env_sep(';'),
+    Opt::new("-j", "LuaJIT option. Takes arguments like luajit's -j (e.g. -j dump=+rsx,out.txt or -j v=trace.log), and may be given more than once.")
+        .arg("CMD", Text).choices(JitModule::VARIANTS).choice_help(JitModule::help).key("jit").repeat(),
+    Opt::new("-O", "Set LuaJIT optimizations like luajit's -O: a level (0-3), +FLAG or -FLAG, or PARAM=VALUE, separated by commas. May be given more than once.")
+        .arg("OPT", Text).key("jit-opt").repeat().project(),
+    Opt::new("-c", "Set maximal connection count")
+        .arg("NUM", Text).default("64").key("worker-connections").project(),
+    Opt::new("--ns", "Specify a custom name server (multiple instances are supported).")
+        .arg("IP", Text).key("ns").repeat().env_sep(';').project(),
+    Opt::new("--shdict", "Create the specified lua shared dicts in the http configuration block (multiple instances are supported).")
+        .arg("NAME SIZE", Text).key("shdict").repeat().env_sep(';').project(),
+    Opt::new("--nginx", "Specify the nginx path (this option might be removed in the future).")
+        .arg("PATH", Program).key("nginx"),
+    Opt::new("--http-conf", "Specifies nginx.conf snippet inserted into the http {} configuration block (multiple instances are supported).")
+        .arg("CONF", Text).key("http-conf").repeat(),
+    Opt::new("--stream-conf", "Specifies nginx.conf snippet inserted into the nginx stream {} configuration block (multiple instances are supported).")
+        .arg("CONF", Text).key("stream-conf").repeat(),
+    Opt::new("--load-module", "Load the specified nginx module. (multiple instances are supported).")
+        .arg("MOD", Text).since(0, 31).key("load-module").repeat().env_sep(';'),
+    Opt::new("--main-conf", "Specifies nginx.conf snippet inserted into the nginx main {} configuration block (multiple instances are supported).")
+        .arg("CONF", Text).key("main-conf").repeat(),
+    Opt::new("--http-include", "Include the specified file in the nginx http configuration block (multiple instances are supported).")
+        .arg("PATH", Path).key("http-include").repeat().env_sep(':'),
+    Opt::new("--main-include", "Include the specified file in the nginx main configuration block (multiple instances are supported).")
+        .arg("PATH", Path).key("main-include").repeat().env_sep(':'),
+    Opt::new("--dump-nginx-conf", "Print the generated nginx configuration file instead of running nginx.")
+        .since(0, 32),
+    Opt::new("--valgrind", "Use valgrind to run nginx.")
+        .key("valgrind").group(RUNNER),
+    Opt::new("--valgrind-opts", "Pass extra options to valgrind.")
+        .arg("OPTS", Text).key("valgrind-opts").group(RUNNER),
+    Opt::new("--errlog-level", "Set nginx error_log level.")
+        .arg("LEVEL", Text).choices(LogLevel::VARIANTS).key("errlog-level").project(),
+    Opt::new("--resolve-ipv6", "Make the nginx resolver lookup both IPv4 and IPv6 addresses.")
+        .key("resolve-ipv6").project(),
+    Opt::new("--user-runner", "Use CMD as user runner for the underlying nginx process.")
+        .arg("user-runner", Text).key("user-runner").group(RUNNER),
+    Opt::new("--stap", "Use sysetmtap to run the underlying nginx C process.")
+        .key("stap").group(RUNNER),
+    Opt::new("--stap-opts", "Pass extra systemtap command line options.")
+        .arg("stap-opts", Text).key("stap-opts").group(RUNNER),
+    Opt::new("--gdb", "Use GDB to run the underlying nginx C process.")
+        .key("gdb").group(RUNNER),
+    Opt::new("--gdb-opts", "Pass extra command-line options to GDB.")
+        .arg("gdb-opts", Text).key("gdb-opts").group(RUNNER),
+    Opt::new("--no-stream", "Disable the stream {} configuration in auto-generated nginx.conf.")
+        .key("no-stream").project(),
+    Opt::new("--rr", "Use Mozilla rr to record the execution of the underlying nginx C process.")
+        .key("rr").group(RUNNER),
+    Opt::new("--prefix", "Use DIR as the nginx prefix directory instead of a temporary directory. The directory is created if needed and is not removed on exit.")
+        .arg("DIR", Dir).key("prefix").group(PREFIX),
+    Opt::new("--tmpdir", "Create the temporary nginx prefix directory inside DIR (default: $RUSTY_CLI_TMPDIR, $TMPDIR, or /tmp).")
+        .arg("DIR", Dir).key("tmpdir").group(PREFIX),
+    Opt::new("--keep-prefix", "Do not remove the temporary nginx prefix directory on exit.")
+        .key("keep-prefix").group(KEEP_PREFIX),
+    Opt::new("--keep-prefix-on-error", "Do not remove the temporary nginx prefix directory if nginx exits with a non-zero status.")
+        .key("keep-prefix-on-error").group(KEEP_PREFIX),
+    Opt::new("--timeout", "Stop nginx if it is still running after SECS seconds (fractions are allowed). nginx is sent SIGQUIT, then SIGKILL after the grace period, and the exit code is 124. As with timeout(1), 0 means no timeout.")
+        .arg("SECS", Text).key("timeout").project(),
+    Opt::new("--timeout-grace", "How long to wait after SIGQUIT before sending SIGKILL when --timeout elapses")
+        .arg("SECS", Text).default("5").key("timeout-grace").project(),
+    Opt::new("--rlimit-nofile", RLIMIT_HELP).arg("N", Text).key("rlimit-nofile"),
+    Opt::new("--rlimit-core", RLIMIT_HELP).arg("N", Text).key("rlimit-core"),
+    Opt::new("--rlimit-as", RLIMIT_HELP).arg("N", Text).key("rlimit-as"),
+    Opt::new("--rlimit-data", RLIMIT_HELP).arg("N", Text).key("rlimit-data"),
+    Opt::new("--rlimit-stack", RLIMIT_HELP).arg("N", Text).key("rlimit-stack"),
+    Opt::new("--rlimit-cpu", RLIMIT_HELP).arg("N", Text).key("rlimit-cpu"),
+    Opt::new("--rlimit-nproc", RLIMIT_HELP).arg("N", Text).key("rlimit-nproc"),
+    Opt::new("--backtrace", "If nginx crashes and dumps core, print a backtrace of the core with gdb (including the Lua backtrace when openresty-gdb-utils is loaded). Raises RLIMIT_CORE unless --rlimit-core is given.")
+        .key("backtrace"),
+    Opt::new("--save-core", "If nginx crashes and dumps core, copy the core file into DIR. Raises RLIMIT_CORE unless --rlimit-core is given.")
+        .arg("DIR", Dir).key("save-core"),
+    Opt::new("--report-json", "Write a JSON report of the run (nginx command, timings, exit status, signals, and the tail of the error log) to FILE.")
+        .arg("FILE", Path).key("report-json"),
+    Opt::new("--coverage", "Count the lines of Lua code run from files, and write luacov.stats.out and lcov.info when nginx exits.")
+        .key("coverage"),
+    Opt::new("--coverage-dir", "Write the --coverage reports into DIR instead of the current directory.")
+        .arg("DIR", Dir).key("coverage-dir"),
+    Opt::new("--coverage-include", "Only count files whose names match the Lua PATTERN. May be given more than once.")
+        .arg("PATTERN", Text).key("coverage-include").repeat().env_sep(';'),
+    Opt::new("--coverage-exclude", "Do not count files whose names match the Lua PATTERN. May be given more than once.")
+        .arg("PATTERN", Text).key("coverage-exclude").repeat().env_sep(';'),
+    Opt::new("--lua-profile", "Sample the running Lua code with LuaJIT's profiler and write the stacks to FILE when nginx exits: as an SVG flame graph if FILE ends in .svg, and as folded stacks otherwise.")
+        .arg("FILE", Path).key("lua-profile"),
+    Opt::new("--lua-profile-interval", "Take a --lua-profile sample every MS milliseconds.")
+        .arg("MS", Text).default("10").key("lua-profile-interval"),
+    Opt::new("--lua-profile-depth", "Keep at most N stack frames for each --lua-profile sample.")
+        .arg("N", Text).default("64").key("lua-profile-depth"),
+    Opt::new("--watch", "Rerun whenever the Lua file, a file in one of the -I directories, or an --http-include/--main-include file changes.")
+        .key("watch"),
+    Opt::new("--parallel", "Run each of the Lua files given (instead of one Lua file and its arguments) in its own nginx instance, with up to N of them running at once. Output lines are tagged with the file name, and a summary of exit codes and durations is printed at the end.")
+        .arg("N", Text),
+    Opt::new("--relative-include", "Resolve relative -I directories from the command line (but not from config files, environment variables, or directives) against the directory of the Lua file instead of the current directory. Useful in a script's #! line.")
+        .key("relative-include"),
+    Opt::new("--no-config", "Do not read options from config files (/etc/rusty-cli/config.toml, ~/.config/rusty-cli/config.toml, and .rustyrc in the current directory or its parents, up to the repository root or home directory) or RUSTY_CLI_* environment variables."),
+    Opt::new("--no-directives", "Ignore the `--! key: value` directives at the top of the Lua file."),
+    Opt::new("--print-config", "Print the options merged from all config files, environment variables, and profiles, and exit."),
+    Opt::new("--profile", "Apply the options from a profile (~/.config/rusty-cli/profiles/NAME.toml). May be given more than once; later profiles take precedence.")
+        .arg("NAME", Text).repeat(),
+    Opt::new("--completions", "Print a completion script for SHELL and exit.")
+        .arg("SHELL", Text).choices(Shell::VARIANTS),
+    Opt::new("--man", "Print a man page in roff format and exit."),
+    Opt::new("--repl", "Start an interactive Lua prompt (the default when no Lua input is given and stdin is a terminal)."),
+    Opt::new("-h", "Print help (see more with '--help')")
+        .aliases(&["--help"]),
+];
+
+/// A subcommand, given as the first argument in place of a Lua file.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    strum_macros::Display,
+    strum_macros::EnumString,
+    strum_macros::VariantNames,
+    PartialEq,
+    Eq,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub(crate) enum Subcommand {
+    Test,
+    Compile,
+    Bundle,
+    BuildExe,
+}
+
+#[rustfmt::skip]
+static TEST_OPTIONS: &[Opt] = &[
+    Opt::new("--filter", "Only run the tests whose full name (the names of the enclosing describe blocks and of the test, separated by spaces) matches the Lua PATTERN.")
+        .arg("PATTERN", Text),
+    Opt::new("--junit", "Also write the results to FILE as JUnit XML.")
+        .arg("FILE", Path),
+    Opt::new("--single-instance", "Run all test files in one nginx instance instead of starting one for each file."),
+];
+
+#[rustfmt::skip]
+static COMPILE_OPTIONS: &[Opt] = &[
+    Opt::new("--strip", "Leave debug info (line numbers and variable names) out of the bytecode. Smaller and faster to load, but errors only show the file name."),
+    Opt::new("--force", "Compile every file, even if its .ljbc file is newer than the .lua file."),
+];
+
+#[rustfmt::skip]
+static BUNDLE_OPTIONS: &[Opt] = &[
+    Opt::new("--output", "Write to FILE instead of a file named after the Lua file in the current directory.")
+        .aliases(&["-o"]).arg("FILE", Path),
+    Opt::new("--module", "Also bundle the module NAME (and the modules that it requires), for modules that aren't loaded with a literal require \"NAME\" call.")
+        .arg("NAME", Text).repeat(),
+];
+
+impl Subcommand {
+    /// Find the subcommand at the start of a command line (not including
+    /// the program name).
+    ///
+    /// A Lua file that happens to be named like a subcommand (`rusty-cli
+    /// test`) is run as before, so the name is only a subcommand when no such
+    /// file exists. A directory of the same name (like `test/`) doesn't count.
+    pub(crate) fn find<S: AsRef<str>>(args: &[S]) -> Option<Self> {
+        let arg = args.first()?.as_ref();
+        let command = arg.parse().ok()?;
+        (!std::path::Path::new(arg).is_file()).then_some(command)
+    }
+
+    /// What the subcommand does, for help text.
+    pub(crate) fn about(self) -> &'static str {
+        match self {
+            Self::Test => "Run the *_spec.lua and *_test.lua files found in each PATH (the current directory by default), and report the results as TAP.",
+            Self::Compile => "Compile the *.lua files found in each PATH (the current directory by default) to LuaJIT bytecode with the LuaJIT in nginx, writing each foo.lua to foo.ljbc. Files whose .ljbc file is newer are skipped.",
+            Self::BuildExe => "Build a self-contained executable from the Lua FILE: a copy of this program with the script, the modules that it requires from the -I directories, and the other options given appended to it. Running the executable runs the script with those options, passing all arguments to the script.",
+            Self::Bundle => "Bundle the Lua FILE, the modules that it requires from the -I directories, and its --shdict and --*-conf options into a single .rusty file that can be run like a Lua file.",
+        }
+    }
+
+    /// The arguments that the subcommand takes, for help text.
+    pub(crate) fn args(self) -> &'static str {
+        match self {
+            Self::Test | Self::Compile => "[PATH]...",
+            Self::Bundle | Self::BuildExe => "FILE",
+        }
+    }
+
+    /// The options that only the subcommand accepts. All other options are
+    /// accepted as well.
+    pub(crate) fn options(self) -> &'static [Opt] {
+        match self {
+            Self::Test => TEST_OPTIONS,
+            Self::Compile => COMPILE_OPTIONS,
+            Self::Bundle | Self::BuildExe => BUNDLE_OPTIONS,
+        }
+    }
+
+    /// Find one of the subcommand's own options by any of its names.
+    pub(crate) fn find_opt(self, name: &str) -> Option<&'static Opt> {
+        self.options()
+            .iter()
+            .find(|opt| opt.names().any(|n| n == name))
+    }
+}
+
+/// The options to document for the current `RESTY_CLI_COMPAT_VERSION`.
+///
+/// Adjacent options that share their help text (like the `--rlimit-*`
+/// options) are documented together.
+pub(crate) fn documented() -> impl Iterator<Item = &'static [Opt]> {
+    OPTIONS
+        .chunk_by(|a, b| a.help == b.help)
+        .filter(|opts| opts[0].available())
+}
+
+/// Split an option from the value attached to it, if any: `--opt=value`, or
+/// the way luajit takes them: `-jv`, `-O3`, `-Ohotloop=5`.
+///
+/// The parser splits options the same way, so that scanning the command line
+/// ahead of it agrees with what it parses.
+pub(crate) fn split_attached(arg: &str) -> (&str, Option<&str>) {
+    let luajit = ["-j", "-O"]
+        .into_iter()
+        .find(|opt| arg.len() > 2 && arg.starts_with(opt) && !arg[2..].starts_with('='));
+
+    match (luajit, arg.split_once('=')) {
+        (Some(opt), _) => (opt, Some(&arg[2..])),
+        (None, Some((name, value))) => (name, Some(value)),
+        (None, None) => (arg, None),
+    }
+}
+
+/// Find the options (and their values) given in a list of command line
+/// arguments, not including the program name.
+///
+/// Scanning stops at the Lua file or `--`, since everything after that
+/// belongs to the Lua script. Unknown options are skipped; reporting them is
+/// left to the parser.
+pub(crate) fn given<S: AsRef<str>>(args: &[S]) -> Vec<(&'static Opt, Option<&str>)> {
+    let command = Subcommand::find(args);
+
+    let mut found = vec![];
+    let mut args = args
+        .iter()
+        .map(AsRef::as_ref)
+        .skip(usize::from(command.is_some()));
+
+    while let Some(arg) = args.next() {
+        if arg == "--" || arg == "-" || !arg.starts_with('-') {
+            // subcommands take arguments in between their options
+            if command.is_some() && arg != "--" {
+                continue;
+            }
+            break;
+        }
+
+        let (name, value) = split_attached(arg);
+
+        if let Some(opt) = Opt::find(name) {
+            let value = match value {
+                None if opt.takes_value() => args.next(),
+                value => value,
+            };
+            found.push((opt, value));
+        } else if let Some(opt) = command.and_then(|command| command.find_opt(name)) {
+            if value.is_none() && opt.takes_value() {
+                args.next();
+            }
+        }
+    }
+
+    found
+}
+
+/// Find the Lua file in a list of command line arguments, not including the
+/// program name.
+///
+/// Returns `None` when the Lua file is read from stdin, for a subcommand, or
+/// when running several files with `--parallel`.
+pub(crate) fn lua_file<S: AsRef<str>>(args: &[S]) -> Option<&str> {
+    if Subcommand::find(args).is_some()
+        || given(args).iter().any(|(opt, _)| opt.name == "--parallel")
+    {
+        return None;
+    }
+
+    let mut args = args.iter().map(AsRef::as_ref);
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--" => return args.next().filter(|arg| *arg != "-"),
+            "-" => return None,
+            arg if !arg.starts_with('-') => return Some(arg),
+            arg => {
+                let (name, value) = split_attached(arg);
+                if value.is_none() && Opt::find(name).is_some_and(Opt::takes_value) {
+                    args.next();
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RLIMIT_OPTS;
+
+    #[test]
+    fn option_table() {
+        for (name, _) in RLIMIT_OPTS {
+            assert!(Opt::find(name).is_some(), "{name}");
+        }
+
+        for opt in OPTIONS {
+            if let Some(key) = opt.key {
+                assert_eq!(Some(opt), Opt::find_key(key));
+            }
+        }
+
+        assert_eq!("-V", Opt::find("--version").unwrap().name);
+        assert_eq!("-I", Opt::find_key("include").unwrap().name);
+        assert_eq!("-c", Opt::find_key("worker_connections").unwrap().name);
+        assert_eq!(None, Opt::find_key("eval"));
+    }
+
+    #[test]
+    fn env_vars() {
+        let var = |name| Opt::find(name).unwrap().env_var();
+
+        assert_eq!(Some("RUSTY_CLI_INCLUDE".into()), var("-I"));
+        assert_eq!(Some("RUSTY_CLI_ERRLOG_LEVEL".into()), var("--errlog-level"));
+        assert_eq!(None, var("-e"));
+        assert_eq!(None, var("--tmpdir"));
+
+        for opt in OPTIONS {
+            assert!(opt.env_sep.is_none() || opt.repeat, "{}", opt.name);
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let opt = |name| Opt::find(name).unwrap();
+
+        assert!(opt("--nginx").overridden_by(opt("--nginx")));
+        assert!(opt("--gdb").overridden_by(opt("--valgrind-opts")));
+        assert!(opt("--tmpdir").overridden_by(opt("--prefix")));
+        assert!(!opt("--nginx").overridden_by(opt("--prefix")));
+        assert!(!opt("-I").overridden_by(opt("-I")));
+    }
+
+    #[test]
+    fn project_options() {
+        let opt = |name| Opt::find(name).unwrap();
+
+        assert!(opt("--shdict").project);
+        assert!(opt("--ns").project);
+        assert!(opt("-c").project);
+        assert!(!opt("--nginx").project);
+        assert!(!opt("--user-runner").project);
+        assert!(!opt("--http-conf").project);
+        assert!(!opt("-I").project);
+        assert!(!opt("-l").project);
+        assert!(!opt("--report-json").project);
+        assert!(!opt("--prefix").project);
+
+        for opt in OPTIONS {
+            assert!(!opt.project || opt.key.is_some(), "{}", opt.name);
+        }
+    }
+
+    #[test]
+    fn given_options() {
+        let names = |args: &[&str]| -> Vec<&str> {
+            given(args).into_iter().map(|(opt, _)| opt.name).collect()
+        };
+
+        // `--no-stream` is the value for `--nginx` the first time around
+        assert_eq!(
+            vec!["--nginx", "-I", "--no-stream", "-c"],
+            names(&["--nginx", "--no-stream", "-I=lib", "--no-stream", "-c", "1"])
+        );
+
+        assert_eq!(
+            vec!["-e", "--gdb"],
+            names(&["-e", "--nginx", "--bogus", "--gdb", "x.lua", "--prefix", "p"])
+        );
+
+        assert_eq!(vec!["--no-config"], names(&["--no-config", "--", "--gdb"]));
+        assert_eq!(
+            vec![
+                (Opt::find("-I").unwrap(), Some("a")),
+                (Opt::find("--profile").unwrap(), Some("b"))
+            ],
+            given(&["-I=a", "--profile", "b", "--gdb=x"])[..2]
+        );
+        assert_eq!(Vec::<&str>::new(), names(&["-", "--gdb"]));
+
+        assert_eq!(
+            vec!["-I", "--profile"],
+            names(&[
+                "test",
+                "-I",
+                "lib",
+                "spec",
+                "--filter",
+                "-c",
+                "--profile",
+                "ci"
+            ])
+        );
+        assert_eq!(vec!["-I"], names(&["test", "-I", "lib", "--", "--gdb"]));
+
+        assert_eq!(
+            vec![
+                (Opt::find("-O").unwrap(), Some("3")),
+                (Opt::find("-j").unwrap(), Some("v")),
+                (Opt::find("-O").unwrap(), Some("hotloop=5")),
+                (Opt::find("-j").unwrap(), Some("off")),
+            ],
+            given(&["-O3", "-jv", "-Ohotloop=5", "-j=off", "a.lua"])
+        );
+    }
+
+    #[test]
+    fn attached_values() {
+        assert_eq!(("--nginx", Some("a=b")), split_attached("--nginx=a=b"));
+        assert_eq!(("-O", Some("hotloop=5")), split_attached("-Ohotloop=5"));
+        assert_eq!(("-O", Some("3")), split_attached("-O=3"));
+        assert_eq!(
+            ("-j", Some("dump=+rs,out.txt")),
+            split_attached("-jdump=+rs,out.txt")
+        );
+        assert_eq!(("-j", None), split_attached("-j"));
+        assert_eq!(("--jit", None), split_attached("--jit"));
+    }
+
+    #[test]
+    fn subcommands() {
+        assert_eq!(Some(Subcommand::Test), Subcommand::find(&["test", "spec"]));
+        assert_eq!(None, Subcommand::find(&["test.lua"]));
+        assert_eq!(None, Subcommand::find(&["-I", "lib", "test"]));
+        assert_eq!(None, Subcommand::find::<&str>(&[]));
+
+        for command in [
+            Subcommand::Test,
+            Subcommand::Compile,
+            Subcommand::Bundle,
+            Subcommand::BuildExe,
+        ] {
+            for opt in command.options() {
+                assert_eq!(None, Opt::find(opt.name), "{}", opt.name);
+                assert_eq!(None, opt.key, "{}", opt.name);
+                assert_eq!(Some(opt), command.find_opt(opt.name));
+            }
+        }
+    }
+
+    #[test]
+    fn find_lua_file() {
+        assert_eq!(Some("a.lua"), lua_file(&["-I", "lib", "a.lua", "b.lua"]));
+        assert_eq!(
+            Some("a.lua"),
+            lua_file(&["--shdict=x 1m", "--gdb", "a.lua"])
+        );
+        assert_eq!(Some("-a.lua"), lua_file(&["--no-stream", "--", "-a.lua"]));
+        assert_eq!(Some("a.lua"), lua_file(&["--bogus", "a.lua"]));
+        assert_eq!(None, lua_file(&["-e", "a.lua"]));
+        assert_eq!(None, lua_file(&["-", "a.lua"]));
+        assert_eq!(None, lua_file(&["--", "-"]));
+        assert_eq!(None, lua_file::<&str>(&[]));
+        assert_eq!(None, lua_file(&["test", "a_spec.lua"]));
+        assert_eq!(Some("test"), lua_file(&["--", "test"]));
+        assert_eq!(None, lua_file(&["--parallel", "2", "a.lua", "b.lua"]));
+        assert_eq!(Some("a.lua"), lua_file(&["-O3", "-jv", "a.lua"]));
+        assert_eq!(Some("a.lua"), lua_file(&["-O", "3", "a.lua"]));
+    }
+}
@@ -0,0 +1,401 @@
+//! The LuaJIT sampling profiler for `--lua-profile` and `-j p`.
+//!
+//! Like `--coverage`, the generated init_worker code wraps `gen`: it starts
+//! `jit.profile` before the Lua code runs, counts the sampled stacks, and
+//! writes them to a file in the prefix directory when the code is done, or
+//! when `os.exit` or `ngx.exit` is called. Once nginx exits, the stacks are
+//! written to the named file as folded stacks or as an SVG flame graph.
+
+use crate::lua::LuaString;
+use crate::types::{Error, Prefix};
+use crate::util::xml_escape;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where the sampled stacks are written, relative to the prefix directory.
+pub(crate) const RAW: &str = "logs/lua-profile";
+
+/// The output file for `-j p` without `--lua-profile`.
+pub(crate) const DEFAULT_OUTPUT: &str = "lua-profile.folded";
+
+/// The default sampling interval, in milliseconds.
+pub(crate) const DEFAULT_INTERVAL: u32 = 10;
+
+/// The default number of stack frames kept for each sample.
+pub(crate) const DEFAULT_DEPTH: u32 = 64;
+
+/// Options for `--lua-profile`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProfileArgs {
+    /// Where to write the profile; profiling is off without it.
+    pub(crate) output: Option<PathBuf>,
+    pub(crate) interval: Option<u32>,
+    pub(crate) depth: Option<u32>,
+}
+
+impl ProfileArgs {
+    pub(crate) fn enabled(&self) -> bool {
+        self.output.is_some()
+    }
+}
+
+const PROFILE_LUA: &str = r##"local prof_wrap = function(run)
+    local profile = require "jit.profile"
+    local dumpstack = profile.dumpstack
+
+    -- stack => samples
+    local stacks = {}
+
+    local function sample(thread, samples, vmstate)
+        -- outermost frame first, as flame graphs want it
+        local stack = dumpstack(thread, "pFZ;", -prof_depth)
+        if stack == "" then
+            stack = "[" .. vmstate .. "]"
+        end
+        stacks[stack] = (stacks[stack] or 0) + samples
+    end
+
+    -- the samples so far replace the previous ones, so this can be called
+    -- more than once
+    local function flush()
+        profile.stop()
+
+        local f = io.open(prof_results, "w")
+        if not f then
+            return
+        end
+
+        for stack, samples in pairs(stacks) do
+            f:write(stack, " ", samples, "\n")
+        end
+
+        f:close()
+    end
+
+    local exit = os.exit
+    os.exit = function(...)
+        flush()
+        return exit(...)
+    end
+
+    local ngx_exit = ngx.exit
+    ngx.exit = function(...)
+        flush()
+        return ngx_exit(...)
+    end
+
+    return function()
+        profile.start("fi" .. prof_interval, sample)
+
+        local ok, err = xpcall(run, function(err)
+            -- the same traceback as the handler that this takes over from
+            return debug.traceback(err, 3)
+        end)
+
+        flush()
+
+        if not ok then
+            err = string.gsub(err, "^init_worker_by_lua:%d+: ", "")
+            io.stderr:write("ERROR: ", err, "\n")
+            -- not `exit`, so that anything else waiting for os.exit runs
+            return os.exit(1)
+        end
+    end
+end"##;
+
+/// The Lua code that runs the profiler, for `LuaGenerator`.
+pub(crate) fn lua_loader(args: &ProfileArgs, prefix: &Prefix) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "local prof_interval = {}",
+            args.interval.unwrap_or(DEFAULT_INTERVAL)
+        ),
+        format!("local prof_depth = {}", args.depth.unwrap_or(DEFAULT_DEPTH)),
+        format!(
+            "local prof_results = {}",
+            prefix.root.join(RAW).to_string_lossy().lua_quote()
+        ),
+    ];
+
+    lines.extend(PROFILE_LUA.lines().map(String::from));
+    lines
+}
+
+/// Sample counts for each stack, with frames separated by `;`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Profile {
+    stacks: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    /// Read the stacks written by a run, if there are any.
+    pub(crate) fn read(prefix: &Prefix) -> Self {
+        fs::read_to_string(prefix.root.join(RAW))
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Parse folded stacks: one `STACK COUNT` line per stack.
+    fn parse(src: &str) -> Self {
+        let mut profile = Self::default();
+
+        for line in src.lines() {
+            // frame names can have spaces in them, but counts can't
+            if let Some((stack, count)) = line.rsplit_once(' ') {
+                if let Ok(count) = count.parse::<u64>() {
+                    *profile.stacks.entry(stack.to_string()).or_default() += count;
+                }
+            }
+        }
+
+        profile
+    }
+
+    /// Add the samples from another run.
+    pub(crate) fn merge(&mut self, other: Profile) {
+        for (stack, count) in other.stacks {
+            *self.stacks.entry(stack).or_default() += count;
+        }
+    }
+
+    fn folded(&self) -> String {
+        let mut out = String::new();
+        for (stack, count) in &self.stacks {
+            let _ = writeln!(out, "{stack} {count}");
+        }
+        out
+    }
+
+    /// Render a flame graph, with the outermost frames at the bottom.
+    fn svg(&self) -> String {
+        const WIDTH: f64 = 1200.0;
+        const PAD: f64 = 10.0;
+        const TOP: f64 = 40.0;
+        const FRAME: f64 = 16.0;
+        const CHAR: f64 = 7.0;
+
+        let mut root = Frame::default();
+        for (stack, count) in &self.stacks {
+            root.add(stack.split(';'), *count);
+        }
+
+        let height = TOP + FRAME * (root.depth() + 1) as f64 + PAD;
+        let scale = match root.samples {
+            0 => 0.0,
+            n => (WIDTH - 2.0 * PAD) / n as f64,
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#);
+        let _ = writeln!(
+            out,
+            r#"<svg version="1.1" width="{WIDTH}" height="{height}" viewBox="0 0 {WIDTH} {height}" xmlns="http://www.w3.org/2000/svg">"#
+        );
+        let _ = writeln!(
+            out,
+            r##"<rect x="0" y="0" width="{WIDTH}" height="{height}" fill="#eeeeee"/>"##
+        );
+        let _ = writeln!(
+            out,
+            r#"<text x="{}" y="24" text-anchor="middle" font-family="Verdana" font-size="17">Lua Flame Graph</text>"#,
+            WIDTH / 2.0
+        );
+        let _ = writeln!(out, r#"<g font-family="Verdana" font-size="12">"#);
+
+        // (frame, name, depth, x)
+        let mut todo = vec![(&root, "all", 0, PAD)];
+
+        while let Some((frame, name, depth, x)) = todo.pop() {
+            let width = frame.samples as f64 * scale;
+
+            // too narrow to see
+            if width < 0.1 {
+                continue;
+            }
+
+            let y = height - PAD - FRAME * (depth + 1) as f64;
+            let percent = 100.0 * frame.samples as f64 / root.samples as f64;
+            let samples = match frame.samples {
+                1 => "1 sample".to_string(),
+                n => format!("{n} samples"),
+            };
+
+            let _ = writeln!(
+                out,
+                r#"<g><title>{} ({samples}, {percent:.2}%)</title><rect x="{x:.1}" y="{y:.1}" width="{width:.1}" height="{:.1}" fill="{}" rx="2"/>{}</g>"#,
+                xml_escape(name),
+                FRAME - 1.0,
+                color(name),
+                label(name, width, CHAR)
+                    .map(|text| format!(
+                        r#"<text x="{:.1}" y="{:.1}">{}</text>"#,
+                        x + 3.0,
+                        y + FRAME - 4.5,
+                        xml_escape(&text)
+                    ))
+                    .unwrap_or_default(),
+            );
+
+            let mut child_x = x;
+            for (name, child) in &frame.children {
+                todo.push((child, name.as_str(), depth + 1, child_x));
+                child_x += child.samples as f64 * scale;
+            }
+        }
+
+        out.push_str("</g>\n</svg>\n");
+        out
+    }
+
+    /// Write the profile to `args.output`: an SVG flame graph if the file
+    /// name ends in `.svg`, and folded stacks otherwise.
+    pub(crate) fn write(&self, args: &ProfileArgs) -> Result<(), Error> {
+        let Some(path) = &args.output else {
+            return Ok(());
+        };
+
+        let contents = match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("svg") => self.svg(),
+            _ => self.folded(),
+        };
+
+        fs::write(path, contents).map_err(|source| Error::Io {
+            context: "failed writing Lua profile",
+            source,
+        })
+    }
+}
+
+/// A node in the tree of stacks.
+#[derive(Debug, Default)]
+struct Frame {
+    samples: u64,
+    children: BTreeMap<String, Frame>,
+}
+
+impl Frame {
+    fn add<'a, I>(&mut self, mut stack: I, samples: u64)
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        self.samples += samples;
+        if let Some(name) = stack.next() {
+            self.children
+                .entry(name.to_string())
+                .or_default()
+                .add(stack, samples);
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.children
+            .values()
+            .map(|child| child.depth() + 1)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// The text that fits in a frame `width` pixels wide, if any does.
+fn label(name: &str, width: f64, char_width: f64) -> Option<String> {
+    let fits = ((width - 6.0) / char_width) as usize;
+    let len = name.chars().count();
+
+    if len <= fits {
+        Some(name.to_string())
+    } else if fits >= 3 {
+        Some(name.chars().take(fits - 2).chain("..".chars()).collect())
+    } else {
+        None
+    }
+}
+
+/// A warm color for a frame, the same each time for the same name.
+fn color(name: &str) -> String {
+    // FNV-1a
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+
+    let r = 205 + (hash % 50);
+    let g = (hash >> 8) % 230;
+    let b = (hash >> 16) % 55;
+    format!("rgb({r},{g},{b})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::KeepPrefix;
+
+    #[test]
+    fn loader() {
+        let prefix = Prefix::new(None, None, KeepPrefix::Never).unwrap();
+        let args = ProfileArgs {
+            depth: Some(8),
+            ..Default::default()
+        };
+
+        let lines = lua_loader(&args, &prefix);
+        let results = prefix.root.join(RAW);
+        assert_eq!(
+            vec![
+                format!("local prof_interval = {DEFAULT_INTERVAL}"),
+                "local prof_depth = 8".to_string(),
+                format!("local prof_results = [=[{}]=]", results.display()),
+                "local prof_wrap = function(run)".to_string(),
+            ],
+            lines[..4]
+        );
+
+        // the stacks are read back from where the Lua code writes them
+        fs::write(&results, "main.lua:1 2\n").unwrap();
+        assert_eq!("main.lua:1 2\n", Profile::read(&prefix).folded());
+    }
+
+    #[test]
+    fn parse_and_merge() {
+        let mut profile = Profile::parse("main.lua:1;a b 3\nmain.lua:1 2\n[N] 1\nbogus\n");
+        profile.merge(Profile::parse("main.lua:1 4\n"));
+
+        assert_eq!("[N] 1\nmain.lua:1 6\nmain.lua:1;a b 3\n", profile.folded());
+    }
+
+    #[test]
+    fn flame_graph() {
+        let profile = Profile::parse("main:1;f 3\nmain:1;g<x> 1\n");
+        let svg = profile.svg();
+
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(r#"height="98""#), "{svg}");
+        assert!(svg.contains("<title>all (4 samples, 100.00%)</title>"));
+        assert!(svg.contains("<title>main:1 (4 samples, 100.00%)</title>"));
+        assert!(svg.contains("<title>f (3 samples, 75.00%)</title>"));
+        assert!(svg.contains("<title>g&lt;x&gt; (1 sample, 25.00%)</title>"));
+
+        // `f` starts at the left edge, and `g<x>` right after it
+        assert!(
+            svg.contains(r#"<rect x="10.0" y="40.0" width="885.0""#),
+            "{svg}"
+        );
+        assert!(
+            svg.contains(r#"<rect x="895.0" y="40.0" width="295.0""#),
+            "{svg}"
+        );
+
+        assert!(Profile::default().svg().contains("</svg>"));
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(Some("abc".to_string()), label("abc", 100.0, 7.0));
+        assert_eq!(Some("abcd..".to_string()), label("abcdefghij", 50.0, 7.0));
+        assert_eq!(None, label("abcdefghij", 20.0, 7.0));
+
+        assert_eq!(color("f"), color("f"));
+        assert!(color("f").starts_with("rgb("));
+    }
+}
@@ -0,0 +1,322 @@
+use crate::lua::LuaString;
+use crate::types::Prefix;
+use nix::sys::stat::Mode;
+use nix::sys::termios::{tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::mkfifo;
+use rustyline::error::ReadlineError;
+use rustyline::{Config, DefaultEditor};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ">> ";
+
+/// Maximum number of history entries that we keep on disk.
+const HISTORY_MAX: usize = 1000;
+
+/// Terminal settings from before the line editor started.
+static ORIGINAL_TERMIOS: Mutex<Option<Termios>> = Mutex::new(None);
+
+/// The named pipes used to exchange Lua chunks with nginx.
+///
+/// Chunks are sent to nginx as `<length>\n<chunk>`. After each chunk nginx
+/// responds with a single line:
+///
+/// * `more` - the chunk is incomplete and needs more input
+/// * `done` - the chunk was evaluated (and its results or errors printed)
+#[derive(Debug, Clone)]
+pub(crate) struct Fifos {
+    pub(crate) input: PathBuf,
+    pub(crate) output: PathBuf,
+}
+
+impl Fifos {
+    pub(crate) fn new(prefix: &Prefix) -> Self {
+        Self {
+            input: prefix.root.join("repl.in"),
+            output: prefix.root.join("repl.out"),
+        }
+    }
+
+    pub(crate) fn create(&self) -> io::Result<()> {
+        for path in [&self.input, &self.output] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+
+            mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lua code for the nginx side of the REPL.
+///
+/// Returned values are printed the way `ngx.say` prints its arguments, with
+/// the `expand_table` function from the init_by_lua block.
+const REPL_LUA: &str = r##"local repl_gen = function()
+    local stdout, stderr = io.stdout, io.stderr
+    local input = assert(io.open(repl_input, "rb"))
+    local output = assert(io.open(repl_output, "wb"))
+    local expand_table = ngx.config.expand_table
+
+    local function reply(status)
+        stdout:flush()
+        stderr:flush()
+        output:write(status, "\n")
+        output:flush()
+    end
+
+    local function pack(...)
+        return { n = select("#", ...), ... }
+    end
+
+    local function traceback(err)
+        return debug.traceback(err, 2)
+    end
+
+    while true do
+        local len = input:read("*l")
+        if not len then
+            break
+        end
+
+        local src = input:read(tonumber(len)) or ""
+
+        local fn, err = loadstring("return " .. src, "=stdin")
+        if not fn then
+            fn, err = loadstring(src, "=stdin")
+        end
+
+        if not fn and err:sub(-7) == "'<eof>'" then
+            reply("more")
+
+        else
+            if not fn then
+                stderr:write(err, "\n")
+
+            else
+                local res = pack(xpcall(fn, traceback))
+                if not res[1] then
+                    stderr:write(tostring(res[2]), "\n")
+
+                elseif res.n > 1 then
+                    local out = {}
+                    for i = 2, res.n do
+                        local value = res[i]
+                        if value == nil then
+                            value = "nil"
+                        end
+
+                        if i > 2 then
+                            out[#out + 1] = "\t"
+                        end
+                        out[#out + 1] = value
+                    end
+                    stdout:write(expand_table(out, true), "\n")
+                end
+            end
+
+            reply("done")
+        end
+    end
+
+    input:close()
+    output:close()
+end"##;
+
+pub(crate) fn lua_loader(fifos: &Fifos) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "local repl_input = {}",
+            fifos.input.to_string_lossy().lua_quote()
+        ),
+        format!(
+            "local repl_output = {}",
+            fifos.output.to_string_lossy().lua_quote()
+        ),
+    ];
+
+    lines.extend(REPL_LUA.lines().map(String::from));
+    lines
+}
+
+fn history_file() -> Option<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+
+    Some(state.join("rusty-cli").join("history"))
+}
+
+enum ReadLine {
+    Line(String),
+    Interrupted,
+    Eof,
+}
+
+/// Reads lines with rustyline when talking to a terminal, and plainly
+/// otherwise.
+struct Editor {
+    line: Option<DefaultEditor>,
+    history: Option<PathBuf>,
+}
+
+impl Editor {
+    fn new() -> io::Result<Self> {
+        let terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+        if !terminal {
+            return Ok(Self {
+                line: None,
+                history: None,
+            });
+        }
+
+        // rustyline restores the terminal after each line, but not when nginx
+        // exits while it is waiting for one
+        let orig = tcgetattr(io::stdin())?;
+        ORIGINAL_TERMIOS.lock().unwrap().get_or_insert(orig);
+
+        let config = Config::builder()
+            .max_history_size(HISTORY_MAX)
+            .map_err(io::Error::other)?
+            .auto_add_history(false)
+            .build();
+        let mut line = DefaultEditor::with_config(config).map_err(io::Error::other)?;
+
+        let history = history_file();
+        if let Some(fname) = &history {
+            // there is none yet on the first run
+            let _ = line.load_history(fname);
+        }
+
+        Ok(Self {
+            line: Some(line),
+            history,
+        })
+    }
+
+    fn read_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        let Some(line) = &mut self.line else {
+            return read_plain_line();
+        };
+
+        let text = match line.readline(prompt) {
+            Ok(text) => text,
+            Err(ReadlineError::Interrupted) => return Ok(ReadLine::Interrupted),
+            Err(ReadlineError::Eof) => return Ok(ReadLine::Eof),
+            Err(ReadlineError::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        // history is appended as we go so that nothing is lost if nginx
+        // takes us down with it
+        if !text.trim().is_empty() && line.add_history_entry(text.as_str()).unwrap_or(false) {
+            if let Some(fname) = &self.history {
+                if let Some(dir) = fname.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+                let _ = line.append_history(fname);
+            }
+        }
+
+        Ok(ReadLine::Line(text))
+    }
+}
+
+/// Restore the terminal settings that were in place before the REPL started
+/// editing lines.
+pub(crate) fn restore_terminal() {
+    let Ok(mut orig) = ORIGINAL_TERMIOS.lock() else {
+        return;
+    };
+
+    if let Some(termios) = orig.take() {
+        let _ = tcsetattr(io::stdin(), SetArg::TCSADRAIN, &termios);
+    }
+}
+
+fn read_plain_line() -> io::Result<ReadLine> {
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(ReadLine::Eof);
+    }
+
+    let line = line.trim_end_matches(['\r', '\n']).to_string();
+    Ok(ReadLine::Line(line))
+}
+
+/// Drives the terminal side of the REPL, sending each chunk of Lua code
+/// to nginx for evaluation.
+///
+/// Returns when the user signals EOF or nginx closes its end of the pipe.
+pub(crate) fn frontend(fifos: Fifos) {
+    if let Err(e) = impl_frontend(fifos) {
+        restore_terminal();
+        eprintln!("ERROR: repl: {}", e);
+    }
+}
+
+fn impl_frontend(fifos: Fifos) -> io::Result<()> {
+    let mut editor = Editor::new()?;
+
+    // opening a fifo blocks until the other end has been opened too, so
+    // this waits for nginx to be ready
+    let mut input = File::options().write(true).open(&fifos.input)?;
+    let mut output = BufReader::new(File::open(&fifos.output)?);
+
+    let mut chunk = String::new();
+    let mut status = String::new();
+
+    loop {
+        let prompt = if chunk.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+
+        match editor.read_line(prompt)? {
+            ReadLine::Line(line) => {
+                if !chunk.is_empty() {
+                    chunk.push('\n');
+                }
+                chunk.push_str(&line);
+            }
+            ReadLine::Interrupted => {
+                chunk.clear();
+                continue;
+            }
+            ReadLine::Eof => break,
+        }
+
+        if chunk.trim().is_empty() {
+            chunk.clear();
+            continue;
+        }
+
+        write!(input, "{}\n{}", chunk.len(), chunk)?;
+        input.flush()?;
+
+        status.clear();
+        if output.read_line(&mut status)? == 0 {
+            // nginx went away
+            break;
+        }
+
+        if status.trim_end() != "more" {
+            chunk.clear();
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use crate::run::Status;
+use crate::RESTY_COMPAT_VERSION;
+use crate::VERSION;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Where nginx writes a copy of its error log when a report was requested,
+/// relative to the prefix directory.
+pub(crate) const ERROR_LOG: &str = "logs/error.log";
+
+/// How many lines from the end of the error log to include.
+const ERROR_LOG_TAIL_LINES: usize = 50;
+
+/// A machine-readable summary of a run, written by `--report-json`.
+pub(crate) struct Report {
+    pub(crate) nginx: PathBuf,
+    pub(crate) argv: Vec<String>,
+    pub(crate) prefix: PathBuf,
+    pub(crate) started: SystemTime,
+    pub(crate) ended: SystemTime,
+    pub(crate) status: Status,
+}
+
+impl Report {
+    fn error_log_tail(&self) -> Vec<String> {
+        let Ok(log) = fs::read(self.prefix.join(ERROR_LOG)) else {
+            return vec![];
+        };
+
+        let log = String::from_utf8_lossy(&log);
+        let lines: Vec<&str> = log.lines().collect();
+        let skip = lines.len().saturating_sub(ERROR_LOG_TAIL_LINES);
+
+        lines[skip..].iter().map(|line| line.to_string()).collect()
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let status = &self.status;
+        let duration = self
+            .ended
+            .duration_since(self.started)
+            .unwrap_or(Duration::ZERO);
+
+        let json = Json {
+            rusty_cli_version: VERSION,
+            resty_compat_version: RESTY_COMPAT_VERSION.to_string(),
+            nginx: self.nginx.to_string_lossy(),
+            argv: &self.argv,
+            prefix: self.prefix.to_string_lossy(),
+            started_at: rfc3339(self.started),
+            ended_at: rfc3339(self.ended),
+            duration_ms: duration.as_millis(),
+            exit_code: status.code,
+            pid: status.pid.map(Pid::as_raw),
+            signal: status.signal.map(Signal::as_str),
+            caught_signal: status.caught_signal.map(Signal::as_str),
+            handled: status.caught_signal.is_some(),
+            core_dumped: status.core_dumped,
+            timed_out: status.timed_out,
+            error_log_tail: self.error_log_tail(),
+        };
+
+        serde_json::to_string_pretty(&json).expect("reports are serializable")
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> io::Result<()> {
+        let mut json = self.to_json();
+        json.push('\n');
+        fs::write(path, json)
+    }
+}
+
+/// The fields of a report, in the order they are written.
+#[derive(Serialize)]
+struct Json<'a> {
+    rusty_cli_version: &'a str,
+    resty_compat_version: String,
+    nginx: Cow<'a, str>,
+    argv: &'a [String],
+    prefix: Cow<'a, str>,
+    started_at: String,
+    ended_at: String,
+    duration_ms: u128,
+    exit_code: i32,
+    pid: Option<i32>,
+    signal: Option<&'static str>,
+    caught_signal: Option<&'static str>,
+    handled: bool,
+    core_dumped: bool,
+    timed_out: bool,
+    error_log_tail: Vec<String>,
+}
+
+/// Format a timestamp as RFC 3339 in UTC with millisecond precision.
+fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn format_rfc3339() {
+        let at = |secs: u64, millis: u64| {
+            rfc3339(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+        };
+
+        assert_eq!("1970-01-01T00:00:00.000Z", at(0, 0));
+        assert_eq!("2000-02-29T12:34:56.789Z", at(951827696, 789));
+        assert_eq!("2024-12-31T23:59:59.001Z", at(1735689599, 1));
+        assert_eq!("2100-03-01T00:00:00.000Z", at(4107542400, 0));
+    }
+
+    #[test]
+    fn report_json() {
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let report = Report {
+            nginx: PathBuf::from("/usr/bin/nginx"),
+            argv: vec!["/usr/bin/nginx".into(), "-p".into(), "/tmp/x/".into()],
+            prefix: PathBuf::from("/a/b/i-dont-exist"),
+            started,
+            ended: started + Duration::from_millis(1500),
+            status: Status {
+                code: 130,
+                pid: Some(Pid::from_raw(42)),
+                signal: None,
+                caught_signal: Some(Signal::SIGINT),
+                ..Default::default()
+            },
+        };
+
+        let json = report.to_json();
+        assert!(json.starts_with("{\n  \"rusty_cli_version\": "), "{json}");
+
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            json!({
+                "rusty_cli_version": VERSION,
+                "resty_compat_version": RESTY_COMPAT_VERSION.to_string(),
+                "nginx": "/usr/bin/nginx",
+                "argv": ["/usr/bin/nginx", "-p", "/tmp/x/"],
+                "prefix": "/a/b/i-dont-exist",
+                "started_at": "2023-11-14T22:13:20.000Z",
+                "ended_at": "2023-11-14T22:13:21.500Z",
+                "duration_ms": 1500,
+                "exit_code": 130,
+                "pid": 42,
+                "signal": null,
+                "caught_signal": "SIGINT",
+                "handled": true,
+                "core_dumped": false,
+                "timed_out": false,
+                "error_log_tail": [],
+            }),
+            parsed
+        );
+    }
+}
@@ -3,20 +3,68 @@ use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet,
 use nix::sys::signal::{
     SIGHUP, SIGINT, SIGKILL, SIGPIPE, SIGQUIT, SIGSEGV, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH,
 };
+use nix::sys::wait::{waitid, Id, WaitPidFlag};
 use nix::unistd::Pid;
+use std::io::Read;
 use std::os::unix::process::ExitStatusExt;
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Mutex, PoisonError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Exit code used when nginx is stopped because `--timeout` elapsed. This
+/// matches the convention of coreutils' `timeout(1)`.
+pub(crate) const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How long nginx is given to exit after SIGQUIT when it is being stopped due
+/// to a caught signal.
+const SIGNAL_GRACE: Duration = Duration::from_millis(100);
 
 const SIGNALS: [Signal; 8] = [
     SIGINT, SIGTERM, SIGQUIT, SIGHUP, SIGUSR1, SIGUSR2, SIGWINCH, SIGPIPE,
 ];
 
+/// The most child processes that signals can be forwarded to at once.
+pub(crate) const MAX_CHILDREN: usize = 64;
+
 static HANDLED: AtomicBool = AtomicBool::new(false);
 static SIGNAL: AtomicI32 = AtomicI32::new(0);
-static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// The pids of the running child processes that signals are forwarded to,
+/// with 0 marking a free slot. The signal handler can't take locks or
+/// allocate, so this is a fixed table of atomics.
+static CHILDREN: [AtomicI32; MAX_CHILDREN] = [const { AtomicI32::new(0) }; MAX_CHILDREN];
+
+/// Our signal handlers, along with the number of running processes that
+/// need them. They are installed by the first process to start and restored
+/// when the last one exits.
+static HANDLERS: Mutex<Handlers> = Mutex::new(Handlers {
+    users: 0,
+    actions: Vec::new(),
+});
+
+fn children() -> impl Iterator<Item = Pid> {
+    CHILDREN
+        .iter()
+        .map(|slot| slot.load(Ordering::Relaxed))
+        .filter(|pid| *pid > 0)
+        .map(Pid::from_raw)
+}
+
+fn add_child(pid: Pid) -> bool {
+    CHILDREN.iter().any(|slot| {
+        slot.compare_exchange(0, pid.as_raw(), Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+fn remove_child(pid: Pid) {
+    for slot in CHILDREN.iter() {
+        let _ = slot.compare_exchange(pid.as_raw(), 0, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
 
 fn send_signal_unchecked(pid: Pid, sig: Signal) {
     let _ = kill(pid, sig);
@@ -47,10 +95,22 @@ fn get_caught_signal() -> Option<i32> {
     }
 }
 
-fn send_quit_and_kill(pid: Pid) {
+/// Block until a child process exits, without reaping it.
+fn wait_for_exit(pid: Pid) {
+    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT;
+    while let Err(Errno::EINTR) = waitid(Id::Pid(pid), flags) {}
+}
+
+/// Ask nginx to quit, then kill it unless `wait_for_exit` reports that it
+/// has exited.
+fn send_quit_and_kill<F>(pid: Pid, wait_for_exit: F)
+where
+    F: FnOnce() -> bool,
+{
     send_signal(pid, SIGQUIT);
-    thread::sleep(Duration::from_millis(100));
-    send_signal(pid, SIGKILL);
+    if !wait_for_exit() {
+        send_signal(pid, SIGKILL);
+    }
 }
 
 #[no_mangle]
@@ -59,37 +119,67 @@ extern "C" fn signal_handler(sig: libc::c_int) {
         return;
     };
 
-    let pid = CHILD_PID.load(Ordering::Relaxed);
-    if pid <= 0 {
+    if children().next().is_none() {
         return;
     }
-    let pid = Pid::from_raw(pid);
 
     match sig {
         SIGINT | SIGPIPE => {
             set_caught_signal(sig);
-            send_quit_and_kill(pid);
+            children().for_each(|pid| send_signal(pid, SIGQUIT));
+            thread::sleep(SIGNAL_GRACE);
+            children().for_each(|pid| send_signal(pid, SIGKILL));
         }
 
         SIGTERM => {
             set_caught_signal(sig);
-            send_signal_unchecked(pid, sig);
+            children().for_each(|pid| send_signal_unchecked(pid, sig));
         }
 
         SIGHUP => {
             // translate to QUIT
-            send_signal_unchecked(pid, SIGQUIT);
+            children().for_each(|pid| send_signal_unchecked(pid, SIGQUIT));
         }
 
         SIGWINCH | SIGQUIT | SIGUSR1 | SIGUSR2 => {
             // forward as-is
-            send_signal_unchecked(pid, sig);
+            children().for_each(|pid| send_signal_unchecked(pid, sig));
         }
         // ignore others
         _ => {}
     }
 }
 
+/// Whether a signal has been caught (and forwarded to nginx) by our handler.
+pub(crate) fn interrupted() -> bool {
+    HANDLED.load(Ordering::Acquire)
+}
+
+/// The exit code for the signal caught by our handler (128 plus the signal
+/// number), if any.
+pub(crate) fn interrupted_code() -> Option<i32> {
+    get_caught_signal().map(|sig| sig + 128)
+}
+
+/// Stop the running child processes the same way as SIGINT does, without
+/// treating it as a caught signal.
+///
+/// This only affects processes that were started with signal forwarding.
+pub(crate) fn stop_children() {
+    let pids: Vec<Pid> = children().collect();
+
+    pids.iter().for_each(|pid| send_signal(*pid, SIGQUIT));
+
+    let deadline = Instant::now() + SIGNAL_GRACE;
+    while Instant::now() < deadline && children().any(|pid| pids.contains(&pid)) {
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    children()
+        .filter(|pid| pids.contains(pid))
+        .for_each(|pid| send_signal(pid, SIGKILL));
+}
+
 struct SignalAction(Signal, SigAction);
 
 impl SignalAction {
@@ -120,16 +210,195 @@ impl Drop for SignalAction {
     }
 }
 
-pub(crate) fn run(mut cmd: Command) -> i32 {
-    let mut old_actions = Vec::with_capacity(SIGNALS.len());
-    for signal in SIGNALS {
-        match SignalAction::try_new(signal) {
-            Ok(action) => old_actions.push(action),
-            Err(e) => {
-                eprintln!("sigaction({signal}) failure => {e}");
-                return 2;
+struct Handlers {
+    users: usize,
+    actions: Vec<SignalAction>,
+}
+
+/// Keeps our signal handlers installed while it is alive.
+struct HandlerGuard;
+
+impl HandlerGuard {
+    fn install() -> Result<Self, (Signal, Errno)> {
+        let mut handlers = HANDLERS.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if handlers.users == 0 {
+            for signal in SIGNALS {
+                match SignalAction::try_new(signal) {
+                    Ok(action) => handlers.actions.push(action),
+                    Err(e) => {
+                        handlers.actions.clear();
+                        return Err((signal, e));
+                    }
+                }
             }
         }
+
+        handlers.users += 1;
+        Ok(HandlerGuard)
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        let mut handlers = HANDLERS.lock().unwrap_or_else(PoisonError::into_inner);
+
+        handlers.users -= 1;
+        if handlers.users == 0 {
+            handlers.actions.clear();
+        }
+    }
+}
+
+/// How the child process ended.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Status {
+    /// The exit code that rusty-cli should exit with.
+    pub(crate) code: i32,
+
+    /// The child's pid, if it was spawned.
+    pub(crate) pid: Option<Pid>,
+
+    /// The signal that terminated the child, if any.
+    pub(crate) signal: Option<Signal>,
+
+    /// The signal caught by our handler (and forwarded to the child), if
+    /// any.
+    pub(crate) caught_signal: Option<Signal>,
+
+    /// Whether the child dumped core.
+    pub(crate) core_dumped: bool,
+
+    /// Whether the child was stopped because the timeout elapsed.
+    pub(crate) timed_out: bool,
+
+    /// The child's stdout and stderr, when captured.
+    pub(crate) output: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Status {
+    fn from_code(code: i32) -> Self {
+        Self {
+            code,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Timeout {
+    after: Duration,
+    grace: Duration,
+}
+
+/// Runs a command to completion while forwarding signals to it.
+pub(crate) struct Process {
+    cmd: Command,
+    on_spawn: Option<Box<dyn FnOnce() + Send>>,
+    timeout: Option<Timeout>,
+    forward_signals: bool,
+    capture_output: bool,
+}
+
+impl Process {
+    pub(crate) fn new(cmd: Command) -> Self {
+        Self {
+            cmd,
+            on_spawn: None,
+            timeout: None,
+            forward_signals: true,
+            capture_output: false,
+        }
+    }
+
+    /// Don't install signal handlers for the duration of the run.
+    ///
+    /// The handlers are process-wide, so this must be used when embedding.
+    pub(crate) fn without_signal_forwarding(mut self) -> Self {
+        self.forward_signals = false;
+        self
+    }
+
+    /// Collect the child's stdout and stderr instead of inheriting them.
+    pub(crate) fn capture_output(mut self) -> Self {
+        self.capture_output = true;
+        self
+    }
+
+    /// Stop the child process if it is still running after `after` has
+    /// elapsed.
+    ///
+    /// The child is sent SIGQUIT, followed by SIGKILL if it has not exited
+    /// within `grace`. When this happens the exit code is
+    /// [`TIMEOUT_EXIT_CODE`].
+    pub(crate) fn timeout(mut self, after: Duration, grace: Duration) -> Self {
+        self.timeout = Some(Timeout { after, grace });
+        self
+    }
+
+    /// Run a function in a background thread once the child process has been
+    /// spawned.
+    ///
+    /// The thread is not joined: the function is expected to return on its own
+    /// when the child exits.
+    pub(crate) fn on_spawn<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.on_spawn = Some(Box::new(f));
+        self
+    }
+
+    /// The program and arguments that will be executed.
+    pub(crate) fn argv(&self) -> Vec<String> {
+        std::iter::once(self.cmd.get_program())
+            .chain(self.cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    pub(crate) fn run(self) -> i32 {
+        self.run_with_status().code
+    }
+
+    pub(crate) fn run_with_status(self) -> Status {
+        impl_run(self)
+    }
+}
+
+pub(crate) fn run(cmd: Command) -> i32 {
+    Process::new(cmd).run()
+}
+
+fn read_all<R: Read + Send + 'static>(reader: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut reader) = reader {
+            let _ = reader.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+fn impl_run(process: Process) -> Status {
+    let Process {
+        mut cmd,
+        on_spawn,
+        timeout,
+        forward_signals,
+        capture_output,
+    } = process;
+
+    let handlers = match forward_signals.then(HandlerGuard::install).transpose() {
+        Ok(handlers) => handlers,
+        Err((signal, e)) => {
+            eprintln!("sigaction({signal}) failure => {e}");
+            return Status::from_code(2);
+        }
+    };
+
+    if capture_output {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
     }
 
     let mut proc = match cmd.spawn() {
@@ -137,19 +406,80 @@ pub(crate) fn run(mut cmd: Command) -> i32 {
         Err(e) => {
             let prog = cmd.get_program().to_string_lossy();
             eprintln!("ERROR: failed to run command \"{prog}\": {e}");
-            return 2;
+            return Status::from_code(2);
         }
     };
 
-    CHILD_PID.store(proc.id() as i32, Ordering::Relaxed);
-    let res = proc.wait();
+    let pid = Pid::from_raw(proc.id() as i32);
+    if forward_signals && !add_child(pid) {
+        eprintln!("WARN: too many child processes, signals will not be forwarded to {pid}");
+    }
+
+    let readers =
+        capture_output.then(|| (read_all(proc.stdout.take()), read_all(proc.stderr.take())));
+
+    if let Some(f) = on_spawn {
+        thread::spawn(f);
+    }
+
+    let (exited, exit_rx) = channel::<()>();
+
+    let watchdog = timeout.map(|timeout| {
+        thread::spawn(move || match exit_rx.recv_timeout(timeout.after) {
+            Err(RecvTimeoutError::Timeout) => {
+                send_quit_and_kill(pid, || {
+                    !matches!(
+                        exit_rx.recv_timeout(timeout.grace),
+                        Err(RecvTimeoutError::Timeout)
+                    )
+                });
+                true
+            }
+            _ => false,
+        })
+    });
+
+    // nginx is left unreaped until nothing else can signal it, so that its
+    // pid cannot be reused in the meantime
+    wait_for_exit(pid);
+
+    if forward_signals {
+        remove_child(pid);
+    }
 
     // restore signal handlers to their defaults as soon as possible
-    drop(old_actions);
+    drop(handlers);
+
+    // wake up the watchdog, if any, which reports whether the timeout
+    // elapsed before nginx exited
+    drop(exited);
+    let timed_out = watchdog.is_some_and(|w| w.join().unwrap_or(false));
+
+    let res = proc.wait();
+
+    let output = readers.map(|(stdout, stderr)| {
+        (
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default(),
+        )
+    });
 
-    match res {
+    // the handler state is process-wide, so it is only ours to look at when
+    // our handler was installed
+    let caught = forward_signals.then(get_caught_signal).flatten();
+
+    let code = match &res {
+        Ok(_) if timed_out => {
+            if let Some(timeout) = timeout {
+                eprintln!(
+                    "ERROR: nginx timed out after {}s",
+                    timeout.after.as_secs_f64()
+                );
+            }
+            TIMEOUT_EXIT_CODE
+        }
         Ok(status) => {
-            let signal = get_caught_signal().or_else(|| {
+            let signal = caught.or_else(|| {
                 status.signal().or(status.stopped_signal()).or_else(|| {
                     if status.core_dumped() {
                         Some(SIGSEGV as i32)
@@ -174,5 +504,19 @@ pub(crate) fn run(mut cmd: Command) -> i32 {
             let _ = proc.kill();
             SIGKILL as i32 + 128
         }
+    };
+
+    let status = res.ok();
+
+    Status {
+        code,
+        pid: Some(pid),
+        signal: status
+            .and_then(|st| st.signal())
+            .and_then(|sig| Signal::try_from(sig).ok()),
+        caught_signal: caught.and_then(|sig| Signal::try_from(sig).ok()),
+        core_dumped: status.is_some_and(|st| st.core_dumped()),
+        timed_out,
+        output,
     }
 }
@@ -0,0 +1,1110 @@
+//! The `test` subcommand: a small busted-style test runner.
+//!
+//! Test files are run inside nginx like any other Lua code. The generated
+//! init_worker code defines `describe`, `it`, `pending`, `before_each`,
+//! `after_each`, and an extended `assert`, then loads each test file and
+//! runs the tests that it registered. Results are written to a file in the
+//! prefix directory, one line per test, and reported as TAP (and optionally
+//! JUnit XML) once nginx exits.
+
+use crate::cli::{run_captured, UserArgs};
+use crate::coverage::Coverage;
+use crate::lua::LuaString;
+use crate::profile::Profile;
+use crate::run;
+use crate::types::{ArgError, Error, Prefix};
+use crate::util::{find_files, xml_chars};
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::name::QName;
+use quick_xml::Writer;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where the test results are written, relative to the prefix directory.
+pub(crate) const RESULTS: &str = "logs/test-results";
+
+const SUFFIXES: [&str; 2] = ["_spec.lua", "_test.lua"];
+
+/// Options for the `test` subcommand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TestArgs {
+    /// The test files to run.
+    pub(crate) files: Vec<String>,
+    pub(crate) filter: Option<String>,
+    pub(crate) junit: Option<PathBuf>,
+    pub(crate) single_instance: bool,
+}
+
+fn is_test_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| SUFFIXES.iter().any(|suffix| name.ends_with(suffix)))
+}
+
+/// Find the test files to run.
+///
+/// Directories are searched recursively for `*_spec.lua` and `*_test.lua`
+/// files, skipping hidden ones. Files are run no matter what they are named.
+pub(crate) fn discover(paths: &[String]) -> Result<Vec<String>, ArgError> {
+    let mut files = vec![];
+
+    let default = [".".to_string()];
+    let paths = if paths.is_empty() {
+        &default[..]
+    } else {
+        paths
+    };
+
+    for path in paths {
+        let p = Path::new(path);
+        if p.is_dir() {
+            find_files(p, &is_test_file, &mut files);
+        } else if p.is_file() {
+            files.push(path.clone());
+        } else {
+            return Err(ArgError::LuaFileNotFound(path.clone()));
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+
+    if files.is_empty() {
+        return Err(ArgError::NoTestFiles);
+    }
+
+    Ok(files)
+}
+
+/// Check the arguments for the `test` subcommand and find the test files.
+pub(crate) fn validate(user: &mut UserArgs) -> Result<(), ArgError> {
+    for (set, opt) in [
+        (user.repl, "--repl"),
+        (user.dump_nginx_conf, "--dump-nginx-conf"),
+        (user.watch, "--watch"),
+        (user.report_json.is_some(), "--report-json"),
+        (user.parallel.is_some(), "--parallel"),
+    ] {
+        if set {
+            return Err(ArgError::Conflict("test".to_string(), opt.to_string()));
+        }
+    }
+
+    user.test.files = discover(&user.operands)?;
+    Ok(())
+}
+
+const SPEC_LUA: &str = r##"local spec_gen = function()
+    local concat = table.concat
+    local fmt = string.format
+    local lua_assert = assert
+
+    local results = lua_assert(io.open(spec_results, "a"))
+    local file
+
+    local function escape(s)
+        return (tostring(s or ""):gsub("[\\\t\n\r]", {
+            ["\\"] = "\\\\", ["\t"] = "\\t", ["\n"] = "\\n", ["\r"] = "\\r",
+        }))
+    end
+
+    local function record(outcome, name, elapsed, message)
+        results:write(outcome, "\t", escape(file), "\t", escape(name), "\t",
+                      fmt("%.6f", elapsed or 0), "\t", escape(message), "\n")
+        results:flush()
+    end
+
+    local function now()
+        ngx.update_time()
+        return ngx.now()
+    end
+
+    local function traceback(err)
+        return debug.traceback(tostring(err), 2)
+    end
+
+    -- values are shown the way `ngx.say` prints them, with strings quoted
+    local expand_table = ngx.config.expand_table
+
+    local function show(v)
+        if type(v) == "string" then
+            return fmt("%q", v)
+        elseif v == nil then
+            return "nil"
+        end
+        return expand_table({ v }, false)
+    end
+
+    local same
+    same = function(a, b)
+        if a == b then
+            return true
+        elseif type(a) ~= "table" or type(b) ~= "table" then
+            return false
+        end
+
+        for k, v in pairs(a) do
+            if not same(v, b[k]) then
+                return false
+            end
+        end
+        for k in pairs(b) do
+            if a[k] == nil then
+                return false
+            end
+        end
+        return true
+    end
+
+    -- level 3 points at the caller of the assertion
+    local function fail(msg, default)
+        error(msg or default, 3)
+    end
+
+    local checks = {}
+
+    function checks.equals(expected, actual, msg)
+        if expected ~= actual then
+            fail(msg, fmt("expected %s, got %s", show(expected), show(actual)))
+        end
+    end
+    checks.equal = checks.equals
+
+    function checks.same(expected, actual, msg)
+        if not same(expected, actual) then
+            fail(msg, fmt("expected %s, got %s", show(expected), show(actual)))
+        end
+    end
+
+    function checks.is_true(v, msg)
+        if v ~= true then fail(msg, "expected true, got " .. show(v)) end
+    end
+
+    function checks.is_false(v, msg)
+        if v ~= false then fail(msg, "expected false, got " .. show(v)) end
+    end
+
+    function checks.is_nil(v, msg)
+        if v ~= nil then fail(msg, "expected nil, got " .. show(v)) end
+    end
+
+    function checks.is_not_nil(v, msg)
+        if v == nil then fail(msg, "expected a value, got nil") end
+    end
+
+    function checks.truthy(v, msg)
+        if not v then fail(msg, "expected a truthy value, got " .. show(v)) end
+    end
+
+    function checks.falsy(v, msg)
+        if v then fail(msg, "expected a falsy value, got " .. show(v)) end
+    end
+
+    function checks.matches(pattern, s, msg)
+        if type(s) ~= "string" or not s:find(pattern) then
+            fail(msg, fmt("expected %s to match %s", show(s), show(pattern)))
+        end
+    end
+
+    function checks.has_error(fn, expected, msg)
+        local ok, err = pcall(fn)
+        if ok then
+            fail(msg, "expected an error")
+        elseif expected ~= nil and not tostring(err):find(expected, 1, true) then
+            fail(msg, fmt("expected an error containing %s, got %s", show(expected), show(err)))
+        end
+    end
+
+    function checks.fail(msg)
+        fail(msg, "failed")
+    end
+
+    assert = setmetatable(checks, {
+        __call = function(_, ...) return lua_assert(...) end,
+    })
+
+    local root, current
+
+    local function block(name, parent)
+        return { name = name, parent = parent, before = {}, after = {}, children = {} }
+    end
+
+    local function full_name(node)
+        local names = {}
+        while node do
+            if node.name then
+                table.insert(names, 1, node.name)
+            end
+            node = node.parent
+        end
+        return concat(names, " ")
+    end
+
+    local function add(node)
+        local children = current.children
+        children[#children + 1] = node
+    end
+
+    function describe(name, fn)
+        local node = block(name, current)
+        add(node)
+
+        local parent = current
+        current = node
+        local ok, err = xpcall(fn, traceback)
+        current = parent
+
+        if not ok then
+            record("error", full_name(node), 0, err)
+        end
+    end
+    context = describe
+
+    function it(name, fn)
+        add({ name = name, parent = current, fn = fn, pending = fn == nil })
+    end
+
+    function pending(name)
+        add({ name = name, parent = current, pending = true })
+    end
+
+    function before_each(fn)
+        local before = current.before
+        before[#before + 1] = fn
+    end
+
+    function after_each(fn)
+        local after = current.after
+        after[#after + 1] = fn
+    end
+
+    local function hooks(node, kind, list)
+        if node.parent then
+            hooks(node.parent, kind, list)
+        end
+        for _, fn in ipairs(node[kind]) do
+            list[#list + 1] = fn
+        end
+        return list
+    end
+
+    local function run_test(test)
+        local name = full_name(test)
+        if spec_filter and not name:find(spec_filter) then
+            return
+        end
+
+        if test.pending then
+            return record("skip", name, 0)
+        end
+
+        local started = now()
+        local ok, err = true, nil
+
+        for _, fn in ipairs(hooks(test.parent, "before", {})) do
+            ok, err = xpcall(fn, traceback)
+            if not ok then
+                break
+            end
+        end
+
+        if ok then
+            ok, err = xpcall(test.fn, traceback)
+        end
+
+        -- innermost first
+        local after = hooks(test.parent, "after", {})
+        for i = #after, 1, -1 do
+            local after_ok, after_err = xpcall(after[i], traceback)
+            if ok and not after_ok then
+                ok, err = after_ok, after_err
+            end
+        end
+
+        record(ok and "ok" or "fail", name, now() - started, err)
+    end
+
+    local function run(node)
+        for _, child in ipairs(node.children) do
+            if child.children then
+                run(child)
+            else
+                run_test(child)
+            end
+        end
+    end
+
+    for _, fname in ipairs(spec_files) do
+        file = fname
+        root = block(nil, nil)
+        current = root
+
+        local chunk, err
+        local f, open_err = io.open(fname, "r")
+        if f then
+            local src = f:read("*a")
+            f:close()
+            src = src:gsub("^#![^\n]*", "", 1)
+            chunk, err = loadstring(src, "@" .. fname)
+        else
+            err = open_err
+        end
+
+        if chunk then
+            local ok, run_err = xpcall(chunk, traceback)
+            if ok then
+                run(root)
+            else
+                err = run_err
+            end
+        end
+
+        if err then
+            record("error", "", 0, err)
+        end
+    end
+
+    results:close()
+end"##;
+
+/// The Lua code that runs the test files, for `LuaGenerator`.
+pub(crate) fn lua_loader(test: &TestArgs, prefix: &Prefix) -> Vec<String> {
+    let files: Vec<String> = test.files.iter().map(|file| file.lua_quote()).collect();
+
+    let mut lines = vec![
+        format!("local spec_files = {{ {} }}", files.join(", ")),
+        format!(
+            "local spec_filter = {}",
+            test.filter
+                .as_ref()
+                .map_or("nil".to_string(), |filter| filter.lua_quote())
+        ),
+        format!(
+            "local spec_results = {}",
+            prefix.root.join(RESULTS).to_string_lossy().lua_quote()
+        ),
+    ];
+
+    lines.extend(SPEC_LUA.lines().map(String::from));
+    lines
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Fail,
+    Error,
+    Skip,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TestResult {
+    outcome: Outcome,
+    file: String,
+    name: String,
+    time: Duration,
+    message: String,
+}
+
+impl TestResult {
+    /// The name to report, which is the file for errors outside of any
+    /// `describe` block.
+    fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.file
+        } else {
+            &self.name
+        }
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+/// Parse the results file written by the test runner.
+fn parse_results(src: &str) -> Vec<TestResult> {
+    src.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, '\t');
+
+            let outcome = match fields.next()? {
+                "ok" => Outcome::Ok,
+                "fail" => Outcome::Fail,
+                "error" => Outcome::Error,
+                "skip" => Outcome::Skip,
+                _ => return None,
+            };
+
+            Some(TestResult {
+                outcome,
+                file: unescape(fields.next()?),
+                name: unescape(fields.next()?),
+                time: fields
+                    .next()?
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .unwrap_or_default(),
+                message: unescape(fields.next().unwrap_or_default()),
+            })
+        })
+        .collect()
+}
+
+/// Format a result as a TAP test line, with the failure message as a YAML
+/// block.
+fn tap_line(number: usize, result: &TestResult) -> String {
+    // `#` starts a directive, and a line break would end the test line
+    let name = result
+        .display_name()
+        .replace('#', "\\#")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+
+    let mut out = match result.outcome {
+        Outcome::Ok => format!("ok {number} - {name}"),
+        Outcome::Skip => format!("ok {number} - {name} # SKIP"),
+        Outcome::Fail | Outcome::Error => format!("not ok {number} - {name}"),
+    };
+
+    if !result.message.is_empty() {
+        out.push_str("\n  ---\n  message: |\n");
+        for line in result.message.lines() {
+            let _ = writeln!(out, "    {line}");
+        }
+        out.push_str("  ...");
+    }
+
+    out
+}
+
+/// Format captured output as TAP comments.
+fn tap_comments(output: &str) -> String {
+    output.lines().map(|line| format!("# {line}\n")).collect()
+}
+
+/// The results for one test file.
+#[derive(Debug, Default)]
+struct Suite {
+    file: String,
+    results: Vec<TestResult>,
+
+    /// Output of the nginx instance that ran this file (the first one, when
+    /// running all files in one instance).
+    stdout: String,
+    stderr: String,
+}
+
+impl Suite {
+    fn count(&self, outcome: Outcome) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome == outcome)
+            .count()
+    }
+
+    fn time(&self) -> Duration {
+        self.results.iter().map(|result| result.time).sum()
+    }
+}
+
+/// An attribute for the JUnit report. Tabs and newlines are written as
+/// character references, since XML parsers turn them into spaces otherwise.
+fn attr<'a>(key: &'a str, value: impl ToString) -> Attribute<'a> {
+    let value = value.to_string();
+    let value = quick_xml::escape::escape(xml_chars(&value))
+        .replace('\t', "&#9;")
+        .replace('\n', "&#10;");
+
+    Attribute {
+        key: QName(key),
+        value: value.into(),
+    }
+}
+
+/// The counts and the time for a `<testsuites>` or `<testsuite>` element.
+fn totals<'a>(suites: &[&Suite]) -> [Attribute<'a>; 5] {
+    let total = |outcome| -> usize { suites.iter().map(|suite| suite.count(outcome)).sum() };
+    let tests: usize = suites.iter().map(|suite| suite.results.len()).sum();
+    let time: Duration = suites.iter().map(|suite| suite.time()).sum();
+
+    [
+        attr("tests", tests),
+        attr("failures", total(Outcome::Fail)),
+        attr("errors", total(Outcome::Error)),
+        attr("skipped", total(Outcome::Skip)),
+        attr("time", format!("{:.3}", time.as_secs_f64())),
+    ]
+}
+
+fn write_testcase<W: io::Write>(
+    xml: &mut Writer<W>,
+    suite: &Suite,
+    result: &TestResult,
+) -> io::Result<()> {
+    let testcase = xml.create_element("testcase").with_attributes([
+        attr("classname", &suite.file),
+        attr("name", result.display_name()),
+        attr("time", format!("{:.3}", result.time.as_secs_f64())),
+    ]);
+
+    let element = match result.outcome {
+        Outcome::Ok => {
+            testcase.write_empty()?;
+            return Ok(());
+        }
+        Outcome::Skip => {
+            testcase.write_inner_content(|xml| {
+                xml.create_element("skipped").write_empty()?;
+                Ok(())
+            })?;
+            return Ok(());
+        }
+        Outcome::Fail => "failure",
+        Outcome::Error => "error",
+    };
+
+    let summary = result.message.lines().next().unwrap_or_default();
+    testcase.write_inner_content(|xml| {
+        xml.create_element(element)
+            .with_attribute(attr("message", summary))
+            .write_text_content(BytesText::new(&xml_chars(&result.message)))?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+fn write_junit<W: io::Write>(xml: &mut Writer<W>, suites: &[Suite]) -> io::Result<()> {
+    xml.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+    let all: Vec<&Suite> = suites.iter().collect();
+    xml.create_element("testsuites")
+        .with_attributes(totals(&all))
+        .write_inner_content(|xml| {
+            for suite in suites {
+                xml.create_element("testsuite")
+                    .with_attribute(attr("name", &suite.file))
+                    .with_attributes(totals(&[suite]))
+                    .write_inner_content(|xml| {
+                        for result in &suite.results {
+                            write_testcase(xml, suite, result)?;
+                        }
+
+                        for (element, output) in
+                            [("system-out", &suite.stdout), ("system-err", &suite.stderr)]
+                        {
+                            if !output.is_empty() {
+                                xml.create_element(element)
+                                    .write_text_content(BytesText::new(&xml_chars(output)))?;
+                            }
+                        }
+
+                        Ok(())
+                    })?;
+            }
+
+            Ok(())
+        })?;
+
+    xml.get_mut().write_all(b"\n")
+}
+
+/// Format the results as a JUnit XML report.
+fn junit(suites: &[Suite]) -> String {
+    let mut xml = Writer::new_with_indent(vec![], b' ', 2);
+    write_junit(&mut xml, suites).expect("writing to a Vec doesn't fail");
+    String::from_utf8(xml.into_inner()).expect("the report is built from strings")
+}
+
+/// What happened when running one nginx instance.
+struct Batch {
+    results: Vec<TestResult>,
+    code: i32,
+    stdout: String,
+    stderr: String,
+    coverage: Coverage,
+    profile: Profile,
+}
+
+fn run_batch(user: UserArgs) -> Result<Batch, Error> {
+    let (status, (results, coverage, profile)) = run_captured(user, true, |prefix| {
+        let results = fs::read_to_string(prefix.root.join(RESULTS))
+            .map(|src| parse_results(&src))
+            .unwrap_or_default();
+        (results, Coverage::read(prefix), Profile::read(prefix))
+    })?;
+
+    let (stdout, stderr) = status.output.unwrap_or_default();
+
+    Ok(Batch {
+        results,
+        code: status.code,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        coverage,
+        profile,
+    })
+}
+
+/// Run the tests, printing TAP to stdout, and return the exit code.
+pub(crate) fn run(user: Box<UserArgs>) -> i32 {
+    let batches: Vec<Vec<String>> = if user.test.single_instance {
+        vec![user.test.files.clone()]
+    } else {
+        user.test
+            .files
+            .iter()
+            .map(|file| vec![file.clone()])
+            .collect()
+    };
+
+    let mut suites: Vec<Suite> = user
+        .test
+        .files
+        .iter()
+        .map(|file| Suite {
+            file: file.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+    println!("TAP version 13");
+
+    let mut count = 0;
+    let mut interrupted = None;
+    let mut coverage = Coverage::default();
+    let mut profile = Profile::default();
+
+    for files in batches {
+        let mut batch_user = user.as_ref().clone();
+        batch_user.test.files = files.clone();
+
+        let mut batch = match run_batch(batch_user) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("{e}");
+                return e.exit_code();
+            }
+        };
+
+        coverage.merge(std::mem::take(&mut batch.coverage));
+        profile.merge(std::mem::take(&mut batch.profile));
+
+        if run::interrupted() {
+            interrupted = Some(batch.code);
+        } else if batch.code != 0 {
+            // blame the file that was running when nginx went away
+            let file = batch
+                .results
+                .last()
+                .map_or(files[0].clone(), |result| result.file.clone());
+
+            batch.results.push(TestResult {
+                outcome: Outcome::Error,
+                file,
+                name: String::new(),
+                time: Duration::ZERO,
+                message: format!("nginx exited with code {}", batch.code),
+            });
+        }
+
+        let mut current = None;
+        for result in batch.results {
+            if current.as_ref() != Some(&result.file) {
+                println!("# {}", result.file);
+                current = Some(result.file.clone());
+            }
+
+            count += 1;
+            println!("{}", tap_line(count, &result));
+
+            if let Some(suite) = suites.iter_mut().find(|suite| suite.file == result.file) {
+                suite.results.push(result);
+            }
+        }
+
+        print!("{}", tap_comments(&batch.stdout));
+        print!("{}", tap_comments(&batch.stderr));
+
+        if let Some(suite) = suites.iter_mut().find(|suite| suite.file == files[0]) {
+            suite.stdout = batch.stdout;
+            suite.stderr = batch.stderr;
+        }
+
+        if interrupted.is_some() {
+            break;
+        }
+    }
+
+    let total = |outcome| -> usize { suites.iter().map(|suite| suite.count(outcome)).sum() };
+    let (failed, errors) = (total(Outcome::Fail), total(Outcome::Error));
+
+    println!("1..{count}");
+    println!(
+        "# tests {count}, passed {}, failed {failed}, errors {errors}, skipped {}",
+        total(Outcome::Ok),
+        total(Outcome::Skip),
+    );
+
+    if let Some(path) = &user.test.junit {
+        if let Err(e) = fs::write(path, junit(&suites)) {
+            eprintln!("failed writing JUnit report to {}: {}", path.display(), e);
+        }
+    }
+
+    if user.coverage.enabled {
+        if let Err(e) = coverage.write(&user.coverage) {
+            eprintln!("{e}");
+        }
+    }
+
+    if user.profile.enabled() {
+        if let Err(e) = profile.write(&user.profile) {
+            eprintln!("{e}");
+        }
+    }
+
+    match interrupted {
+        Some(code) => code,
+        None if failed + errors > 0 => 1,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::KeepPrefix;
+    use crate::util::tempdir;
+    use std::env;
+
+    fn result(outcome: Outcome, name: &str, message: &str) -> TestResult {
+        TestResult {
+            outcome,
+            file: "spec/a_spec.lua".to_string(),
+            name: name.to_string(),
+            time: Duration::from_millis(2),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn discover_test_files() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        for path in [
+            "spec/b_spec.lua",
+            "spec/a_spec.lua",
+            "spec/nested/c_test.lua",
+            "spec/helpers.lua",
+            "spec/.hidden/d_spec.lua",
+            "other.lua",
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        assert_eq!(
+            Ok(vec![
+                path("spec/a_spec.lua"),
+                path("spec/b_spec.lua"),
+                path("spec/nested/c_test.lua"),
+            ]),
+            discover(&[path("spec")])
+        );
+
+        // files are taken as given, and only run once
+        assert_eq!(
+            Ok(vec![path("other.lua"), path("spec/nested/c_test.lua")]),
+            discover(&[path("other.lua"), path("spec/nested")])
+        );
+
+        assert_eq!(
+            Err(ArgError::LuaFileNotFound(path("nope"))),
+            discover(&[path("nope")])
+        );
+        // hidden directories are only searched when given explicitly
+        assert_eq!(
+            Ok(vec![path("spec/.hidden/d_spec.lua")]),
+            discover(&[path("spec/.hidden")])
+        );
+
+        fs::create_dir(dir.join("empty")).unwrap();
+        assert_eq!(Err(ArgError::NoTestFiles), discover(&[path("empty")]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loader() {
+        let prefix = Prefix::new(None, None, KeepPrefix::Never).unwrap();
+        let test = TestArgs {
+            files: vec![
+                "spec/a_spec.lua".to_string(),
+                "spec/]]_test.lua".to_string(),
+            ],
+            filter: Some("adds".to_string()),
+            ..Default::default()
+        };
+
+        let lines = lua_loader(&test, &prefix);
+        assert_eq!(
+            vec![
+                "local spec_files = { [=[spec/a_spec.lua]=], [=[spec/]]_test.lua]=] }".to_string(),
+                "local spec_filter = [=[adds]=]".to_string(),
+                format!(
+                    "local spec_results = [=[{}]=]",
+                    prefix.root.join(RESULTS).display()
+                ),
+                "local spec_gen = function()".to_string(),
+            ],
+            lines[..4]
+        );
+
+        let lines = lua_loader(&TestArgs::default(), &prefix);
+        assert_eq!(
+            vec!["local spec_files = {  }", "local spec_filter = nil"],
+            lines[..2]
+        );
+    }
+
+    #[test]
+    fn results() {
+        let src = "ok\tspec/a_spec.lua\tmath adds\t0.002000\t\n\
+                   fail\tspec/a_spec.lua\tmath\\tsubtracts\t0.002000\tspec/a_spec.lua:5: expected 1, got 2\\nstack traceback:\\n\\t[C]: in function 'error'\n\
+                   bogus line\n\
+                   error\tspec/a_spec.lua\t\t0.000000\tboom\n";
+
+        assert_eq!(
+            vec![
+                result(Outcome::Ok, "math adds", ""),
+                result(
+                    Outcome::Fail,
+                    "math\tsubtracts",
+                    "spec/a_spec.lua:5: expected 1, got 2\nstack traceback:\n\t[C]: in function 'error'"
+                ),
+                TestResult {
+                    time: Duration::ZERO,
+                    ..result(Outcome::Error, "", "boom")
+                },
+            ],
+            parse_results(src)
+        );
+
+        assert_eq!(r"a\b", unescape(r"a\\b"));
+        assert_eq!("a\\", unescape("a\\"));
+    }
+
+    /// The results file that the runner writes for `spec/math_spec.lua`:
+    ///
+    /// ```lua
+    /// describe("math\tops", function()
+    ///   it("adds #1", function() end)
+    ///   it("handles\nnewlines", function() error("expected 3, got 4") end)
+    ///   pending("C:\\path")
+    /// end)
+    /// ```
+    ///
+    /// and for `spec/broken_spec.lua`, which doesn't compile.
+    const RUNNER_OUTPUT: &str = concat!(
+        "ok\tspec/math_spec.lua\tmath\\tops adds #1\t0.000117\t\n",
+        "fail\tspec/math_spec.lua\tmath\\tops handles\\nnewlines\t0.000231\t",
+        "spec/math_spec.lua:3: expected 3, got 4\\nstack traceback:\\n",
+        "\\t[C]: in function 'error'\\n",
+        "\\tspec/math_spec.lua:3: in function <spec/math_spec.lua:3>\n",
+        "skip\tspec/math_spec.lua\tmath\\tops C:\\\\path\t0.000000\t\n",
+        "error\tspec/broken_spec.lua\t\t0.000000\t",
+        "spec/broken_spec.lua:1: '=' expected near 'x'\n",
+    );
+
+    fn runner_results() -> Vec<TestResult> {
+        parse_results(RUNNER_OUTPUT)
+    }
+
+    #[test]
+    fn runner_output() {
+        let results = runner_results();
+
+        assert_eq!(
+            vec![
+                "math\tops adds #1",
+                "math\tops handles\nnewlines",
+                "math\tops C:\\path",
+                "spec/broken_spec.lua",
+            ],
+            results
+                .iter()
+                .map(TestResult::display_name)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Outcome::Ok, Outcome::Fail, Outcome::Skip, Outcome::Error],
+            results.iter().map(|r| r.outcome).collect::<Vec<_>>()
+        );
+        assert_eq!(Duration::from_micros(231), results[1].time);
+        assert_eq!(
+            "spec/math_spec.lua:3: expected 3, got 4\nstack traceback:\n\t[C]: in function 'error'\n\tspec/math_spec.lua:3: in function <spec/math_spec.lua:3>",
+            results[1].message
+        );
+
+        assert_eq!("a\tb\nc\rd\\e", unescape(r"a\tb\nc\rd\\e"));
+        assert_eq!("q", unescape(r"\q"));
+
+        let tap: Vec<String> = results
+            .iter()
+            .enumerate()
+            .map(|(i, result)| tap_line(i + 1, result))
+            .collect();
+        assert_eq!(
+            vec![
+                "ok 1 - math\tops adds \\#1",
+                "not ok 2 - math\tops handles\\nnewlines\n  ---\n  message: |\n    spec/math_spec.lua:3: expected 3, got 4\n    stack traceback:\n    \t[C]: in function 'error'\n    \tspec/math_spec.lua:3: in function <spec/math_spec.lua:3>\n  ...",
+                "ok 3 - math\tops C:\\path # SKIP",
+                "not ok 4 - spec/broken_spec.lua\n  ---\n  message: |\n    spec/broken_spec.lua:1: '=' expected near 'x'\n  ...",
+            ],
+            tap
+        );
+
+        let result = TestResult {
+            name: "crlf\r\nname".to_string(),
+            message: String::new(),
+            ..results[0].clone()
+        };
+        assert_eq!("ok 5 - crlf\\r\\nname", tap_line(5, &result));
+    }
+
+    #[test]
+    fn runner_junit() {
+        let mut results = runner_results();
+        let broken = results.split_off(3);
+        let suites = [
+            Suite {
+                file: "spec/math_spec.lua".to_string(),
+                results,
+                stdout: "\u{1b}[32mcolored\u{1b}[0m\n".to_string(),
+                ..Default::default()
+            },
+            Suite {
+                file: "spec/broken_spec.lua".to_string(),
+                results: broken,
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites tests="4" failures="1" errors="1" skipped="1" time="0.000">
+  <testsuite name="spec/math_spec.lua" tests="3" failures="1" errors="0" skipped="1" time="0.000">
+    <testcase classname="spec/math_spec.lua" name="math&#9;ops adds #1" time="0.000"/>
+    <testcase classname="spec/math_spec.lua" name="math&#9;ops handles&#10;newlines" time="0.000">
+      <failure message="spec/math_spec.lua:3: expected 3, got 4">spec/math_spec.lua:3: expected 3, got 4
+stack traceback:
+	[C]: in function &apos;error&apos;
+	spec/math_spec.lua:3: in function &lt;spec/math_spec.lua:3&gt;</failure>
+    </testcase>
+    <testcase classname="spec/math_spec.lua" name="math&#9;ops C:\path" time="0.000">
+      <skipped/>
+    </testcase>
+    <system-out>[32mcolored[0m
+</system-out>
+  </testsuite>
+  <testsuite name="spec/broken_spec.lua" tests="1" failures="0" errors="1" skipped="0" time="0.000">
+    <testcase classname="spec/broken_spec.lua" name="spec/broken_spec.lua" time="0.000">
+      <error message="spec/broken_spec.lua:1: &apos;=&apos; expected near &apos;x&apos;">spec/broken_spec.lua:1: &apos;=&apos; expected near &apos;x&apos;</error>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#,
+            junit(&suites)
+        );
+    }
+
+    #[test]
+    fn tap_output() {
+        assert_eq!("ok 1 - adds", tap_line(1, &result(Outcome::Ok, "adds", "")));
+        assert_eq!(
+            "ok 2 - issue \\#1 # SKIP",
+            tap_line(2, &result(Outcome::Skip, "issue #1", ""))
+        );
+        assert_eq!(
+            "not ok 3 - subtracts\n  ---\n  message: |\n    expected 1, got 2\n    stack traceback:\n  ...",
+            tap_line(
+                3,
+                &result(Outcome::Fail, "subtracts", "expected 1, got 2\nstack traceback:")
+            )
+        );
+        assert_eq!(
+            "not ok 4 - spec/a_spec.lua\n  ---\n  message: |\n    boom\n  ...",
+            tap_line(4, &result(Outcome::Error, "", "boom"))
+        );
+        assert_eq!("# a\n# b\n", tap_comments("a\nb\n"));
+    }
+
+    #[test]
+    fn junit_output() {
+        let suites = [
+            Suite {
+                file: "spec/a_spec.lua".to_string(),
+                results: vec![
+                    result(Outcome::Ok, "adds", ""),
+                    result(Outcome::Fail, "<subtracts>", "expected 1, got 2\ntraceback"),
+                    result(Outcome::Skip, "later", ""),
+                ],
+                stdout: "hi & bye\n".to_string(),
+                stderr: String::new(),
+            },
+            Suite {
+                file: "spec/b_spec.lua".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites tests="3" failures="1" errors="0" skipped="1" time="0.006">
+  <testsuite name="spec/a_spec.lua" tests="3" failures="1" errors="0" skipped="1" time="0.006">
+    <testcase classname="spec/a_spec.lua" name="adds" time="0.002"/>
+    <testcase classname="spec/a_spec.lua" name="&lt;subtracts&gt;" time="0.002">
+      <failure message="expected 1, got 2">expected 1, got 2
+traceback</failure>
+    </testcase>
+    <testcase classname="spec/a_spec.lua" name="later" time="0.002">
+      <skipped/>
+    </testcase>
+    <system-out>hi &amp; bye
+</system-out>
+  </testsuite>
+  <testsuite name="spec/b_spec.lua" tests="0" failures="0" errors="0" skipped="0" time="0.000">
+  </testsuite>
+</testsuites>
+"#,
+            junit(&suites)
+        );
+    }
+}
@@ -1,9 +1,12 @@
-use crate::util::tempdir;
+use crate::lua::LuaString;
+use crate::util::{temp_root, tempdir, try_split_shell_args, validate_temp_root};
+use nix::sys::resource::{rlim_t, Resource, RLIM_INFINITY};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::fs;
 use std::net;
 use std::path::PathBuf;
 use std::string::ToString;
+use std::time::Duration;
 use thiserror::Error as ThisError;
 
 fn trim_brackets(s: &str) -> &str {
@@ -44,6 +47,15 @@ impl From<&IpAddr> for String {
     }
 }
 
+impl From<net::IpAddr> for IpAddr {
+    fn from(addr: net::IpAddr) -> Self {
+        IpAddr {
+            str: addr.to_string(),
+            inner: addr,
+        }
+    }
+}
+
 impl std::str::FromStr for IpAddr {
     type Err = String;
 
@@ -68,29 +80,44 @@ impl IpAddr {
     }
 }
 
+/// A lua shared dict (`--shdict`), parsed from `"NAME SIZE"`.
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub(crate) struct Shdict(String);
+pub struct Shdict {
+    name: String,
+    size: String,
+}
 
 impl From<Shdict> for String {
     fn from(val: Shdict) -> Self {
-        val.0
+        val.to_string()
     }
 }
 
 impl Display for Shdict {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{}", self.0)
+        write!(f, "{} {}", self.name, self.size)
     }
 }
 
 impl Shdict {
+    /// A shared dict named NAME (letters, digits and `_`) of SIZE bytes,
+    /// with an optional `k` or `m` suffix.
+    pub fn new(name: &str, size: &str) -> Result<Self, InvalidShdict> {
+        let spec = format!("{name} {size}");
+        match spec.parse::<Shdict>() {
+            Ok(shdict) if shdict.name == name && shdict.size == size => Ok(shdict),
+            _ => Err(InvalidShdict(spec)),
+        }
+    }
+
     pub(crate) fn to_nginx(&self) -> String {
-        format!("lua_shared_dict {};", self.0)
+        format!("lua_shared_dict {};", self)
     }
 }
 
+/// The error for an invalid [`Shdict`].
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub(crate) struct InvalidShdict(String);
+pub struct InvalidShdict(String);
 
 impl From<&str> for InvalidShdict {
     fn from(input: &str) -> Self {
@@ -138,27 +165,59 @@ impl std::str::FromStr for Shdict {
             .parse::<u32>()
             .map_err(|_| InvalidShdict::from(s))?;
 
-        Ok(Shdict(format!("{} {}", name, size)))
+        Ok(Shdict {
+            name: name.to_string(),
+            size: size.to_string(),
+        })
     }
 }
 
-#[derive(Clone, Debug, Default, strum_macros::Display, strum_macros::EnumString, PartialEq, Eq)]
+/// An nginx error_log level (`--errlog-level`).
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    strum_macros::Display,
+    strum_macros::EnumString,
+    strum_macros::VariantNames,
+    PartialEq,
+    Eq,
+)]
 #[strum(serialize_all = "lowercase")]
-pub(crate) enum LogLevel {
+pub enum LogLevel {
+    /// `debug`
     Debug,
+    /// `info`
     Info,
+    /// `notice`
     Notice,
+    /// `warn`, the default.
     #[default]
     Warn,
+    /// `error`
     Error,
+    /// `crit`
     Crit,
+    /// `alert`
     Alert,
+    /// `emerg`
     Emerg,
 }
 
-#[derive(Clone, Debug, strum_macros::EnumString, PartialEq, Eq)]
+/// The LuaJIT command of a `-j` value.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    strum_macros::EnumString,
+    strum_macros::VariantNames,
+    strum_macros::EnumMessage,
+    strum_macros::AsRefStr,
+    PartialEq,
+    Eq,
+)]
 #[strum(serialize_all = "lowercase")]
-pub(crate) enum JitCmd {
+pub enum JitModule {
     /// Use LuaJIT's jit.v module to output brief info of the
     /// traces generated by the JIT compiler.
     V,
@@ -167,30 +226,330 @@ pub(crate) enum JitCmd {
     /// the traces generated by the JIT compiler.
     Dump,
 
+    /// Turn on the LuaJIT JIT compiler.
+    On,
+
     /// Turn off the LuaJIT JIT compiler.
     Off,
+
+    /// Flush the LuaJIT JIT compiler's cache of compiled code.
+    Flush,
+
+    /// Use LuaJIT's sampling profiler, writing the stacks to
+    /// lua-profile.folded (or the --lua-profile file).
+    P,
+}
+
+impl JitModule {
+    /// The description of a `-j` value, for help text.
+    pub(crate) fn help(name: &str) -> Option<&'static str> {
+        use strum::EnumMessage;
+        name.parse::<JitModule>().ok()?.get_documentation()
+    }
+
+    /// How many arguments the command takes: the output file for `v`, and
+    /// the dump mode and output file for `dump`.
+    fn max_args(self) -> usize {
+        match self {
+            JitModule::V => 1,
+            JitModule::Dump => 2,
+            JitModule::On | JitModule::Off | JitModule::Flush | JitModule::P => 0,
+        }
+    }
+}
+
+/// A `-j` value: a LuaJIT command with optional arguments, like
+/// `luajit -j cmd[=arg[,arg...]]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JitCmd {
+    pub(crate) module: JitModule,
+    pub(crate) args: Vec<String>,
+}
+
+impl std::str::FromStr for JitCmd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = match s.split_once('=') {
+            Some((name, args)) => (name, args.split(',').map(String::from).collect()),
+            None => (s, vec![]),
+        };
+
+        let module: JitModule = name
+            .parse()
+            .map_err(|_| format!("unknown LuaJIT command `{name}`"))?;
+
+        let max = module.max_args();
+        if args.len() > max {
+            return Err(match (module, max) {
+                (JitModule::P, _) => "`p` takes no arguments; see --lua-profile".to_string(),
+                (_, 0) => format!("`{name}` takes no arguments"),
+                (_, 1) => format!("`{name}` takes at most 1 argument"),
+                (_, max) => format!("`{name}` takes at most {max} arguments"),
+            });
+        }
+
+        Ok(Self { module, args })
+    }
+}
+
+impl Display for JitCmd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.module.as_ref())?;
+        if !self.args.is_empty() {
+            write!(f, "={}", self.args.join(","))?;
+        }
+        Ok(())
+    }
 }
 
 impl JitCmd {
-    pub(crate) fn to_lua(&self) -> String {
+    /// The Lua code to run before everything else, if any. The profiler
+    /// is set up separately, around the Lua code (see `profile`).
+    pub(crate) fn to_lua(&self) -> Option<String> {
+        // an empty argument (`dump=,out.txt`) means the default
+        let args = self
+            .args
+            .iter()
+            .map(|arg| match arg.as_str() {
+                "" => "nil".to_string(),
+                arg => arg.lua_quote(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        match self.module {
+            JitModule::V | JitModule::Dump => Some(format!(
+                r#"require "jit.{}".on({args})"#,
+                self.module.as_ref()
+            )),
+            JitModule::On | JitModule::Off | JitModule::Flush => {
+                Some(format!(r#"require "jit".{}()"#, self.module.as_ref()))
+            }
+            JitModule::P => None,
+        }
+    }
+}
+
+const JIT_OPT_FLAGS: &[&str] = &[
+    "fold", "cse", "dce", "narrow", "loop", "fwd", "dse", "abc", "sink", "fuse", "fma",
+];
+
+const JIT_OPT_PARAMS: &[&str] = &[
+    "maxtrace",
+    "maxrecord",
+    "maxirconst",
+    "maxside",
+    "maxsnap",
+    "minstitch",
+    "hotloop",
+    "hotexit",
+    "tryside",
+    "instunroll",
+    "loopunroll",
+    "callunroll",
+    "recunroll",
+    "sizemcode",
+    "maxmcode",
+];
+
+/// LuaJIT optimization settings from `-O`, like `luajit -O`: levels (`3`),
+/// flags (`+fold`, `-dce`), and parameters (`hotloop=5`), separated by
+/// commas.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JitOpts(Vec<String>);
+
+impl std::str::FromStr for JitOpts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for opt in s.split(',') {
+            if let Some((param, value)) = opt.split_once('=') {
+                if !JIT_OPT_PARAMS.contains(&param) {
+                    return Err(format!("unknown optimization parameter `{param}`"));
+                }
+                if value.parse::<u32>().is_err() {
+                    return Err(format!("expected a number for `{param}`"));
+                }
+                continue;
+            }
+
+            if matches!(opt, "0" | "1" | "2" | "3") {
+                continue;
+            }
+
+            let flag = opt.strip_prefix(['+', '-']).unwrap_or(opt);
+            if flag.is_empty() {
+                return Err("expected a level, a flag, or PARAM=VALUE".to_string());
+            }
+            if !JIT_OPT_FLAGS.contains(&flag) {
+                return Err(format!("unknown optimization flag `{flag}`"));
+            }
+        }
+
+        Ok(Self(s.split(',').map(String::from).collect()))
+    }
+}
+
+impl Display for JitOpts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.0.join(","))
+    }
+}
+
+impl JitOpts {
+    /// Add the settings from another `-O`.
+    pub(crate) fn extend(&mut self, other: JitOpts) {
+        self.0.extend(other.0);
+    }
+
+    /// The call to `jit.opt.start()`, if there are any settings.
+    pub(crate) fn to_lua(&self) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let args: Vec<String> = self.0.iter().map(|opt| opt.lua_quote()).collect();
+        Some(format!(r#"require "jit.opt".start({})"#, args.join(", ")))
+    }
+}
+
+/// Arguments split the way a shell would split them (e.g. `--gdb-opts`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ShellArgs(pub(crate) Vec<String>);
+
+impl std::str::FromStr for ShellArgs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        try_split_shell_args(s)
+            .map(Self)
+            .ok_or_else(|| "unbalanced quotes".to_string())
+    }
+}
+
+/// A non-negative number of seconds, with an optional fractional part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Seconds(Duration);
+
+impl From<Seconds> for Duration {
+    fn from(val: Seconds) -> Self {
+        val.0
+    }
+}
+
+impl From<Duration> for Seconds {
+    fn from(val: Duration) -> Self {
+        Seconds(val)
+    }
+}
+
+impl std::str::FromStr for Seconds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite())
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(Seconds)
+            .ok_or_else(|| "expecting a number of seconds".to_string())
+    }
+}
+
+/// The value of a resource limit: either `unlimited` or a number with an
+/// optional binary `k`, `m`, or `g` suffix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RlimitValue {
+    Unlimited,
+    Limit(u64),
+}
+
+impl RlimitValue {
+    pub(crate) fn to_rlim(self) -> rlim_t {
         match self {
-            JitCmd::V => r#"require "jit.v".on()"#,
-            JitCmd::Dump => r#"require "jit.dump".on()"#,
-            JitCmd::Off => r#"require "jit".off()"#,
+            Self::Unlimited => RLIM_INFINITY,
+            Self::Limit(n) => n as rlim_t,
         }
-        .to_string()
     }
 }
 
-impl From<&JitCmd> for String {
-    fn from(val: &JitCmd) -> Self {
-        val.to_lua()
+impl std::str::FromStr for RlimitValue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" || s == "infinity" {
+            return Ok(Self::Unlimited);
+        }
+
+        let (num, mult) = match s.strip_suffix(['k', 'K']) {
+            Some(num) => (num, 1 << 10),
+            None => match s.strip_suffix(['m', 'M']) {
+                Some(num) => (num, 1 << 20),
+                None => match s.strip_suffix(['g', 'G']) {
+                    Some(num) => (num, 1 << 30),
+                    None => (s, 1),
+                },
+            },
+        };
+
+        num.parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(mult))
+            .filter(|n| (*n as rlim_t) != RLIM_INFINITY)
+            .map(Self::Limit)
+            .ok_or_else(|| {
+                "expecting a number (with optional k/m/g suffix) or `unlimited`".to_string()
+            })
+    }
+}
+
+/// Resource limits that can be set on the nginx process, keyed by option name.
+pub(crate) const RLIMIT_OPTS: [(&str, Resource); 7] = [
+    ("--rlimit-as", Resource::RLIMIT_AS),
+    ("--rlimit-core", Resource::RLIMIT_CORE),
+    ("--rlimit-cpu", Resource::RLIMIT_CPU),
+    ("--rlimit-data", Resource::RLIMIT_DATA),
+    ("--rlimit-nofile", Resource::RLIMIT_NOFILE),
+    ("--rlimit-nproc", Resource::RLIMIT_NPROC),
+    ("--rlimit-stack", Resource::RLIMIT_STACK),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Rlimit {
+    pub(crate) resource: Resource,
+    pub(crate) value: RlimitValue,
+}
+
+impl Rlimit {
+    pub(crate) fn resource_for_opt(opt: &str) -> Option<Resource> {
+        RLIMIT_OPTS
+            .iter()
+            .find(|(name, _)| *name == opt)
+            .map(|(_, resource)| *resource)
+    }
+
+    /// Set `resource` to `value`, replacing any previous limit for it.
+    pub(crate) fn set(limits: &mut Vec<Rlimit>, resource: Resource, value: RlimitValue) {
+        limits.retain(|lim| lim.resource != resource);
+        limits.push(Rlimit { resource, value });
     }
 }
 
+/// Controls when the prefix directory is left behind after nginx exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum KeepPrefix {
+    #[default]
+    Never,
+    OnError,
+    Always,
+}
+
 pub(crate) struct Prefix {
     pub(crate) root: PathBuf,
     pub(crate) conf: PathBuf,
+    keep: KeepPrefix,
+    remove: bool,
 }
 
 impl Debug for Prefix {
@@ -206,8 +565,35 @@ impl Display for Prefix {
 }
 
 impl Prefix {
-    pub(crate) fn new() -> Result<Self, std::io::Error> {
-        let root = tempdir()?;
+    /// Create the prefix directory.
+    ///
+    /// When `path` is `None`, a new temporary directory is created under
+    /// `tmpdir` (or the default temp root). A user-supplied directory is
+    /// created if missing and is never removed.
+    pub(crate) fn new(
+        path: Option<PathBuf>,
+        tmpdir: Option<PathBuf>,
+        keep: KeepPrefix,
+    ) -> Result<Self, std::io::Error> {
+        let (root, remove) = match path {
+            Some(path) => {
+                fs::create_dir_all(&path)?;
+                (fs::canonicalize(path)?, false)
+            }
+            None => {
+                let tmp = temp_root(tmpdir.as_deref());
+                if let Err(e) = validate_temp_root(&tmp.path) {
+                    return Err(std::io::Error::other(format!(
+                        "invalid temporary directory {} (from {}): {}",
+                        tmp.path.display(),
+                        tmp.source,
+                        e
+                    )));
+                }
+
+                (tempdir(&tmp.path)?, keep != KeepPrefix::Always)
+            }
+        };
 
         let conf = root.join("conf");
 
@@ -215,12 +601,32 @@ impl Prefix {
         fs::create_dir_all(&conf)?;
         fs::create_dir_all(root.join("logs"))?;
 
-        Ok(Prefix { root, conf })
+        Ok(Prefix {
+            root,
+            conf,
+            keep,
+            remove,
+        })
+    }
+
+    /// Clean up after nginx has exited with the given status.
+    pub(crate) fn finish(mut self, rc: i32) {
+        if rc != 0 && self.keep == KeepPrefix::OnError {
+            self.remove = false;
+        }
+
+        if rc != 0 && !self.remove {
+            eprintln!("nginx prefix directory kept at {}", self.root.display());
+        }
     }
 }
 
 impl Drop for Prefix {
     fn drop(&mut self) {
+        if !self.remove {
+            return;
+        }
+
         if let Err(e) = fs::remove_dir_all(&self.root) {
             eprintln!("Failed to remove directory {}: {}", self.root.display(), e);
         }
@@ -267,42 +673,108 @@ impl Buf {
     }
 }
 
+/// An invalid command line argument or combination of arguments.
 #[derive(ThisError, Debug, PartialEq, Eq)]
-pub(crate) enum ArgError {
+#[non_exhaustive]
+pub enum ArgError {
+    /// A `--http-include` or `--main-include` file (the section and the
+    /// file name) doesn't exist.
     #[error("ERROR: could not find {0} include file '{1}'")]
     MissingInclude(String, String),
 
+    /// Two options that can't be combined were given.
     #[error("ERROR: options {0} and {1} cannot be specified at the same time.")]
     Conflict(String, String),
 
+    /// An option was given a value it doesn't accept.
     #[error("ERROR: Invalid {arg} option value: {value}\n  ({err})")]
     InvalidValue {
+        /// The option.
         arg: String,
+        /// The value it was given.
         value: String,
+        /// What is wrong with the value.
         err: String,
     },
 
+    /// An option that doesn't exist was given.
     #[error("unknown argument: `{0}`")]
     UnknownArgument(String),
 
+    /// An option that takes a value was given last, without one.
     #[error("option {0} takes an argument but found none.")]
     MissingValue(String),
 
+    /// There is no Lua code to run.
     #[error("Neither Lua input file nor -e \"\" option specified.")]
     NoLuaInput,
 
+    /// `--watch` was given without any files to watch.
+    #[error("ERROR: --watch needs a Lua file, -I directory, or include file to watch.")]
+    NothingToWatch,
+
+    /// `rusty-cli test` found no test files.
+    #[error("ERROR: no *_spec.lua or *_test.lua files found.")]
+    NoTestFiles,
+
+    /// `rusty-cli compile` found no Lua files.
+    #[error("ERROR: no *.lua files found.")]
+    NoLuaFiles,
+
+    /// An option that can only be given once was repeated.
     #[error("duplicate {0} options")]
     Duplicate(String),
 
+    /// The Lua file doesn't exist.
     #[error("Lua input file {0} not found.")]
     LuaFileNotFound(String),
 
+    /// The argument list was empty, without even a program name.
     #[error("ARGV[0] is empty")]
     EmptyArgv0,
+
+    /// A config file couldn't be read or has an invalid setting.
+    #[error("ERROR: invalid config file {file}: {err}")]
+    Config {
+        /// The config file, with the line number if known.
+        file: String,
+        /// What is wrong with it.
+        err: String,
+    },
+
+    /// A `--!` directive in the Lua file is invalid.
+    #[error("ERROR: invalid directive in {file}: {err}")]
+    Directive {
+        /// The Lua file, with the line number if known.
+        file: String,
+        /// What is wrong with the directive.
+        err: String,
+    },
+
+    /// A bundle couldn't be read.
+    #[error("ERROR: invalid bundle {file}: {err}")]
+    InvalidBundle {
+        /// The bundle file.
+        file: String,
+        /// What is wrong with it.
+        err: String,
+    },
+
+    /// A `RUSTY_CLI_*` environment variable has an invalid value.
+    #[error("ERROR: Invalid {var} environment variable value: {value}\n  ({err})")]
+    InvalidEnv {
+        /// The environment variable.
+        var: String,
+        /// Its value.
+        value: String,
+        /// What is wrong with the value.
+        err: String,
+    },
 }
 
 impl ArgError {
-    pub(crate) fn exit_code(&self) -> i32 {
+    /// The exit code that resty-cli uses for this error.
+    pub fn exit_code(&self) -> i32 {
         match self {
             // I/O error
             Self::MissingInclude(_, _) => 2,
@@ -326,8 +798,66 @@ impl ArgError {
 
             Self::NoLuaInput => 2,
             Self::LuaFileNotFound(_) => 2,
+            Self::NothingToWatch => 2,
+            Self::NoTestFiles => 2,
+            Self::NoLuaFiles => 2,
 
             Self::Duplicate(_) => 255,
+
+            Self::Config { file: _, err: _ } => 2,
+
+            Self::Directive { file: _, err: _ } => 2,
+
+            Self::InvalidBundle { file: _, err: _ } => 2,
+
+            Self::InvalidEnv {
+                var: _,
+                value: _,
+                err: _,
+            } => 255,
+        }
+    }
+}
+
+/// An error that prevented nginx from being run.
+#[derive(ThisError, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Invalid arguments.
+    #[error(transparent)]
+    Args(#[from] ArgError),
+
+    /// The prefix directory could not be created.
+    #[error("failed creating prefix directory: {0}")]
+    Prefix(#[source] std::io::Error),
+
+    /// The Lua loader could not be generated.
+    #[error("failed to generate inline lua: {0}")]
+    Lua(#[source] std::io::Error),
+
+    /// Any other I/O error.
+    #[error("{context}: {source}")]
+    Io {
+        /// What was being done.
+        context: &'static str,
+        /// The underlying error.
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The options ask for something that only the `rusty-cli` binary
+    /// can do.
+    #[error("not supported when embedded: {0}")]
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// The exit code that the CLI uses for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Args(e) => e.exit_code(),
+            Self::Lua(e) => e.raw_os_error().unwrap_or(2),
+            Self::Prefix(_) | Self::Io { .. } | Self::Unsupported(_) => 2,
         }
     }
 }
@@ -336,6 +866,83 @@ impl ArgError {
 mod tests {
     use super::*;
 
+    #[test]
+    fn jit_cmd_from_str() {
+        let cmd = |s: &str| s.parse::<JitCmd>();
+
+        assert_eq!(
+            Ok(Some(r#"require "jit.v".on()"#.to_string())),
+            cmd("v").map(|cmd| cmd.to_lua())
+        );
+        assert_eq!(
+            Ok(Some(r#"require "jit.v".on([=[trace.log]=])"#.to_string())),
+            cmd("v=trace.log").map(|cmd| cmd.to_lua())
+        );
+        assert_eq!(
+            Ok(Some(
+                r#"require "jit.dump".on([=[+rsx]=], [=[out.txt]=])"#.to_string()
+            )),
+            cmd("dump=+rsx,out.txt").map(|cmd| cmd.to_lua())
+        );
+        assert_eq!(
+            Ok(Some(
+                r#"require "jit.dump".on(nil, [=[out.txt]=])"#.to_string()
+            )),
+            cmd("dump=,out.txt").map(|cmd| cmd.to_lua())
+        );
+        assert_eq!(
+            Ok(Some(r#"require "jit".flush()"#.to_string())),
+            cmd("flush").map(|cmd| cmd.to_lua())
+        );
+        assert_eq!(Ok(None), cmd("p").map(|cmd| cmd.to_lua()));
+
+        assert_eq!(
+            Err("unknown LuaJIT command `bogus`".to_string()),
+            cmd("bogus=1")
+        );
+        assert_eq!(Err("`off` takes no arguments".to_string()), cmd("off=1"));
+        assert_eq!(
+            Err("`v` takes at most 1 argument".to_string()),
+            cmd("v=a,b")
+        );
+        assert_eq!(
+            Err("`dump` takes at most 2 arguments".to_string()),
+            cmd("dump=a,b,c")
+        );
+        assert_eq!(
+            Err("`p` takes no arguments; see --lua-profile".to_string()),
+            cmd("p=out")
+        );
+    }
+
+    #[test]
+    fn jit_opts_from_str() {
+        let mut opts: JitOpts = "3".parse().unwrap();
+        opts.extend("-fold,+cse,dce,hotloop=5".parse().unwrap());
+        assert_eq!(
+            Some(
+                r#"require "jit.opt".start([=[3]=], [=[-fold]=], [=[+cse]=], [=[dce]=], [=[hotloop=5]=])"#
+                    .to_string()
+            ),
+            opts.to_lua()
+        );
+        assert_eq!(None, JitOpts::default().to_lua());
+
+        for value in ["-fwd", "+dse", "fwd,dse"] {
+            assert!(value.parse::<JitOpts>().is_ok(), "{value}");
+        }
+
+        for (value, err) in [
+            ("4", "unknown optimization flag `4`"),
+            ("+bogus", "unknown optimization flag `bogus`"),
+            ("hot=5", "unknown optimization parameter `hot`"),
+            ("hotloop=x", "expected a number for `hotloop`"),
+            ("3,", "expected a level, a flag, or PARAM=VALUE"),
+        ] {
+            assert_eq!(Err(err.to_string()), value.parse::<JitOpts>(), "{value}");
+        }
+    }
+
     #[test]
     fn ip_addr_from_str() {
         assert_eq!("[::1]".to_string(), "[::1]".parse::<IpAddr>().unwrap().str);
@@ -354,6 +961,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn seconds_from_str() {
+        let secs = |s: &str| s.parse::<Seconds>().map(Duration::from);
+
+        assert_eq!(Ok(Duration::from_secs(5)), secs("5"));
+        assert_eq!(Ok(Duration::from_millis(1500)), secs("1.5"));
+        assert_eq!(Ok(Duration::from_millis(250)), secs(".25"));
+        assert_eq!(Ok(Duration::ZERO), secs("0"));
+
+        for invalid in ["", "-1", "1s", "inf", "NaN", "five"] {
+            assert_eq!(
+                Err("expecting a number of seconds".to_string()),
+                secs(invalid),
+                "{invalid:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rlimit_value_from_str() {
+        let lim = |s: &str| s.parse::<RlimitValue>();
+
+        assert_eq!(Ok(RlimitValue::Unlimited), lim("unlimited"));
+        assert_eq!(Ok(RlimitValue::Unlimited), lim("infinity"));
+        assert_eq!(Ok(RlimitValue::Limit(0)), lim("0"));
+        assert_eq!(Ok(RlimitValue::Limit(65536)), lim("65536"));
+        assert_eq!(Ok(RlimitValue::Limit(64 * 1024)), lim("64k"));
+        assert_eq!(Ok(RlimitValue::Limit(512 * 1024 * 1024)), lim("512M"));
+        assert_eq!(Ok(RlimitValue::Limit(2 * 1024 * 1024 * 1024)), lim("2g"));
+
+        for invalid in ["", "-1", "k", "1.5m", "1t", "lots", "99999999999999999999g"] {
+            assert!(lim(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
     #[test]
     fn ip_addr_to_string() {
         let addr = "127.0.0.1".parse::<IpAddr>().unwrap();
@@ -369,7 +1011,10 @@ mod tests {
     #[test]
     fn shdict_from_str() {
         fn shdict(name: &str, size: &str) -> Shdict {
-            Shdict(format!("{} {}", name, size))
+            Shdict {
+                name: name.to_string(),
+                size: size.to_string(),
+            }
         }
 
         fn must_parse(input: &str, (name, size): (&str, &str)) {
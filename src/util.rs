@@ -1,18 +1,124 @@
 use crate::types::IpAddr;
-use nix::unistd::mkdtemp;
+use nix::errno::Errno;
+use nix::sys::stat::{fstat, SFlag};
+use nix::unistd::{access, mkdtemp, AccessFlags};
+use nix::NixPath;
+use std::borrow::Cow;
+use std::env;
 use std::ffi::OsString;
 use std::fs;
-use std::io::{self, BufRead, BufReader, Read};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read};
 use std::os::unix::prelude::{OsStrExt, OsStringExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-fn impl_tempdir(tpl: &str) -> io::Result<PathBuf> {
+/// Env var that overrides `TMPDIR` for the location of temporary prefix
+/// directories.
+pub(crate) const TMPDIR_VAR: &str = "RUSTY_CLI_TMPDIR";
+
+const DEFAULT_TMPDIR: &str = "/tmp";
+const MKDTEMP_BASENAME: &str = "resty_XXXXXX";
+
+fn impl_tempdir<P: ?Sized + NixPath>(tpl: &P) -> io::Result<PathBuf> {
     Ok(mkdtemp(tpl)?)
 }
 
-pub(crate) fn tempdir() -> io::Result<PathBuf> {
-    const MKDTEMP_TEMPLATE: &str = "/tmp/resty_XXXXXX";
-    impl_tempdir(MKDTEMP_TEMPLATE)
+/// Create a new temporary directory inside `root`.
+pub(crate) fn tempdir(root: &Path) -> io::Result<PathBuf> {
+    impl_tempdir(&root.join(MKDTEMP_BASENAME))
+}
+
+/// A directory in which temporary directories are created, along with a
+/// description of where its location came from.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct TempRoot {
+    pub(crate) path: PathBuf,
+    pub(crate) source: String,
+}
+
+fn impl_temp_root<F>(explicit: Option<&Path>, getenv: F) -> TempRoot
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    if let Some(path) = explicit {
+        return TempRoot {
+            path: path.to_owned(),
+            source: "--tmpdir".to_string(),
+        };
+    }
+
+    for var in [TMPDIR_VAR, "TMPDIR"] {
+        if let Some(value) = getenv(var).filter(|value| !value.is_empty()) {
+            return TempRoot {
+                path: PathBuf::from(value),
+                source: format!("${}", var),
+            };
+        }
+    }
+
+    TempRoot {
+        path: PathBuf::from(DEFAULT_TMPDIR),
+        source: "default".to_string(),
+    }
+}
+
+/// Determine where temporary directories should be created.
+///
+/// In order of precedence: `explicit` (from `--tmpdir`), `$RUSTY_CLI_TMPDIR`,
+/// `$TMPDIR`, and finally `/tmp`.
+pub(crate) fn temp_root(explicit: Option<&Path>) -> TempRoot {
+    impl_temp_root(explicit, |name| env::var_os(name))
+}
+
+/// Check that `path` is a directory that we can create files in, returning
+/// an error message suitable for humans if not.
+pub(crate) fn validate_temp_root(path: &Path) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(md) if !md.is_dir() => return Err("not a directory".to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err("directory does not exist".to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    }
+
+    match access(path, AccessFlags::W_OK | AccessFlags::X_OK) {
+        Ok(()) => Ok(()),
+        Err(Errno::EROFS) => Err("directory is on a read-only file system".to_string()),
+        Err(Errno::EACCES) => Err("directory is not writable".to_string()),
+        Err(e) => Err(e.desc().to_string()),
+    }
+}
+
+/// Describes what is attached to our stdin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stdin {
+    /// An interactive terminal.
+    Terminal,
+
+    /// A pipe or regular file that we can read a Lua script from.
+    Stream,
+
+    /// Anything else (e.g. `/dev/null` or a closed descriptor).
+    Other,
+}
+
+pub(crate) fn stdin_kind() -> Stdin {
+    let stdin = io::stdin();
+
+    if stdin.is_terminal() {
+        return Stdin::Terminal;
+    }
+
+    let Ok(st) = fstat(&stdin) else {
+        return Stdin::Other;
+    };
+
+    let fmt = SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT;
+    if fmt == SFlag::S_IFIFO || fmt == SFlag::S_IFREG {
+        Stdin::Stream
+    } else {
+        Stdin::Other
+    }
 }
 
 fn impl_try_parse_resolv_conf<T: Read>(buf: T) -> Vec<IpAddr> {
@@ -48,30 +154,38 @@ pub(crate) fn try_parse_resolv_conf() -> Vec<IpAddr> {
     impl_try_parse_resolv_conf(file)
 }
 
-pub(crate) fn split_shell_args<T: AsRef<str> + ?Sized>(s: &T) -> Vec<String> {
-    shlex::split(s.as_ref()).expect("Invalid shell args")
-}
+/// Resolve `bin` the way execvp(3) would: names containing a `/` are used
+/// as-is, and anything else is looked up in `$PATH`.
+pub(crate) fn find_in_path(bin: &Path) -> Option<PathBuf> {
+    if bin.as_os_str().as_bytes().contains(&b'/') {
+        return Some(bin.to_owned());
+    }
 
-pub(crate) trait SplitArgs {
-    fn split_shell_args(&self) -> Vec<String>;
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(bin))
+        .find(|candidate| candidate.is_file())
 }
 
-impl SplitArgs for &str {
-    fn split_shell_args(&self) -> Vec<String> {
-        split_shell_args(self)
-    }
+/// Split a string into arguments the way a shell would, returning `None` for
+/// unbalanced quotes.
+pub(crate) fn try_split_shell_args<T: AsRef<str> + ?Sized>(s: &T) -> Option<Vec<String>> {
+    shlex::split(s.as_ref())
 }
 
-impl SplitArgs for String {
-    fn split_shell_args(&self) -> Vec<String> {
-        self.as_str().split_shell_args()
-    }
-}
+/// Read the `#!` line of a file, if it has one.
+pub(crate) fn shebang_line<P: AsRef<Path>>(path: P) -> Option<String> {
+    // the kernel doesn't look further than this either
+    const MAX_LEN: u64 = 4096;
 
-impl SplitArgs for OsString {
-    fn split_shell_args(&self) -> Vec<String> {
-        self.to_string_lossy().as_ref().split_shell_args()
-    }
+    let file = fs::File::open(path).ok()?;
+    let mut line = String::new();
+    BufReader::new(file.take(MAX_LEN))
+        .read_line(&mut line)
+        .ok()?;
+
+    line.starts_with("#!")
+        .then(|| line.trim_end_matches(['\r', '\n']).to_string())
 }
 
 // The shlex crate takes a slightly different approach of wrapping the
@@ -158,6 +272,59 @@ impl JoinArgs for Vec<OsString> {
     }
 }
 
+/// Add the files under `dir` for which `matches` is true to `files`,
+/// recursively and in sorted order, skipping hidden files and directories.
+pub(crate) fn find_files(dir: &Path, matches: &dyn Fn(&Path) -> bool, files: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut names: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name())
+        .filter(|name| !name.to_string_lossy().starts_with('.'))
+        .collect();
+    names.sort();
+
+    for name in names {
+        let path = if dir == Path::new(".") {
+            PathBuf::from(name)
+        } else {
+            dir.join(name)
+        };
+
+        if path.is_dir() {
+            find_files(&path, matches, files);
+        } else if matches(&path) {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Drop the characters that XML 1.0 doesn't allow at all, even escaped:
+/// control characters other than tab, newline and carriage return, and
+/// U+FFFE and U+FFFF.
+pub(crate) fn xml_chars(s: &str) -> Cow<'_, str> {
+    let allowed = |c: char| matches!(c, '\t' | '\n' | '\r' | ' '..='\u{fffd}' | '\u{10000}'..);
+
+    if s.chars().all(allowed) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(s.chars().filter(|&c| allowed(c)).collect())
+    }
+}
+
+/// Escape text for XML, dropping characters that XML 1.0 doesn't allow.
+pub(crate) fn xml_escape(s: &str) -> String {
+    quick_xml::escape::escape(xml_chars(s)).into_owned()
+}
+
+/// The line number (starting at 1) of a byte offset in `src`, such as the
+/// start of a span reported by the TOML parser.
+pub(crate) fn line_at(src: &str, offset: usize) -> usize {
+    src.bytes().take(offset).filter(|&b| b == b'\n').count() + 1
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -178,8 +345,10 @@ mod tests {
         ];
 
         for (input, expect) in tests {
-            assert_eq!(expect, input.split_shell_args());
+            assert_eq!(expect, try_split_shell_args(input).unwrap());
         }
+
+        assert_eq!(None, try_split_shell_args("'unbalanced"));
     }
 
     #[test]
@@ -217,9 +386,9 @@ mod tests {
         ];
 
         let joined = args.join_shell_args();
-        let split = joined.split_shell_args();
+        let split = try_split_shell_args(&joined.to_string_lossy()).unwrap();
         let rejoined = split.join_shell_args();
-        let resplit = rejoined.split_shell_args();
+        let resplit = try_split_shell_args(&rejoined.to_string_lossy()).unwrap();
         assert_eq!(joined, rejoined);
         assert_eq!(args, resplit);
     }
@@ -365,6 +534,85 @@ nameserver 127.0.0.3
         assert!(matches!(e.kind(), ErrorKind::NotFound));
     }
 
+    #[test]
+    fn temp_dir_in_root() {
+        let root = impl_tempdir("/tmp/cargo_test_root_XXXXXX").expect("temp root");
+
+        let path = tempdir(&root).expect("temp dir inside root");
+        assert!(path.is_dir());
+        assert_eq!(Some(root.as_path()), path.parent());
+        assert!(path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("resty_")));
+
+        std::fs::remove_dir_all(root).expect("cleanup of temp dir");
+    }
+
+    #[test]
+    fn temp_root_precedence() {
+        fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<OsString> {
+            |name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        }
+
+        fn root(path: &str, source: &str) -> TempRoot {
+            TempRoot {
+                path: PathBuf::from(path),
+                source: source.to_string(),
+            }
+        }
+
+        assert_eq!(root("/tmp", "default"), impl_temp_root(None, env(&[])));
+
+        assert_eq!(
+            root("/a", "$TMPDIR"),
+            impl_temp_root(None, env(&[("TMPDIR", "/a")]))
+        );
+
+        assert_eq!(
+            root("/b", "$RUSTY_CLI_TMPDIR"),
+            impl_temp_root(None, env(&[("TMPDIR", "/a"), ("RUSTY_CLI_TMPDIR", "/b")]))
+        );
+
+        assert_eq!(
+            root("/a", "$TMPDIR"),
+            impl_temp_root(None, env(&[("TMPDIR", "/a"), ("RUSTY_CLI_TMPDIR", "")]))
+        );
+
+        assert_eq!(
+            root("/c", "--tmpdir"),
+            impl_temp_root(
+                Some(Path::new("/c")),
+                env(&[("TMPDIR", "/a"), ("RUSTY_CLI_TMPDIR", "/b")])
+            )
+        );
+    }
+
+    #[test]
+    fn temp_root_validation() {
+        assert_eq!(Ok(()), validate_temp_root(Path::new("/tmp")));
+
+        assert_eq!(
+            Err("directory does not exist".to_string()),
+            validate_temp_root(Path::new("/a/b/i-dont-exist"))
+        );
+
+        let root = impl_tempdir("/tmp/cargo_test_root_XXXXXX").expect("temp root");
+        let file = root.join("file");
+        std::fs::write(&file, "").expect("create file");
+
+        assert_eq!(
+            Err("not a directory".to_string()),
+            validate_temp_root(&file)
+        );
+
+        std::fs::remove_dir_all(root).expect("cleanup of temp dir");
+    }
+
     #[test]
     fn temp_dir_happy_path() {
         let result = impl_tempdir("/tmp/cargo_test_XXXXXX");
@@ -466,4 +714,12 @@ nameserver 127.0.0.3
 
         no_parse!("00000000000000");
     }
+
+    #[test]
+    fn xml_text() {
+        assert_eq!("a&quot;b&apos;c\td", xml_escape("a\"b'c\td\u{1b}"));
+        assert_eq!("&lt;a&gt; &amp; &#13;\n", xml_escape("<a> & \r\n"));
+        assert_eq!("ab\u{85}c", xml_chars("a\0b\u{85}\u{fffe}c\u{ffff}"));
+        assert!(matches!(xml_chars("plain\ttext"), Cow::Borrowed(_)));
+    }
 }
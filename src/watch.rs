@@ -0,0 +1,249 @@
+//! File watching for `--watch`.
+//!
+//! Files are watched through their parent directory, so that editors that
+//! save by writing a new file and renaming it over the old one are noticed.
+//! Directories (the `-I` paths) are watched recursively.
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for more changes before reporting them, so that saving
+/// several files at once (or an editor writing a file in several steps)
+/// only results in one rerun.
+pub(crate) const DEBOUNCE: Duration = Duration::from_millis(200);
+
+const EVENTS: AddWatchFlags = AddWatchFlags::IN_CLOSE_WRITE
+    .union(AddWatchFlags::IN_CREATE)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_MOVED_TO);
+
+/// What a watch descriptor is watching.
+struct Target {
+    dir: PathBuf,
+
+    /// The names of the files that we care about, or `None` for every file
+    /// in the directory.
+    files: Option<Vec<OsString>>,
+}
+
+impl Target {
+    fn matches(&self, name: &OsStr) -> bool {
+        match &self.files {
+            Some(files) => files.iter().any(|file| file == name),
+            // skip editor swap and backup files
+            None => {
+                let name = name.to_string_lossy();
+                !name.starts_with('.') && !name.ends_with('~')
+            }
+        }
+    }
+}
+
+pub(crate) struct Watcher {
+    inotify: Inotify,
+    targets: HashMap<WatchDescriptor, Target>,
+}
+
+impl Watcher {
+    pub(crate) fn new() -> io::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+
+        Ok(Self {
+            inotify,
+            targets: HashMap::new(),
+        })
+    }
+
+    fn add(&mut self, dir: &Path, file: Option<&OsStr>) -> io::Result<()> {
+        let wd = self.inotify.add_watch(dir, EVENTS)?;
+
+        let target = self.targets.entry(wd).or_insert_with(|| Target {
+            dir: dir.to_path_buf(),
+            files: Some(vec![]),
+        });
+
+        match (&mut target.files, file) {
+            (Some(files), Some(file)) => files.push(file.to_os_string()),
+            (files, None) => *files = None,
+            // already watching the whole directory
+            (None, Some(_)) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Watch a file for changes.
+    pub(crate) fn file(&mut self, path: &Path) -> io::Result<()> {
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a file: {}", path.display()),
+            ));
+        };
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        self.add(dir, Some(name))
+    }
+
+    /// Watch a directory and all of its subdirectories for changes.
+    pub(crate) fn dir(&mut self, path: &Path) -> io::Result<()> {
+        self.add(path, None)?;
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.')
+            {
+                self.dir(&entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for events, returning an empty list when `timeout` elapses.
+    fn read(&self, timeout: PollTimeout) -> io::Result<Vec<InotifyEvent>> {
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+
+        match poll(&mut fds, timeout) {
+            Ok(0) => Ok(vec![]),
+            Ok(_) => match self.inotify.read_events() {
+                Err(nix::errno::Errno::EAGAIN) => Ok(vec![]),
+                res => Ok(res?),
+            },
+            Err(nix::errno::Errno::EINTR) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The changed paths from a batch of events.
+    fn changes(&mut self, events: Vec<InotifyEvent>) -> io::Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+
+        for event in events {
+            let Some(target) = self.targets.get(&event.wd) else {
+                continue;
+            };
+
+            let Some(name) = event.name.as_deref().filter(|name| target.matches(name)) else {
+                continue;
+            };
+
+            let path = target.dir.join(name);
+
+            // pick up new subdirectories of watched directories
+            if target.files.is_none()
+                && event.mask.contains(AddWatchFlags::IN_ISDIR)
+                && event
+                    .mask
+                    .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+            {
+                self.dir(&path)?;
+            }
+
+            changed.push(path);
+        }
+
+        Ok(changed)
+    }
+
+    /// Block until a watched file changes.
+    ///
+    /// Once something has changed, this keeps collecting changes until none
+    /// have been seen for [`DEBOUNCE`], and returns everything that changed
+    /// (in order, without duplicates).
+    pub(crate) fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut changed: Vec<PathBuf> = vec![];
+        let debounce = PollTimeout::try_from(DEBOUNCE).expect("valid poll timeout");
+
+        loop {
+            let timeout = if changed.is_empty() {
+                PollTimeout::NONE
+            } else {
+                debounce
+            };
+
+            let events = self.read(timeout)?;
+            if events.is_empty() && !changed.is_empty() {
+                return Ok(changed);
+            }
+
+            for path in self.changes(events)? {
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn watch_files_and_dirs() {
+        let dir = tempdir(&env::temp_dir()).unwrap();
+        let script = dir.join("main.lua");
+        let lib = dir.join("lib");
+        fs::write(&script, "print(1)\n").unwrap();
+        fs::write(dir.join("other.lua"), "print(2)\n").unwrap();
+        fs::create_dir_all(lib.join("resty")).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.file(&script).unwrap();
+        watcher.dir(&lib).unwrap();
+
+        let write = |changes: Vec<(PathBuf, &'static str)>| {
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                for (path, content) in changes {
+                    fs::write(path, content).unwrap();
+                }
+            })
+        };
+
+        // files next to the watched file are ignored
+        let writer = write(vec![
+            (dir.join("other.lua"), "print(3)\n"),
+            (script.clone(), "print(4)\n"),
+            (script.clone(), "print(5)\n"),
+        ]);
+        assert_eq!(vec![script.clone()], watcher.wait().unwrap());
+        writer.join().unwrap();
+
+        // subdirectories are watched, swap files are not
+        let mod_file = lib.join("resty").join("mod.lua");
+        let writer = write(vec![
+            (lib.join("resty").join(".mod.lua.swp"), "x"),
+            (mod_file.clone(), "return {}\n"),
+        ]);
+        assert_eq!(vec![mod_file], watcher.wait().unwrap());
+        writer.join().unwrap();
+
+        // so are new ones
+        let new_dir = lib.join("new");
+        let new_file = new_dir.join("mod.lua");
+        fs::create_dir(&new_dir).unwrap();
+        assert_eq!(vec![new_dir], watcher.wait().unwrap());
+        let writer = write(vec![(new_file.clone(), "return {}\n")]);
+        assert_eq!(vec![new_file], watcher.wait().unwrap());
+        writer.join().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
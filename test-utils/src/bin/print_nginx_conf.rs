@@ -1,6 +1,5 @@
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
 use test_utils::nginx::Nginx;
 
 fn main() {
@@ -0,0 +1,39 @@
+//! Stands in for nginx when testing the REPL frontend.
+//!
+//! Each chunk received over the repl fifos is printed to stdout. Chunks that
+//! end with `do` are treated as incomplete.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use test_utils::nginx::Nginx;
+
+fn main() {
+    let nginx = Nginx::try_from_args();
+
+    let input = File::open(nginx.prefix().join("repl.in")).expect("open repl.in");
+    let mut input = BufReader::new(input);
+    let mut output = File::options()
+        .write(true)
+        .open(nginx.prefix().join("repl.out"))
+        .expect("open repl.out");
+
+    let mut len = String::new();
+    loop {
+        len.clear();
+        if input.read_line(&mut len).expect("read chunk length") == 0 {
+            break;
+        }
+
+        let len: usize = len.trim().parse().expect("parse chunk length");
+        let mut chunk = vec![0; len];
+        input.read_exact(&mut chunk).expect("read chunk");
+        let chunk = String::from_utf8(chunk).expect("invalid utf-8 chunk");
+
+        if chunk.trim_end().ends_with("do") {
+            writeln!(output, "more").expect("write status");
+        } else {
+            println!("CHUNK {:?}", chunk);
+            writeln!(output, "done").expect("write status");
+        }
+    }
+}
@@ -71,7 +71,7 @@ fn main() {
                     return;
                 };
 
-                write!(f, "{sig}\n").expect("write failed");
+                writeln!(f, "{sig}").expect("write failed");
             }
             drop(f);
         })
@@ -97,7 +97,7 @@ fn main() {
     let rc = {
         let mut f = File::open(exit_fname).expect("open exit file");
         let mut buf: [u8; 1] = [0; 1];
-        f.read(&mut buf).expect("read exit file");
+        f.read_exact(&mut buf).expect("read exit file");
 
         let c: char = buf[0].into();
         c.to_digit(10).unwrap_or(0)
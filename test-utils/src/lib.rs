@@ -18,6 +18,7 @@ pub use std::{
     str::FromStr,
 };
 use std::{
+    os::unix::fs::PermissionsExt,
     process::{Child, Stdio},
     thread::sleep,
     time::Duration,
@@ -175,6 +176,29 @@ pub fn wait_pidfile<P: Into<PathBuf>>(path: P) -> Pid {
     Pid::from_raw(num)
 }
 
+/// Write an executable shell script.
+pub fn script<P: Into<PathBuf>>(path: P, body: &str) -> PathBuf {
+    let path = path.into();
+    fs::write(&path, format!("#!/bin/sh\n{body}\n")).expect("write script");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("chmod script");
+    path
+}
+
+/// Write a shell script that stands in for nginx to `dir/nginx`.
+///
+/// `body` runs with `$prefix` set to the prefix directory (without a trailing
+/// slash) and `$conf` to the generated nginx.conf.
+pub fn fake_nginx<P: Into<PathBuf>>(dir: P, body: &str) -> PathBuf {
+    let preamble = r#"while [ $# -gt 0 ]; do
+    case "$1" in -p) prefix=${2%/}; shift;; esac
+    shift
+done
+conf="$prefix/conf/nginx.conf"
+"#;
+
+    script(dir.into().join("nginx"), &format!("{preamble}{body}"))
+}
+
 pub fn cleanup_proc<P: Into<Proc>>(p: P) -> Proc {
     p.into()
 }
@@ -36,6 +36,10 @@ impl Nginx {
         Self::new(prefix.into(), conf)
     }
 
+    pub fn prefix(&self) -> &PathBuf {
+        &self.prefix
+    }
+
     pub fn conf_filename(&self) -> PathBuf {
         if self.conf.is_absolute() {
             self.conf.clone()
@@ -0,0 +1,100 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod api {
+    use super::*;
+    use rusty_cli::{ArgError, Error, LogLevel, Options, Shdict};
+
+    #[test]
+    fn render_conf() {
+        let conf = Options::new()
+            .shdict(Shdict::new("my_cache", "1m").expect("valid shdict"))
+            .http_conf("lua_socket_log_errors off;")
+            .no_stream()
+            .eval("ngx.say('hi')")
+            .render_conf()
+            .expect("render nginx.conf");
+
+        assert!(conf.contains("lua_shared_dict my_cache 1m;"), "{conf}");
+        assert!(conf.contains("lua_socket_log_errors off;"), "{conf}");
+        assert!(!conf.contains("stream {"), "{conf}");
+
+        // the files that the config refers to exist until it is dropped
+        let prefix = conf.prefix().to_path_buf();
+        assert!(prefix.join("conf").is_dir());
+        drop(conf);
+        assert!(!prefix.exists());
+    }
+
+    #[test]
+    fn run_captures_output() {
+        let nginx = testlib::testbin("print_args");
+
+        let out = Options::new()
+            .nginx(nginx.as_str())
+            .eval("nothing")
+            .run()
+            .expect("run nginx");
+
+        assert_eq!(0, out.code);
+        assert_eq!(None, out.signal);
+        assert!(out.stderr.is_empty());
+
+        let stdout = String::from_utf8(out.stdout).expect("utf-8 output");
+        assert!(stdout.contains("ARG[3] -p"), "{stdout}");
+        assert!(stdout.contains("ARG[6] conf/nginx.conf"), "{stdout}");
+    }
+
+    #[test]
+    fn run_in_parallel() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let opts = Options::new()
+                    .nginx(nginx.as_str())
+                    .shdict(Shdict::new(&format!("dict_{i}"), "1m").expect("valid shdict"))
+                    .eval("nothing");
+
+                std::thread::spawn(move || (i, opts.run().expect("run nginx")))
+            })
+            .collect();
+
+        for handle in handles {
+            let (i, out) = handle.join().expect("thread join");
+            assert_eq!(0, out.code);
+
+            let stdout = String::from_utf8(out.stdout).expect("utf-8 output");
+            assert!(stdout.contains(&format!("lua_shared_dict dict_{i} 1m;")));
+        }
+    }
+
+    #[test]
+    fn invalid_options() {
+        let err = Options::new()
+            .worker_connections(10)
+            .errlog_level(LogLevel::Debug)
+            .eval("nothing")
+            .prefix("/dev/null/prefix")
+            .run()
+            .unwrap_err();
+
+        assert!(matches!(&err, Error::Prefix(_)), "{err:?}");
+        assert_ne!(0, err.exit_code());
+
+        assert!("loud".parse::<LogLevel>().is_err());
+        assert!(matches!(
+            Options::new().file("-").run(),
+            Err(Error::Unsupported(_))
+        ));
+
+        let err = Options::new()
+            .http_include("/does/not/exist.conf")
+            .eval("nothing")
+            .run()
+            .unwrap_err();
+        assert!(matches!(&err, Error::Args(ArgError::MissingInclude(..))));
+        assert_eq!(2, err.exit_code());
+    }
+}
@@ -0,0 +1,216 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod build_exe {
+    use super::*;
+
+    /// A stand-in for nginx that prints the `arg` table, the error log level
+    /// and the shdicts from the generated config, and the bundled module that
+    /// was extracted into the prefix.
+    const FAKE_NGINX: &str = r#"sed -n 's/^ *\(arg\[.*\)$/\1/p' "$conf"
+sed -n 's/^ *\(error_log .*\)$/\1/p' "$conf"
+sed -n 's/^ *\(lua_shared_dict .*\)$/\1/p' "$conf" | sort -u
+cat "$prefix/bundle/lua/util.lua"
+"#;
+
+    fn setup(tmp: &TmpDir) {
+        fake_nginx(tmp.path(), FAKE_NGINX);
+
+        fs::create_dir_all(tmp.join("lib")).expect("create lib dir");
+        touch!(
+            tmp.join("tool.lua"),
+            "local util = require \"util\"\nutil.run(arg)\n"
+        );
+        touch!(tmp.join("lib/util.lua"), "return { run = print }\n");
+    }
+
+    fn build(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.arg("build-exe");
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    fn tool(tmp: &TmpDir, args: &[&str]) -> Command {
+        let mut cmd = Command::new(tmp.join("tool"));
+        cmd.current_dir(tmp.path());
+        cmd.env_clear();
+        cmd.args(args);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd
+    }
+
+    #[test]
+    fn build_and_run() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        let nginx = tmp.join("nginx");
+
+        let out = build(
+            &tmp,
+            &[
+                "-I",
+                "lib",
+                "--nginx",
+                nginx.to_str().unwrap(),
+                "--errlog-level",
+                "info",
+                "--shdict",
+                "cache 1m",
+                "tool.lua",
+            ],
+        );
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec!["tool.lua", "lib/util.lua", "wrote tool"],
+            out.stdout_lines()
+        );
+
+        // options after the script are passed to it
+        let out = tool(&tmp, &["a", "--errlog-level", "debug"]).assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        let exe = tmp.join("tool");
+        assert_eq!(
+            vec![
+                format!("arg[0] = [=[{}]=]", exe.display()),
+                "arg[1] = [=[a]=]".to_string(),
+                "arg[2] = [=[--errlog-level]=]".to_string(),
+                "arg[3] = [=[debug]=]".to_string(),
+                format!("arg[-6] = [=[{}]=]", exe.display()),
+                "error_log stderr info;".to_string(),
+                "lua_shared_dict cache 1m;".to_string(),
+                "return { run = print }".to_string(),
+            ],
+            out.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn host_config_is_ignored() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        let nginx = tmp.join("nginx");
+
+        // profiles are looked up where the executable is built
+        let config = tmp.join("config");
+        let profile = config.join("rusty-cli/profiles/verbose.toml");
+        fs::create_dir_all(profile.parent().unwrap()).expect("create profile dir");
+        touch!(&profile, "errlog-level = \"info\"\n");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("XDG_CONFIG_HOME", &config);
+        cmd.args(["build-exe", "-I", "lib", "--profile", "verbose", "tool.lua"]);
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        fs::write(&profile, "errlog-level = \"debug\"\n").expect("update profile");
+        touch!(tmp.join(".rustyrc"), "shdict = \"host 1m\"\n");
+
+        // only the nginx path is taken from the host
+        let out = tool(&tmp, &[])
+            .env("XDG_CONFIG_HOME", &config)
+            .env("RUSTY_CLI_NGINX", &nginx)
+            .env("RUSTY_CLI_ERRLOG_LEVEL", "debug")
+            .assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        let lines = out.stdout_lines();
+        assert_all_matched!(vec!["error_log stderr info;"], lines.clone());
+        assert!(
+            !lines.iter().any(|line| line.contains("debug")),
+            "{lines:#?}"
+        );
+        assert!(
+            !lines.iter().any(|line| line.contains("host")),
+            "{lines:#?}"
+        );
+    }
+
+    #[test]
+    fn includes_are_embedded() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        let nginx = fake_nginx(
+            tmp.path(),
+            r#"sed -n 's/^ *include \(.*\);$/\1/p' "$conf" | while read -r f; do
+    echo "${f#"$prefix"/}"
+    cat "$f"
+done
+"#,
+        );
+
+        fs::create_dir_all(tmp.join("conf")).expect("create conf dir");
+        touch!(tmp.join("conf/extra.conf"), "# http\n");
+        touch!(tmp.join("main.conf"), "# main\n");
+
+        let out = build(
+            &tmp,
+            &[
+                "-I",
+                "lib",
+                "--http-include",
+                "conf/extra.conf",
+                "--main-include",
+                "main.conf",
+                "tool.lua",
+            ],
+        );
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "tool.lua".to_string(),
+                "lib/util.lua".to_string(),
+                tmp.join("conf/extra.conf").display().to_string(),
+                tmp.join("main.conf").display().to_string(),
+                "wrote tool".to_string(),
+            ],
+            out.stdout_lines()
+        );
+
+        // the executable doesn't need the original files
+        fs::remove_dir_all(tmp.join("conf")).expect("remove conf dir");
+        fs::remove_file(tmp.join("main.conf")).expect("remove main.conf");
+
+        let out = tool(&tmp, &[])
+            .env("RUSTY_CLI_NGINX", &nginx)
+            .assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "bundle/include/main/1-main.conf",
+                "# main",
+                "bundle/include/http/1-extra.conf",
+                "# http",
+            ],
+            out.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn output_is_the_script() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        touch!(tmp.join("script"), "print(1)\n");
+
+        let out = build(&tmp, &["script"]);
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            vec![
+                "ERROR: Invalid --output option value: script",
+                "  (that is the Lua file)"
+            ],
+            out.stderr_lines()
+        );
+    }
+}
@@ -0,0 +1,191 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod bundle {
+    use super::*;
+
+    /// A stand-in for nginx that prints the `arg` table and the shdicts from
+    /// the generated config, and the bundled modules that were extracted into
+    /// the prefix.
+    const FAKE_NGINX: &str = r#"sed -n 's/^ *\(arg\[.*\)$/\1/p' "$conf"
+sed -n 's/^ *\(lua_shared_dict .*\)$/\1/p' "$conf" | sort -u
+lua="$prefix/bundle/lua"
+if grep -q "lua_package_path \"$lua/" "$conf"; then
+    cat "$lua/util/init.lua" "$lua/util/name.lua"
+fi
+"#;
+
+    fn setup(tmp: &TmpDir) {
+        fake_nginx(tmp.path(), FAKE_NGINX);
+
+        fs::create_dir_all(tmp.join("lib/util")).expect("create lib dir");
+        touch!(
+            tmp.join("tool.lua"),
+            "--! shdict: locks 1m\nlocal util = require \"util\"\nprint(util.name)\n"
+        );
+        touch!(
+            tmp.join("lib/util/init.lua"),
+            "return { name = require(\"util.name\") }\n"
+        );
+        touch!(tmp.join("lib/util/name.lua"), "return \"util\"\n");
+        touch!(tmp.join("lib/unused.lua"), "return {}\n");
+    }
+
+    fn rusty(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.args(["--nginx", tmp.join("nginx").to_str().unwrap()]);
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    fn bundle(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.arg("bundle");
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    #[test]
+    fn bundle_and_run() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+
+        let out = bundle(&tmp, &["-I", "lib", "--shdict", "cache 10m", "tool.lua"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "tool.lua",
+                "lib/util/init.lua",
+                "lib/util/name.lua",
+                "wrote tool.rusty",
+            ],
+            out.stdout_lines()
+        );
+
+        let bundled = rusty(&tmp, &["tool.rusty", "a", "b"]);
+        assert_eq!(Some(0), bundled.status.code());
+        assert_empty!(bundled.stderr_lines());
+
+        // the shdicts from the manifest and from the script's directives
+        assert_eq!(
+            vec![
+                "lua_shared_dict cache 10m;",
+                "lua_shared_dict locks 1m;",
+                "return { name = require(\"util.name\") }",
+                "return \"util\"",
+            ],
+            bundled.stdout_lines()[4..]
+        );
+
+        // the same `arg` table as the script itself, except for `arg[0]`
+        let plain = rusty(&tmp, &["tool.lua", "a", "b"]);
+        assert_eq!(Some(0), plain.status.code());
+        assert_eq!("arg[0] = [=[tool.rusty]=]", bundled.stdout_lines()[0]);
+        assert_eq!("arg[0] = [=[tool.lua]=]", plain.stdout_lines()[0]);
+        assert_eq!(plain.stdout_lines()[1..4], bundled.stdout_lines()[1..4]);
+        assert_eq!("arg[1] = [=[a]=]", bundled.stdout_lines()[1]);
+    }
+
+    #[test]
+    fn run_directly() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+
+        let out = bundle(&tmp, &["-I", "lib", "tool.lua", "-o", "tool"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(
+            Some("wrote tool"),
+            out.stdout_lines().last().map(String::as_str)
+        );
+
+        // the `#!` line finds rusty-cli in $PATH
+        let bin_dir = testlib::RUSTY.path().parent().unwrap().to_path_buf();
+        let mut cmd = Command::new(tmp.join("tool"));
+        cmd.current_dir(tmp.path());
+        cmd.env_clear();
+        cmd.env("PATH", format!("{}:/usr/bin:/bin", bin_dir.display()));
+        cmd.env("RUSTY_CLI_NGINX", tmp.join("nginx"));
+        cmd.arg("x");
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            format!("arg[0] = [=[{}]=]", tmp.join("tool").display()),
+            out.stdout_lines()[0]
+        );
+        assert_eq!("arg[1] = [=[x]=]", out.stdout_lines()[1]);
+    }
+
+    #[test]
+    fn invalid_bundle() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+
+        let out = bundle(&tmp, &["-I", "lib", "tool.lua"]);
+        assert_eq!(Some(0), out.status.code());
+
+        // a zip archive that looks like a bundle, but has no manifest
+        let mut data = fs::read(tmp.join("tool.rusty")).expect("read bundle");
+        let name = b"manifest.toml";
+        let mut renamed = 0;
+        for i in 0..data.len() - name.len() {
+            if data[i..].starts_with(name) {
+                data[i..i + name.len()].copy_from_slice(b"manifest.txt!");
+                renamed += 1;
+            }
+        }
+        // the local header and the central directory
+        assert_eq!(2, renamed);
+        fs::write(tmp.join("bad.rusty"), data).expect("write bad bundle");
+
+        let out = rusty(&tmp, &["bad.rusty"]);
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["ERROR: invalid bundle bad.rusty: missing manifest"],
+            out.stderr_lines()
+        );
+    }
+
+    #[test]
+    fn dynamic_modules() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        touch!(
+            tmp.join("dynamic.lua"),
+            "local name = \"util\"\nprint(require(name).name)\n"
+        );
+
+        let out = bundle(&tmp, &["-I", "lib", "dynamic.lua"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(
+            vec!["dynamic.lua", "wrote dynamic.rusty"],
+            out.stdout_lines()
+        );
+
+        let out = bundle(&tmp, &["-I", "lib", "--module", "util", "dynamic.lua"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(
+            vec![
+                "dynamic.lua",
+                "lib/util/init.lua",
+                "lib/util/name.lua",
+                "wrote dynamic.rusty",
+            ],
+            out.stdout_lines()
+        );
+
+        let out = bundle(&tmp, &["-I", "lib", "--module", "nope", "dynamic.lua"]);
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["ERROR: failed reading Lua files: module `nope` not found"],
+            out.stderr_lines()
+        );
+    }
+}
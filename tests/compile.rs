@@ -0,0 +1,116 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod compile {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    /// A stand-in for nginx that "compiles" each file listed in the generated
+    /// config the way the real Lua code would report it, records whether
+    /// `--strip` was given in `$WORKDIR/strip`, and counts its runs in
+    /// `$WORKDIR/runs`.
+    const FAKE_NGINX: &str = r#"echo run >> "$WORKDIR/runs"
+sed -n 's/^ *local compile_strip = \(.*\)$/\1/p' "$conf" > "$WORKDIR/strip"
+sed -n 's/^ *{ \[=\[\(.*\)\]=\], \[=\[\(.*\)\]=\] },$/\1 \2/p' "$conf" \
+    | while read -r src dst; do
+        echo "bytecode of $src" > "$dst"
+        echo "$src -> $dst"
+    done
+"#;
+
+    fn setup(tmp: &TmpDir) {
+        fake_nginx(tmp.path(), FAKE_NGINX);
+
+        for file in ["lib/a.lua", "lib/nested/b.lua", "lib/notes.txt"] {
+            let path = tmp.join(file);
+            fs::create_dir_all(path.parent().unwrap()).expect("create lib dir");
+            touch!(&path, "return {}\n");
+        }
+    }
+
+    fn compile(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args(["compile", "--nginx", tmp.join("nginx").to_str().unwrap()]);
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    fn runs(tmp: &TmpDir) -> usize {
+        fs::read_to_string(tmp.join("runs"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    #[test]
+    fn compiles_stale_files() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+
+        let out = compile(&tmp, &["lib"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "lib/a.lua -> lib/a.ljbc",
+                "lib/nested/b.lua -> lib/nested/b.ljbc",
+            ],
+            out.stdout_lines()
+        );
+        assert_eq!(
+            "bytecode of lib/a.lua\n",
+            fs::read_to_string(tmp.join("lib/a.ljbc")).expect("bytecode")
+        );
+        assert_eq!("false\n", fs::read_to_string(tmp.join("strip")).unwrap());
+        assert!(!tmp.join("lib/notes.ljbc").exists());
+
+        // nothing to do, so nginx isn't started
+        let out = compile(&tmp, &["lib"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(vec!["2 files up to date"], out.stdout_lines());
+        assert_eq!(1, runs(&tmp));
+
+        // only the file that changed
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(tmp.join("lib/nested/b.lua"))
+            .and_then(|f| f.set_modified(later))
+            .expect("set mtime");
+
+        let out = compile(&tmp, &["lib"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(
+            vec!["1 file up to date", "lib/nested/b.lua -> lib/nested/b.ljbc"],
+            out.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn force_and_strip() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+
+        assert_eq!(Some(0), compile(&tmp, &["lib"]).status.code());
+
+        let out = compile(&tmp, &["--force", "--strip", "lib/a.lua"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(vec!["lib/a.lua -> lib/a.ljbc"], out.stdout_lines());
+        assert_eq!("true\n", fs::read_to_string(tmp.join("strip")).unwrap());
+    }
+
+    #[test]
+    fn no_lua_files() {
+        let tmp = testlib::tmpdir();
+        setup(&tmp);
+        fs::create_dir(tmp.join("empty")).expect("create dir");
+
+        let out = compile(&tmp, &["empty"]);
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(vec!["ERROR: no *.lua files found."], out.stderr_lines());
+        assert_eq!(0, runs(&tmp));
+    }
+}
@@ -0,0 +1,75 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod completions {
+    use super::*;
+
+    fn completions(shell: &str, compat: Option<&str>) -> String {
+        let mut cmd = testlib::RUSTY.cmd();
+        if let Some(version) = compat {
+            cmd.env("RESTY_CLI_COMPAT_VERSION", version);
+        }
+        cmd.args(["--completions", shell]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(lines(out.stderr));
+        String::from_utf8(out.stdout).expect("utf-8 output")
+    }
+
+    fn syntax_check(shell: &str, script: &str) {
+        let tmp = testlib::tmpdir();
+        let file = tmp.join(format!("completions.{shell}"));
+        touch!(&file, script);
+
+        let Ok(out) = Command::new(shell).arg("-n").arg(&file).output() else {
+            eprintln!("{shell} not found, skipping syntax check");
+            return;
+        };
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    #[test]
+    fn valid_scripts() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = completions(shell, None);
+            assert!(script.contains("errlog-level"));
+            syntax_check(shell, &script);
+        }
+    }
+
+    #[test]
+    fn compat_version() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = completions(shell, Some("0.30"));
+            assert!(!script.contains("load-module"), "{script}");
+            assert!(!script.contains("dump-nginx-conf"), "{script}");
+
+            let script = completions(shell, Some("0.31"));
+            assert!(script.contains("load-module"), "{script}");
+            assert!(!script.contains("dump-nginx-conf"), "{script}");
+
+            let script = completions(shell, Some("0.32"));
+            assert!(script.contains("load-module"), "{script}");
+            assert!(script.contains("dump-nginx-conf"), "{script}");
+        }
+    }
+
+    #[test]
+    fn invalid_shell() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--completions", "tcsh"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            "ERROR: Invalid --completions option value: tcsh",
+            lines(out.stderr)[0]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod config {
+    use super::*;
+
+    struct Project {
+        tmp: TmpDir,
+        home: PathBuf,
+        cwd: PathBuf,
+    }
+
+    impl Project {
+        fn new() -> Self {
+            let tmp = testlib::tmpdir();
+            let home = tmp.join("home");
+            let cwd = tmp.join("project/src");
+
+            fs::create_dir_all(home.join(".config/rusty-cli")).expect("create user config dir");
+            fs::create_dir_all(&cwd).expect("create project dir");
+
+            Self { tmp, home, cwd }
+        }
+
+        fn user_config(&self, src: &str) {
+            touch!(self.home.join(".config/rusty-cli/config.toml"), src);
+        }
+
+        fn profile(&self, name: &str, src: &str) {
+            let dir = self.home.join(".config/rusty-cli/profiles");
+            fs::create_dir_all(&dir).expect("create profile dir");
+            touch!(dir.join(name), src);
+        }
+
+        fn project_config(&self, src: &str) {
+            touch!(self.tmp.join("project/.rustyrc"), src);
+        }
+
+        fn cmd(&self) -> Command {
+            let mut cmd = testlib::RUSTY.cmd();
+            cmd.env("HOME", &self.home);
+            cmd.current_dir(&self.cwd);
+            cmd
+        }
+    }
+
+    #[test]
+    fn layered_config() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let project = Project::new();
+        project.user_config(&format!(
+            "nginx = {:?}\nshdict = ['user 1m']\nerrlog-level = 'info'\ninclude = ['lib']\n",
+            nginx.as_str()
+        ));
+        project.project_config("shdict = ['project 2m']\n");
+
+        let mut cmd = project.cmd();
+        cmd.args(["--shdict", "cli 3m", "--errlog-level", "crit", "-e", "1"]);
+
+        let conf = cmd.stdout_lines();
+        let lib = project.home.join(".config/rusty-cli/lib");
+
+        assert_all_matched!(
+            vec![
+                "lua_shared_dict user 1m;".to_string(),
+                "lua_shared_dict project 2m;".to_string(),
+                "lua_shared_dict cli 3m;".to_string(),
+                format!("{}/?.lua", lib.display()),
+                "error_log stderr crit;".to_string(),
+            ],
+            conf
+        );
+
+        let mut cmd = project.cmd();
+        cmd.args(["--no-config", "--nginx", nginx.as_str(), "-e", "1"]);
+
+        let conf = cmd.stdout_lines();
+        assert!(!conf.iter().any(|line| line.contains("lua_shared_dict")));
+    }
+
+    #[test]
+    fn print_config() {
+        let project = Project::new();
+        project.user_config("nginx = 'openresty'\n");
+        project.project_config("no-stream = true\n");
+
+        let mut cmd = project.cmd();
+        cmd.arg("--print-config");
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+
+        let stdout = lines(out.stdout);
+        assert_all_matched!(
+            vec![
+                "# config files (lowest precedence first):",
+                ".config/rusty-cli/config.toml",
+                "project/.rustyrc",
+                "nginx = \"openresty\"",
+                "no-stream = true",
+            ],
+            stdout
+        );
+
+        let mut cmd = project.cmd();
+        cmd.args(["--no-config", "--print-config"]);
+        assert_eq!(vec!["# no config files found"], cmd.stdout_lines());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let project = Project::new();
+        project.project_config("no-stream = true\nbogus = 1\n");
+
+        let mut cmd = project.cmd();
+        cmd.args(["-e", "1"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+
+        let stderr = lines(out.stderr);
+        let expected = format!(
+            "ERROR: invalid config file {}:2: unknown key `bogus`",
+            project.tmp.join("project/.rustyrc").display()
+        );
+        assert_eq!(vec![expected], stderr);
+
+        // a checkout can't pick the programs that get run
+        let project = Project::new();
+        project.project_config("no-stream = true\nnginx = 'sh'\n");
+
+        let mut cmd = project.cmd();
+        cmd.args(["-e", "1"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+
+        let stderr = lines(out.stderr);
+        let expected = format!(
+            "ERROR: invalid config file {}:2: `nginx` can't be set in .rustyrc (use the user config file or a profile)",
+            project.tmp.join("project/.rustyrc").display()
+        );
+        assert_eq!(vec![expected], stderr);
+
+        // help and version output don't need a working config
+        for args in [
+            &["-h"][..],
+            &["--help"],
+            &["--man"],
+            &["--completions", "bash"],
+        ] {
+            let mut cmd = project.cmd();
+            cmd.args(args);
+
+            let out = cmd.assert_output();
+            assert_eq!(Some(0), out.status.code(), "{args:?}");
+            assert_empty!(out.stderr_lines());
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let project = Project::new();
+        project.user_config(&format!("nginx = {:?}\n", nginx.as_str()));
+        project.profile("bare.toml", "no-stream = true\nerrlog-level = 'notice'\n");
+        project.profile("debug", "errlog-level = 'debug'\ngdb = true\n");
+
+        let mut cmd = project.cmd();
+        cmd.args(["--profile", "bare", "-e", "1"]);
+
+        let conf = cmd.stdout_lines();
+        assert!(conf
+            .iter()
+            .any(|line| line.contains("error_log stderr notice;")));
+        assert!(!conf.iter().any(|line| line.contains("stream {")));
+
+        let mut cmd = project.cmd();
+        cmd.arg("--help");
+        let help = cmd.stdout_lines();
+        assert!(
+            help.iter()
+                .any(|line| line.ends_with("[available: bare, debug]")),
+            "{help:#?}"
+        );
+
+        let mut cmd = project.cmd();
+        cmd.args([
+            "--profile",
+            "bare",
+            "--profile",
+            "debug",
+            "--valgrind",
+            "-e",
+            "1",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(25), out.status.code());
+        assert_eq!(
+            vec!["ERROR: options --gdb (from profile debug) and --valgrind cannot be specified at the same time."],
+            lines(out.stderr)
+        );
+
+        let mut cmd = project.cmd();
+        cmd.args(["--profile", "nope", "-e", "1"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            "ERROR: Invalid --profile option value: nope",
+            lines(out.stderr)[0]
+        );
+    }
+
+    #[test]
+    fn env_defaults() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let project = Project::new();
+        project.user_config("errlog-level = 'info'\nshdict = ['config 1m']\n");
+
+        let mut cmd = project.cmd();
+        cmd.env("RUSTY_CLI_NGINX", nginx.as_str());
+        cmd.env("RUSTY_CLI_SHDICT", "env_a 1m;env_b 2m");
+        cmd.env("RUSTY_CLI_ERRLOG_LEVEL", "warn");
+        cmd.env("RUSTY_CLI_NO_STREAM", "1");
+        cmd.args(["--errlog-level", "alert", "-e", "1"]);
+
+        let conf = cmd.stdout_lines();
+        assert_all_matched!(
+            vec![
+                "lua_shared_dict config 1m;",
+                "lua_shared_dict env_a 1m;",
+                "lua_shared_dict env_b 2m;",
+                "error_log stderr alert;",
+            ],
+            conf
+        );
+        assert!(!conf.iter().any(|line| line.contains("stream {")));
+
+        let mut cmd = project.cmd();
+        cmd.env("RUSTY_CLI_NGINX", nginx.as_str());
+        cmd.env("RUSTY_CLI_ERRLOG_LEVEL", "loud");
+        cmd.args(["-e", "1"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            "ERROR: Invalid --errlog-level (from $RUSTY_CLI_ERRLOG_LEVEL) option value: loud",
+            lines(out.stderr)[0]
+        );
+
+        let mut cmd = project.cmd();
+        cmd.env("RUSTY_CLI_ERRLOG_LEVEL", "loud");
+        cmd.args(["--no-config", "--nginx", nginx.as_str(), "-e", "1"]);
+        assert!(cmd
+            .stdout_lines()
+            .iter()
+            .any(|line| line.contains("error_log stderr warn;")));
+    }
+}
@@ -0,0 +1,76 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod coredump {
+    use super::*;
+    use std::env;
+
+    fn core_pattern_is_piped() -> bool {
+        fs::read_to_string("/proc/sys/kernel/core_pattern").is_ok_and(|p| p.starts_with('|'))
+    }
+
+    #[test]
+    fn save_core_and_backtrace() {
+        if core_pattern_is_piped() {
+            eprintln!("skipping: core dumps are piped to a handler");
+            return;
+        }
+
+        let tmp = testlib::tmpdir();
+        let bin = tmp.join("bin");
+        fs::create_dir(&bin).expect("create bin dir");
+
+        let nginx = script(tmp.join("crash"), "kill -SEGV $$");
+        script(bin.join("gdb"), r#"echo "fake gdb: $*""#);
+
+        let path = format!("{}:{}", bin.display(), env::var("PATH").unwrap());
+        let saved = tmp.join("saved");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("PATH", path);
+        cmd.arg("--nginx").arg(&nginx);
+        cmd.arg("--save-core").arg(&saved);
+        cmd.args(["--backtrace", "-e", "nothing"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(139), out.status.code());
+
+        let stderr = out.stderr_lines();
+        assert!(
+            stderr[0].ends_with("was killed by SIGSEGV and dumped core"),
+            "{stderr:?}"
+        );
+
+        let saved_line = stderr
+            .iter()
+            .find_map(|line| line.strip_prefix("core dump saved to "))
+            .expect("core dump is saved");
+        assert!(PathBuf::from(saved_line).starts_with(&saved));
+        assert!(PathBuf::from(saved_line).is_file());
+
+        let gdb_line = stderr
+            .iter()
+            .find_map(|line| line.strip_prefix("fake gdb: "))
+            .expect("gdb is run");
+        assert!(gdb_line.starts_with("-batch -x "), "{gdb_line}");
+        assert!(
+            gdb_line.contains(&format!(" {} ", nginx.display())),
+            "{gdb_line}"
+        );
+    }
+
+    #[test]
+    fn backtrace_conflicts_with_runner() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--backtrace", "--rr", "-e", "nothing"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(25), out.status.code());
+        assert_eq!(
+            vec!["ERROR: options --backtrace and --rr cannot be specified at the same time."],
+            out.stderr_lines()
+        );
+    }
+}
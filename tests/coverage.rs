@@ -0,0 +1,85 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod coverage {
+    use super::*;
+
+    /// A stand-in for nginx that records hits for lines of main.lua and
+    /// lib/util.lua in the file named by `cov_results`, the way the generated
+    /// Lua code would, and saves the include/exclude patterns in
+    /// `$WORKDIR/patterns`.
+    const FAKE_NGINX: &str = r#"grep -q '^ *local cov_wrap = function(run)$' "$conf" || exit 0
+sed -n 's/^ *local cov_\(include\|exclude\) = \(.*\)$/\1 \2/p' "$conf" > "$WORKDIR/patterns"
+results=$(sed -n 's/^ *local cov_results = \[=\[\(.*\)\]=\]$/\1/p' "$conf")
+printf '@main.lua\n1 1\n2 3\n@lib/util.lua\n2 1\n' > "$results"
+"#;
+
+    fn run(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let nginx = fake_nginx(tmp.path(), FAKE_NGINX);
+
+        touch!(
+            tmp.join("main.lua"),
+            "local util = require \"util\"\nutil.run()\n"
+        );
+        fs::create_dir_all(tmp.join("lib")).expect("create lib dir");
+        touch!(
+            tmp.join("lib/util.lua"),
+            "-- util\nlocal M = {}\nfunction M.run()\n    return 1\nend\nreturn M\n"
+        );
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args(["--nginx", nginx.to_str().unwrap()]);
+        cmd.args(args);
+        cmd.arg("main.lua");
+        cmd.assert_output()
+    }
+
+    #[test]
+    fn reports() {
+        let tmp = testlib::tmpdir();
+        let out = run(
+            &tmp,
+            &[
+                "--coverage",
+                "--coverage-dir",
+                "cov",
+                "--coverage-include",
+                "%.lua$",
+                "--coverage-exclude",
+                "_spec%.lua$",
+            ],
+        );
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        assert_eq!(
+            "include { [=[%.lua$]=] }\nexclude { [=[_spec%.lua$]=] }\n",
+            fs::read_to_string(tmp.join("patterns")).expect("patterns")
+        );
+
+        assert_eq!(
+            "2:lib/util.lua\n0 1 \n2:main.lua\n1 3 \n",
+            fs::read_to_string(tmp.join("cov/luacov.stats.out")).expect("luacov stats")
+        );
+
+        assert_eq!(
+            "TN:\nSF:lib/util.lua\nDA:2,1\nDA:3,0\nDA:4,0\nDA:6,0\nLF:4\nLH:1\nend_of_record\n\
+             TN:\nSF:main.lua\nDA:1,1\nDA:2,3\nLF:2\nLH:2\nend_of_record\n",
+            fs::read_to_string(tmp.join("cov/lcov.info")).expect("lcov tracefile")
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let tmp = testlib::tmpdir();
+        let out = run(&tmp, &[]);
+        assert_eq!(Some(0), out.status.code());
+
+        assert!(!tmp.join("patterns").exists());
+        assert!(!tmp.join("luacov.stats.out").exists());
+        assert!(!tmp.join("lcov.info").exists());
+    }
+}
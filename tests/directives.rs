@@ -0,0 +1,101 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod directives {
+    use super::*;
+
+    fn script(tmp: &TmpDir) -> PathBuf {
+        let dir = tmp.join("app");
+        fs::create_dir(&dir).expect("create script dir");
+
+        let path = dir.join("run.lua");
+        touch!(
+            &path,
+            "--! shdict: cache 10m\n\
+             --! include: ./lib\n\
+             --! errlog-level: info\n\
+             --! http-conf: lua_socket_log_errors off;\n\
+             \n\
+             ngx.say('hi')\n"
+        );
+        path
+    }
+
+    #[test]
+    fn directives_are_applied() {
+        let tmp = testlib::tmpdir();
+        let script = script(&tmp);
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--nginx", nginx.as_str()]);
+        cmd.args(["--errlog-level", "crit", "--shdict", "cli 1m"]);
+        cmd.arg(&script);
+
+        let conf = cmd.stdout_lines();
+        assert_all_matched!(
+            vec![
+                "lua_shared_dict cache 10m;".to_string(),
+                "lua_shared_dict cli 1m;".to_string(),
+                format!("{}/lib/?.lua", tmp.join("app").display()),
+                "lua_socket_log_errors off;".to_string(),
+                "error_log stderr crit;".to_string(),
+            ],
+            conf
+        );
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--nginx", nginx.as_str(), "--no-directives"]);
+        cmd.arg(&script);
+
+        let conf = cmd.stdout_lines();
+        assert!(!conf.iter().any(|line| line.contains("cache 10m")));
+        assert!(conf
+            .iter()
+            .any(|line| line.contains("error_log stderr warn;")));
+    }
+
+    #[test]
+    fn invalid_directive() {
+        let tmp = testlib::tmpdir();
+        let script = tmp.join("bad.lua");
+        touch!(
+            &script,
+            "--! shdict: cache 10m\n--! no-stream: maybe\nprint(1)\n"
+        );
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.arg(&script);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec![format!(
+                "ERROR: invalid directive in {}:2: no-stream: expected a boolean",
+                script.display()
+            )],
+            lines(out.stderr)
+        );
+    }
+
+    #[test]
+    fn other_header_comments_are_ignored() {
+        let tmp = testlib::tmpdir();
+        let script = tmp.join("strict.lua");
+        touch!(
+            &script,
+            "--! strict\n--! Note: runs under resty-cli too\n--! shdict: cache 10m\nprint(1)\n"
+        );
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--nginx", nginx.as_str()]);
+        cmd.arg(&script);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_all_matched!(vec!["lua_shared_dict cache 10m;"], out.stdout_lines());
+    }
+}
@@ -0,0 +1,84 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod lua_profile {
+    use super::*;
+
+    /// A stand-in for nginx that records some sampled stacks in the file
+    /// named by `prof_results`, the way the generated Lua code would, and
+    /// saves the profiler settings in `$WORKDIR/settings`.
+    const FAKE_NGINX: &str = r#"grep -q '^ *local prof_wrap = function(run)$' "$conf" || exit 0
+sed -n 's/^ *local prof_\(interval\|depth\) = \(.*\)$/\1 \2/p' "$conf" > "$WORKDIR/settings"
+results=$(sed -n 's/^ *local prof_results = \[=\[\(.*\)\]=\]$/\1/p' "$conf")
+printf 'main.lua:1;main.lua:work 3\nmain.lua:1 1\n' > "$results"
+"#;
+
+    fn run(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let nginx = fake_nginx(tmp.path(), FAKE_NGINX);
+        touch!(tmp.join("main.lua"), "print(1)\n");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args(["--nginx", nginx.to_str().unwrap()]);
+        cmd.args(args);
+        cmd.arg("main.lua");
+        cmd.assert_output()
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let tmp = testlib::tmpdir();
+        let out = run(&tmp, &["-j", "p"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        assert_eq!(
+            "interval 10\ndepth 64\n",
+            fs::read_to_string(tmp.join("settings")).expect("settings")
+        );
+
+        assert_eq!(
+            "main.lua:1 1\nmain.lua:1;main.lua:work 3\n",
+            fs::read_to_string(tmp.join("lua-profile.folded")).expect("folded stacks")
+        );
+    }
+
+    #[test]
+    fn flame_graph() {
+        let tmp = testlib::tmpdir();
+        let out = run(
+            &tmp,
+            &[
+                "--lua-profile",
+                "flame.svg",
+                "--lua-profile-interval",
+                "1",
+                "--lua-profile-depth",
+                "8",
+            ],
+        );
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+
+        assert_eq!(
+            "interval 1\ndepth 8\n",
+            fs::read_to_string(tmp.join("settings")).expect("settings")
+        );
+
+        let svg = fs::read_to_string(tmp.join("flame.svg")).expect("flame graph");
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("<title>main.lua:work (3 samples, 75.00%)</title>"));
+        assert!(!tmp.join("lua-profile.folded").exists());
+    }
+
+    #[test]
+    fn disabled() {
+        let tmp = testlib::tmpdir();
+        let out = run(&tmp, &[]);
+        assert_eq!(Some(0), out.status.code());
+        assert!(!tmp.join("settings").exists());
+        assert!(!tmp.join("lua-profile.folded").exists());
+    }
+}
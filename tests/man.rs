@@ -0,0 +1,68 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod man {
+    use super::*;
+
+    fn run(args: &[&str], compat: &str) -> std::process::Output {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.env("RESTY_CLI_COMPAT_VERSION", compat);
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    #[test]
+    fn man_page() {
+        let out = run(&["--man"], "0.32");
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(lines(out.stderr));
+
+        let page = lines(out.stdout);
+        assert!(page[0].starts_with(".TH RUSTY\\-CLI 1 "), "{page:#?}");
+        assert_all_matched!(
+            vec![
+                ".SH OPTIONS",
+                "\\fB\\-\\-load\\-module\\fR \\fIMOD\\fR",
+                "\\fB\\-\\-dump\\-nginx\\-conf\\fR",
+                "\\fB\\-j\\fR \\fBdump\\fR",
+                ".SH ENVIRONMENT",
+                ".SH FILES",
+            ],
+            page
+        );
+    }
+
+    #[test]
+    fn compat_version() {
+        let out = run(&["--man"], "0.30");
+        let page = String::from_utf8(out.stdout).unwrap();
+        assert!(!page.contains("load\\-module"));
+        assert!(!page.contains("dump\\-nginx\\-conf"));
+
+        let out = run(&["--help"], "0.30");
+        let help = String::from_utf8(out.stdout).unwrap();
+        assert!(!help.contains("--load-module"));
+        assert!(!help.contains("--dump-nginx-conf"));
+
+        // hidden options are still accepted
+        let out = run(
+            &[
+                "--load-module",
+                "ngx_foo.so",
+                "--dump-nginx-conf",
+                "-e",
+                "1",
+            ],
+            "0.28",
+        );
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(lines(out.stderr));
+        assert_all_matched!(vec!["load_module ngx_foo.so;"], lines(out.stdout));
+
+        let out = run(&["--help"], "0.31");
+        let help = String::from_utf8(out.stdout).unwrap();
+        assert!(help.contains("\n      --load-module <MOD>\n"));
+        assert!(!help.contains("--dump-nginx-conf"));
+    }
+}
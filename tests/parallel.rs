@@ -0,0 +1,132 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod parallel {
+    use super::*;
+
+    /// A stand-in for nginx that prints the name of the Lua file that it was
+    /// given and records each run in `$WORKDIR/runs`.
+    fn fake_nginx(tmp: &TmpDir, then: &str) -> PathBuf {
+        testlib::fake_nginx(
+            tmp.path(),
+            &format!(
+                r#"file=$(sed -n 's/^ *local fname = \[=\[\(.*\)\]=\]$/\1/p' "$conf")
+echo "$file" >> "$WORKDIR/runs"
+echo "stdout of $file"
+echo "stderr of $file" >&2
+{then}
+"#
+            ),
+        )
+    }
+
+    fn parallel(tmp: &TmpDir, then: &str, n: &str, scripts: &[&str]) -> Command {
+        let nginx = fake_nginx(tmp, then);
+        for script in scripts {
+            touch!(tmp.join(script), "print(1)\n");
+        }
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args(["--nginx", nginx.to_str().unwrap(), "--parallel", n]);
+        cmd.args(scripts);
+        cmd
+    }
+
+    /// The Lua files that have been run, sorted.
+    fn runs(tmp: &TmpDir) -> Vec<String> {
+        let mut runs: Vec<String> = fs::read_to_string(tmp.join("runs"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect();
+        runs.sort();
+        runs
+    }
+
+    #[test]
+    fn tagged_output_and_summary() {
+        let tmp = testlib::tmpdir();
+        let mut cmd = parallel(
+            &tmp,
+            r#"case "$file" in b.lua) exit 3;; esac"#,
+            "2",
+            &["a.lua", "b.lua", "c.lua"],
+        );
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(3), out.status.code());
+
+        let mut stdout = out.stdout_lines();
+        stdout.sort();
+        assert_eq!(
+            vec![
+                "[a.lua] stdout of a.lua",
+                "[b.lua] stdout of b.lua",
+                "[c.lua] stdout of c.lua",
+            ],
+            stdout
+        );
+
+        let stderr = out.stderr_lines();
+        assert_eq!(7, stderr.len(), "{stderr:#?}");
+        for script in ["a.lua", "b.lua", "c.lua"] {
+            assert!(stderr.contains(&format!("[{script}] stderr of {script}")));
+        }
+
+        assert_eq!("SCRIPT  EXIT  TIME", stderr[3]);
+        assert!(stderr[4].starts_with("a.lua      0  "), "{}", stderr[4]);
+        assert!(stderr[5].starts_with("b.lua      3  "), "{}", stderr[5]);
+        assert!(stderr[6].starts_with("c.lua      0  "), "{}", stderr[6]);
+    }
+
+    #[test]
+    fn sigint_stops_every_run() {
+        let tmp = testlib::tmpdir();
+        let mut cmd = parallel(&tmp, "exec sleep 30", "2", &["a.lua", "b.lua", "c.lua"]);
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+
+        let proc = cmd.spawn().expect("command spawned");
+        let _cleanup = testlib::cleanup_proc(&proc);
+
+        for _ in 0..500 {
+            if runs(&tmp).len() >= 2 {
+                break;
+            }
+            testlib::sleep_ms(10);
+        }
+        assert_eq!(vec!["a.lua", "b.lua"], runs(&tmp));
+
+        kill(Pid::from_raw(proc.id() as i32), SIGINT).expect("kill()");
+        let out = proc.wait_with_output().expect("wait for rusty-cli");
+        assert_eq!(Some(130), out.status.code());
+
+        // nothing new is started after Ctrl-C
+        assert_eq!(vec!["a.lua", "b.lua"], runs(&tmp));
+
+        let stderr = out.stderr_lines();
+        let summary = &stderr[stderr.len() - 4..];
+        assert!(summary[1].starts_with("a.lua    130  "), "{stderr:#?}");
+        assert!(summary[2].starts_with("b.lua    130  "), "{stderr:#?}");
+        assert_eq!("c.lua      -  not run", summary[3]);
+    }
+
+    #[test]
+    fn invalid_args() {
+        let tmp = testlib::tmpdir();
+
+        let out = parallel(&tmp, "", "0", &["a.lua"]).assert_output();
+        assert_eq!(Some(255), out.status.code());
+
+        let tmp = testlib::tmpdir();
+        let out = parallel(&tmp, "", "2", &[]).assert_output();
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["Neither Lua input file nor -e \"\" option specified."],
+            out.stderr_lines()
+        );
+    }
+}
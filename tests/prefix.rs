@@ -0,0 +1,111 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod prefix {
+    use super::*;
+
+    #[test]
+    fn user_prefix_is_kept() {
+        let tmp = testlib::tmpdir();
+        let prefix = tmp.join("my-prefix");
+
+        let nginx = testlib::testbin("print_args");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.arg("--prefix").arg(&prefix);
+        cmd.args(["--nginx", nginx.as_str(), "-e", "nothing"]);
+
+        assert_all_matched!(vec![format!("{}/", prefix.display())], cmd.stdout_lines());
+
+        assert!(prefix.join("conf/nginx.conf").is_file());
+        assert!(prefix.join("conf/a.lua").is_file());
+        assert!(prefix.join("logs").is_dir());
+    }
+
+    #[test]
+    fn keep_prefix_on_error() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args([
+            "--nginx",
+            "false",
+            "--keep-prefix-on-error",
+            "-e",
+            "nothing",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(1), out.status.code());
+
+        let stderr = out.stderr_lines();
+        let kept = stderr
+            .iter()
+            .find_map(|line| line.strip_prefix("nginx prefix directory kept at "))
+            .expect("prefix location is printed");
+
+        let kept = PathBuf::from(kept);
+        assert!(kept.join("conf/nginx.conf").is_file());
+        fs::remove_dir_all(kept).expect("cleanup of kept prefix");
+    }
+
+    #[test]
+    fn temp_prefix_removed_on_success() {
+        let tmp = testlib::tmpdir();
+        let out_file = tmp.join("args");
+
+        let nginx = testlib::testbin("print_args");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args([
+            "--nginx",
+            nginx.as_str(),
+            "--keep-prefix-on-error",
+            "-e",
+            "nothing",
+        ]);
+        cmd.stdout(File::create(&out_file).expect("create output file"));
+        assert!(cmd.status().expect("run command").success());
+
+        let args = fs::read_to_string(out_file).expect("read output");
+        let prefix = args
+            .lines()
+            .skip_while(|line| !line.ends_with(" -p"))
+            .nth(1)
+            .and_then(|line| line.split_once(' '))
+            .map(|(_, prefix)| PathBuf::from(prefix))
+            .expect("prefix arg");
+
+        assert!(!prefix.exists());
+    }
+
+    #[test]
+    fn temp_prefix_in_tmpdir_env() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("print_args");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.env("RUSTY_CLI_TMPDIR", tmp.path());
+        cmd.env("TMPDIR", "/a/b/i-dont-exist");
+        cmd.args(["--nginx", nginx.as_str(), "-e", "nothing"]);
+
+        assert_all_matched!(
+            vec![format!("{}/resty_", tmp.path().display())],
+            cmd.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn invalid_tmpdir_env() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.env_remove("RUSTY_CLI_TMPDIR");
+        cmd.env("TMPDIR", "/a/b/i-dont-exist");
+        cmd.args(["-e", "nothing"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["failed creating prefix directory: invalid temporary directory /a/b/i-dont-exist (from $TMPDIR): directory does not exist"],
+            out.stderr_lines()
+        );
+    }
+}
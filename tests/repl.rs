@@ -0,0 +1,55 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod repl {
+    use super::*;
+
+    #[test]
+    fn chunks_from_piped_stdin() {
+        let tmp = testlib::tmpdir();
+        let fname = tmp.join("input");
+        touch!(&fname, "x = 1\n\nfor i = 1, 2 do\nend\nreturn x\n");
+
+        let nginx = testlib::testbin("repl_server");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.stdin(File::open(fname).expect("open input"));
+        cmd.args(["--nginx", nginx.as_str(), "--repl"]);
+
+        assert_eq!(
+            vec![
+                r#"CHUNK "x = 1""#,
+                r#"CHUNK "for i = 1, 2 do\nend""#,
+                r#"CHUNK "return x""#,
+            ],
+            cmd.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn repl_lua_in_nginx_conf() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.stdin(Stdio::null());
+        cmd.args(["--nginx", nginx.as_str(), "--repl", "--dump-nginx-conf"]);
+
+        assert_all_matched!(
+            vec![
+                "ngx.config.expand_table = expand_table",
+                "local repl_gen = function()",
+                "repl_gen()",
+            ],
+            cmd.stdout_lines()
+        );
+
+        // resty-cli doesn't expose it, so neither does a regular run
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--nginx", nginx.as_str(), "-e", "1", "--dump-nginx-conf"]);
+        assert!(!cmd
+            .stdout_lines()
+            .iter()
+            .any(|line| line.contains("ngx.config.expand_table")));
+    }
+}
@@ -0,0 +1,61 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod report {
+    use super::*;
+
+    fn fake_nginx(tmp: &TmpDir) -> PathBuf {
+        testlib::fake_nginx(
+            tmp.path(),
+            r#"grep -q 'error_log logs/error.log' "$conf" || exit 99
+echo '2024/01/01 00:00:00 [error] 1#0: "quoted" boom' >> "$prefix/logs/error.log"
+exit 3
+"#,
+        )
+    }
+
+    #[test]
+    fn report_for_failed_run() {
+        let tmp = testlib::tmpdir();
+        let nginx = fake_nginx(&tmp);
+        let report = tmp.join("report.json");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.arg("--nginx").arg(&nginx);
+        cmd.arg("--report-json").arg(&report);
+        cmd.args(["-e", "nothing"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(3), out.status.code());
+
+        let json = fs::read_to_string(&report).expect("read report");
+        let json: serde_json::Value = serde_json::from_str(&json).expect("parse report");
+
+        assert_eq!(json["nginx"], nginx.to_str().unwrap());
+        assert_eq!(json["exit_code"], 3);
+        assert_eq!(json["signal"], serde_json::Value::Null);
+        assert_eq!(json["handled"], false);
+        assert_eq!(json["core_dumped"], false);
+        assert_eq!(json["timed_out"], false);
+        assert_eq!(
+            json["error_log_tail"],
+            serde_json::json!([r#"2024/01/01 00:00:00 [error] 1#0: "quoted" boom"#])
+        );
+
+        let argv = json["argv"].as_array().expect("argv in report");
+        assert_eq!(argv[0], nginx.to_str().unwrap());
+        assert_eq!(argv[argv.len() - 2..], ["-c", "conf/nginx.conf"]);
+    }
+
+    #[test]
+    fn no_error_log_without_report() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--nginx", nginx.as_str(), "-e", "nothing"]);
+
+        let stdout = cmd.stdout_lines();
+        assert!(!stdout.iter().any(|line| line.contains("logs/error.log")));
+    }
+}
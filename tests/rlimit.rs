@@ -0,0 +1,42 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod rlimit {
+    use super::*;
+
+    #[test]
+    fn limits_applied_to_child() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args([
+            "--user-runner",
+            "sh -c 'ulimit -Sn; ulimit -Sc' sh",
+            "--rlimit-nofile",
+            "100",
+            "--rlimit-core",
+            "0",
+            "-e",
+            "nothing",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert_eq!(vec!["100", "0"], out.stdout_lines());
+    }
+
+    #[test]
+    fn invalid_limit() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--rlimit-nofile", "many", "-e", "nothing"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            vec![
+                "ERROR: Invalid --rlimit-nofile option value: many",
+                "  (expecting a number (with optional k/m/g suffix) or `unlimited`)",
+            ],
+            out.stderr_lines()
+        );
+    }
+}
@@ -0,0 +1,74 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod shebang {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(path: PathBuf, shebang: &str) -> PathBuf {
+        touch!(&path, format!("#!{shebang}\nprint('hi')\n"));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("chmod script");
+        path
+    }
+
+    #[test]
+    fn combined_shebang_args() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let scripts = tmp.join("scripts");
+        fs::create_dir(&scripts).expect("create scripts dir");
+
+        let path = script(
+            scripts.join("run.lua"),
+            &format!(
+                "{} --shdict \"cache 1m\" --relative-include -I lib --",
+                testlib::RUSTY_PATH
+            ),
+        );
+
+        // the kernel passes the script path as it was invoked
+        let mut cmd = path.cmd();
+        cmd.env_clear();
+        cmd.env("RUSTY_CLI_NGINX", nginx.as_str());
+        cmd.args(["a", "-b"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code(), "{out:?}");
+
+        assert_all_matched!(
+            vec![
+                "lua_shared_dict cache 1m;".to_string(),
+                format!("{}/lib/?.lua", scripts.display()),
+                format!("arg[0] = [=[{}]=]", path.display()),
+                "arg[1] = [=[a]=]".to_string(),
+                "arg[2] = [=[-b]=]".to_string(),
+                "if chunk:sub(1, 2) == \"#!\" then".to_string(),
+            ],
+            lines(out.stdout)
+        );
+    }
+
+    #[test]
+    fn quoted_option_value() {
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        // not a #! line, so the value is left alone
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args([
+            "--nginx",
+            nginx.as_str(),
+            "--shdict=cache 1m -I lib",
+            "-e",
+            "1",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(255), out.status.code());
+        assert_eq!(
+            "ERROR: Invalid --shdict option value: cache 1m -I lib",
+            lines(out.stderr)[0]
+        );
+    }
+}
@@ -64,18 +64,14 @@ fn sigtest(bin: testlib::Bin, run: fn(bin: testlib::Bin, ppid: Pid, pid: Pid) ->
     assert_eq!(exp_sigs, got_sigs, "signals $expected != $received",);
 }
 
+#[derive(Default)]
 enum ExpectedSignals {
+    #[default]
     None,
     AtLeast(usize, Signal),
     Exactly(Vec<Signal>),
 }
 
-impl Default for ExpectedSignals {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 impl ExpectedSignals {
     fn assert(self, got: Vec<Signal>) {
         match self {
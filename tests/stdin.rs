@@ -0,0 +1,64 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod stdin {
+    use super::*;
+
+    fn script_file(tmp: &TmpDir) -> File {
+        let fname = tmp.join("script.lua");
+        touch!(&fname, "print('hello from stdin')");
+        File::open(fname).expect("open script file")
+    }
+
+    #[test]
+    fn dash_reads_from_stdin() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.stdin(script_file(&tmp));
+        cmd.args(["--nginx", nginx.as_str(), "-", "a"]);
+
+        assert_all_matched!(
+            vec![
+                "arg[0] = [=[-]=]",
+                "arg[1] = [=[a]=]",
+                "stdin.lua]=]",
+                "local file_gen = assert(loadstring(chunk, [=[=stdin]=]))",
+            ],
+            cmd.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn implicit_stdin() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.stdin(script_file(&tmp));
+        cmd.args(["--nginx", nginx.as_str()]);
+
+        assert_all_matched!(
+            vec![
+                "arg[0] = [=[-]=]",
+                "local file_gen = assert(loadstring(chunk, [=[=stdin]=]))",
+            ],
+            cmd.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn no_input_from_dev_null() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.stdin(Stdio::null());
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["Neither Lua input file nor -e \"\" option specified."],
+            out.stderr_lines()
+        );
+    }
+}
@@ -0,0 +1,176 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod test_command {
+    use super::*;
+
+    /// A stand-in for nginx that reports one passing test for each test file
+    /// in the generated config (and a failing one for files named `bad_*`),
+    /// and counts its runs in `$WORKDIR/runs`.
+    const FAKE_NGINX: &str = r#"echo run >> "$WORKDIR/runs"
+echo "started"
+sed -n 's/^ *local spec_files = { \(.*\) }$/\1/p' "$conf" \
+    | tr ',' '\n' \
+    | sed 's/^ *\[=\[\(.*\)\]=\]$/\1/' \
+    | while read -r file; do
+        printf 'ok\t%s\tworks\t0.001000\t\n' "$file"
+        case "$file" in
+            *bad_*) printf 'fail\t%s\tbreaks\t0.002000\texpected 1, got 2\n' "$file";;
+        esac
+    done > "$prefix/logs/test-results"
+"#;
+
+    fn test_files(tmp: &TmpDir, files: &[&str]) {
+        for file in files {
+            let path = tmp.join(file);
+            fs::create_dir_all(path.parent().unwrap()).expect("create test dir");
+            touch!(&path, "");
+        }
+    }
+
+    fn run_tests(tmp: &TmpDir, args: &[&str]) -> std::process::Output {
+        let nginx = fake_nginx(tmp.path(), FAKE_NGINX);
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.current_dir(tmp.path());
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args(["test", "--nginx", nginx.to_str().unwrap()]);
+        cmd.args(args);
+        cmd.assert_output()
+    }
+
+    fn runs(tmp: &TmpDir) -> usize {
+        fs::read_to_string(tmp.join("runs"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    #[test]
+    fn lua_file_named_like_a_subcommand() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("print_nginx_conf");
+
+        for name in ["test", "compile", "bundle", "build-exe"] {
+            touch!(tmp.join(name), "print(1)\n");
+
+            let mut cmd = testlib::RUSTY.cmd();
+            cmd.current_dir(tmp.path());
+            cmd.env("RUSTY_CLI_NGINX", nginx.as_str());
+            cmd.args([name, "--help"]);
+
+            let out = cmd.assert_output();
+            assert_eq!(Some(0), out.status.code(), "{name}");
+            assert_empty!(out.stderr_lines());
+            assert_all_matched!(
+                vec![
+                    format!("local fname = [=[{name}]=]"),
+                    "arg[1] = [=[--help]=]".to_string(),
+                ],
+                out.stdout_lines()
+            );
+        }
+
+        // a directory named like a subcommand is not run as a Lua file
+        let tmp = testlib::tmpdir();
+        test_files(&tmp, &["test/a_spec.lua"]);
+
+        let out = run_tests(&tmp, &["test"]);
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "TAP version 13",
+                "# test/a_spec.lua",
+                "ok 1 - works",
+                "# started",
+                "1..1",
+                "# tests 1, passed 1, failed 0, errors 0, skipped 0",
+            ],
+            out.stdout_lines()
+        );
+    }
+
+    #[test]
+    fn passing() {
+        let tmp = testlib::tmpdir();
+        test_files(
+            &tmp,
+            &[
+                "spec/a_spec.lua",
+                "spec/nested/b_test.lua",
+                "spec/helpers.lua",
+            ],
+        );
+
+        let out = run_tests(&tmp, &[]);
+        assert_eq!(Some(0), out.status.code());
+        assert_empty!(out.stderr_lines());
+        assert_eq!(
+            vec![
+                "TAP version 13",
+                "# spec/a_spec.lua",
+                "ok 1 - works",
+                "# started",
+                "# spec/nested/b_test.lua",
+                "ok 2 - works",
+                "# started",
+                "1..2",
+                "# tests 2, passed 2, failed 0, errors 0, skipped 0",
+            ],
+            out.stdout_lines()
+        );
+        assert_eq!(2, runs(&tmp));
+    }
+
+    #[test]
+    fn failing_single_instance() {
+        let tmp = testlib::tmpdir();
+        test_files(&tmp, &["spec/a_spec.lua", "spec/bad_spec.lua"]);
+
+        let out = run_tests(
+            &tmp,
+            &["--single-instance", "--junit", "report.xml", "spec"],
+        );
+        assert_eq!(Some(1), out.status.code());
+        assert_eq!(
+            vec![
+                "TAP version 13",
+                "# spec/a_spec.lua",
+                "ok 1 - works",
+                "# spec/bad_spec.lua",
+                "ok 2 - works",
+                "not ok 3 - breaks",
+                "  ---",
+                "  message: |",
+                "    expected 1, got 2",
+                "  ...",
+                "# started",
+                "1..3",
+                "# tests 3, passed 2, failed 1, errors 0, skipped 0",
+            ],
+            out.stdout_lines()
+        );
+        assert_eq!(1, runs(&tmp));
+
+        let report = fs::read_to_string(tmp.join("report.xml")).expect("JUnit report");
+        assert!(report.contains(r#"<testsuites tests="3" failures="1" errors="0""#));
+        assert!(
+            report.contains(r#"<failure message="expected 1, got 2">expected 1, got 2</failure>"#)
+        );
+    }
+
+    #[test]
+    fn no_test_files() {
+        let tmp = testlib::tmpdir();
+
+        let out = run_tests(&tmp, &[]);
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["ERROR: no *_spec.lua or *_test.lua files found."],
+            out.stderr_lines()
+        );
+        assert_empty!(out.stdout_lines());
+    }
+}
@@ -0,0 +1,55 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod timeout {
+    use super::*;
+
+    #[test]
+    fn quit_then_kill_after_timeout() {
+        let tmp = testlib::tmpdir();
+        let nginx = testlib::testbin("signal_logger");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args([
+            "--nginx",
+            nginx.as_str(),
+            "--timeout",
+            "0.5",
+            "--timeout-grace",
+            "0.2",
+            "-e",
+            "nothing",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(124), out.status.code());
+        assert_eq!(
+            vec!["ERROR: nginx timed out after 0.5s"],
+            out.stderr_lines()
+        );
+
+        let signals = fs::read_to_string(tmp.join("signals")).expect("read signals");
+        assert_eq!(vec!["SIGQUIT"], signals.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn exit_before_timeout() {
+        let nginx = testlib::testbin("print_args");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args([
+            "--nginx",
+            nginx.as_str(),
+            "--timeout",
+            "30",
+            "-e",
+            "nothing",
+        ]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(0), out.status.code());
+        assert!(out.stderr_lines().is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+mod testlib;
+use testlib::*;
+
+#[integration]
+mod watch {
+    use super::*;
+
+    /// A stand-in for nginx that records each run in `$WORKDIR/runs`.
+    fn fake_nginx(tmp: &TmpDir, then: &str) -> PathBuf {
+        testlib::fake_nginx(
+            tmp.path(),
+            &format!("echo run >> \"$WORKDIR/runs\"\n{then}"),
+        )
+    }
+
+    fn wait_for_runs(tmp: &TmpDir, n: usize) {
+        for _ in 0..500 {
+            let runs = fs::read_to_string(tmp.join("runs")).unwrap_or_default();
+            if runs.lines().count() >= n {
+                return;
+            }
+            testlib::sleep_ms(10);
+        }
+        panic!("timed out waiting for run #{n}");
+    }
+
+    fn watch(tmp: &TmpDir, then: &str) -> (std::process::Child, PathBuf) {
+        let nginx = fake_nginx(tmp, then);
+        let script = tmp.join("main.lua");
+        touch!(&script, "print(1)\n");
+
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.env("WORKDIR", tmp.path());
+        cmd.args([
+            "--nginx",
+            nginx.to_str().unwrap(),
+            "--watch",
+            script.to_str().unwrap(),
+        ]);
+
+        (cmd.spawn().expect("command spawned"), script)
+    }
+
+    #[test]
+    fn rerun_on_change() {
+        let tmp = testlib::tmpdir();
+        let (proc, script) = watch(&tmp, "exec sleep 30");
+        let _cleanup = testlib::cleanup_proc(&proc);
+
+        wait_for_runs(&tmp, 1);
+        fs::write(&script, "print(2)\n").expect("update script");
+        wait_for_runs(&tmp, 2);
+
+        kill(Pid::from_raw(proc.id() as i32), SIGINT).expect("kill()");
+        let out = proc.wait_with_output().expect("wait for rusty-cli");
+        assert_eq!(Some(130), out.status.code());
+
+        assert_eq!(
+            vec![format!(
+                "----- {} changed, rerunning -----",
+                script.display()
+            )],
+            out.stderr_lines()
+        );
+    }
+
+    #[test]
+    fn wait_after_exit() {
+        let tmp = testlib::tmpdir();
+        let (proc, script) = watch(&tmp, "exit 3");
+        let _cleanup = testlib::cleanup_proc(&proc);
+
+        wait_for_runs(&tmp, 1);
+        testlib::sleep_ms(100);
+        fs::write(&script, "print(2)\n").expect("update script");
+        wait_for_runs(&tmp, 2);
+        testlib::sleep_ms(100);
+
+        // no nginx to forward the signal to, so it's the default action
+        kill(Pid::from_raw(proc.id() as i32), SIGTERM).expect("kill()");
+        let out = proc.wait_with_output().expect("wait for rusty-cli");
+        assert_eq!(None, out.status.code());
+
+        assert_eq!(
+            vec![
+                "----- exited with code 3, waiting for changes -----".to_string(),
+                format!("----- {} changed, rerunning -----", script.display()),
+                "----- exited with code 3, waiting for changes -----".to_string(),
+            ],
+            out.stderr_lines()
+        );
+    }
+
+    #[test]
+    fn nothing_to_watch() {
+        let mut cmd = testlib::RUSTY.cmd();
+        cmd.args(["--watch", "-e", "1"]);
+
+        let out = cmd.assert_output();
+        assert_eq!(Some(2), out.status.code());
+        assert_eq!(
+            vec!["ERROR: --watch needs a Lua file, -I directory, or include file to watch."],
+            out.stderr_lines()
+        );
+    }
+}